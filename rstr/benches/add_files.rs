@@ -0,0 +1,98 @@
+//! Compares the throughput of [`BlobStore::add_files_with_options`] against statically
+//! splitting the input in one chunk per thread, on a directory mixing a few large files
+//! with many small ones.
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use rstr::{AddOptions, BlobStore};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use tempfile::TempDir;
+
+const THREADS: usize = 4;
+const SMALL_FILES: usize = 400;
+const SMALL_FILE_SIZE: usize = 4 * 1024;
+const LARGE_FILES: usize = 4;
+const LARGE_FILE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Creates the input files. The large files come first so that splitting the sorted list
+/// in equal chunks puts all of them in the same chunk.
+fn mixed_files() -> (TempDir, Vec<PathBuf>) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut paths = vec![];
+    for i in 0..LARGE_FILES + SMALL_FILES {
+        let size = if i < LARGE_FILES {
+            LARGE_FILE_SIZE
+        } else {
+            SMALL_FILE_SIZE
+        };
+        let content: Vec<u8> = (0..size).map(|j| (i * 31 + j * 7) as u8).collect();
+        let path = dir.path().join(format!("{:05}.bin", i));
+        fs::write(&path, &content).unwrap();
+        paths.push(path);
+    }
+    (dir, paths)
+}
+
+/// The strategy used before the pipeline: one thread per equal chunk of the input.
+fn add_static_chunks(blob_store: &BlobStore, paths: &[PathBuf], threads: usize) {
+    let (tx, rx) = mpsc::channel();
+    let chunk_size = std::cmp::max(paths.len() / threads, 1);
+    for chunk in paths.chunks(chunk_size) {
+        let tx = tx.clone();
+        let chunk = chunk.to_owned();
+        let blob_store = blob_store.clone();
+        thread::spawn(move || {
+            for path in chunk {
+                tx.send(blob_store.add(&path).is_ok()).unwrap();
+            }
+        });
+    }
+    drop(tx);
+    assert!(rx.iter().all(|ok| ok));
+}
+
+fn fresh_store() -> (TempDir, BlobStore) {
+    let dir = tempfile::tempdir().unwrap();
+    let blob_store = BlobStore::new(dir.path()).unwrap();
+    (dir, blob_store)
+}
+
+fn bench_mixed_sizes(c: &mut Criterion) {
+    let (_input_dir, paths) = mixed_files();
+    let total_bytes = (LARGE_FILES * LARGE_FILE_SIZE + SMALL_FILES * SMALL_FILE_SIZE) as u64;
+
+    let mut group = c.benchmark_group("mixed_sizes");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(total_bytes));
+
+    group.bench_function("static_chunks", |b| {
+        b.iter_batched(
+            fresh_store,
+            |(_dir, blob_store)| add_static_chunks(&blob_store, &paths, THREADS),
+            BatchSize::PerIteration,
+        );
+    });
+
+    let options = AddOptions {
+        hash_threads: THREADS,
+        write_threads: THREADS,
+        queue_capacity: 64,
+    };
+    group.bench_function("pipeline", |b| {
+        b.iter_batched(
+            fresh_store,
+            |(_dir, blob_store)| {
+                let inputs: Vec<&Path> = paths.iter().map(PathBuf::as_path).collect();
+                let (_, errors) = blob_store.add_files_with_options(&inputs, &options);
+                assert!(errors.is_empty());
+            },
+            BatchSize::PerIteration,
+        );
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_mixed_sizes);
+criterion_main!(benches);
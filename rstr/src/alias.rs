@@ -0,0 +1,172 @@
+use super::error::{Error, Result};
+use super::models::BlobRef;
+use chrono::{offset::Utc, DateTime};
+use fs2::FileExt;
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Name of the directory, inside the blob store root, holding the aliases.
+const ALIASES_DIR: &str = "refs";
+
+/// Name of the file used to serialise updates to the aliases.
+const LOCK_FILE: &str = ".lock";
+
+/// An entry in the history of an alias, see [`BlobStore::alias_history`].
+///
+/// [`BlobStore::alias_history`]: crate::BlobStore::alias_history
+#[derive(Debug, Clone)]
+pub struct AliasEntry {
+    /// The blob the alias pointed to
+    pub target: BlobRef,
+    /// When the alias was set to point to `target`
+    pub updated: DateTime<Utc>,
+}
+
+/// Condition an alias must satisfy for [`set`] to update it.
+pub(crate) enum Precondition<'a> {
+    /// Always update the alias
+    Any,
+    /// Only create the alias if it does not exist
+    Missing,
+    /// Only update the alias if it currently points to the given blob
+    Target(&'a BlobRef),
+}
+
+/// Returns `true` if `name` is a valid alias name: one or more `/` separated segments
+/// made of ASCII letters, digits, `.`, `_` and `-`, not starting with a `.`.
+fn is_valid_name(name: &str) -> bool {
+    name.split('/').all(|segment| {
+        !segment.is_empty()
+            && !segment.starts_with('.')
+            && segment
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+    })
+}
+
+fn aliases_dir(root: &Path) -> PathBuf {
+    root.join(ALIASES_DIR)
+}
+
+fn alias_path(root: &Path, name: &str) -> Result<PathBuf> {
+    if !is_valid_name(name) {
+        return Err(Error::InvalidAlias);
+    }
+    Ok(aliases_dir(root).join(name))
+}
+
+fn parse_history(content: &str) -> Result<Vec<AliasEntry>> {
+    content
+        .lines()
+        .map(|line| {
+            let (updated, target) = line.split_once('\t').ok_or(Error::InvalidAlias)?;
+            let updated = DateTime::parse_from_rfc3339(updated).map_err(|_| Error::InvalidAlias)?;
+            Ok(AliasEntry {
+                target: BlobRef::new(target)?,
+                updated: updated.into(),
+            })
+        })
+        .collect()
+}
+
+/// Returns the history of the alias `name`, oldest first.
+pub(crate) fn history(root: &Path, name: &str) -> Result<Vec<AliasEntry>> {
+    match fs::read_to_string(alias_path(root, name)?) {
+        Ok(content) => parse_history(&content),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            Err(Error::AliasNotFound(name.to_string()))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Returns the blob the alias `name` currently points to.
+pub(crate) fn resolve(root: &Path, name: &str) -> Result<BlobRef> {
+    history(root, name)?
+        .pop()
+        .map(|entry| entry.target)
+        .ok_or_else(|| Error::AliasNotFound(name.to_string()))
+}
+
+/// Points the alias `name` to `target` if `precondition` holds.
+pub(crate) fn set(
+    root: &Path,
+    name: &str,
+    target: &BlobRef,
+    precondition: &Precondition,
+) -> Result<()> {
+    let path = alias_path(root, name)?;
+    let dir = aliases_dir(root);
+    fs::create_dir_all(path.parent().unwrap_or(&dir))?;
+
+    // Hold an exclusive lock for the whole read-compare-write sequence. It is released
+    // when the file is dropped.
+    let lock = File::create(dir.join(LOCK_FILE))?;
+    lock.lock_exclusive()?;
+
+    let mut content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let expected = match precondition {
+        Precondition::Any => None,
+        Precondition::Missing => Some(None),
+        Precondition::Target(blob_ref) => Some(Some(blob_ref.reference())),
+    };
+    if let Some(expected) = expected {
+        let current = parse_history(&content)?.pop().map(|entry| entry.target);
+        if current.as_ref().map(BlobRef::reference) != expected {
+            return Err(Error::AliasConflict);
+        }
+    }
+
+    let _ = writeln!(
+        content,
+        "{}\t{}",
+        Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Nanos, true),
+        target.reference()
+    );
+
+    // Readers never see a partially written file as it is replaced atomically.
+    let tmp_path = dir.join(format!("{LOCK_FILE}.tmp"));
+    fs::write(&tmp_path, content)?;
+    fs::rename(tmp_path, path)?;
+    Ok(())
+}
+
+/// Returns the names of all aliases with their current target, sorted by name.
+pub(crate) fn list(root: &Path) -> Result<Vec<(String, BlobRef)>> {
+    let dir = aliases_dir(root);
+    let mut aliases = vec![];
+    let mut to_visit = vec![dir.clone()];
+    while let Some(current) = to_visit.pop() {
+        let entries = match fs::read_dir(&current) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path.is_dir() {
+                to_visit.push(path);
+                continue;
+            }
+            let name = path
+                .strip_prefix(&dir)
+                .unwrap_or(&path)
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            if is_valid_name(&name) {
+                let target = resolve(root, &name)?;
+                aliases.push((name, target));
+            }
+        }
+    }
+    aliases.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(aliases)
+}
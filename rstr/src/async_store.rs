@@ -0,0 +1,304 @@
+use super::bucket;
+use super::erasure::BlobReader;
+use super::error::Result;
+use super::models::{sanitize_filename, BlobMetadata, BlobRef, BlobStore};
+use super::pipeline::PlannedFile;
+use blocking::{unblock, Unblock};
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use sha2::Digest;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// Size of the chunks the content of new blobs is read in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Counter making the names of the temporary directories of the uploads unique.
+static UPLOADS: AtomicUsize = AtomicUsize::new(0);
+
+/// A [`BlobStore`] with an `async` API, e.g. for web servers.
+///
+/// The blocking file system calls of the blob store run on a thread pool, so that they do
+/// not block the executor, whichever async runtime it is. Readers implement the
+/// [`AsyncRead`] trait of the `futures` crate; with `tokio`, they can be converted with
+/// `tokio_util::compat`.
+///
+/// # Examples
+///
+/// ```
+/// use futures::io::AsyncReadExt;
+/// use rstr::{AsyncBlobStore, BlobStore};
+/// # let dir = tempfile::tempdir().unwrap();
+/// let blob_store = AsyncBlobStore::from(BlobStore::new(dir.path()).unwrap());
+///
+/// futures::executor::block_on(async {
+///     let blob_ref = blob_store.add_reader(&b"hello world"[..], "hello.txt").await.unwrap();
+///     assert!(blob_store.exists(&blob_ref).await);
+///
+///     let mut content = String::new();
+///     let mut reader = blob_store.open(&blob_ref).await.unwrap();
+///     reader.read_to_string(&mut content).await.unwrap();
+///     assert_eq!(content, "hello world");
+/// });
+/// ```
+#[derive(Clone, Debug)]
+pub struct AsyncBlobStore {
+    blob_store: Arc<BlobStore>,
+}
+
+impl From<BlobStore> for AsyncBlobStore {
+    fn from(blob_store: BlobStore) -> Self {
+        AsyncBlobStore {
+            blob_store: Arc::new(blob_store),
+        }
+    }
+}
+
+impl AsyncBlobStore {
+    /// Opens a blob store with `open`, e.g. `|| BlobStore::new(path)`, on the thread pool,
+    /// as opening a blob store reads from the disk.
+    ///
+    /// # Errors
+    ///
+    /// It errors if `open` does.
+    pub async fn build<F>(open: F) -> Result<AsyncBlobStore>
+    where
+        F: FnOnce() -> Result<BlobStore> + Send + 'static,
+    {
+        unblock(open).await.map(AsyncBlobStore::from)
+    }
+
+    /// Returns the underlying, blocking, blob store.
+    pub fn blob_store(&self) -> &BlobStore {
+        &self.blob_store
+    }
+
+    /// Runs `f` with the blob store on the thread pool, e.g. to call the methods of
+    /// [`BlobStore`] without an async counterpart.
+    pub async fn run<T, F>(&self, f: F) -> T
+    where
+        F: FnOnce(&BlobStore) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let blob_store = Arc::clone(&self.blob_store);
+        unblock(move || f(&blob_store)).await
+    }
+
+    /// Adds the content read from `reader` to the blob store, saving it under `filename`.
+    /// The content is hashed while it is written to a temporary file, so it is never
+    /// loaded in memory.
+    ///
+    /// # Errors
+    ///
+    /// It errors if `reader` fails, or if the blob cannot be written to the blob store.
+    pub async fn add_reader<R>(&self, reader: R, filename: &str) -> Result<BlobRef>
+    where
+        R: AsyncRead + Unpin,
+    {
+        self.add_with(reader, filename, |blob_store, path, blob_ref, _| {
+            if blob_store.exists(&blob_ref) {
+                blob_store.disown(&blob_ref)?;
+            } else {
+                blob_store.write_file(path, &blob_ref)?;
+            }
+            Ok(blob_ref)
+        })
+        .await
+    }
+
+    /// Adds the content read from `reader` to the bucket `name`, saving it under
+    /// `filename`, see [`BlobStore::add_to_bucket`]. The content is not loaded in memory,
+    /// as with [`AsyncBlobStore::add_reader`].
+    ///
+    /// # Errors
+    ///
+    /// It errors if `reader` fails, and like [`BlobStore::add_to_bucket`] otherwise.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::{AsyncBlobStore, BlobStore};
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let blob_store = AsyncBlobStore::from(BlobStore::new(dir.path()).unwrap());
+    /// blob_store.blob_store().create_bucket("team-a", None, Default::default()).unwrap();
+    ///
+    /// futures::executor::block_on(async {
+    ///     let blob_ref = blob_store
+    ///         .add_reader_to_bucket("team-a", &b"hello world"[..], "hello.txt")
+    ///         .await
+    ///         .unwrap();
+    ///     let entry = blob_store.blob_store().bucket_entry("team-a", &blob_ref).unwrap();
+    ///     assert_eq!(entry.filename, "hello.txt");
+    /// });
+    /// ```
+    pub async fn add_reader_to_bucket<R>(
+        &self,
+        name: &str,
+        reader: R,
+        filename: &str,
+    ) -> Result<BlobRef>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let name = name.to_string();
+        self.add_with(reader, filename, move |blob_store, path, blob_ref, size| {
+            let file = PlannedFile {
+                path: path.to_path_buf(),
+                blob_ref,
+                size,
+            };
+            bucket::add_hashed(blob_store, &blob_store.roots()[0], &name, file)
+        })
+        .await
+    }
+
+    /// Copies the content read from `reader` to a temporary file named `filename` while
+    /// hashing it, then runs `add` with the file, its reference and its size on the
+    /// thread pool. The temporary file is removed afterwards.
+    async fn add_with<R, F>(&self, mut reader: R, filename: &str, add: F) -> Result<BlobRef>
+    where
+        R: AsyncRead + Unpin,
+        F: FnOnce(&BlobStore, &Path, BlobRef, u64) -> Result<BlobRef> + Send + 'static,
+    {
+        let tmp_dir = self.blob_store.roots()[0].join(format!(
+            ".upload-{}-{}.tmp",
+            std::process::id(),
+            UPLOADS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let tmp_file = tmp_dir.join(sanitize_filename(filename));
+
+        let copy = async {
+            let (dir, path) = (tmp_dir.clone(), tmp_file.clone());
+            let file = unblock(move || fs::create_dir_all(dir).and_then(|()| File::create(path)));
+            let mut file = Unblock::new(file.await?);
+            let mut hasher = BlobStore::hasher();
+            let mut size = 0;
+            let mut chunk = vec![0; CHUNK_SIZE];
+            loop {
+                let read = reader.read(&mut chunk).await?;
+                if read == 0 {
+                    break;
+                }
+                hasher.update(&chunk[..read]);
+                file.write_all(&chunk[..read]).await?;
+                size += read as u64;
+            }
+            file.flush().await?;
+            Ok::<_, io::Error>((BlobRef::from(hasher), size))
+        };
+        let copied = copy.await;
+
+        let blob_store = Arc::clone(&self.blob_store);
+        unblock(move || {
+            let result = copied
+                .map_err(Into::into)
+                .and_then(|(blob_ref, size)| add(&blob_store, &tmp_file, blob_ref, size));
+            remove_dir(&tmp_dir)?;
+            result
+        })
+        .await
+    }
+
+    /// Returns an async reader over the content of the blob, see [`BlobStore::open`].
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::BlobNotFound`](crate::Error::BlobNotFound) if the blob is
+    /// not in the blob store, or if it cannot be opened.
+    pub async fn open(&self, blob_ref: &BlobRef) -> Result<AsyncBlobReader> {
+        let blob_ref = blob_ref.clone();
+        let reader = self.run(move |blob_store| blob_store.open(&blob_ref));
+        Ok(AsyncBlobReader {
+            reader: Unblock::new(reader.await?),
+        })
+    }
+
+    /// Checks the content of a blob and returns an async reader over it, see
+    /// [`BlobStore::verify_and_open`].
+    ///
+    /// # Errors
+    ///
+    /// See [`BlobStore::verify_and_open`].
+    pub async fn verify_and_open(&self, blob_ref: &BlobRef) -> Result<AsyncBlobReader> {
+        let blob_ref = blob_ref.clone();
+        let reader = self.run(move |blob_store| blob_store.verify_and_open(&blob_ref));
+        Ok(AsyncBlobReader {
+            reader: Unblock::new(reader.await?),
+        })
+    }
+
+    /// Reads the whole content of a blob to check that it matches its reference, see
+    /// [`BlobStore::verify`].
+    ///
+    /// # Errors
+    ///
+    /// See [`BlobStore::verify`].
+    pub async fn verify(&self, blob_ref: &BlobRef) -> Result<()> {
+        let blob_ref = blob_ref.clone();
+        self.run(move |blob_store| blob_store.verify(&blob_ref))
+            .await
+    }
+
+    /// Returns `true` if the blob is in the blob store, see [`BlobStore::exists`].
+    pub async fn exists(&self, blob_ref: &BlobRef) -> bool {
+        let blob_ref = blob_ref.clone();
+        self.run(move |blob_store| blob_store.exists(&blob_ref))
+            .await
+    }
+
+    /// Deletes a blob, see [`BlobStore::delete`].
+    ///
+    /// # Errors
+    ///
+    /// See [`BlobStore::delete`].
+    pub async fn delete(&self, blob_ref: &BlobRef) -> Result<()> {
+        let blob_ref = blob_ref.clone();
+        self.run(move |blob_store| blob_store.delete(&blob_ref))
+            .await
+    }
+
+    /// Returns the metadata of a blob, see [`BlobStore::metadata`].
+    ///
+    /// # Errors
+    ///
+    /// See [`BlobStore::metadata`].
+    pub async fn metadata(&self, blob_ref: &BlobRef) -> Result<BlobMetadata> {
+        let blob_ref = blob_ref.clone();
+        self.run(move |blob_store| blob_store.metadata(&blob_ref))
+            .await
+    }
+}
+
+/// An async reader over the content of a blob, see [`AsyncBlobStore::open`].
+pub struct AsyncBlobReader {
+    reader: Unblock<BlobReader>,
+}
+
+impl fmt::Debug for AsyncBlobReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AsyncBlobReader").finish_non_exhaustive()
+    }
+}
+
+impl AsyncRead for AsyncBlobReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.reader).poll_read(cx, buf)
+    }
+}
+
+/// Removes a temporary directory, which may not have been created.
+fn remove_dir(dir: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(dir) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,174 @@
+use super::error::Result;
+use super::models::{BlobMetadata, BlobRef, BlobStore};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::Read;
+use std::path::Path;
+
+/// A store of blobs which is not a local [`BlobStore`] itself, e.g. a remote rstr server,
+/// which a blob store can read through to, see [`BlobStore::with_upstream`].
+///
+/// A [`BlobStore`] is also a backend, e.g. to cache a blob store on a network file
+/// system on a local disk.
+pub trait Backend: fmt::Debug + Send + Sync {
+    /// Returns `true` if the blob is in the backend.
+    ///
+    /// # Errors
+    ///
+    /// It errors if the backend cannot be reached.
+    fn exists(&self, blob_ref: &BlobRef) -> Result<bool>;
+
+    /// Returns the metadata of a blob.
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::BlobNotFound`] if the blob is not in the backend.
+    fn metadata(&self, blob_ref: &BlobRef) -> Result<BlobMetadata>;
+
+    /// Opens a blob for reading.
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::BlobNotFound`] if the blob is not in the backend.
+    fn open(&self, blob_ref: &BlobRef) -> Result<Box<dyn Read + Send>>;
+
+    /// Stores the file at `path` in the backend, under its filename.
+    ///
+    /// The caller is responsible for `blob_ref` being the actual hash of the file.
+    ///
+    /// # Errors
+    ///
+    /// It errors if the file cannot be read or stored.
+    fn upload(&self, path: &Path, blob_ref: &BlobRef) -> Result<()>;
+
+    /// Deletes a blob.
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::BlobNotFound`] if the blob is not in the backend.
+    fn delete(&self, blob_ref: &BlobRef) -> Result<()>;
+
+    /// Returns the references of all the blobs in the backend.
+    ///
+    /// # Errors
+    ///
+    /// It errors if the blobs cannot be listed.
+    fn blob_refs(&self) -> Result<Vec<BlobRef>>;
+}
+
+impl Backend for BlobStore {
+    fn exists(&self, blob_ref: &BlobRef) -> Result<bool> {
+        Ok(BlobStore::exists(self, blob_ref))
+    }
+
+    fn metadata(&self, blob_ref: &BlobRef) -> Result<BlobMetadata> {
+        BlobStore::metadata(self, blob_ref)
+    }
+
+    fn open(&self, blob_ref: &BlobRef) -> Result<Box<dyn Read + Send>> {
+        Ok(Box::new(BlobStore::open(self, blob_ref)?))
+    }
+
+    fn upload(&self, path: &Path, blob_ref: &BlobRef) -> Result<()> {
+        if BlobStore::exists(self, blob_ref) {
+            return self.disown(blob_ref);
+        }
+        self.write_file(path, blob_ref)
+    }
+
+    fn delete(&self, blob_ref: &BlobRef) -> Result<()> {
+        BlobStore::delete(self, blob_ref)
+    }
+
+    fn blob_refs(&self) -> Result<Vec<BlobRef>> {
+        BlobStore::blob_refs(self)
+    }
+}
+
+/// A backend chosen at runtime, e.g. from a URL or a path.
+impl Backend for Box<dyn Backend> {
+    fn exists(&self, blob_ref: &BlobRef) -> Result<bool> {
+        (**self).exists(blob_ref)
+    }
+
+    fn metadata(&self, blob_ref: &BlobRef) -> Result<BlobMetadata> {
+        (**self).metadata(blob_ref)
+    }
+
+    fn open(&self, blob_ref: &BlobRef) -> Result<Box<dyn Read + Send>> {
+        (**self).open(blob_ref)
+    }
+
+    fn upload(&self, path: &Path, blob_ref: &BlobRef) -> Result<()> {
+        (**self).upload(path, blob_ref)
+    }
+
+    fn delete(&self, blob_ref: &BlobRef) -> Result<()> {
+        (**self).delete(blob_ref)
+    }
+
+    fn blob_refs(&self) -> Result<Vec<BlobRef>> {
+        (**self).blob_refs()
+    }
+}
+
+/// How a blob store caches its upstream store, see [`BlobStore::with_upstream`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheOptions {
+    /// Size in bytes over which the least recently read blobs are evicted, whenever a blob
+    /// is fetched from or written through to the upstream store. There is no limit if
+    /// `None`.
+    pub max_size: Option<u64>,
+    /// Whether new blobs are also uploaded to the upstream store, and deleted blobs
+    /// deleted from it
+    pub write_through: bool,
+}
+
+/// The blobs of a caching blob store by how recently they were used, and their total
+/// size, so that blobs are evicted without listing the blob store every time.
+#[derive(Debug, Default)]
+pub(crate) struct CacheUsage {
+    size: u64,
+    clock: u64,
+    blobs: HashMap<String, (u64, u64)>,
+    by_use: BTreeMap<u64, BlobRef>,
+}
+
+impl CacheUsage {
+    /// Returns the total size of the blobs.
+    pub(crate) fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns the size of the blob, if it is known.
+    pub(crate) fn size_of(&self, blob_ref: &BlobRef) -> Option<u64> {
+        self.blobs.get(blob_ref.reference()).map(|(_, size)| *size)
+    }
+
+    /// Records that the blob of the given size was used last.
+    pub(crate) fn touch(&mut self, blob_ref: &BlobRef, size: u64) {
+        self.remove(blob_ref);
+        self.clock += 1;
+        self.size += size;
+        self.blobs
+            .insert(blob_ref.reference().to_string(), (self.clock, size));
+        self.by_use.insert(self.clock, blob_ref.clone());
+    }
+
+    /// Forgets the blob, e.g. once it is deleted.
+    pub(crate) fn remove(&mut self, blob_ref: &BlobRef) {
+        if let Some((used, size)) = self.blobs.remove(blob_ref.reference()) {
+            self.by_use.remove(&used);
+            self.size -= size;
+        }
+    }
+
+    /// Returns the least recently used blob among those used after `after`, with when it
+    /// was used. Blobs are used from 1 on.
+    pub(crate) fn used_after(&self, after: u64) -> Option<(u64, BlobRef)> {
+        self.by_use
+            .range(after + 1..)
+            .next()
+            .map(|(used, blob_ref)| (*used, blob_ref.clone()))
+    }
+}
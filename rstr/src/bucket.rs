@@ -0,0 +1,525 @@
+use super::error::{Error, Result};
+use super::models::{hash_file, BlobRef, BlobStore};
+use super::pipeline::{AddOptions, PlannedFile};
+use super::tree::{decode_path, encode_path};
+use chrono::{offset::Utc, DateTime};
+use fs2::FileExt;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// First line of every bucket record, used to recognise them and their version.
+const BUCKET_HEADER: &str = "rstr-bucket 1";
+
+/// Name of the directory, inside the blob store root, holding the buckets.
+const BUCKETS_DIR: &str = "buckets";
+
+/// Name of the file, inside a bucket directory, holding the bucket record.
+const BUCKET_FILE: &str = "bucket";
+
+/// Name of the directory, inside a bucket directory, holding one entry per blob.
+const ENTRIES_DIR: &str = "blobs";
+
+/// Name of the directory, inside the buckets directory, marking the blobs whose content
+/// was written by a bucket (rather than added to the store directly).
+const OWNED_DIR: &str = ".owned";
+
+/// Name of the file used to serialise updates to the buckets.
+const LOCK_FILE: &str = ".lock";
+
+/// Struct representing a named collection of blobs inside a blob store.
+///
+/// Buckets only hold references: the content of a blob is stored once in the blob store
+/// no matter how many buckets contain it. See [`BlobStore::create_bucket`].
+#[derive(Debug, Clone)]
+pub struct Bucket {
+    /// The name of the bucket
+    pub name: String,
+    /// When the bucket was created
+    pub created: DateTime<Utc>,
+    /// The maximum number of bytes the blobs in the bucket can take, if any
+    pub quota: Option<u64>,
+    /// Free-form metadata attached to the bucket (e.g. the owning team)
+    pub metadata: BTreeMap<String, String>,
+    /// Number of blobs in the bucket
+    pub blob_count: usize,
+    /// Total size in bytes of the blobs in the bucket. Blobs shared with other buckets
+    /// are counted in full in each of them.
+    pub usage: u64,
+}
+
+/// A blob in a [`Bucket`], see [`BlobStore::bucket_entries`].
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct BucketEntry {
+    /// The reference of the blob
+    pub blob_ref: BlobRef,
+    /// The filename the blob was added to the bucket with
+    pub filename: String,
+    /// The size of the blob in bytes
+    pub size: u64,
+    /// When the blob was added to the bucket
+    pub added: DateTime<Utc>,
+}
+
+impl Bucket {
+    fn to_record(&self) -> String {
+        let mut record = format!(
+            "{}\ncreated\t{}\nquota\t{}\n",
+            BUCKET_HEADER,
+            self.created
+                .to_rfc3339_opts(chrono::SecondsFormat::Nanos, true),
+            self.quota
+                .map_or_else(|| "-".to_string(), |q| q.to_string())
+        );
+        for (key, value) in &self.metadata {
+            let _ = writeln!(record, "meta\t{key}\t{value}");
+        }
+        record
+    }
+
+    fn from_record(name: &str, record: &str) -> Option<Bucket> {
+        let mut lines = record.lines();
+        if lines.next() != Some(BUCKET_HEADER) {
+            return None;
+        }
+        let mut field = |name: &str| {
+            let (key, value) = lines.next()?.split_once('\t')?;
+            if key == name {
+                Some(value.to_string())
+            } else {
+                None
+            }
+        };
+        let created = DateTime::parse_from_rfc3339(&field("created")?).ok()?;
+        let quota = match field("quota")?.as_str() {
+            "-" => None,
+            quota => Some(quota.parse().ok()?),
+        };
+        let mut metadata = BTreeMap::new();
+        for line in lines {
+            let (key, value) = line.strip_prefix("meta\t")?.split_once('\t')?;
+            metadata.insert(key.to_string(), value.to_string());
+        }
+        Some(Bucket {
+            name: name.to_string(),
+            created: created.into(),
+            quota,
+            metadata,
+            blob_count: 0,
+            usage: 0,
+        })
+    }
+}
+
+impl BucketEntry {
+    fn to_record(&self) -> String {
+        format!(
+            "added\t{}\nsize\t{}\nfilename\t{}\n",
+            self.added
+                .to_rfc3339_opts(chrono::SecondsFormat::Nanos, true),
+            self.size,
+            encode_path(Path::new(&self.filename))
+        )
+    }
+
+    fn from_record(blob_ref: BlobRef, record: &str) -> Option<BucketEntry> {
+        let mut lines = record.lines();
+        let mut field = |name: &str| {
+            let (key, value) = lines.next()?.split_once('\t')?;
+            if key == name {
+                Some(value.to_string())
+            } else {
+                None
+            }
+        };
+        let added = DateTime::parse_from_rfc3339(&field("added")?).ok()?;
+        let size = field("size")?.parse().ok()?;
+        let filename = decode_path(&field("filename")?)?
+            .to_string_lossy()
+            .into_owned();
+        Some(BucketEntry {
+            blob_ref,
+            filename,
+            size,
+            added: added.into(),
+        })
+    }
+}
+
+/// Returns `true` if `name` is a valid bucket name: ASCII letters, digits, `.`, `_` and
+/// `-`, not starting with a `.` and at most 64 characters long.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+}
+
+fn buckets_dir(root: &Path) -> PathBuf {
+    root.join(BUCKETS_DIR)
+}
+
+fn bucket_dir(root: &Path, name: &str) -> Result<PathBuf> {
+    if !is_valid_name(name) {
+        return Err(Error::InvalidBucket);
+    }
+    Ok(buckets_dir(root).join(name))
+}
+
+fn entry_path(root: &Path, name: &str, blob_ref: &BlobRef) -> Result<PathBuf> {
+    Ok(bucket_dir(root, name)?
+        .join(ENTRIES_DIR)
+        .join(blob_ref.reference()))
+}
+
+pub(crate) fn owned_dir(root: &Path) -> PathBuf {
+    buckets_dir(root).join(OWNED_DIR)
+}
+
+pub(crate) fn owned_marker(root: &Path, blob_ref: &BlobRef) -> PathBuf {
+    owned_dir(root).join(blob_ref.reference())
+}
+
+/// Takes the lock serialising all the updates to the buckets. It is released when the
+/// returned file is dropped.
+fn lock(root: &Path) -> Result<File> {
+    let dir = buckets_dir(root);
+    fs::create_dir_all(&dir)?;
+    let lock = File::create(dir.join(LOCK_FILE))?;
+    lock.lock_exclusive()?;
+    Ok(lock)
+}
+
+/// Writes `content` to `path` atomically.
+fn write_atomic(path: &Path, content: &str) -> Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    fs::write(&tmp_path, content)?;
+    fs::rename(tmp_path, path)?;
+    Ok(())
+}
+
+/// Checks that metadata keys and values can be stored in a bucket record.
+fn check_metadata(metadata: &BTreeMap<String, String>) -> Result<()> {
+    let valid = metadata.iter().all(|(key, value)| {
+        !key.is_empty() && !key.contains(char::is_control) && !value.contains(char::is_control)
+    });
+    if valid {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid bucket metadata").into())
+    }
+}
+
+/// Creates a new, empty, bucket.
+pub(crate) fn create(
+    root: &Path,
+    name: &str,
+    quota: Option<u64>,
+    metadata: BTreeMap<String, String>,
+) -> Result<Bucket> {
+    check_metadata(&metadata)?;
+    let dir = bucket_dir(root, name)?;
+    let _lock = lock(root)?;
+    if dir.exists() {
+        return Err(Error::BucketExists);
+    }
+
+    let bucket = Bucket {
+        name: name.to_string(),
+        created: Utc::now(),
+        quota,
+        metadata,
+        blob_count: 0,
+        usage: 0,
+    };
+    fs::create_dir_all(dir.join(ENTRIES_DIR))?;
+    write_atomic(&dir.join(BUCKET_FILE), &bucket.to_record())?;
+    Ok(bucket)
+}
+
+/// Reads the record of a bucket, without computing its usage.
+fn read(root: &Path, name: &str) -> Result<Bucket> {
+    match fs::read_to_string(bucket_dir(root, name)?.join(BUCKET_FILE)) {
+        Ok(record) => Bucket::from_record(name, &record).ok_or(Error::InvalidBucket),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            Err(Error::BucketNotFound(name.to_string()))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Returns the bucket `name`, including its usage.
+pub(crate) fn get(root: &Path, name: &str) -> Result<Bucket> {
+    let mut bucket = read(root, name)?;
+    let entries = entries(root, name)?;
+    bucket.blob_count = entries.len();
+    bucket.usage = entries.iter().map(|e| e.size).sum();
+    Ok(bucket)
+}
+
+/// Returns the names of all the buckets, sorted.
+fn names(root: &Path) -> Result<Vec<String>> {
+    let dir_entries = match fs::read_dir(buckets_dir(root)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut names = vec![];
+    for dir_entry in dir_entries {
+        let name = dir_entry?.file_name().to_string_lossy().into_owned();
+        if is_valid_name(&name) {
+            names.push(name);
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Returns all the buckets, sorted by name.
+pub(crate) fn list(root: &Path) -> Result<Vec<Bucket>> {
+    names(root)?.iter().map(|name| get(root, name)).collect()
+}
+
+/// Updates the quota and the metadata of a bucket. Setting a quota lower than the
+/// current usage is allowed and only prevents new blobs from being added.
+pub(crate) fn update(
+    root: &Path,
+    name: &str,
+    quota: Option<u64>,
+    metadata: BTreeMap<String, String>,
+) -> Result<()> {
+    check_metadata(&metadata)?;
+    let _lock = lock(root)?;
+    let bucket = Bucket {
+        quota,
+        metadata,
+        ..read(root, name)?
+    };
+    write_atomic(
+        &bucket_dir(root, name)?.join(BUCKET_FILE),
+        &bucket.to_record(),
+    )
+}
+
+/// Returns the entry of `blob_ref` in the bucket `name`.
+pub(crate) fn entry(root: &Path, name: &str, blob_ref: &BlobRef) -> Result<BucketEntry> {
+    read(root, name)?;
+    match fs::read_to_string(entry_path(root, name, blob_ref)?) {
+        Ok(record) => {
+            BucketEntry::from_record(blob_ref.clone(), &record).ok_or(Error::InvalidBucket)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(Error::BlobNotFound(blob_ref.clone())),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Returns all the entries of the bucket `name`, sorted by the time they were added.
+pub(crate) fn entries(root: &Path, name: &str) -> Result<Vec<BucketEntry>> {
+    let dir = bucket_dir(root, name)?.join(ENTRIES_DIR);
+    let dir_entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::BucketNotFound(name.to_string()))
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut entries = vec![];
+    for dir_entry in dir_entries {
+        let dir_entry = dir_entry?;
+        // Skip the temporary files of interrupted updates
+        let Ok(blob_ref) = BlobRef::new(&dir_entry.file_name().to_string_lossy()) else {
+            continue;
+        };
+        let record = fs::read_to_string(dir_entry.path())?;
+        entries.push(BucketEntry::from_record(blob_ref, &record).ok_or(Error::InvalidBucket)?);
+    }
+    entries.sort_by(|a, b| {
+        a.added
+            .cmp(&b.added)
+            .then(a.blob_ref.reference().cmp(b.blob_ref.reference()))
+    });
+    Ok(entries)
+}
+
+/// Adds already hashed files to the bucket `name`, writing their content to the blob
+/// store if needed. Files that would take the bucket over its quota are not added.
+pub(crate) fn insert(
+    blob_store: &BlobStore,
+    root: &Path,
+    name: &str,
+    files: Vec<PlannedFile>,
+) -> Result<Vec<(PathBuf, Result<BlobRef>)>> {
+    let _lock = lock(root)?;
+    let bucket = read(root, name)?;
+    let mut usage: u64 = entries(root, name)?.iter().map(|e| e.size).sum();
+
+    let mut results = vec![];
+    let mut inserted = HashSet::new();
+    for file in files {
+        let entry_path = entry_path(root, name, &file.blob_ref)?;
+        if entry_path.exists() || !inserted.insert(file.blob_ref.reference().to_string()) {
+            results.push((file.path, Ok(file.blob_ref)));
+            continue;
+        }
+        if let Some(quota) = bucket.quota.filter(|quota| usage + file.size > *quota) {
+            let err = Error::QuotaExceeded {
+                bucket: name.to_string(),
+                quota,
+            };
+            results.push((file.path, Err(err)));
+            continue;
+        }
+
+        let result = insert_file(blob_store, root, &entry_path, &file);
+        if result.is_ok() {
+            usage += file.size;
+        }
+        let PlannedFile { path, blob_ref, .. } = file;
+        results.push((path, result.map(|()| blob_ref)));
+    }
+    Ok(results)
+}
+
+fn insert_file(
+    blob_store: &BlobStore,
+    root: &Path,
+    entry_path: &Path,
+    file: &PlannedFile,
+) -> Result<()> {
+    if !blob_store.exists(&file.blob_ref) {
+        // Writing the blob drops any previous claim on it, so it is claimed afterwards
+        blob_store.write_file(&file.path, &file.blob_ref)?;
+        let marker = owned_marker(root, &file.blob_ref);
+        fs::create_dir_all(owned_dir(root))?;
+        File::create(&marker)?;
+        blob_store.record_owned_marker(marker);
+    }
+
+    let entry = BucketEntry {
+        blob_ref: file.blob_ref.clone(),
+        filename: file
+            .path
+            .file_name()
+            .map(|f| f.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        size: file.size,
+        added: Utc::now(),
+    };
+    write_atomic(entry_path, &entry.to_record())
+}
+
+/// Hashes the files found in `paths` and adds them to the bucket `name`.
+pub(crate) fn add_files<P: AsRef<Path> + Sync>(
+    blob_store: &BlobStore,
+    root: &Path,
+    name: &str,
+    paths: &[P],
+    options: &AddOptions,
+) -> Result<Vec<(PathBuf, Result<BlobRef>)>> {
+    read(root, name)?;
+    let plan = blob_store.plan_add_with_options(paths, options);
+    let mut files = plan.new;
+    files.extend(plan.existing);
+    files.extend(plan.duplicates);
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let mut results = insert(blob_store, root, name, files)?;
+    results.extend(plan.errors.into_iter().map(|(path, e)| (path, Err(e))));
+    Ok(results)
+}
+
+/// Adds a single file to the bucket `name`.
+pub(crate) fn add(blob_store: &BlobStore, root: &Path, name: &str, path: &Path) -> Result<BlobRef> {
+    read(root, name)?;
+    let (blob_ref, size) = hash_file(path)?;
+    let file = PlannedFile {
+        path: path.to_path_buf(),
+        blob_ref,
+        size,
+    };
+    add_hashed(blob_store, root, name, file)
+}
+
+/// Adds a single file, already hashed, to the bucket `name`.
+pub(crate) fn add_hashed(
+    blob_store: &BlobStore,
+    root: &Path,
+    name: &str,
+    file: PlannedFile,
+) -> Result<BlobRef> {
+    let (_, result) = insert(blob_store, root, name, vec![file])?
+        .pop()
+        .expect("insert returns one result per file");
+    result
+}
+
+/// Removes `blob_ref` from the bucket `name`. The content of the blob is deleted from
+/// the blob store if it was written by a bucket and no other bucket contains it.
+///
+/// Returns `true` if the content was deleted.
+fn remove_locked(
+    blob_store: &BlobStore,
+    root: &Path,
+    name: &str,
+    blob_ref: &BlobRef,
+) -> Result<bool> {
+    match fs::remove_file(entry_path(root, name, blob_ref)?) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::BlobNotFound(blob_ref.clone()))
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    let marker = owned_marker(root, blob_ref);
+    if !marker.exists() {
+        return Ok(false);
+    }
+    for other in names(root)? {
+        if entry_path(root, &other, blob_ref)?.exists() {
+            return Ok(false);
+        }
+    }
+    if blob_store.exists(blob_ref) {
+        blob_store.delete(blob_ref)?;
+    }
+    fs::remove_file(marker)?;
+    Ok(true)
+}
+
+/// See [`remove_locked`].
+pub(crate) fn remove(
+    blob_store: &BlobStore,
+    root: &Path,
+    name: &str,
+    blob_ref: &BlobRef,
+) -> Result<bool> {
+    let _lock = lock(root)?;
+    read(root, name)?;
+    remove_locked(blob_store, root, name, blob_ref)
+}
+
+/// Deletes the bucket `name` and all of its blobs, as in [`remove`]. Returns the blobs
+/// whose content was deleted from the blob store.
+pub(crate) fn delete(blob_store: &BlobStore, root: &Path, name: &str) -> Result<Vec<BlobRef>> {
+    let _lock = lock(root)?;
+    read(root, name)?;
+
+    let mut deleted = vec![];
+    for entry in entries(root, name)? {
+        if remove_locked(blob_store, root, name, &entry.blob_ref)? {
+            deleted.push(entry.blob_ref);
+        }
+    }
+    fs::remove_dir_all(bucket_dir(root, name)?)?;
+    Ok(deleted)
+}
@@ -0,0 +1,179 @@
+use super::car::ImportReport;
+use super::error::{Error, Result};
+use super::models::{BlobRef, BlobStore};
+use chrono::{offset::Utc, DateTime};
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Component, Path};
+
+/// Name of the first entry of a bundle, listing the blobs it contains. Its first line is
+/// `rstr-bundle<TAB>version`, followed by one `reference<TAB>size<TAB>created<TAB>mime
+/// type` line per blob. The filenames are those of the entries of the blobs.
+const MANIFEST: &str = "MANIFEST";
+
+/// Version of the bundles which are read and written
+const BUNDLE_VERSION: u32 = 1;
+
+/// Directory of the bundle holding the blobs, as `blobs/reference/filename`
+const BLOBS_DIR: &str = "blobs";
+
+/// Writes the blobs to `writer` as a tar archive: a manifest listing the blobs with their
+/// metadata, followed by an entry with the content of each blob.
+pub(crate) fn write<W: Write>(
+    blob_store: &BlobStore,
+    blob_refs: &[BlobRef],
+    writer: W,
+) -> Result<()> {
+    let mut manifest = format!("rstr-bundle\t{BUNDLE_VERSION}\n");
+    let mut entries = Vec::with_capacity(blob_refs.len());
+    let mut seen = BTreeSet::new();
+    for blob_ref in blob_refs.iter().filter(|blob_ref| seen.insert(*blob_ref)) {
+        let metadata = blob_store.metadata(blob_ref)?;
+        let _ = writeln!(
+            manifest,
+            "{}\t{}\t{}\t{}",
+            blob_ref.reference(),
+            metadata.size,
+            metadata.created.to_rfc3339(),
+            metadata.mime_type
+        );
+        entries.push((blob_ref, metadata));
+    }
+
+    let mut builder = tar::Builder::new(writer);
+    let mut header = new_header(manifest.len() as u64, Utc::now());
+    builder.append_data(&mut header, MANIFEST, manifest.as_bytes())?;
+    for (blob_ref, metadata) in entries {
+        let path = Path::new(BLOBS_DIR)
+            .join(blob_ref.reference())
+            .join(&metadata.filename);
+        let reader = ExactReader {
+            inner: blob_store.open(blob_ref)?,
+            remaining: metadata.size,
+        };
+        let mut header = new_header(metadata.size, metadata.created);
+        builder.append_data(&mut header, path, reader)?;
+    }
+    Ok(builder.into_inner()?.flush()?)
+}
+
+/// Reads a bundle from `reader`, adding the blobs missing from the blob store. The content
+/// of each blob is verified against its reference before it is added.
+pub(crate) fn read<R: Read>(blob_store: &BlobStore, reader: R) -> Result<ImportReport> {
+    let mut archive = tar::Archive::new(reader);
+    let mut entries = archive.entries()?;
+    let mut manifest = String::new();
+    match entries.next() {
+        Some(entry) => {
+            let mut entry = entry?;
+            if entry.path()? != Path::new(MANIFEST) {
+                return Err(invalid("the manifest is not the first entry"));
+            }
+            entry.read_to_string(&mut manifest)?;
+        }
+        None => return Err(invalid("missing manifest")),
+    }
+    let mut expected = parse_manifest(&manifest)?;
+
+    let mut report = ImportReport::default();
+    for entry in entries {
+        let mut entry = entry?;
+        let (blob_ref, filename) = parse_path(&entry.path()?)?;
+        if !expected.remove(&blob_ref) {
+            return Err(invalid(&format!(
+                "{} is not in the manifest",
+                blob_ref.reference()
+            )));
+        }
+        if blob_store.exists(&blob_ref) {
+            report.present.push(blob_ref);
+            continue;
+        }
+        let size = entry.header().size()?;
+        blob_store.store_verified(&blob_ref, &filename, |tmp_file| {
+            if io::copy(&mut entry, &mut File::create(tmp_file)?)? < size {
+                return Err(invalid("truncated archive"));
+            }
+            Ok(())
+        })?;
+        blob_store.write_through(&blob_ref, None)?;
+        report.imported.push(blob_ref);
+    }
+    match expected.len() {
+        0 => Ok(report),
+        missing => Err(invalid(&format!(
+            "{missing} blobs of the manifest are missing"
+        ))),
+    }
+}
+
+/// Reads exactly `remaining` bytes, the size in the header of an entry, so that an entry
+/// shorter than expected does not corrupt the archive.
+struct ExactReader<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R: Read> Read for ExactReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        let read = (&mut self.inner).take(self.remaining).read(buf)?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+/// Returns the header of a regular file entry.
+fn new_header(size: u64, modified: DateTime<Utc>) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(u64::try_from(modified.timestamp()).unwrap_or_default());
+    header.set_entry_type(tar::EntryType::Regular);
+    header
+}
+
+/// Returns the blobs listed by a manifest, checking its version.
+fn parse_manifest(manifest: &str) -> Result<BTreeSet<BlobRef>> {
+    let mut lines = manifest.lines();
+    match lines.next().and_then(|line| line.split_once('\t')) {
+        Some(("rstr-bundle", version)) if version == BUNDLE_VERSION.to_string() => (),
+        Some(("rstr-bundle", version)) => {
+            return Err(invalid(&format!("unsupported version {version}")))
+        }
+        _ => return Err(invalid("the manifest is not valid")),
+    }
+    lines
+        .map(|line| {
+            let reference = line.split('\t').next().unwrap_or_default();
+            BlobRef::new(reference).map_err(|_| invalid("the manifest is not valid"))
+        })
+        .collect()
+}
+
+/// Parses the path of a blob in a bundle, i.e. `blobs/reference/filename`.
+fn parse_path(path: &Path) -> Result<(BlobRef, String)> {
+    let components: Vec<_> = path.components().collect();
+    if let [Component::Normal(dir), Component::Normal(reference), Component::Normal(filename)] =
+        components[..]
+    {
+        if dir == BLOBS_DIR {
+            if let Ok(blob_ref) = BlobRef::new(&reference.to_string_lossy()) {
+                return Ok((blob_ref, filename.to_string_lossy().into_owned()));
+            }
+        }
+    }
+    Err(invalid(&format!("unexpected entry {}", path.display())))
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::InvalidBundle(reason.to_string())
+}
@@ -0,0 +1,302 @@
+use super::cid::{self, SHA2_256};
+use super::error::{Error, Result};
+use super::models::{BlobRef, BlobStore};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, Read, Write};
+
+/// Version of the CAR files which are read and written
+const CAR_VERSION: u64 = 1;
+/// Multicodec of `dag-pb` nodes, the codec implied by a `CIDv0`
+const DAG_PB_CODEC: u64 = 0x70;
+/// CBOR tag of the CIDs in DAG-CBOR
+const CID_TAG: u64 = 42;
+/// Largest header accepted when reading a CAR file
+const MAX_HEADER_SIZE: u64 = 64 * 1024 * 1024;
+/// Deepest nesting of CBOR items accepted in the header of a CAR file
+const MAX_CBOR_DEPTH: usize = 64;
+
+/// What [`BlobStore::import_car`] or [`BlobStore::import_bundle`] did.
+///
+/// [`BlobStore::import_car`]: crate::BlobStore::import_car
+/// [`BlobStore::import_bundle`]: crate::BlobStore::import_bundle
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    /// The blobs which were added to the blob store
+    pub imported: Vec<BlobRef>,
+    /// The blobs which were already in the blob store
+    pub present: Vec<BlobRef>,
+    /// The CIDs of the blocks which are not blobs, e.g. `dag-pb` nodes, and were skipped
+    pub skipped: Vec<String>,
+}
+
+/// Writes the blobs to `writer` as a `CARv1` file: a header listing the blobs as roots,
+/// followed by a block with the CID and the content of each blob.
+pub(crate) fn write<W: Write>(
+    blob_store: &BlobStore,
+    blob_refs: &[BlobRef],
+    mut writer: W,
+) -> Result<()> {
+    let header = header(blob_refs);
+    let mut prefix = vec![];
+    write_varint(header.len() as u64, &mut prefix);
+    writer.write_all(&prefix)?;
+    writer.write_all(&header)?;
+
+    for blob_ref in blob_refs {
+        let size = blob_store.metadata(blob_ref)?.size;
+        let cid = blob_ref.to_cid_bytes();
+        let mut prefix = vec![];
+        write_varint(cid.len() as u64 + size, &mut prefix);
+        prefix.extend_from_slice(&cid);
+        writer.write_all(&prefix)?;
+        let copied = io::copy(&mut blob_store.open(blob_ref)?, &mut writer)?;
+        if copied != size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} is not {size} bytes long", blob_ref.reference()),
+            )
+            .into());
+        }
+    }
+    Ok(writer.flush()?)
+}
+
+/// Reads a `CARv1` file from `reader`, adding the blocks which are blobs to the blob store.
+/// The content of each block is verified against its CID before it is added.
+pub(crate) fn read<R: Read>(blob_store: &BlobStore, mut reader: R) -> Result<ImportReport> {
+    let header_size = read_varint(&mut reader)?
+        .filter(|size| *size <= MAX_HEADER_SIZE)
+        .ok_or_else(|| invalid("missing header"))?;
+    let mut header = vec![];
+    (&mut reader).take(header_size).read_to_end(&mut header)?;
+    match header_version(&header)? {
+        CAR_VERSION => (),
+        version => return Err(invalid(&format!("unsupported version {version}"))),
+    }
+
+    let mut report = ImportReport::default();
+    while let Some(block_size) = read_varint(&mut reader)? {
+        let mut block = (&mut reader).take(block_size);
+        match read_cid(&mut block)? {
+            Cid::Blob(blob_ref) if blob_store.exists(&blob_ref) => {
+                io::copy(&mut block, &mut io::sink())?;
+                report.present.push(blob_ref);
+            }
+            Cid::Blob(blob_ref) => {
+                blob_store.store_verified(&blob_ref, &blob_ref.to_cid(), |tmp_file| {
+                    io::copy(&mut block, &mut File::create(tmp_file)?)?;
+                    check_complete(&block)
+                })?;
+                blob_store.write_through(&blob_ref, None)?;
+                report.imported.push(blob_ref);
+            }
+            Cid::Other(cid) => {
+                io::copy(&mut block, &mut io::sink())?;
+                report.skipped.push(cid);
+            }
+        }
+        check_complete(&block)?;
+    }
+    Ok(report)
+}
+
+/// Checks that the whole block was read, i.e. the file did not end in the middle of it.
+fn check_complete<R>(block: &io::Take<R>) -> Result<()> {
+    if block.limit() > 0 {
+        return Err(invalid("truncated block"));
+    }
+    Ok(())
+}
+
+/// The CID of a block of a CAR file.
+enum Cid {
+    /// The CID of a blob, i.e. of raw content hashed with sha2-256
+    Blob(BlobRef),
+    /// Any other CID, formatted in base32
+    Other(String),
+}
+
+/// Reads the binary form of a CID. A `CIDv0` is read as the equivalent `CIDv1`.
+fn read_cid<R: Read>(reader: &mut R) -> Result<Cid> {
+    let mut next = || read_varint(&mut *reader)?.ok_or_else(|| invalid("truncated CID"));
+    let first = next()?;
+    // A CIDv0 is a bare sha2-256 multihash of a `dag-pb` node
+    let (version, codec, hash) = if first == u64::from(SHA2_256) {
+        (1, DAG_PB_CODEC, first)
+    } else {
+        (first, next()?, next()?)
+    };
+    if version != 1 {
+        return Err(invalid(&format!("unsupported CID version {version}")));
+    }
+    let length = next()?;
+    let mut digest = vec![];
+    reader.take(length).read_to_end(&mut digest)?;
+    if digest.len() as u64 != length {
+        return Err(invalid("truncated CID"));
+    }
+
+    let mut bytes = vec![];
+    for value in [version, codec, hash, length] {
+        write_varint(value, &mut bytes);
+    }
+    bytes.extend_from_slice(&digest);
+    Ok(match BlobRef::from_cid_bytes(&bytes) {
+        Some(blob_ref) => Cid::Blob(blob_ref),
+        None => Cid::Other(cid::format_cid(&bytes)),
+    })
+}
+
+/// Encodes the header of a CAR file as DAG-CBOR, i.e. `{"roots": [..], "version": 1}`
+/// with the keys sorted by length.
+fn header(roots: &[BlobRef]) -> Vec<u8> {
+    let mut header = vec![];
+    write_cbor_head(5, 2, &mut header);
+    write_cbor_text("roots", &mut header);
+    write_cbor_head(4, roots.len() as u64, &mut header);
+    for root in roots {
+        let cid = root.to_cid_bytes();
+        write_cbor_head(6, CID_TAG, &mut header);
+        // CIDs are prefixed by the identity multibase in DAG-CBOR
+        write_cbor_head(2, cid.len() as u64 + 1, &mut header);
+        header.push(0x00);
+        header.extend_from_slice(&cid);
+    }
+    write_cbor_text("version", &mut header);
+    write_cbor_head(0, CAR_VERSION, &mut header);
+    header
+}
+
+/// Returns the `version` of the header of a CAR file, ignoring any other key.
+fn header_version(header: &[u8]) -> Result<u64> {
+    let mut pos = 0;
+    let (major, entries) = read_cbor_head(header, &mut pos)?;
+    if major != 5 {
+        return Err(invalid("the header is not a map"));
+    }
+    let mut version = None;
+    for _ in 0..entries {
+        let (major, length) = read_cbor_head(header, &mut pos)?;
+        let key = cbor_slice(header, &mut pos, length)?;
+        if major == 3 && key == b"version" {
+            match read_cbor_head(header, &mut pos)? {
+                (0, value) => version = Some(value),
+                _ => return Err(invalid("the version is not an integer")),
+            }
+        } else {
+            skip_cbor(header, &mut pos, 1)?;
+        }
+    }
+    version.ok_or_else(|| invalid("missing version"))
+}
+
+/// Appends the head of a CBOR item: its major type and its argument, e.g. a length.
+fn write_cbor_head(major: u8, argument: u64, bytes: &mut Vec<u8>) {
+    let major = major << 5;
+    // The argument follows the initial byte on 1, 2, 4 or 8 bytes, unless it is small
+    let size = match argument {
+        0..=23 => return bytes.push(major | argument.to_be_bytes()[7]),
+        24..=0xff => 0,
+        0x100..=0xffff => 1,
+        0x1_0000..=0xffff_ffff => 2,
+        _ => 3,
+    };
+    bytes.push(major | 0x18 | size);
+    bytes.extend_from_slice(&argument.to_be_bytes()[8 - (1 << size)..]);
+}
+
+/// Appends a CBOR text string.
+fn write_cbor_text(text: &str, bytes: &mut Vec<u8>) {
+    write_cbor_head(3, text.len() as u64, bytes);
+    bytes.extend_from_slice(text.as_bytes());
+}
+
+/// Reads the head of the CBOR item at `pos`, see [`write_cbor_head`].
+fn read_cbor_head(bytes: &[u8], pos: &mut usize) -> Result<(u8, u64)> {
+    let initial = *cbor_slice(bytes, pos, 1)?.first().unwrap_or(&0);
+    let (major, info) = (initial >> 5, initial & 0x1f);
+    let argument = match info {
+        0..=23 => u64::from(info),
+        24..=27 => cbor_slice(bytes, pos, 1 << (info - 24))?
+            .iter()
+            .fold(0, |argument, byte| argument << 8 | u64::from(*byte)),
+        // Indefinite lengths are not allowed in DAG-CBOR
+        _ => return Err(invalid("the header is not valid DAG-CBOR")),
+    };
+    Ok((major, argument))
+}
+
+/// Skips the CBOR item at `pos`, including the items it contains. The item is nested
+/// `depth` items deep, which is limited so that a crafted header cannot overflow the stack.
+fn skip_cbor(bytes: &[u8], pos: &mut usize, depth: usize) -> Result<()> {
+    if depth > MAX_CBOR_DEPTH {
+        return Err(invalid("header nested too deep"));
+    }
+    let (major, argument) = read_cbor_head(bytes, pos)?;
+    match major {
+        2 | 3 => {
+            cbor_slice(bytes, pos, argument)?;
+        }
+        4 | 5 => {
+            let items = if major == 5 {
+                argument.saturating_mul(2)
+            } else {
+                argument
+            };
+            for _ in 0..items {
+                skip_cbor(bytes, pos, depth + 1)?;
+            }
+        }
+        6 => skip_cbor(bytes, pos, depth + 1)?,
+        _ => (),
+    }
+    Ok(())
+}
+
+/// Returns the `length` bytes at `pos` and moves past them.
+fn cbor_slice<'a>(bytes: &'a [u8], pos: &mut usize, length: u64) -> Result<&'a [u8]> {
+    let end = usize::try_from(length)
+        .ok()
+        .and_then(|length| pos.checked_add(length))
+        .filter(|end| *end <= bytes.len())
+        .ok_or_else(|| invalid("truncated header"))?;
+    let slice = &bytes[*pos..end];
+    *pos = end;
+    Ok(slice)
+}
+
+/// Appends `value` to `bytes` as an unsigned varint, i.e. in groups of 7 bits, least
+/// significant first, with the high bit set on all but the last byte.
+fn write_varint(mut value: u64, bytes: &mut Vec<u8>) {
+    while value >= 0x80 {
+        bytes.push(value.to_le_bytes()[0] | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value.to_le_bytes()[0]);
+}
+
+/// Reads an unsigned varint, see [`write_varint`]. It returns `None` if `reader` is
+/// already at its end.
+fn read_varint<R: Read>(reader: &mut R) -> Result<Option<u64>> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0];
+        if reader.read(&mut byte)? == 0 {
+            return if shift == 0 {
+                Ok(None)
+            } else {
+                Err(invalid("truncated varint"))
+            };
+        }
+        value |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+    }
+    Err(invalid("varint too long"))
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::InvalidCar(reason.to_string())
+}
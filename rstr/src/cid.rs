@@ -0,0 +1,110 @@
+use super::error::{Error, Result};
+use super::models::BlobRef;
+use std::convert::TryFrom;
+
+/// Version of the CIDs of the blobs
+const CID_VERSION: u8 = 0x01;
+/// Multicodec of raw binary content
+const RAW_CODEC: u8 = 0x55;
+/// Multihash code of sha2-256
+pub(crate) const SHA2_256: u8 = 0x12;
+/// Length in bytes of a sha2-256 digest
+const DIGEST_LENGTH: u8 = 32;
+/// Multibase prefix of lowercase base32, the default encoding of `CIDv1`
+const BASE32_PREFIX: char = 'b';
+/// Alphabet of base32, see RFC 4648
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+impl BlobRef {
+    /// Returns the CID of the blob, as used by IPFS: a `CIDv1` with the raw codec and a
+    /// sha2-256 multihash, encoded in base32.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use rstr::BlobRef;
+    /// let blob_ref = BlobRef::new("b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9").unwrap();
+    ///
+    /// let cid = blob_ref.to_cid();
+    /// assert_eq!(cid, "bafkreifzjut3te2nhyekklss27nh3k72ysco7y32koao5eei66wof36n5e");
+    /// assert_eq!(BlobRef::from_cid(&cid).unwrap(), blob_ref);
+    /// ```
+    pub fn to_cid(&self) -> String {
+        format_cid(&self.to_cid_bytes())
+    }
+
+    /// Parses the CID of a blob, see [`BlobRef::to_cid`]. Both the lowercase and the
+    /// uppercase base32 encodings are accepted.
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::InvalidCid`] if `cid` is not a base32 `CIDv1` with the raw
+    /// codec and a sha2-256 multihash, e.g. a `CIDv0` or the CID of a `dag-pb` node.
+    pub fn from_cid(cid: &str) -> Result<BlobRef> {
+        let invalid = || Error::InvalidCid(cid.to_string());
+        let encoded = match cid.chars().next() {
+            Some('b') => cid[1..].to_string(),
+            Some('B') => cid[1..].to_ascii_lowercase(),
+            _ => return Err(invalid()),
+        };
+        let bytes = base32_decode(&encoded).ok_or_else(invalid)?;
+        BlobRef::from_cid_bytes(&bytes).ok_or_else(invalid)
+    }
+
+    /// Returns the binary form of the CID of the blob, see [`BlobRef::to_cid`].
+    pub(crate) fn to_cid_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![CID_VERSION, RAW_CODEC, SHA2_256, DIGEST_LENGTH];
+        bytes.extend_from_slice(&self.to_bytes());
+        bytes
+    }
+
+    /// Parses the binary form of the CID of a blob. It returns `None` for any other CID.
+    pub(crate) fn from_cid_bytes(bytes: &[u8]) -> Option<BlobRef> {
+        match bytes {
+            [CID_VERSION, RAW_CODEC, SHA2_256, DIGEST_LENGTH, digest @ ..] => {
+                <&[u8; 32]>::try_from(digest).ok().map(BlobRef::from_bytes)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Formats the binary form of any CID, e.g. of a `dag-pb` node, in base32.
+pub(crate) fn format_cid(bytes: &[u8]) -> String {
+    format!("{BASE32_PREFIX}{}", base32_encode(bytes))
+}
+
+/// Encodes `bytes` in base32, without padding.
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity((bytes.len() * 8).div_ceil(5));
+    let (mut buffer, mut bits) = (0_u32, 0);
+    for byte in bytes {
+        buffer = (buffer << 8) | u32::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[(buffer >> bits) as usize & 0x1f] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[(buffer << (5 - bits)) as usize & 0x1f] as char);
+    }
+    encoded
+}
+
+/// Decodes lowercase base32 without padding. It returns `None` if `encoded` is not valid.
+fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(encoded.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0_u32, 0);
+    for c in encoded.bytes() {
+        let (value, _) = (0_u8..).zip(BASE32_ALPHABET).find(|(_, a)| **a == c)?;
+        buffer = (buffer << 5) | u32::from(value);
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits).to_le_bytes()[0]);
+        }
+    }
+    // The leftover bits are padding and must be zero
+    (buffer & ((1 << bits) - 1) == 0).then_some(bytes)
+}
@@ -0,0 +1,114 @@
+use super::backend::Backend;
+use super::error::{Error, Result};
+use super::layout;
+use super::models::{BlobRef, BlobRefAndPath};
+use super::pipeline::{self, AddOptions};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+/// One of the sides compared by [`diff`].
+#[derive(Debug, Clone, Copy)]
+pub enum DiffSide<'a> {
+    /// A blob store, local or e.g. behind an rstr server, whose blobs are listed
+    Store(&'a dyn Backend),
+    /// A directory, or a single file, whose files are hashed
+    Directory(&'a Path),
+}
+
+/// How two stores or directories differ, see [`diff`].
+#[derive(Debug, Default)]
+pub struct StoreDiff {
+    /// The blobs only on the left
+    pub only_left: Vec<BlobRef>,
+    /// The blobs only on the right
+    pub only_right: Vec<BlobRef>,
+    /// The files of the left directory whose content is not on the right
+    pub only_left_files: Vec<BlobRefAndPath>,
+    /// The files of the right directory whose content is not on the left
+    pub only_right_files: Vec<BlobRefAndPath>,
+    /// The files which could not be hashed, with the reason
+    pub errors: Vec<(PathBuf, Error)>,
+}
+
+impl StoreDiff {
+    /// Returns `true` if both sides have the same blobs, and all the files could be hashed.
+    pub fn is_empty(&self) -> bool {
+        self.only_left.is_empty() && self.only_right.is_empty() && self.errors.is_empty()
+    }
+}
+
+/// Compares the blobs of two stores, or the content of a directory with a store or
+/// another directory, e.g. to check that a directory was fully added to a blob store.
+/// The blobs of a store are listed, while the files of a directory are hashed, so
+/// that only their content matters, not their name nor where they are.
+///
+/// # Errors
+///
+/// It errors if the blobs of a store cannot be listed, or if a directory is a blob store
+/// written before its layout was recorded, which must be migrated first, see
+/// [`BlobStore::migrate`]. The files which cannot be hashed are reported in
+/// [`StoreDiff::errors`] instead.
+///
+/// # Examples
+///
+/// ```
+/// use rstr::{diff, BlobStore, DiffSide};
+/// # let dir = tempfile::tempdir().unwrap();
+/// let blob_store = BlobStore::new(dir.path().join("store")).unwrap();
+/// let files = dir.path().join("files");
+/// std::fs::create_dir(&files).unwrap();
+/// std::fs::write(files.join("added.txt"), b"hello world").unwrap();
+/// std::fs::write(files.join("new.txt"), b"hello there").unwrap();
+/// blob_store.add_bytes(b"hello world", "added.txt").unwrap();
+///
+/// let diff = diff(DiffSide::Directory(&files), DiffSide::Store(&blob_store)).unwrap();
+/// assert_eq!(diff.only_left_files.len(), 1);
+/// assert_eq!(diff.only_left_files[0].0, files.join("new.txt"));
+/// assert!(diff.only_right.is_empty());
+///
+/// // A blob store written before its layout was recorded
+/// let legacy = std::path::Path::new("../tests/test_data_store");
+/// let err = rstr::diff(DiffSide::Directory(legacy), DiffSide::Store(&blob_store)).unwrap_err();
+/// assert!(matches!(err, rstr::Error::IncompatibleLayout(_)));
+/// ```
+///
+/// [`BlobStore::migrate`]: crate::BlobStore::migrate
+pub fn diff(left: DiffSide, right: DiffSide) -> Result<StoreDiff> {
+    let mut diff = StoreDiff::default();
+    let (left_refs, left_files) = list(left, &mut diff.errors)?;
+    let (right_refs, right_files) = list(right, &mut diff.errors)?;
+
+    diff.only_left = left_refs.difference(&right_refs).cloned().collect();
+    diff.only_right = right_refs.difference(&left_refs).cloned().collect();
+    diff.only_left_files = left_files
+        .into_iter()
+        .filter(|(_, blob_ref)| !right_refs.contains(blob_ref))
+        .collect();
+    diff.only_right_files = right_files
+        .into_iter()
+        .filter(|(_, blob_ref)| !left_refs.contains(blob_ref))
+        .collect();
+    Ok(diff)
+}
+
+/// Returns the blobs of one side, and the files they come from if it is a directory.
+fn list(
+    side: DiffSide,
+    errors: &mut Vec<(PathBuf, Error)>,
+) -> Result<(BTreeSet<BlobRef>, Vec<BlobRefAndPath>)> {
+    match side {
+        DiffSide::Store(backend) => Ok((backend.blob_refs()?.into_iter().collect(), vec![])),
+        DiffSide::Directory(path) => {
+            layout::check_not_unversioned(path)?;
+            let mut files = vec![];
+            for (path, result) in pipeline::hash_files(&[path], &AddOptions::default()) {
+                match result {
+                    Ok((blob_ref, _)) => files.push((path, blob_ref)),
+                    Err(e) => errors.push((path, e)),
+                }
+            }
+            let blob_refs = files.iter().map(|(_, blob_ref)| blob_ref.clone()).collect();
+            Ok((blob_refs, files))
+        }
+    }
+}
@@ -0,0 +1,543 @@
+use super::error::{Error, Result};
+use super::models::{BlobRef, BlobStore};
+use super::quarantine;
+use super::tree::{decode_path, encode_path};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Name of the file holding a shard inside a blob directory.
+pub(crate) const SHARD_FILENAME: &str = ".shard";
+/// First field of the header of every shard file.
+const SHARD_MAGIC: &str = "rstr-shard 1";
+/// Number of bytes of each shard in a stripe. Blobs are encoded one stripe at a time so
+/// that large blobs are never loaded in memory.
+const STRIPE_SIZE: usize = 64 * 1024;
+
+/// Layout of erasure coded blobs, see [`BlobStore::with_erasure_coding`].
+///
+/// The content of a blob is split into `data_shards` shards, from which `parity_shards`
+/// more are computed using Reed-Solomon codes. Each shard is stored in a different root
+/// and any `data_shards` of them are enough to rebuild the blob. This tolerates the loss
+/// of `parity_shards` roots while only taking `(data_shards + parity_shards) /
+/// data_shards` times the size of the blob.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErasureCoding {
+    /// The number of shards the content of a blob is split into
+    pub data_shards: usize,
+    /// The number of parity shards, i.e. how many shards can be lost
+    pub parity_shards: usize,
+    /// Blobs smaller than this, in bytes, are stored whole instead
+    pub min_size: u64,
+}
+
+impl Default for ErasureCoding {
+    fn default() -> Self {
+        ErasureCoding {
+            data_shards: 4,
+            parity_shards: 2,
+            min_size: 1024 * 1024,
+        }
+    }
+}
+
+/// Parses `K+M`, i.e. `K` data shards and `M` parity shards, with the default minimum
+/// size.
+///
+/// # Examples
+///
+/// ```
+/// use rstr::ErasureCoding;
+///
+/// let coding: ErasureCoding = "6+3".parse().unwrap();
+/// assert_eq!((coding.data_shards, coding.parity_shards), (6, 3));
+/// assert!("6".parse::<ErasureCoding>().is_err());
+/// ```
+impl FromStr for ErasureCoding {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ErasureCoding> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "expected K+M");
+        let (data, parity) = s.split_once('+').ok_or_else(invalid)?;
+        Ok(ErasureCoding {
+            data_shards: data.parse().map_err(|_| invalid())?,
+            parity_shards: parity.parse().map_err(|_| invalid())?,
+            ..ErasureCoding::default()
+        })
+    }
+}
+
+impl ErasureCoding {
+    /// Returns the total number of shards of a blob.
+    pub fn shards(&self) -> usize {
+        self.data_shards + self.parity_shards
+    }
+
+    /// Returns the encoder for the layout.
+    pub(crate) fn codec(&self) -> Result<ReedSolomon> {
+        ReedSolomon::new(self.data_shards, self.parity_shards).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid erasure coding: {e:?}"),
+            )
+            .into()
+        })
+    }
+}
+
+/// The header at the start of every shard file, on a single line.
+#[derive(Debug, Clone)]
+pub(crate) struct ShardHeader {
+    /// The position of the shard, data shards first
+    pub index: usize,
+    /// The layout the blob was encoded with
+    pub coding: ErasureCoding,
+    /// The size of the blob in bytes
+    pub size: u64,
+    /// The sha256 hash of the content of the shard, without the header
+    pub hash: String,
+    /// The filename of the blob
+    pub filename: String,
+}
+
+impl ShardHeader {
+    /// Returns `true` if both shards are part of the same encoding of a blob.
+    fn same_layout(&self, other: &ShardHeader) -> bool {
+        self.coding.data_shards == other.coding.data_shards
+            && self.coding.parity_shards == other.coding.parity_shards
+            && self.size == other.size
+    }
+}
+
+/// Returns the shard file in the blob directory `dir`, if any.
+pub(crate) fn shard_path(dir: &Path) -> Option<PathBuf> {
+    let path = dir.join(SHARD_FILENAME);
+    path.is_file().then_some(path)
+}
+
+/// Returns the length of each shard in every stripe of a blob of `size` bytes. The last
+/// stripe is shorter, so that small blobs are not padded to a whole stripe.
+fn stripe_lengths(size: u64, data_shards: usize) -> Vec<usize> {
+    let data_shards = data_shards as u64;
+    let stripe = STRIPE_SIZE as u64 * data_shards;
+    let full = usize::try_from(size / stripe).unwrap_or(usize::MAX);
+    let mut lengths = vec![STRIPE_SIZE; full];
+    if size % stripe != 0 {
+        let last = (size % stripe).div_ceil(data_shards);
+        lengths.push(usize::try_from(last).unwrap_or(STRIPE_SIZE));
+    }
+    lengths
+}
+
+/// Reads the header of a shard file, leaving `reader` at the start of the content.
+fn read_header<R: BufRead>(reader: &mut R) -> Result<ShardHeader> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid shard header");
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let fields: Vec<&str> = line.trim_end_matches('\n').split('\t').collect();
+    let [magic, index, data_shards, parity_shards, size, hash, filename] = fields[..] else {
+        return Err(invalid().into());
+    };
+    if magic != SHARD_MAGIC {
+        return Err(invalid().into());
+    }
+    let coding = ErasureCoding {
+        data_shards: data_shards.parse().map_err(|_| invalid())?,
+        parity_shards: parity_shards.parse().map_err(|_| invalid())?,
+        ..ErasureCoding::default()
+    };
+    let index: usize = index.parse().map_err(|_| invalid())?;
+    if index >= coding.shards() {
+        return Err(invalid().into());
+    }
+    Ok(ShardHeader {
+        index,
+        coding,
+        size: size.parse().map_err(|_| invalid())?,
+        hash: hash.to_string(),
+        filename: decode_path(filename)
+            .ok_or_else(invalid)?
+            .to_string_lossy()
+            .into_owned(),
+    })
+}
+
+/// Returns the header of the shard file at `path` if its content matches the hash in
+/// the header.
+pub(crate) fn verify_shard(path: &Path) -> Option<ShardHeader> {
+    let mut reader = BufReader::new(File::open(path).ok()?);
+    let header = read_header(&mut reader).ok()?;
+    let mut hasher = BlobStore::hasher();
+    io::copy(&mut reader, &mut hasher).ok()?;
+    (format!("{:x}", hasher.finalize()) == header.hash).then_some(header)
+}
+
+/// A shard file being written.
+struct ShardFile {
+    index: usize,
+    file: File,
+    hasher: Sha256,
+    /// Where the hash is in the header, it is only known once the whole shard is written
+    hash_offset: u64,
+    tmp_path: PathBuf,
+    dir: PathBuf,
+}
+
+/// Writes some of the shards of a blob, one stripe at a time.
+///
+/// The shards are written to temporary files next to their blob directory, and moved to
+/// it by [`ShardWriter::finish`].
+struct ShardWriter {
+    files: Vec<ShardFile>,
+}
+
+impl ShardWriter {
+    /// Creates the files of the shards `targets`, given as shard index and blob
+    /// directory, and writes their header.
+    fn create(header: &ShardHeader, targets: &[(usize, PathBuf)]) -> Result<ShardWriter> {
+        let mut files = vec![];
+        for (index, dir) in targets {
+            let parent = dir.parent().unwrap_or(dir);
+            fs::create_dir_all(parent)?;
+            let mut tmp_name = std::ffi::OsString::from(".");
+            tmp_name.push(dir.file_name().unwrap_or_default());
+            tmp_name.push(format!(".{index}.tmp"));
+            let tmp_path = parent.join(tmp_name);
+
+            let coding = header.coding;
+            let prefix = format!(
+                "{SHARD_MAGIC}\t{index}\t{}\t{}\t{}\t",
+                coding.data_shards, coding.parity_shards, header.size
+            );
+            let mut file = File::create(&tmp_path)?;
+            writeln!(
+                file,
+                "{prefix}{}\t{}",
+                "0".repeat(64),
+                encode_path(Path::new(&header.filename))
+            )?;
+            files.push(ShardFile {
+                index: *index,
+                file,
+                hasher: BlobStore::hasher(),
+                hash_offset: prefix.len() as u64,
+                tmp_path,
+                dir: dir.clone(),
+            });
+        }
+        Ok(ShardWriter { files })
+    }
+
+    /// Appends a stripe, given as all the shards of the blob, to the files.
+    fn write_stripe(&mut self, shards: &[Vec<u8>]) -> Result<()> {
+        for shard_file in &mut self.files {
+            let shard = &shards[shard_file.index];
+            shard_file.hasher.update(shard);
+            shard_file.file.write_all(shard)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the hash of each shard in its header and moves the shards to their blob
+    /// directory.
+    fn finish(self) -> Result<()> {
+        for mut shard_file in self.files {
+            shard_file
+                .file
+                .seek(SeekFrom::Start(shard_file.hash_offset))?;
+            write!(shard_file.file, "{:x}", shard_file.hasher.finalize())?;
+            drop(shard_file.file);
+            fs::create_dir_all(&shard_file.dir)?;
+            fs::rename(&shard_file.tmp_path, shard_file.dir.join(SHARD_FILENAME))?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits the `size` bytes read from `reader` into shards and writes shard `i` to
+/// `dirs[i]`.
+pub(crate) fn write_shards<R: Read>(
+    mut reader: R,
+    size: u64,
+    filename: &str,
+    coding: ErasureCoding,
+    dirs: &[PathBuf],
+) -> Result<()> {
+    let codec = coding.codec()?;
+    let header = ShardHeader {
+        index: 0,
+        coding,
+        size,
+        hash: String::new(),
+        filename: filename.to_string(),
+    };
+    let targets: Vec<(usize, PathBuf)> = dirs.iter().cloned().enumerate().collect();
+    let mut writer = ShardWriter::create(&header, &targets)?;
+
+    let mut remaining = size;
+    for length in stripe_lengths(size, coding.data_shards) {
+        let mut shards = vec![vec![0_u8; length]; coding.shards()];
+        // The end of the last stripe is padded with zeros
+        for shard in &mut shards[..coding.data_shards] {
+            let read = usize::try_from(remaining).map_or(length, |r| r.min(length));
+            reader.read_exact(&mut shard[..read])?;
+            remaining -= read as u64;
+        }
+        codec.encode(&mut shards).map_err(codec_error)?;
+        writer.write_stripe(&shards)?;
+    }
+    writer.finish()
+}
+
+/// Rebuilds the shards missing from `paths`, the shard files of the blob `blob_ref`, and
+/// writes them to the blob directories `dirs`, one per directory.
+///
+/// Returns how many shards were written, i.e. how many of `dirs` were used.
+pub(crate) fn rebuild_shards(
+    paths: &[PathBuf],
+    blob_ref: &BlobRef,
+    dirs: &[PathBuf],
+) -> Result<usize> {
+    let mut reader = ShardReader::open(paths, blob_ref)?;
+    let targets: Vec<(usize, PathBuf)> = reader
+        .files
+        .iter()
+        .enumerate()
+        .filter(|(_, file)| file.is_none())
+        .map(|(index, _)| index)
+        .zip(dirs.iter().cloned())
+        .collect();
+    if targets.is_empty() {
+        return Ok(0);
+    }
+
+    let mut writer = ShardWriter::create(&reader.header, &targets)?;
+    while let Some(shards) = reader.next_stripe(true)? {
+        writer.write_stripe(&shards)?;
+    }
+    writer.finish()?;
+    Ok(targets.len())
+}
+
+/// Reads the content of an erasure coded blob from its shards, rebuilding the missing
+/// ones on the fly.
+///
+/// The shards are not verified: a corrupted shard yields corrupted content.
+pub(crate) struct ShardReader {
+    header: ShardHeader,
+    codec: ReedSolomon,
+    /// The shard files, by index, positioned after the header
+    files: Vec<Option<BufReader<File>>>,
+    lengths: std::vec::IntoIter<usize>,
+    remaining: u64,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl ShardReader {
+    /// Opens the shard files at `paths` of the blob `blob_ref`, skipping those which
+    /// cannot be read or which are not part of the same encoding as the first one.
+    pub(crate) fn open(paths: &[PathBuf], blob_ref: &BlobRef) -> Result<ShardReader> {
+        let mut header: Option<ShardHeader> = None;
+        let mut shards = vec![];
+        for path in paths {
+            let Ok(file) = File::open(path) else {
+                continue;
+            };
+            let mut reader = BufReader::new(file);
+            let Ok(shard_header) = read_header(&mut reader) else {
+                continue;
+            };
+            match &header {
+                Some(header) if !header.same_layout(&shard_header) => continue,
+                Some(_) => {}
+                None => header = Some(shard_header.clone()),
+            }
+            shards.push((shard_header.index, reader));
+        }
+        let header = header.ok_or_else(|| Error::BlobNotFound(blob_ref.clone()))?;
+
+        let mut files: Vec<Option<BufReader<File>>> =
+            (0..header.coding.shards()).map(|_| None).collect();
+        for (index, reader) in shards {
+            if files[index].is_none() {
+                files[index] = Some(reader);
+            }
+        }
+        Ok(ShardReader {
+            codec: header.coding.codec()?,
+            files,
+            lengths: stripe_lengths(header.size, header.coding.data_shards).into_iter(),
+            remaining: header.size,
+            buffer: vec![],
+            position: 0,
+            header,
+        })
+    }
+
+    /// Returns the filename of the blob.
+    pub(crate) fn filename(&self) -> &str {
+        &self.header.filename
+    }
+
+    /// Returns the size of the blob in bytes.
+    pub(crate) fn size(&self) -> u64 {
+        self.header.size
+    }
+
+    /// Reads the next stripe, rebuilding all the missing shards if `all` is `true` or
+    /// only the data shards otherwise.
+    fn next_stripe(&mut self, all: bool) -> io::Result<Option<Vec<Vec<u8>>>> {
+        let Some(length) = self.lengths.next() else {
+            return Ok(None);
+        };
+        let mut shards: Vec<Option<Vec<u8>>> = vec![];
+        for slot in &mut self.files {
+            let shard = slot.as_mut().and_then(|file| {
+                let mut shard = vec![0; length];
+                file.read_exact(&mut shard).ok().map(|()| shard)
+            });
+            if shard.is_none() {
+                // The shard is missing or truncated
+                *slot = None;
+            }
+            shards.push(shard);
+        }
+        let result = if all {
+            self.codec.reconstruct(&mut shards)
+        } else {
+            self.codec.reconstruct_data(&mut shards)
+        };
+        result.map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not enough shards left to rebuild the blob",
+            )
+        })?;
+        Ok(Some(
+            shards.into_iter().map(Option::unwrap_or_default).collect(),
+        ))
+    }
+}
+
+impl Read for ShardReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.buffer.len() {
+            let Some(shards) = self.next_stripe(false)? else {
+                return Ok(0);
+            };
+            self.buffer.clear();
+            for shard in &shards[..self.header.coding.data_shards] {
+                self.buffer.extend_from_slice(shard);
+            }
+            // Drop the padding at the end of the last stripe
+            let length = usize::try_from(self.remaining).map_or(self.buffer.len(), |remaining| {
+                remaining.min(self.buffer.len())
+            });
+            self.buffer.truncate(length);
+            self.remaining -= length as u64;
+            self.position = 0;
+        }
+        let read = buf.len().min(self.buffer.len() - self.position);
+        buf[..read].copy_from_slice(&self.buffer[self.position..self.position + read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+/// Converts an error of the Reed-Solomon encoder.
+fn codec_error(e: reed_solomon_erasure::Error) -> Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{e:?}")).into()
+}
+
+/// Reader over the content of a blob, returned by [`BlobStore::open`].
+///
+/// Erasure coded blobs are rebuilt from their shards while they are read. A reader
+/// returned by [`BlobStore::open_verified`] hashes the content as it is read and, if it
+/// does not match the reference of the blob, fails at the end of the content with an
+/// [`io::Error`] wrapping [`Error::Corrupted`], which `?` converts back.
+pub struct BlobReader {
+    inner: BlobReaderInner,
+    verifier: Option<Verifier>,
+}
+
+/// Hashes the content of a blob as it is read, see [`BlobStore::open_verified`].
+struct Verifier {
+    hasher: Sha256,
+    blob_ref: BlobRef,
+    root: PathBuf,
+}
+
+enum BlobReaderInner {
+    File(File),
+    Pack(io::Take<File>),
+    Shards(Box<ShardReader>),
+}
+
+impl BlobReader {
+    pub(crate) fn from_file(file: File) -> BlobReader {
+        BlobReader {
+            inner: BlobReaderInner::File(file),
+            verifier: None,
+        }
+    }
+
+    pub(crate) fn from_pack(pack: io::Take<File>) -> BlobReader {
+        BlobReader {
+            inner: BlobReaderInner::Pack(pack),
+            verifier: None,
+        }
+    }
+
+    pub(crate) fn from_shards(shards: ShardReader) -> BlobReader {
+        BlobReader {
+            inner: BlobReaderInner::Shards(Box::new(shards)),
+            verifier: None,
+        }
+    }
+
+    /// Makes the reader check the content against `blob_ref`, recording a mismatch in the
+    /// quarantine list of the blob store at `root`.
+    pub(crate) fn verified(mut self, blob_ref: &BlobRef, root: &Path) -> BlobReader {
+        self.verifier = Some(Verifier {
+            hasher: BlobStore::hasher(),
+            blob_ref: blob_ref.clone(),
+            root: root.to_path_buf(),
+        });
+        self
+    }
+}
+
+impl Read for BlobReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = match &mut self.inner {
+            BlobReaderInner::File(file) => file.read(buf),
+            BlobReaderInner::Pack(pack) => pack.read(buf),
+            BlobReaderInner::Shards(shards) => shards.read(buf),
+        }?;
+        if read > 0 || buf.is_empty() {
+            if let Some(verifier) = &mut self.verifier {
+                verifier.hasher.update(&buf[..read]);
+            }
+            return Ok(read);
+        }
+        // The content is checked once, at its end
+        match self.verifier.take() {
+            Some(verifier) => {
+                let hash = BlobRef::from(verifier.hasher);
+                if hash.reference() == verifier.blob_ref.reference() {
+                    Ok(0)
+                } else {
+                    quarantine::record(&verifier.root, &verifier.blob_ref).ok();
+                    let err = Error::Corrupted(verifier.blob_ref);
+                    Err(io::Error::new(io::ErrorKind::InvalidData, err))
+                }
+            }
+            None => Ok(0),
+        }
+    }
+}
@@ -1,9 +1,62 @@
+use super::models::BlobRef;
+use std::path::{Path, PathBuf};
 use std::{error, fmt, io};
 
 /// Error raised by the blob store
+///
+/// New variants may be added in minor releases, so matches must have a wildcard arm.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
+    /// Any other error of the file system or of the network.
     Io(io::Error),
+
+    /// Occurs when a file or directory to add to the blob store does not exist. It holds
+    /// the path.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::{BlobStore, Error};
+    ///
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let blob_store = BlobStore::new(dir.path()).unwrap();
+    ///
+    /// let err = blob_store.add("../tests/missing.txt").unwrap_err();
+    /// assert!(matches!(err, Error::SourceNotFound(_)));
+    /// assert_eq!(format!("{}", err), "Error: ../tests/missing.txt does not exist.");
+    /// ```
+    SourceNotFound(PathBuf),
+
+    /// Occurs when the root of a blob store exists but is not a directory. It holds the
+    /// path.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::{BlobStore, Error};
+    ///
+    /// let err = BlobStore::new("../tests/test_file.txt").unwrap_err();
+    /// assert!(matches!(err, Error::NotADirectory(_)));
+    /// assert_eq!(
+    ///     format!("{}", err),
+    ///     "Error: ../tests/test_file.txt is not a directory, it cannot be the root of a blob store."
+    /// );
+    /// ```
+    NotADirectory(PathBuf),
+
+    /// Occurs when the blob store, or a file to add to it, cannot be read or written
+    /// because of its permissions. It holds the path and the error of the file system.
+    PermissionDenied(PathBuf, io::Error),
+
+    /// Occurs when content fetched or copied for a blob, e.g. from the upstream store of
+    /// a cache, does not match the reference of the blob.
+    HashMismatch {
+        /// The reference of the blob
+        expected: BlobRef,
+        /// The reference of the content actually received
+        actual: BlobRef,
+    },
     /// Occurs when trying to instantiate a `BlobRef` with an invalid string.
     ///
     /// # Examples
@@ -13,12 +66,52 @@ pub enum Error {
     /// let err = BlobRef::new("invalid").unwrap_err();
     /// # // io::Error does not implement PartialEq
     /// // err == Error::InvalidRef
-    /// assert_eq!(format!("{}", err), "Error: Invalid reference. Reference must have 64 alphanumerical characters.");
+    /// assert_eq!(format!("{}", err), "Error: Invalid reference. Reference must have 64 hexadecimal characters.");
     /// ```
     InvalidRef,
 
+    /// Occurs when trying to parse a string which is not the CID of a blob with
+    /// [`BlobRef::from_cid`]. It holds the string.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::{BlobRef, Error};
+    ///
+    /// // The CID of a `dag-pb` node, not of raw content
+    /// let err = BlobRef::from_cid("QmWATWQ7fVPP2EFGu71UkfnqhYXDYH566qy47CnJDgvs8u").unwrap_err();
+    /// assert!(matches!(err, Error::InvalidCid(_)));
+    /// ```
+    ///
+    /// [`BlobRef::from_cid`]: crate::BlobRef::from_cid
+    InvalidCid(String),
+
+    /// Occurs when a CAR file passed to [`BlobStore::import_car`] cannot be parsed. It
+    /// holds the reason.
+    ///
+    /// [`BlobStore::import_car`]: crate::BlobStore::import_car
+    InvalidCar(String),
+
+    /// Occurs when an archive passed to [`BlobStore::import_bundle`] cannot be parsed, or
+    /// does not contain all the blobs listed by its manifest. It holds the reason.
+    ///
+    /// [`BlobStore::import_bundle`]: crate::BlobStore::import_bundle
+    InvalidBundle(String),
+
+    /// Occurs when a prefix passed to [`BlobStore::resolve_prefix`] matches more than one
+    /// blob. It holds all the matching blobs.
+    ///
+    /// [`BlobStore::resolve_prefix`]: crate::BlobStore::resolve_prefix
+    AmbiguousRef(Vec<BlobRef>),
+
+    /// Occurs when a prefix passed to [`BlobStore::resolve_prefix`] matches no blob. It
+    /// holds the prefix.
+    ///
+    /// [`BlobStore::resolve_prefix`]: crate::BlobStore::resolve_prefix
+    PrefixNotFound(String),
+
     /// Occurs when trying to perfom some action on a blob that is not present in the
-    /// blob store.
+    /// blob store. It holds the reference of the blob.
     ///
     /// # Examples
     ///
@@ -30,24 +123,201 @@ pub enum Error {
     ///
     /// assert!(!blob_store.exists(&blob_ref));
     /// let err = blob_store.get(&blob_ref).unwrap_err();
-    /// # // io::Error does not implement PartialEq
-    /// // err == Error::BlobNotFound
-    /// assert_eq!(format!("{}", err), "Error: The requested blob was not found in the blob store.");
+    /// assert!(matches!(&err, Error::BlobNotFound(missing) if missing.reference() == blob_ref.reference()));
+    /// assert_eq!(
+    ///     format!("{}", err),
+    ///     "Error: The blob b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9 was not found in the blob store."
+    /// );
     ///
     /// let err = blob_store.delete(&blob_ref).unwrap_err();
-    /// assert_eq!(format!("{}", err), "Error: The requested blob was not found in the blob store.");
+    /// assert!(matches!(err, Error::BlobNotFound(_)));
     ///
     /// let err = blob_store.metadata(&blob_ref).unwrap_err();
-    /// assert_eq!(format!("{}", err), "Error: The requested blob was not found in the blob store.");
+    /// assert!(matches!(err, Error::BlobNotFound(_)));
+    /// ```
+    BlobNotFound(BlobRef),
+
+    /// Occurs when a blob that is expected to be a tree manifest cannot be parsed as one,
+    /// or when a tree manifest describes an entry that cannot be restored.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::{BlobRef, BlobStore};
+    ///
+    /// let blob_store = BlobStore::new("../tests/test_data_store").unwrap();
+    /// let blob_ref = BlobRef::new("f29bc64a9d3732b4b9035125fdb3285f5b6455778edca72414671e0ca3b2e0de").unwrap();
+    ///
+    /// let err = blob_store.tree(&blob_ref).unwrap_err();
+    /// assert_eq!(format!("{}", err), "Error: The blob is not a valid tree manifest.");
+    /// ```
+    InvalidTree,
+
+    /// Occurs when no snapshot matches the requested id, or when more than one does. It
+    /// holds the requested id.
+    ///
+    /// # Examples
+    ///
     /// ```
-    BlobNotFound,
+    /// use rstr::BlobStore;
+    ///
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let blob_store = BlobStore::new(dir.path()).unwrap();
+    ///
+    /// let err = blob_store.snapshot("0123456789").unwrap_err();
+    /// assert_eq!(format!("{}", err), "Error: The snapshot 0123456789 was not found.");
+    /// ```
+    SnapshotNotFound(String),
+
+    /// Occurs when a snapshot record in the blob store cannot be parsed.
+    InvalidSnapshot,
+
+    /// Occurs when an alias name is not valid, or when its record cannot be parsed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::{BlobRef, BlobStore};
+    ///
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let blob_store = BlobStore::new(dir.path()).unwrap();
+    ///
+    /// let err = blob_store.resolve("../escape").unwrap_err();
+    /// assert_eq!(
+    ///     format!("{}", err),
+    ///     "Error: Invalid alias. Aliases must be made of `/` separated segments of letters, digits, `.`, `_` and `-`."
+    /// );
+    /// ```
+    InvalidAlias,
+
+    /// Occurs when trying to resolve an alias that does not exist. It holds the name of
+    /// the alias.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::{BlobStore, Error};
+    ///
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let blob_store = BlobStore::new(dir.path()).unwrap();
+    ///
+    /// let err = blob_store.resolve("releases/latest").unwrap_err();
+    /// assert!(matches!(&err, Error::AliasNotFound(name) if name == "releases/latest"));
+    /// assert_eq!(format!("{}", err), "Error: The alias releases/latest does not exist.");
+    /// ```
+    AliasNotFound(String),
+
+    /// Occurs when a compare-and-swap update of an alias fails because the alias does not
+    /// point to the expected blob.
+    AliasConflict,
+
+    /// Occurs when a bucket name is not valid, or when a bucket record cannot be parsed.
+    InvalidBucket,
+
+    /// Occurs when trying to access a bucket that does not exist. It holds the name of the
+    /// bucket.
+    BucketNotFound(String),
+
+    /// Occurs when trying to create a bucket that already exists.
+    BucketExists,
+
+    /// Occurs when adding a blob to a bucket would take it over its quota.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::{BlobStore, Error};
+    /// # use std::collections::BTreeMap;
+    ///
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let blob_store = BlobStore::new(dir.path()).unwrap();
+    /// blob_store.create_bucket("tiny", Some(4), BTreeMap::new()).unwrap();
+    ///
+    /// let err = blob_store.add_to_bucket("tiny", "../tests/test_file.txt").unwrap_err();
+    /// assert!(matches!(err, Error::QuotaExceeded { quota: 4, .. }));
+    /// ```
+    QuotaExceeded {
+        /// The name of the bucket
+        bucket: String,
+        /// The quota of the bucket, in bytes
+        quota: u64,
+    },
+
+    /// Occurs when opening a blob store whose on-disk layout is not the one of this
+    /// version of rstr, e.g. it was written by a newer version or it must be migrated
+    /// with [`BlobStore::migrate`] first. It holds the root and the reason.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::{BlobStore, Error, LAYOUT_VERSION};
+    ///
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// BlobStore::new(dir.path()).unwrap();
+    /// let descriptor = dir.path().join("rstr.store");
+    /// let newer = std::fs::read_to_string(&descriptor)
+    ///     .unwrap()
+    ///     .replace(&format!("version = {LAYOUT_VERSION}"), "version = 99");
+    /// std::fs::write(&descriptor, newer).unwrap();
+    ///
+    /// let err = BlobStore::new(dir.path()).unwrap_err();
+    /// assert!(matches!(err, Error::IncompatibleLayout(_)));
+    /// ```
+    ///
+    /// [`BlobStore::migrate`]: crate::BlobStore::migrate
+    IncompatibleLayout(String),
+
+    /// Occurs when the content of a blob read with verification, see
+    /// [`BlobStore::with_verify_on_read`], does not match its reference, or when no copy
+    /// of a blob matches it. The blob is added to the quarantine list of the blob store,
+    /// see [`BlobStore::quarantine`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::{BlobStore, Error};
+    ///
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let blob_store = BlobStore::new(dir.path()).unwrap();
+    /// let blob_ref = blob_store.add_bytes(b"hello world", "hello.txt").unwrap();
+    /// let path = blob_store.blob_dir(&blob_ref).join("hello.txt");
+    /// std::fs::write(path, b"hello w0rld").unwrap();
+    ///
+    /// let err = blob_store.get_verified(&blob_ref).unwrap_err();
+    /// assert!(matches!(err, Error::Corrupted(_)));
+    /// ```
+    ///
+    /// [`BlobStore::with_verify_on_read`]: crate::BlobStore::with_verify_on_read
+    /// [`BlobStore::quarantine`]: crate::BlobStore::quarantine
+    Corrupted(BlobRef),
 }
 
 impl error::Error for Error {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match *self {
-            Error::Io(ref err) => Some(err),
-            Error::BlobNotFound | Error::InvalidRef => None,
+            Error::Io(ref err) | Error::PermissionDenied(_, ref err) => Some(err),
+            Error::SourceNotFound(_)
+            | Error::NotADirectory(_)
+            | Error::HashMismatch { .. }
+            | Error::BlobNotFound(_)
+            | Error::InvalidRef
+            | Error::InvalidCid(_)
+            | Error::InvalidCar(_)
+            | Error::InvalidBundle(_)
+            | Error::AmbiguousRef(_)
+            | Error::PrefixNotFound(_)
+            | Error::InvalidTree
+            | Error::SnapshotNotFound(_)
+            | Error::InvalidSnapshot
+            | Error::InvalidAlias
+            | Error::AliasNotFound(_)
+            | Error::AliasConflict
+            | Error::InvalidBucket
+            | Error::BucketNotFound(_)
+            | Error::BucketExists
+            | Error::QuotaExceeded { .. }
+            | Error::IncompatibleLayout(_)
+            | Error::Corrupted(_) => None,
         }
     }
 }
@@ -56,13 +326,84 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Error::Io(ref err) => err.fmt(f),
+            Error::SourceNotFound(ref path) => {
+                write!(f, "Error: {} does not exist.", path.display())
+            }
+            Error::NotADirectory(ref path) => write!(
+                f,
+                "Error: {} is not a directory, it cannot be the root of a blob store.",
+                path.display()
+            ),
+            Error::PermissionDenied(ref path, _) => {
+                write!(f, "Error: Permission denied on {}.", path.display())
+            }
+            Error::HashMismatch {
+                ref expected,
+                ref actual,
+            } => write!(
+                f,
+                "Error: The content received for the blob {} has the reference {}.",
+                expected.reference(),
+                actual.reference()
+            ),
             Error::InvalidRef => write!(
                 f,
-                "Error: Invalid reference. Reference must have 64 alphanumerical characters."
+                "Error: Invalid reference. Reference must have 64 hexadecimal characters."
+            ),
+            Error::InvalidCid(ref cid) => write!(
+                f,
+                "Error: Invalid CID {cid}. Only CIDv1 of raw content with a sha2-256 multihash are supported."
             ),
-            Error::BlobNotFound => write!(
+            Error::InvalidCar(ref reason) => write!(f, "Error: Invalid CAR file, {reason}."),
+            Error::InvalidBundle(ref reason) => write!(f, "Error: Invalid bundle, {reason}."),
+            Error::AmbiguousRef(ref candidates) => {
+                let candidates: Vec<&str> = candidates.iter().map(BlobRef::reference).collect();
+                write!(
+                    f,
+                    "Error: Ambiguous reference, it matches: {}.",
+                    candidates.join(", ")
+                )
+            }
+            Error::PrefixNotFound(ref prefix) => {
+                write!(f, "Error: No blob in the blob store starts with {prefix}.")
+            }
+            Error::BlobNotFound(ref blob_ref) => write!(
                 f,
-                "Error: The requested blob was not found in the blob store."
+                "Error: The blob {} was not found in the blob store.",
+                blob_ref.reference()
+            ),
+            Error::InvalidTree => write!(f, "Error: The blob is not a valid tree manifest."),
+            Error::SnapshotNotFound(ref id) => write!(f, "Error: The snapshot {id} was not found."),
+            Error::InvalidSnapshot => write!(f, "Error: A snapshot record is corrupted."),
+            Error::InvalidAlias => write!(
+                f,
+                "Error: Invalid alias. Aliases must be made of `/` separated segments of letters, digits, `.`, `_` and `-`."
+            ),
+            Error::InvalidBucket => write!(
+                f,
+                "Error: Invalid bucket. Bucket names must be made of letters, digits, `.`, `_` and `-`."
+            ),
+            Error::BucketNotFound(ref name) => write!(f, "Error: The bucket {name} does not exist."),
+            Error::BucketExists => write!(f, "Error: A bucket with this name already exists."),
+            Error::QuotaExceeded {
+                ref bucket,
+                quota,
+            } => write!(
+                f,
+                "Error: The blob was not added as it would exceed the quota of the bucket {bucket} ({quota} bytes)."
+            ),
+            Error::IncompatibleLayout(ref reason) => {
+                write!(f, "Error: Incompatible blob store layout in {reason}.")
+            }
+            Error::Corrupted(ref blob_ref) => write!(
+                f,
+                "Error: The content of the blob {} does not match its reference.",
+                blob_ref.reference()
+            ),
+            Error::AliasNotFound(ref name) => write!(f, "Error: The alias {name} does not exist."),
+            Error::AliasConflict => write!(
+                f,
+                "Error: The alias does not point to the expected blob, it was not updated."
             ),
         }
     }
@@ -70,10 +411,22 @@ impl fmt::Display for Error {
 
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Error {
-        match err.kind() {
-            // TODO: make sure this doesn't swallow some other NotFound error.
-            io::ErrorKind::NotFound => Error::BlobNotFound,
-            _ => Error::Io(err),
+        // Readers report the errors of the blob store, e.g. a corrupted blob, as io errors
+        if matches!(err.get_ref(), Some(inner) if inner.is::<Error>()) {
+            return *err.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
+        Error::Io(err)
+    }
+}
+
+impl Error {
+    /// Adds the path the error occurred on, for the errors where it is the useful part.
+    pub(crate) fn with_path(self, path: &Path) -> Error {
+        match self {
+            Error::Io(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                Error::PermissionDenied(path.to_path_buf(), err)
+            }
+            err => err,
         }
     }
 }
@@ -0,0 +1,172 @@
+use super::error::{Error, Result};
+use super::models::BlobMetadata;
+use chrono::{offset::Utc, DateTime, NaiveDate, TimeZone};
+use std::cmp::Ordering;
+use std::io;
+use std::str::FromStr;
+
+/// A condition on the metadata of a blob, see [`BlobStore::query`].
+///
+/// Filters are parsed from strings like `filename=*.txt`, `mime=image/*`, `size>1000000`
+/// or `created>=2021-06-01`. Filenames and mime types are compared with `=` and `!=` to
+/// patterns where `*` matches any sequence of characters and `?` any single character.
+/// Sizes, in bytes, and creation times, as dates or RFC 3339 timestamps, are also
+/// compared with `<`, `<=`, `>` and `>=`.
+///
+/// # Examples
+///
+/// ```
+/// use rstr::{BlobStore, Filter};
+/// # let dir = tempfile::tempdir().unwrap();
+/// let blob_store = BlobStore::new(dir.path()).unwrap();
+/// let blob_ref = blob_store.add_bytes(b"hello world", "hello.txt").unwrap();
+/// blob_store.add_bytes(b"<p>hello</p>", "hello.html").unwrap();
+///
+/// let filters: Vec<Filter> = vec!["filename=*.txt".parse().unwrap(), "size<100".parse().unwrap()];
+/// assert_eq!(blob_store.query(&filters).unwrap(), vec![blob_ref]);
+///
+/// assert!("colour=red".parse::<Filter>().is_err());
+/// ```
+///
+/// [`BlobStore::query`]: crate::BlobStore::query
+#[derive(Debug, Clone)]
+pub struct Filter {
+    field: Field,
+    comparison: Comparison,
+}
+
+/// The metadata a [`Filter`] applies to, with the value it is compared to.
+#[derive(Debug, Clone)]
+enum Field {
+    Filename(String),
+    MimeType(String),
+    Size(u64),
+    Created(DateTime<Utc>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+/// The comparisons, longest first so that e.g. `<=` is not read as `<`.
+const COMPARISONS: [(&str, Comparison); 6] = [
+    ("!=", Comparison::NotEqual),
+    ("<=", Comparison::LessOrEqual),
+    (">=", Comparison::GreaterOrEqual),
+    ("=", Comparison::Equal),
+    ("<", Comparison::Less),
+    (">", Comparison::Greater),
+];
+
+impl FromStr for Filter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Filter> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid filter {s}, expected e.g. mime=image/* or size>1000"),
+            )
+        };
+        let start = s.find(['!', '<', '>', '=']).ok_or_else(invalid)?;
+        let (name, rest) = s.split_at(start);
+        let (symbol, comparison) = COMPARISONS
+            .iter()
+            .find(|(symbol, _)| rest.starts_with(symbol))
+            .ok_or_else(invalid)?;
+        let value = &rest[symbol.len()..];
+
+        let field = match name.trim() {
+            "filename" => Field::Filename(value.to_string()),
+            "mime" => Field::MimeType(value.to_string()),
+            "size" => Field::Size(value.parse().map_err(|_| invalid())?),
+            "created" => Field::Created(parse_time(value).ok_or_else(invalid)?),
+            _ => return Err(invalid().into()),
+        };
+        let is_pattern = matches!(field, Field::Filename(_) | Field::MimeType(_));
+        if is_pattern && !matches!(comparison, Comparison::Equal | Comparison::NotEqual) {
+            return Err(invalid().into());
+        }
+        Ok(Filter {
+            field,
+            comparison: *comparison,
+        })
+    }
+}
+
+impl Filter {
+    /// Whether the blob with the given metadata satisfies the filter.
+    pub fn matches(&self, metadata: &BlobMetadata) -> bool {
+        let ordering = match &self.field {
+            Field::Filename(pattern) => pattern_ordering(pattern, &metadata.filename),
+            Field::MimeType(pattern) => pattern_ordering(pattern, &metadata.mime_type),
+            Field::Size(size) => metadata.size.cmp(size),
+            Field::Created(created) => metadata.created.cmp(created),
+        };
+        match self.comparison {
+            Comparison::Equal => ordering == Ordering::Equal,
+            Comparison::NotEqual => ordering != Ordering::Equal,
+            Comparison::Less => ordering == Ordering::Less,
+            Comparison::LessOrEqual => ordering != Ordering::Greater,
+            Comparison::Greater => ordering == Ordering::Greater,
+            Comparison::GreaterOrEqual => ordering != Ordering::Less,
+        }
+    }
+}
+
+/// Compares a text to a pattern: only whether it matches is meaningful.
+fn pattern_ordering(pattern: &str, text: &str) -> Ordering {
+    if glob_match(pattern, text) {
+        Ordering::Equal
+    } else {
+        Ordering::Less
+    }
+}
+
+/// Parses a date, as midnight UTC, or an RFC 3339 timestamp.
+fn parse_time(value: &str) -> Option<DateTime<Utc>> {
+    if let Ok(date) = NaiveDate::from_str(value) {
+        return Some(Utc.from_utc_datetime(&date.and_hms(0, 0, 0)));
+    }
+    DateTime::parse_from_rfc3339(value)
+        .ok()
+        .map(|time| time.with_timezone(&Utc))
+}
+
+/// Whether `text` matches `pattern`, where `*` matches any sequence of characters and `?`
+/// any single character.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // The last `*` and the position in the text it was last tried from
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                // Let the `*` match one more character
+                Some((star, from)) => {
+                    backtrack = Some((star, from + 1));
+                    p = star + 1;
+                    t = from + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
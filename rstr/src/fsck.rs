@@ -0,0 +1,153 @@
+use super::erasure;
+use super::error::Result;
+use super::models::{hash_file, BlobRef, BlobStore};
+use super::pack;
+use super::roots;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The state of the copies of a blob, as found by [`BlobStore::check_blob`].
+#[derive(Debug, Clone)]
+pub struct BlobCheck {
+    /// The blob that was checked
+    pub blob_ref: BlobRef,
+    /// The roots holding a copy, or a shard, whose content matches the reference
+    pub healthy: Vec<PathBuf>,
+    /// The roots holding a copy, or a shard, whose content does not match the reference
+    /// or which cannot be read
+    pub corrupted: Vec<PathBuf>,
+    /// The number of copies the blob should have, see [`BlobStore::with_replicas`], or
+    /// its number of shards if it is erasure coded
+    pub replicas: usize,
+    /// The number of shards needed to rebuild the blob, if it is erasure coded, see
+    /// [`BlobStore::with_erasure_coding`]
+    pub data_shards: Option<usize>,
+}
+
+impl BlobCheck {
+    /// Returns `true` if no copy is corrupted and there are enough healthy copies.
+    pub fn is_healthy(&self) -> bool {
+        self.corrupted.is_empty() && self.healthy.len() >= self.replicas
+    }
+
+    /// Returns `true` if too few healthy copies, or shards, are left to rebuild the blob,
+    /// in which case it cannot be repaired.
+    pub fn is_lost(&self) -> bool {
+        self.healthy.len() < self.data_shards.unwrap_or(1)
+    }
+}
+
+/// What [`BlobStore::repair`] did.
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    /// The copies that were written, together with their root
+    pub copied: Vec<(BlobRef, PathBuf)>,
+    /// The corrupted copies that were removed, together with their root
+    pub removed: Vec<(BlobRef, PathBuf)>,
+    /// The blobs without any healthy copy left
+    pub lost: Vec<BlobRef>,
+}
+
+/// Returns `true` if the file at `path` hashes to `blob_ref`.
+pub(crate) fn verify_file(path: &Path, blob_ref: &BlobRef) -> bool {
+    matches!(hash_file(path), Ok((hash, _)) if hash.reference() == blob_ref.reference())
+}
+
+/// Returns `true` if the file in the blob directory `dir` hashes to `blob_ref`.
+fn verify_copy(dir: &Path, blob_ref: &BlobRef) -> bool {
+    roots::blob_file(dir).is_some_and(|file| verify_file(&file, blob_ref))
+}
+
+/// Hashes every copy of `blob_ref` in the roots of the blob store, or every shard if it
+/// is erasure coded. A packed blob has a single copy, in the first root.
+pub(crate) fn check(blob_store: &BlobStore, blob_ref: &BlobRef) -> BlobCheck {
+    let mut check = BlobCheck {
+        blob_ref: blob_ref.clone(),
+        healthy: vec![],
+        corrupted: vec![],
+        replicas: blob_store.replicas(),
+        data_shards: None,
+    };
+    let shards: Vec<(&PathBuf, PathBuf)> = blob_store
+        .roots()
+        .iter()
+        .filter_map(|root| {
+            erasure::shard_path(&root.join(blob_store.shard_layout().blob_path(blob_ref)))
+                .map(|s| (root, s))
+        })
+        .collect();
+    if !shards.is_empty() {
+        check_shards(&mut check, &shards);
+        return check;
+    }
+
+    for root in blob_store.roots() {
+        let dir = root.join(blob_store.shard_layout().blob_path(blob_ref));
+        if !roots::has_blob(&dir) {
+            continue;
+        }
+        if verify_copy(&dir, blob_ref) {
+            check.healthy.push(root.clone());
+        } else {
+            check.corrupted.push(root.clone());
+        }
+    }
+    if check.healthy.is_empty() && check.corrupted.is_empty() {
+        let root = &blob_store.roots()[0];
+        if let Ok(Some(entry)) = pack::find(root, blob_ref) {
+            check.replicas = 1;
+            if pack::verify(root, &entry) {
+                check.healthy.push(root.clone());
+            } else {
+                check.corrupted.push(root.clone());
+            }
+        }
+    }
+    check
+}
+
+/// Hashes the `shards` of an erasure coded blob, given with their root. Shards which are
+/// not part of the same encoding as the first healthy one count as corrupted.
+fn check_shards(check: &mut BlobCheck, shards: &[(&PathBuf, PathBuf)]) {
+    let mut coding = None;
+    for (root, shard) in shards {
+        match erasure::verify_shard(shard) {
+            Some(header) if coding.map_or(true, |coding| coding == header.coding) => {
+                coding = Some(header.coding);
+                check.healthy.push((*root).clone());
+            }
+            _ => check.corrupted.push((*root).clone()),
+        }
+    }
+    if let Some(coding) = coding {
+        check.replicas = coding.shards();
+        check.data_shards = Some(coding.data_shards);
+    } else {
+        // Every shard is corrupted, the layout is unknown
+        check.data_shards = Some(1);
+    }
+}
+
+/// Removes the corrupted copies, or shards, of a blob and restores the missing ones with
+/// [`BlobStore::replicate`]. Lost blobs are left untouched.
+pub(crate) fn repair(
+    blob_store: &BlobStore,
+    check: &BlobCheck,
+    report: &mut RepairReport,
+) -> Result<()> {
+    let blob_ref = &check.blob_ref;
+    if check.is_lost() {
+        report.lost.push(blob_ref.clone());
+        return Ok(());
+    }
+    for root in &check.corrupted {
+        // A corrupted packed blob is lost, so every corrupted copy has a blob directory
+        fs::remove_dir_all(root.join(blob_store.shard_layout().blob_path(blob_ref)))?;
+        roots::prune_shards(root, blob_store.shard_layout(), blob_ref);
+        report.removed.push((blob_ref.clone(), root.clone()));
+    }
+    for root in blob_store.replicate(blob_ref)? {
+        report.copied.push((blob_ref.clone(), root));
+    }
+    Ok(())
+}
@@ -0,0 +1,207 @@
+use super::backend::Backend;
+use super::error::{Error, Result};
+use super::models::{BlobMetadata, BlobRef};
+use super::tier::Tier;
+use chrono::{offset::Utc, DateTime};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Name of the header carrying the API token of the rstr server.
+const TOKEN_HEADER: &str = "X-Auth-Token";
+
+/// A remote rstr server, used through its REST API, e.g. as the upstream of a local cache,
+/// see [`BlobStore::with_upstream`].
+///
+/// [`BlobStore::with_upstream`]: crate::BlobStore::with_upstream
+///
+/// # Examples
+///
+/// ```no_run
+/// use rstr::{BlobStore, CacheOptions, HttpBackend};
+///
+/// let upstream = HttpBackend::new("https://my-rstr-url.rs").with_token("my-token");
+/// let options = CacheOptions {
+///     max_size: Some(10 << 30),
+///     write_through: true,
+/// };
+/// let blob_store = BlobStore::new("/var/cache/rstr")
+///     .unwrap()
+///     .with_upstream(upstream, options);
+/// ```
+#[derive(Clone)]
+pub struct HttpBackend {
+    url: String,
+    token: Option<String>,
+    agent: ureq::Agent,
+}
+
+impl fmt::Debug for HttpBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HttpBackend")
+            .field("url", &self.url)
+            .finish_non_exhaustive()
+    }
+}
+
+impl HttpBackend {
+    /// Creates a backend for the rstr server at `url`, e.g. `https://my-rstr-url.rs`.
+    pub fn new(url: &str) -> HttpBackend {
+        HttpBackend {
+            url: url.trim_end_matches('/').to_string(),
+            token: None,
+            agent: ureq::Agent::new(),
+        }
+    }
+
+    /// Sets the API token sent with every request.
+    #[must_use]
+    pub fn with_token(mut self, token: &str) -> HttpBackend {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    /// Returns the URL of the server.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        let request = self.agent.request(method, &format!("{}{path}", self.url));
+        match &self.token {
+            Some(token) => request.set(TOKEN_HEADER, token),
+            None => request,
+        }
+    }
+
+    fn blob_request(&self, method: &str, blob_ref: &BlobRef) -> ureq::Request {
+        self.request(method, &format!("/blobs/{}", blob_ref.reference()))
+    }
+}
+
+/// Converts the errors of the HTTP client.
+pub(crate) fn to_error(err: ureq::Error) -> Error {
+    match err {
+        ureq::Error::Status(code, response) => {
+            let url = response.get_url().to_string();
+            let body = response.into_string().unwrap_or_default();
+            io::Error::other(format!("{url}: {code} {body}")).into()
+        }
+        ureq::Error::Transport(transport) => io::Error::other(transport.to_string()).into(),
+    }
+}
+
+/// Same as [`to_error`] for the requests on a blob, mapping the responses `404 Not Found`
+/// to [`Error::BlobNotFound`].
+pub(crate) fn blob_error(blob_ref: &BlobRef) -> impl Fn(ureq::Error) -> Error + '_ {
+    move |err| match err {
+        ureq::Error::Status(404, _) => Error::BlobNotFound(blob_ref.clone()),
+        err => to_error(err),
+    }
+}
+
+impl Backend for HttpBackend {
+    fn exists(&self, blob_ref: &BlobRef) -> Result<bool> {
+        match self
+            .blob_request("HEAD", blob_ref)
+            .call()
+            .map_err(blob_error(blob_ref))
+        {
+            Ok(_) => Ok(true),
+            Err(Error::BlobNotFound(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn metadata(&self, blob_ref: &BlobRef) -> Result<BlobMetadata> {
+        let response = self
+            .blob_request("HEAD", blob_ref)
+            .call()
+            .map_err(blob_error(blob_ref))?;
+        let created = response
+            .header("created")
+            .and_then(|created| DateTime::parse_from_rfc3339(created).ok())
+            .map_or_else(Utc::now, |created| created.with_timezone(&Utc));
+        Ok(BlobMetadata {
+            filename: response.header("filename").unwrap_or("file").to_string(),
+            mime_type: response.content_type().to_string(),
+            size: response
+                .header("content-length")
+                .and_then(|size| size.parse().ok())
+                .unwrap_or_default(),
+            created,
+            tier: Tier::Hot,
+        })
+    }
+
+    fn open(&self, blob_ref: &BlobRef) -> Result<Box<dyn Read + Send>> {
+        let response = self
+            .blob_request("GET", blob_ref)
+            .call()
+            .map_err(blob_error(blob_ref))?;
+        Ok(Box::new(response.into_reader()))
+    }
+
+    fn upload(&self, path: &Path, blob_ref: &BlobRef) -> Result<()> {
+        let filename = path
+            .file_name()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?
+            .to_string_lossy()
+            .replace('"', "_");
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+
+        // The file is streamed as the only part of a multipart form
+        let boundary = format!("rstr-{}", blob_ref.reference());
+        let head = format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{filename}\"\r\nContent-Type: application/octet-stream\r\n\r\n"
+        );
+        let tail = format!("\r\n--{boundary}--\r\n");
+        let length = head.len() as u64 + size + tail.len() as u64;
+        let body = io::Cursor::new(head)
+            .chain(file)
+            .chain(io::Cursor::new(tail));
+        let response = self
+            .request("POST", "/blobs")
+            .set(
+                "Content-Type",
+                &format!("multipart/form-data; boundary={boundary}"),
+            )
+            .set("Content-Length", &length.to_string())
+            .send(body)
+            .map_err(to_error)?;
+
+        let uploaded: Vec<String> = serde_json::from_reader(response.into_reader())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        match uploaded.first() {
+            Some(reference) if reference == blob_ref.reference() => Ok(()),
+            Some(reference) => Err(Error::HashMismatch {
+                expected: blob_ref.clone(),
+                actual: BlobRef::new(reference)?,
+            }),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("the server did not store {}", blob_ref.reference()),
+            )
+            .into()),
+        }
+    }
+
+    fn delete(&self, blob_ref: &BlobRef) -> Result<()> {
+        self.blob_request("DELETE", blob_ref)
+            .call()
+            .map_err(blob_error(blob_ref))?;
+        Ok(())
+    }
+
+    fn blob_refs(&self) -> Result<Vec<BlobRef>> {
+        let response = self.request("GET", "/blobs").call().map_err(to_error)?;
+        let references: Vec<String> = serde_json::from_reader(response.into_reader())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        references
+            .iter()
+            .map(|reference| BlobRef::new(reference))
+            .collect()
+    }
+}
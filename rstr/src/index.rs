@@ -0,0 +1,362 @@
+use super::error::Result;
+use super::models::BlobRef;
+use fs2::FileExt;
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Name of the file, inside the blob store root, holding the references of all the blobs.
+const INDEX_FILE: &str = "refs.index";
+
+/// Name of the file used to serialise updates to the index.
+const LOCK_FILE: &str = ".refs.index.lock";
+
+/// Prefix of the first line of the index, followed by a number changed each time the
+/// index is rewritten, so that the processes reading it can tell it was replaced.
+const GENERATION_PREFIX: &str = "#";
+
+/// Minimum number of removals logged before the index is compacted, see
+/// [`RefIndex::compact_if_needed`].
+const COMPACT_MIN_REMOVALS: usize = 1024;
+
+/// How long a blob not found in the index is trusted to be missing, before the changes
+/// made to the index by other processes are read again, see [`RefIndex::is_stale`].
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Number of bits of the bloom filter per reference.
+const BITS_PER_REF: usize = 10;
+
+/// Number of bits set in the bloom filter for each reference, which gives about 1% of
+/// false positives with [`BITS_PER_REF`] bits per reference.
+const HASHES: u64 = 7;
+
+/// A bloom filter over blob references. As references are already uniformly distributed
+/// hashes, the positions of the bits are derived from the references themselves.
+struct BloomFilter {
+    bits: Vec<u64>,
+}
+
+impl BloomFilter {
+    /// Creates an empty bloom filter sized for `capacity` references.
+    fn with_capacity(capacity: usize) -> BloomFilter {
+        let bits = (capacity.max(1024) * BITS_PER_REF).next_power_of_two();
+        BloomFilter {
+            bits: vec![0; bits / 64],
+        }
+    }
+
+    /// Returns the number of references the filter is sized for.
+    fn capacity(&self) -> usize {
+        self.bits.len() * 64 / BITS_PER_REF
+    }
+
+    /// Returns the positions of the bits of `reference`, using double hashing.
+    fn positions(&self, reference: &str) -> impl Iterator<Item = usize> {
+        let h1 = u64::from_str_radix(&reference[..16], 16).unwrap_or(0);
+        let h2 = u64::from_str_radix(&reference[16..32], 16).unwrap_or(0) | 1;
+        // The number of bits is a power of two
+        let mask = (self.bits.len() * 64 - 1) as u64;
+        (0..HASHES)
+            .map(move |i| usize::try_from(h1.wrapping_add(i.wrapping_mul(h2)) & mask).unwrap_or(0))
+    }
+
+    fn insert(&mut self, reference: &str) {
+        for position in self.positions(reference).collect::<Vec<_>>() {
+            self.bits[position / 64] |= 1 << (position % 64);
+        }
+    }
+
+    /// Returns `false` if `reference` was never inserted. It can return `true` for
+    /// references which were not inserted.
+    fn may_contain(&self, reference: &str) -> bool {
+        self.positions(reference)
+            .all(|position| self.bits[position / 64] & (1 << (position % 64)) != 0)
+    }
+}
+
+/// The references of all the blobs in a blob store, see [`BlobStore::with_index`].
+///
+/// The index is kept in memory, together with a bloom filter which answers most lookups
+/// of missing blobs. On disk, it is a log of the references added, one per line, and
+/// removed, prefixed by `-`. The log is compacted once it holds many removals.
+///
+/// [`BlobStore::with_index`]: crate::BlobStore::with_index
+pub(crate) struct RefIndex {
+    root: PathBuf,
+    refs: BTreeSet<String>,
+    bloom: BloomFilter,
+    /// Where the log was read up to, see [`RefIndex::refresh`]
+    read: LogPosition,
+    /// When the log was last read
+    refreshed: Instant,
+    /// Number of removals in the log
+    removals: usize,
+}
+
+/// How much of the log was read: its generation, the number of bytes read, and the size
+/// and modification time of the file then.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct LogPosition {
+    generation: u64,
+    offset: u64,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl fmt::Debug for RefIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RefIndex")
+            .field("root", &self.root)
+            .field("refs", &self.refs.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl RefIndex {
+    /// Loads the index stored in `root`, compacting it if needed. Returns `None` if there
+    /// is no index yet.
+    pub(crate) fn load(root: &Path) -> Result<Option<RefIndex>> {
+        let Some(mut index) = RefIndex::read(root)? else {
+            return Ok(None);
+        };
+        index.compact_if_needed()?;
+        Ok(Some(index))
+    }
+
+    /// Reads the whole index stored in `root`.
+    fn read(root: &Path) -> Result<Option<RefIndex>> {
+        let mut file = match File::open(root.join(INDEX_FILE)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut index = RefIndex::from_refs(root, BTreeSet::new(), LogPosition::default());
+        index.read_log(&mut file)?;
+        index.bloom = BloomFilter::with_capacity(index.refs.len() * 2);
+        for reference in &index.refs {
+            index.bloom.insert(reference);
+        }
+        Ok(Some(index))
+    }
+
+    /// Replaces the index stored in `root` with `blob_refs`, e.g. the blobs found on disk.
+    pub(crate) fn rebuild(root: &Path, blob_refs: &[BlobRef]) -> Result<RefIndex> {
+        let refs: BTreeSet<String> = blob_refs
+            .iter()
+            .map(|blob_ref| blob_ref.reference().to_string())
+            .collect();
+        let _lock = lock(root)?;
+        let read = write_log(root, &refs)?;
+        Ok(RefIndex::from_refs(root, refs, read))
+    }
+
+    fn from_refs(root: &Path, refs: BTreeSet<String>, read: LogPosition) -> RefIndex {
+        let mut bloom = BloomFilter::with_capacity(refs.len() * 2);
+        for reference in &refs {
+            bloom.insert(reference);
+        }
+        RefIndex {
+            root: root.to_path_buf(),
+            refs,
+            bloom,
+            read,
+            refreshed: Instant::now(),
+            removals: 0,
+        }
+    }
+
+    /// Applies the lines of the log from where it was last read. Only whole lines are
+    /// read, as another process may be appending to it.
+    fn read_log(&mut self, file: &mut File) -> Result<()> {
+        let metadata = file.metadata()?;
+        file.seek(SeekFrom::Start(self.read.offset))?;
+        let mut reader = BufReader::new(file);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 || !line.ends_with('\n') {
+                break;
+            }
+            self.read.offset += read as u64;
+            let line = line.trim_end();
+            if let Some(generation) = line.strip_prefix(GENERATION_PREFIX) {
+                self.read.generation = generation.parse().unwrap_or(0);
+            } else if let Some(reference) = line.strip_prefix('-') {
+                self.refs.remove(reference);
+                self.removals += 1;
+            } else if BlobRef::new(line).is_ok() && self.refs.insert(line.to_string()) {
+                self.bloom.insert(line);
+            }
+        }
+        self.read.len = metadata.len();
+        self.read.modified = metadata.modified().ok();
+        Ok(())
+    }
+
+    /// Reads the changes made to the index by other processes since it was last read. It
+    /// only checks the size and modification time of the log if it did not change.
+    pub(crate) fn refresh(&mut self) -> Result<()> {
+        self.refreshed = Instant::now();
+        let mut file = match File::open(self.root.join(INDEX_FILE)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let metadata = file.metadata()?;
+        if metadata.len() == self.read.len && metadata.modified().ok() == self.read.modified {
+            return Ok(());
+        }
+        if read_generation(&mut file)? != self.read.generation || metadata.len() < self.read.offset
+        {
+            // The log was rewritten
+            if let Some(index) = RefIndex::read(&self.root)? {
+                *self = index;
+            }
+            return Ok(());
+        }
+        self.read_log(&mut file)?;
+        if self.refs.len() > self.bloom.capacity() {
+            let refs = std::mem::take(&mut self.refs);
+            *self = RefIndex {
+                removals: self.removals,
+                ..RefIndex::from_refs(&self.root, refs, self.read)
+            };
+        }
+        Ok(())
+    }
+
+    /// Rewrites the log without the removed references, once it holds more removals than
+    /// references (and at least [`COMPACT_MIN_REMOVALS`]).
+    fn compact_if_needed(&mut self) -> Result<()> {
+        if self.removals < COMPACT_MIN_REMOVALS.max(self.refs.len()) {
+            return Ok(());
+        }
+        let _lock = lock(&self.root)?;
+        // Include the changes made by other processes before the lock was taken
+        if let Some(index) = RefIndex::read(&self.root)? {
+            *self = index;
+        }
+        let read = write_log(&self.root, &self.refs)?;
+        self.read = read;
+        self.removals = 0;
+        Ok(())
+    }
+
+    /// Returns `true` if the log was not read for [`REFRESH_INTERVAL`]. Until then, the
+    /// blobs not found in memory are assumed to be missing without touching the disk.
+    pub(crate) fn is_stale(&self) -> bool {
+        self.refreshed.elapsed() > REFRESH_INTERVAL
+    }
+
+    pub(crate) fn contains(&self, blob_ref: &BlobRef) -> bool {
+        self.bloom.may_contain(blob_ref.reference()) && self.refs.contains(blob_ref.reference())
+    }
+
+    /// Returns all the references, sorted.
+    pub(crate) fn blob_refs(&self) -> Vec<BlobRef> {
+        self.refs
+            .iter()
+            .filter_map(|reference| BlobRef::new(reference).ok())
+            .collect()
+    }
+
+    /// Returns the references starting with `prefix`.
+    pub(crate) fn find_prefix(&self, prefix: &str) -> Vec<BlobRef> {
+        self.refs
+            .range(prefix.to_string()..)
+            .take_while(|reference| reference.starts_with(prefix))
+            .filter_map(|reference| BlobRef::new(reference).ok())
+            .collect()
+    }
+
+    /// Adds a blob to the index, both in memory and on disk.
+    pub(crate) fn insert(&mut self, blob_ref: &BlobRef) -> Result<()> {
+        if self.contains(blob_ref) {
+            return Ok(());
+        }
+        self.append(blob_ref.reference())?;
+        self.refs.insert(blob_ref.reference().to_string());
+        if self.refs.len() > self.bloom.capacity() {
+            let refs = std::mem::take(&mut self.refs);
+            *self = RefIndex {
+                removals: self.removals,
+                ..RefIndex::from_refs(&self.root, refs, self.read)
+            };
+        } else {
+            self.bloom.insert(blob_ref.reference());
+        }
+        Ok(())
+    }
+
+    /// Removes a blob from the index, both in memory and on disk. The removal is logged
+    /// even if the blob is not in memory, as another process may have added it since.
+    pub(crate) fn remove(&mut self, blob_ref: &BlobRef) -> Result<()> {
+        self.refs.remove(blob_ref.reference());
+        self.append(&format!("-{}", blob_ref.reference()))?;
+        self.removals += 1;
+        self.compact_if_needed()
+    }
+
+    fn append(&self, line: &str) -> Result<()> {
+        let _lock = lock(&self.root)?;
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.root.join(INDEX_FILE))?
+            .write_all(format!("{line}\n").as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Writes `refs` as the new log, atomically, and returns its position once fully read.
+/// The caller must hold the lock.
+fn write_log(root: &Path, refs: &BTreeSet<String>) -> Result<LogPosition> {
+    let path = root.join(INDEX_FILE);
+    let generation = match File::open(&path) {
+        Ok(mut file) => read_generation(&mut file)?.wrapping_add(1),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => 1,
+        Err(e) => return Err(e.into()),
+    };
+    let tmp_path = root.join(format!(".{INDEX_FILE}.tmp"));
+    let mut file = File::create(&tmp_path)?;
+    writeln!(file, "{GENERATION_PREFIX}{generation}")?;
+    for reference in refs {
+        writeln!(file, "{reference}")?;
+    }
+    file.sync_all()?;
+    fs::rename(tmp_path, &path)?;
+    let metadata = fs::metadata(&path)?;
+    Ok(LogPosition {
+        generation,
+        offset: metadata.len(),
+        len: metadata.len(),
+        modified: metadata.modified().ok(),
+    })
+}
+
+/// Returns the generation of the log, 0 if it has none, e.g. when written by an older
+/// version of rstr.
+fn read_generation(file: &mut File) -> Result<u64> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut line = String::new();
+    BufReader::new(file.take(64)).read_line(&mut line)?;
+    Ok(line
+        .trim_end()
+        .strip_prefix(GENERATION_PREFIX)
+        .and_then(|generation| generation.parse().ok())
+        .unwrap_or(0))
+}
+
+/// Takes the lock serialising all the updates to the index. It is released when the
+/// returned file is dropped.
+fn lock(root: &Path) -> Result<File> {
+    fs::create_dir_all(root)?;
+    let lock = File::create(root.join(LOCK_FILE))?;
+    lock.lock_exclusive()?;
+    Ok(lock)
+}
@@ -0,0 +1,455 @@
+use super::error::{Error, Result};
+use super::models::BlobRef;
+use super::roots;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the file, in each root of the blob store, describing its layout.
+const DESCRIPTOR_FILE: &str = "rstr.store";
+
+/// First line of the descriptor file.
+const DESCRIPTOR_MAGIC: &str = "rstr-store";
+
+/// The version of the on-disk layout written by this version of rstr.
+///
+/// Version 1 stores each blob in `<shards>/<rest of the hash>/<filename>`, where the
+/// hash is the sha256 of the content and the shard directories are recorded in the
+/// descriptor, see [`ShardLayout`]. Roots without a descriptor, written before it was
+/// introduced, are version 0: they have the same layout, with the default shard
+/// directories, but are not marked as such.
+pub const LAYOUT_VERSION: u32 = 1;
+
+/// The hash algorithm the references are computed with.
+const HASH_ALGORITHM: &str = "sha256";
+
+/// How the metadata of the blobs is stored: in the name of the blob file.
+const METADATA_FORMAT: &str = "filename";
+
+/// How the blob directories are spread over nested shard directories named after the
+/// first characters of their reference: `depth` levels of `width` hex characters each.
+///
+/// The default, three levels of two characters, suits most stores. Small stores are
+/// better off with fewer levels, and huge ones with wider levels. The shard layout is
+/// recorded in the blob store, see [`BlobStore::reshard`] to change it.
+///
+/// # Examples
+///
+/// ```
+/// use rstr::{BlobRef, ShardLayout};
+///
+/// let blob_ref = BlobRef::new("f29bc64a9d3732b4b9035125fdb3285f5b6455778edca72414671e0ca3b2e0de").unwrap();
+/// let layout = ShardLayout::new(3, 1).unwrap();
+/// assert_eq!(
+///     layout.blob_path(&blob_ref).to_str().unwrap(),
+///     "f29/bc64a9d3732b4b9035125fdb3285f5b6455778edca72414671e0ca3b2e0de"
+/// );
+/// assert_eq!(ShardLayout::default().blob_path(&blob_ref), blob_ref.to_path());
+///
+/// assert!(ShardLayout::new(0, 3).is_err());
+/// ```
+///
+/// [`BlobStore::reshard`]: crate::BlobStore::reshard
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShardLayout {
+    /// The number of hex characters naming each shard directory
+    width: usize,
+    /// The number of nested shard directories
+    depth: usize,
+}
+
+impl Default for ShardLayout {
+    fn default() -> ShardLayout {
+        ShardLayout { width: 2, depth: 3 }
+    }
+}
+
+impl ShardLayout {
+    /// Creates a shard layout of `depth` levels of `width` characters. A depth of 0
+    /// stores all the blob directories in the root.
+    ///
+    /// # Errors
+    ///
+    /// It errors if `width` is not between 1 and 4, or `depth` is greater than 4.
+    pub fn new(width: usize, depth: usize) -> Result<ShardLayout> {
+        if !(1..=4).contains(&width) || depth > 4 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the shard width must be between 1 and 4 and the depth at most 4",
+            )
+            .into());
+        }
+        Ok(ShardLayout { width, depth })
+    }
+
+    /// Returns the number of hex characters naming each shard directory.
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Returns the number of nested shard directories.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Converts the reference of a blob into the path of its directory, relative to the
+    /// root of the blob store.
+    pub fn blob_path(&self, blob_ref: &BlobRef) -> PathBuf {
+        let reference = blob_ref.reference();
+        let mut path = PathBuf::new();
+        for level in 0..self.depth {
+            path.push(&reference[level * self.width..(level + 1) * self.width]);
+        }
+        path.push(&reference[self.depth * self.width..]);
+        path
+    }
+}
+
+/// What [`BlobStore::migrate`] or [`BlobStore::reshard`] did to a root.
+///
+/// [`BlobStore::migrate`]: crate::BlobStore::migrate
+/// [`BlobStore::reshard`]: crate::BlobStore::reshard
+#[derive(Debug)]
+pub struct Migration {
+    /// The root that was migrated
+    pub root: PathBuf,
+    /// The layout version of the root before the migration
+    pub from: u32,
+    /// The layout version of the root after the migration
+    pub to: u32,
+    /// The number of blobs moved to another directory
+    pub moved: usize,
+}
+
+/// The content of the descriptor file of a root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Descriptor {
+    version: u32,
+    hash: String,
+    metadata: String,
+    shards: ShardLayout,
+    /// The version the root is being migrated to, if a migration was interrupted
+    migrating: Option<u32>,
+    /// The shard layout the root is being converted to, if a conversion was interrupted
+    resharding: Option<ShardLayout>,
+}
+
+impl Descriptor {
+    fn current(shards: ShardLayout) -> Descriptor {
+        Descriptor {
+            version: LAYOUT_VERSION,
+            hash: HASH_ALGORITHM.to_string(),
+            metadata: METADATA_FORMAT.to_string(),
+            shards,
+            migrating: None,
+            resharding: None,
+        }
+    }
+
+    fn to_text(&self) -> String {
+        let mut lines = vec![
+            DESCRIPTOR_MAGIC.to_string(),
+            format!("version = {}", self.version),
+            format!("hash = {}", self.hash),
+            format!("metadata = {}", self.metadata),
+            format!("shard_width = {}", self.shards.width),
+            format!("shard_depth = {}", self.shards.depth),
+        ];
+        if let Some(version) = self.migrating {
+            lines.push(format!("migrating = {version}"));
+        }
+        if let Some(shards) = self.resharding {
+            lines.push(format!("target_shard_width = {}", shards.width));
+            lines.push(format!("target_shard_depth = {}", shards.depth));
+        }
+        lines.join("\n") + "\n"
+    }
+
+    fn from_text(text: &str) -> Option<Descriptor> {
+        let mut lines = text.lines();
+        if lines.next()? != DESCRIPTOR_MAGIC {
+            return None;
+        }
+        let mut descriptor = Descriptor {
+            version: 0,
+            ..Descriptor::current(ShardLayout::default())
+        };
+        let (mut target_width, mut target_depth) = (None, None);
+        for line in lines {
+            let (key, value) = line.split_once('=')?;
+            let value = value.trim();
+            match key.trim() {
+                "version" => descriptor.version = value.parse().ok()?,
+                "hash" => descriptor.hash = value.to_string(),
+                "metadata" => descriptor.metadata = value.to_string(),
+                "shard_width" => descriptor.shards.width = value.parse().ok()?,
+                "shard_depth" => descriptor.shards.depth = value.parse().ok()?,
+                "migrating" => descriptor.migrating = Some(value.parse().ok()?),
+                "target_shard_width" => target_width = Some(value.parse().ok()?),
+                "target_shard_depth" => target_depth = Some(value.parse().ok()?),
+                // Unknown keys are checked through the version
+                _ => {}
+            }
+        }
+        descriptor.shards =
+            ShardLayout::new(descriptor.shards.width, descriptor.shards.depth).ok()?;
+        if let (Some(width), Some(depth)) = (target_width, target_depth) {
+            descriptor.resharding = Some(ShardLayout::new(width, depth).ok()?);
+        }
+        Some(descriptor)
+    }
+}
+
+fn incompatible(root: &Path, reason: &str) -> Error {
+    Error::IncompatibleLayout(format!("{}: {reason}", root.display()))
+}
+
+/// Reads the descriptor of `root`, if it has one.
+fn read(root: &Path) -> Result<Option<Descriptor>> {
+    match fs::read_to_string(root.join(DESCRIPTOR_FILE)) {
+        Ok(text) => Descriptor::from_text(&text)
+            .map(Some)
+            .ok_or_else(|| incompatible(root, "the store descriptor cannot be parsed")),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Error::Io(e)),
+    }
+}
+
+/// Replaces the descriptor of `root` atomically.
+fn write(root: &Path, descriptor: &Descriptor) -> Result<()> {
+    let tmp_path = root.join(format!(".{DESCRIPTOR_FILE}.tmp"));
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(descriptor.to_text().as_bytes())?;
+    file.sync_all()?;
+    fs::rename(tmp_path, root.join(DESCRIPTOR_FILE))?;
+    Ok(())
+}
+
+/// Whether `path` is the root of a blob store, i.e. has a descriptor.
+pub(crate) fn is_root(path: &Path) -> bool {
+    path.join(DESCRIPTOR_FILE).is_file()
+}
+
+/// Checks that the layout of `root` is the one this version of rstr uses, and returns
+/// its shard layout. Empty roots, e.g. just created, are given a descriptor. Roots
+/// written before descriptors were introduced have the default layout and are accepted
+/// as they are.
+pub(crate) fn check(root: &Path) -> Result<ShardLayout> {
+    let Some(descriptor) = read(root)? else {
+        if root.read_dir()?.next().is_none() {
+            write(root, &Descriptor::current(ShardLayout::default()))?;
+        }
+        return Ok(ShardLayout::default());
+    };
+    if let Some(version) = descriptor.migrating {
+        return Err(incompatible(
+            root,
+            &format!("the migration to layout version {version} was interrupted, run `rstr migrate` to resume it"),
+        ));
+    }
+    if descriptor.resharding.is_some() {
+        return Err(incompatible(
+            root,
+            "the conversion to another shard layout was interrupted, run `rstr migrate` to resume it",
+        ));
+    }
+    if descriptor.version > LAYOUT_VERSION {
+        return Err(incompatible(
+            root,
+            &format!(
+                "the store has layout version {}, this version of rstr only supports up to {LAYOUT_VERSION}",
+                descriptor.version
+            ),
+        ));
+    }
+    if descriptor.version < LAYOUT_VERSION {
+        return Err(incompatible(
+            root,
+            &format!(
+                "the store has layout version {}, run `rstr migrate` to upgrade it to {LAYOUT_VERSION}",
+                descriptor.version
+            ),
+        ));
+    }
+    if descriptor.hash != HASH_ALGORITHM {
+        return Err(incompatible(
+            root,
+            &format!(
+                "the store uses the `{}` hash, not `{HASH_ALGORITHM}`",
+                descriptor.hash
+            ),
+        ));
+    }
+    if descriptor.metadata != METADATA_FORMAT {
+        return Err(incompatible(
+            root,
+            &format!(
+                "the store uses the `{}` metadata format",
+                descriptor.metadata
+            ),
+        ));
+    }
+    Ok(descriptor.shards)
+}
+
+/// Upgrades `root` from layout version `from` to the next one. Each step must be safe to
+/// run again if it was interrupted.
+fn upgrade(root: &Path, from: u32) -> Result<()> {
+    match from {
+        // Unversioned roots already have the layout of version 1, with the default shard
+        // layout
+        0 => Ok(()),
+        _ => Err(incompatible(
+            root,
+            &format!("no migration from layout version {from}"),
+        )),
+    }
+}
+
+/// Names of the entries of a root, besides the shard directories and the hidden files,
+/// written by the blob store itself, e.g. for its snapshots or aliases.
+const STORE_ENTRIES: &[&str] = &[
+    "snapshots",
+    "refs",
+    "buckets",
+    "packs",
+    "refs.index",
+    "access",
+    "quarantine",
+];
+
+/// Checks that `path`, to be read as a directory of files, is not the root of a blob store
+/// written before descriptors were introduced, which must be migrated first.
+pub(crate) fn check_not_unversioned(path: &Path) -> Result<()> {
+    if !is_root(path) && is_unversioned_root(path) {
+        return Err(incompatible(
+            path,
+            "the store has no recorded layout, run `rstr migrate` first",
+        ));
+    }
+    Ok(())
+}
+
+/// Whether `path` looks like the root of a blob store without a descriptor: it holds
+/// shard directories of the default layout, and nothing else but the entries of the blob
+/// store itself and hidden files.
+fn is_unversioned_root(path: &Path) -> bool {
+    let Ok(entries) = path.read_dir() else {
+        return false;
+    };
+    let width = ShardLayout::default().width;
+    let mut has_shards = false;
+    for entry in entries {
+        let Ok(entry) = entry else {
+            return false;
+        };
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with('.') || STORE_ENTRIES.contains(&name.as_ref()) {
+            continue;
+        }
+        let is_shard = name.len() == width
+            && name.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f'))
+            && entry.file_type().is_ok_and(|file_type| file_type.is_dir());
+        if !is_shard {
+            return false;
+        }
+        has_shards = true;
+    }
+    has_shards
+}
+
+/// Migrates `root` to [`LAYOUT_VERSION`], resuming an interrupted migration or
+/// conversion if there is one. Returns `None` if there was nothing to do.
+pub(crate) fn migrate(root: &Path) -> Result<Option<Migration>> {
+    let descriptor = read(root)?;
+    let from = descriptor
+        .as_ref()
+        .map_or(0, |descriptor| descriptor.version);
+    if from > LAYOUT_VERSION {
+        return check(root).map(|_| None);
+    }
+    let mut descriptor = descriptor.unwrap_or_else(|| Descriptor {
+        version: 0,
+        ..Descriptor::current(ShardLayout::default())
+    });
+    if from == LAYOUT_VERSION && descriptor.migrating.is_none() {
+        return match descriptor.resharding {
+            Some(target) => reshard(root, target),
+            None => check(root).map(|_| None),
+        };
+    }
+
+    for version in from..LAYOUT_VERSION {
+        // Recorded first, so that the store cannot be opened half migrated
+        descriptor.migrating = Some(version + 1);
+        write(root, &descriptor)?;
+        upgrade(root, version)?;
+        descriptor.version = version + 1;
+    }
+    descriptor.migrating = None;
+    write(root, &descriptor)?;
+    let moved = match descriptor.resharding {
+        Some(target) => reshard(root, target)?.map_or(0, |migration| migration.moved),
+        None => 0,
+    };
+    Ok(Some(Migration {
+        root: root.to_path_buf(),
+        from,
+        to: LAYOUT_VERSION,
+        moved,
+    }))
+}
+
+/// Moves the blobs of `root`, which must have the current layout version, to the shard
+/// layout `target`. An interrupted conversion is finished first. Returns `None` if the
+/// root already has the `target` shard layout.
+pub(crate) fn reshard(root: &Path, target: ShardLayout) -> Result<Option<Migration>> {
+    let Some(mut descriptor) = read(root)? else {
+        return Err(incompatible(root, "run `rstr migrate` first"));
+    };
+    let mut moved = 0;
+    if let Some(interrupted) = descriptor.resharding {
+        moved += move_blobs(root, descriptor.shards, interrupted)?;
+        descriptor.shards = interrupted;
+        descriptor.resharding = None;
+        write(root, &descriptor)?;
+    }
+    check(root)?;
+    if descriptor.shards != target {
+        // Recorded first, so that the store cannot be opened half converted
+        descriptor.resharding = Some(target);
+        write(root, &descriptor)?;
+        moved += move_blobs(root, descriptor.shards, target)?;
+        descriptor.shards = target;
+        descriptor.resharding = None;
+        write(root, &descriptor)?;
+    } else if moved == 0 {
+        return Ok(None);
+    }
+    Ok(Some(Migration {
+        root: root.to_path_buf(),
+        from: descriptor.version,
+        to: descriptor.version,
+        moved,
+    }))
+}
+
+/// Moves the blob directories of `root` from the shard layout `from` to `to`. Blobs
+/// already moved by an interrupted run are skipped, as they are not found in `from`.
+fn move_blobs(root: &Path, from: ShardLayout, to: ShardLayout) -> Result<usize> {
+    let mut moved = 0;
+    for blob_ref in roots::list_blobs(root, from)? {
+        let src = root.join(from.blob_path(&blob_ref));
+        let dest = root.join(to.blob_path(&blob_ref));
+        if roots::has_blob(&dest) {
+            // Copied before the interruption, the content is the same
+            fs::remove_dir_all(&src)?;
+        } else {
+            roots::move_blob(&src, &dest)?;
+        }
+        roots::prune_shards(root, from, &blob_ref);
+        moved += 1;
+    }
+    Ok(moved)
+}
@@ -14,7 +14,7 @@
 //! use std::path::{Path, PathBuf};
 //!
 //! let blob_store = BlobStore::new("../tests/test_data_store").unwrap();
-//! let n_threads: u8 = 8;
+//! let n_threads = 8;
 //! let (blob_refs_with_paths, _): (Vec<(PathBuf, BlobRef)>, _) = blob_store.add_files(
 //!     &[
 //!         // Can add files
@@ -49,10 +49,62 @@
 //! assert_eq!(metadata.mime_type, "text/plain");
 //! ```
 
+mod alias;
+#[cfg(feature = "async")]
+mod async_store;
+mod backend;
+mod bucket;
+mod bundle;
+mod car;
+mod cid;
+mod diff;
+mod erasure;
 mod error;
+mod filter;
+mod fsck;
+#[cfg(feature = "http")]
+mod http;
+mod index;
+mod layout;
 mod models;
+mod owned;
+mod pack;
+mod pipeline;
+mod quarantine;
+mod roots;
+#[cfg(feature = "s3")]
+mod s3;
+#[cfg(feature = "serde")]
+mod serialize;
+mod snapshot;
+mod sync;
+mod tier;
+mod tree;
 mod utils;
 
+pub use alias::AliasEntry;
+#[cfg(feature = "async")]
+pub use async_store::{AsyncBlobReader, AsyncBlobStore};
+pub use backend::{Backend, CacheOptions};
+pub use bucket::{Bucket, BucketEntry};
+pub use car::ImportReport;
+pub use diff::{diff, DiffSide, StoreDiff};
+pub use erasure::{BlobReader, ErasureCoding};
 pub use error::{Error, Result};
+pub use filter::Filter;
+pub use fsck::{BlobCheck, RepairReport};
+#[cfg(feature = "http")]
+pub use http::HttpBackend;
+pub use layout::{Migration, ShardLayout, LAYOUT_VERSION};
 pub use models::{BlobMetadata, BlobRef, BlobStore};
+pub use pack::RepackReport;
+pub use pipeline::{AddOptions, AddPlan, PlannedFile};
+pub use quarantine::QuarantineEntry;
+pub use roots::Placement;
+#[cfg(feature = "s3")]
+pub use s3::S3Backend;
 pub use sha2::Digest as Sha2Digest;
+pub use snapshot::{RetentionPolicy, Snapshot};
+pub use sync::{sync, SyncOptions, SyncReport};
+pub use tier::{Tier, TierReport};
+pub use tree::{EntryKind, Tree, TreeDiff, TreeEntry};
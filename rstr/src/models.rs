@@ -1,25 +1,50 @@
+use super::alias::{self, AliasEntry, Precondition};
+use super::backend::{Backend, CacheOptions, CacheUsage};
+use super::bucket::{self, Bucket, BucketEntry};
+use super::bundle;
+use super::car::{self, ImportReport};
+use super::erasure::{self, BlobReader, ErasureCoding, ShardReader};
 use super::error::{Error, Result};
-use super::utils::collect_file_paths;
-#[cfg(feature = "progress_bar")]
-use super::utils::progress_bar;
-use chrono::{offset::Utc, DateTime};
-#[cfg(feature = "progress_bar")]
-use indicatif::ProgressIterator;
-use lazy_static::lazy_static;
+use super::filter::Filter;
+use super::fsck::{self, BlobCheck, RepairReport};
+use super::index::RefIndex;
+use super::layout::{self, Migration, ShardLayout};
+use super::owned::OwnedMarkers;
+use super::pack::{self, PackEntry, RepackReport};
+use super::pipeline::{self, AddOptions, AddPlan};
+use super::quarantine::{self, QuarantineEntry};
+use super::roots::{self, Placement};
+use super::snapshot::{self, RetentionPolicy, Snapshot};
+use super::tier::{self, Tier, TierReport};
+use super::tree::{self, Tree, TreeDiff};
+use chrono::{offset::Utc, DateTime, Duration};
 use regex::Regex;
 use sha2::{Digest, Sha256};
-use std::sync::mpsc;
-use std::thread;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, PoisonError, RwLock, RwLockReadGuard};
 use std::{
     fs::{self, File},
-    io,
+    io::{self, Read},
     path::Path,
     path::PathBuf,
 };
 use tree_magic_mini as magic;
 
+static VALID_HASH_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^[a-f0-9]{64}$").unwrap());
+static VALID_PREFIX_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[a-f0-9]{6,64}$").unwrap());
+/// Counts the temporary directories created, to give each its own name.
+static TMP_DIRS: AtomicUsize = AtomicUsize::new(0);
+
 /// Struct representing a reference to an entry in the blob store
-#[derive(Debug, Clone)]
+///
+/// References are ordered like their hex representation, which is also the order of the
+/// hashes. With the `serde` feature, they are serialized as their hex representation in
+/// human readable formats (e.g. JSON) and as their 32 bytes otherwise.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct BlobRef {
     /// The value of the reference, i.e. the sha256 hash of the blob
     value: String,
@@ -27,6 +52,7 @@ pub struct BlobRef {
 
 /// Struct representing the metadata associated to a blob
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct BlobMetadata {
     /// The filename of the blob
     pub filename: String,
@@ -36,6 +62,8 @@ pub struct BlobMetadata {
     pub size: u64,
     /// The creation timestamp of the blob
     pub created: DateTime<Utc>,
+    /// The tier the blob is stored in, see [`BlobStore::with_cold_tier`]
+    pub tier: Tier,
 }
 
 /// Returns a [`BlobRef`] instance from a hasher
@@ -63,7 +91,7 @@ impl BlobRef {
     ///
     /// The method will return a [`Error::InvalidRef`] if the input string
     /// - has `len() != 64`
-    /// - contains any char except lowercase letters and digits
+    /// - contains any char except lowercase hexadecimal digits
     ///
     /// # Examples
     /// ```
@@ -78,12 +106,7 @@ impl BlobRef {
     /// let blob_ref = BlobRef::new("....aninvalidhash.29bc64a9d3732b4b9035125fdb3285f5b6455778edca7");
     /// assert!(blob_ref.is_err());
     /// ```
-
     pub fn new(value: &str) -> Result<BlobRef> {
-        lazy_static! {
-            static ref VALID_HASH_REGEX: Regex = Regex::new(r"^[a-z0-9]{64}$").unwrap();
-        }
-
         if VALID_HASH_REGEX.is_match(value) {
             Ok(BlobRef {
                 value: String::from(value),
@@ -93,7 +116,8 @@ impl BlobRef {
         }
     }
 
-    /// Converts the blob's reference into a path relative to the root of the blob store.
+    /// Converts the blob's reference into a path relative to the root of a blob store with
+    /// the default shard layout, see [`ShardLayout`](crate::ShardLayout).
     ///
     /// # Examples
     ///
@@ -124,6 +148,69 @@ impl BlobRef {
     pub fn reference(&self) -> &str {
         &self.value
     }
+
+    /// Returns the 32 bytes of the sha256 hash of the blob, e.g. for compact binary
+    /// formats.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use rstr::BlobRef;
+    /// let blob_ref: BlobRef = "f29bc64a9d3732b4b9035125fdb3285f5b6455778edca72414671e0ca3b2e0de"
+    ///     .parse()
+    ///     .unwrap();
+    ///
+    /// let bytes = blob_ref.to_bytes();
+    /// assert_eq!(bytes[..2], [0xf2, 0x9b]);
+    /// assert_eq!(BlobRef::from_bytes(&bytes), blob_ref);
+    /// ```
+    pub fn to_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0; 32];
+        for (byte, pair) in bytes.iter_mut().zip(self.value.as_bytes().chunks(2)) {
+            // The reference is validated as lowercase hex when created
+            *byte = (hex_value(pair[0]) << 4) | hex_value(pair[1]);
+        }
+        bytes
+    }
+
+    /// Creates a [`BlobRef`] from the 32 bytes of a sha256 hash, see
+    /// [`BlobRef::to_bytes`].
+    pub fn from_bytes(bytes: &[u8; 32]) -> BlobRef {
+        let value = bytes
+            .iter()
+            .fold(String::with_capacity(64), |mut value, byte| {
+                let _ = write!(value, "{byte:02x}");
+                value
+            });
+        BlobRef { value }
+    }
+}
+
+/// Returns the value of a lowercase hex digit.
+fn hex_value(digit: u8) -> u8 {
+    match digit {
+        b'0'..=b'9' => digit - b'0',
+        _ => digit - b'a' + 10,
+    }
+}
+
+/// Parses a reference, like [`BlobRef::new`].
+///
+/// # Examples
+///
+/// ```
+/// # use rstr::BlobRef;
+/// let blob_ref: BlobRef = "f29bc64a9d3732b4b9035125fdb3285f5b6455778edca72414671e0ca3b2e0de"
+///     .parse()
+///     .unwrap();
+/// assert!("not a reference".parse::<BlobRef>().is_err());
+/// ```
+impl FromStr for BlobRef {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<BlobRef> {
+        BlobRef::new(s)
+    }
 }
 
 impl std::fmt::Display for BlobRef {
@@ -133,22 +220,47 @@ impl std::fmt::Display for BlobRef {
 }
 
 /// Struct for interacting with the blob store
+///
+/// The content of the blobs can be spread over several roots (e.g. one per disk), see
+/// [`BlobStore::with_roots`], and each blob can be copied to more than one of them, see
+/// [`BlobStore::with_replicas`], or erasure coded across them, see
+/// [`BlobStore::with_erasure_coding`]. Small blobs can be packed together instead, see
+/// [`BlobStore::with_packing`]. Snapshots, aliases, buckets, pack files and the index of
+/// the blobs, see [`BlobStore::with_index`], are kept in the first root.
 #[derive(Clone, Debug)]
 pub struct BlobStore {
     root: PathBuf,
+    roots: Vec<PathBuf>,
+    placement: Placement,
+    replicas: usize,
+    erasure_coding: Option<ErasureCoding>,
+    pack_threshold: Option<u64>,
+    index: Option<Arc<RwLock<RefIndex>>>,
+    shard_layout: ShardLayout,
+    cold_tier: Option<Box<BlobStore>>,
+    upstream: Option<Arc<dyn Backend>>,
+    cache: CacheOptions,
+    cache_usage: Arc<Mutex<Option<CacheUsage>>>,
+    owned_markers: Arc<Mutex<OwnedMarkers>>,
+    verify_on_read: bool,
 }
 
-type BlobRefAndPath = (PathBuf, BlobRef);
+pub(crate) type BlobRefAndPath = (PathBuf, BlobRef);
 
 impl BlobStore {
     /// Creates a new instance of the `BlobStore` struct used to interact with the blob
     /// store. If the specified blob store root path does not exists, it tries to create
     /// it.
     ///
+    /// A new blob store is marked with the version of its on-disk layout, which is checked
+    /// whenever it is opened.
+    ///
     /// # Errors
     ///
-    /// It errors if the specified path is not a directory or if it does not exist and
-    /// cannot be created.
+    /// It errors with [`Error::NotADirectory`] if the specified path is not a directory, and
+    /// with [`Error::PermissionDenied`] if it does not exist and cannot be created. It
+    /// errors with [`Error::IncompatibleLayout`] if the blob store was written with another
+    /// layout, see [`BlobStore::migrate`].
     ///
     /// # Examples
     ///
@@ -162,239 +274,2952 @@ impl BlobStore {
     /// assert!(blob_store.is_err());
     /// ```
     pub fn new<P: AsRef<Path>>(path: P) -> Result<BlobStore> {
-        let path = path.as_ref();
-        if !path.exists() {
-            fs::create_dir_all(path)?
-        } else if !path.is_dir() {
-            // TODO: return proper error
-            return Err(io::Error::from(io::ErrorKind::Other).into());
-        }
-        Ok(BlobStore { root: path.into() })
+        BlobStore::with_roots(&[path], Placement::default())
     }
 
-    /// Returns an instance of the hasher used to compute the blob reference for a file
+    /// Returns `true` if `path` is the root of a blob store, as opposed to e.g. a directory
+    /// of files to add. Blob stores written before their layout was recorded, see
+    /// [`BlobStore::migrate`], are not recognised.
     ///
     /// # Examples
     ///
     /// ```
     /// use rstr::BlobStore;
-    /// use sha2::{Digest, Sha256};
-    ///
-    /// let mut hasher = BlobStore::hasher();
-    /// hasher.update(b"hello world");
-    /// let result = hasher.finalize();
-    /// assert_eq!(format!("{:x}", result), "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9")
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// BlobStore::new(dir.path().join("store")).unwrap();
+    /// assert!(BlobStore::is_blob_store(dir.path().join("store")));
+    /// assert!(!BlobStore::is_blob_store(dir.path()));
     /// ```
-    pub fn hasher() -> Sha256 {
-        Sha256::new()
+    pub fn is_blob_store<P: AsRef<Path>>(path: P) -> bool {
+        layout::is_root(path.as_ref())
     }
 
-    /// Given a `BlobRef` it returns it's path inside the blob store
-    fn get_blob_path(&self, blob_ref: &BlobRef) -> PathBuf {
-        self.root.join(blob_ref.to_path())
+    /// Creates a blob store spanning several roots, e.g. one per disk. New blobs are
+    /// stored in the root chosen by `placement`, while lookups search all the roots.
+    ///
+    /// # Errors
+    ///
+    /// It errors if `roots` is empty, or as [`BlobStore::new`] for any of the roots.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::{BlobStore, Placement};
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let roots = [dir.path().join("disk1"), dir.path().join("disk2")];
+    /// let blob_store = BlobStore::with_roots(&roots, Placement::HashRange).unwrap();
+    ///
+    /// // "b94d27..." is in the upper half of the hash range
+    /// let blob_ref = blob_store.add_bytes(b"hello world", "hello.txt").unwrap();
+    /// assert!(roots[1].join(blob_ref.to_path()).exists());
+    /// assert!(blob_store.exists(&blob_ref));
+    /// ```
+    pub fn with_roots<P: AsRef<Path>>(roots: &[P], placement: Placement) -> Result<BlobStore> {
+        let mut paths = vec![];
+        let mut layouts: Vec<ShardLayout> = vec![];
+        for path in roots {
+            let path = path.as_ref();
+            if !path.exists() {
+                fs::create_dir_all(path).map_err(|e| Error::from(e).with_path(path))?;
+            } else if !path.is_dir() {
+                return Err(Error::NotADirectory(path.to_path_buf()));
+            }
+            let shard_layout = layout::check(path).map_err(|e| e.with_path(path))?;
+            if layouts.last().is_some_and(|last| *last != shard_layout) {
+                return Err(Error::IncompatibleLayout(format!(
+                    "{}: the roots of the blob store have different shard layouts",
+                    path.display()
+                )));
+            }
+            layouts.push(shard_layout);
+            paths.push(path.to_path_buf());
+        }
+        let root = paths
+            .first()
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no root given"))?;
+        Ok(BlobStore {
+            root,
+            roots: paths,
+            placement,
+            replicas: 1,
+            erasure_coding: None,
+            pack_threshold: None,
+            index: None,
+            shard_layout: layouts[0],
+            cold_tier: None,
+            upstream: None,
+            cache: CacheOptions::default(),
+            cache_usage: Arc::default(),
+            owned_markers: Arc::default(),
+            verify_on_read: false,
+        })
     }
 
-    /// Given a `BlobRef` it returns it's path inside the blob store, including the filename
+    /// Upgrades the on-disk layout of the roots of a blob store to
+    /// [`LAYOUT_VERSION`](crate::LAYOUT_VERSION), so that it can be opened. Roots already
+    /// up to date are left untouched.
+    ///
+    /// The migration happens in place. If it is interrupted, the blob store cannot be
+    /// opened until the migration is run again, which resumes it.
     ///
     /// # Errors
     ///
-    /// It will error if the directory is not present/cannot be read or there is no file.
-    fn get_blob_file_path(&self, blob_ref: &BlobRef) -> Result<PathBuf> {
-        let mut entries = self.get_blob_path(blob_ref).read_dir()?;
-        if let Some(Ok(entry)) = entries.next() {
-            return Ok(entry.path());
-        };
-        Err(Error::BlobNotFound)
+    /// It errors with [`Error::IncompatibleLayout`] if a root has a layout which cannot be
+    /// migrated, e.g. a newer one, and if a root cannot be read or written.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::{BlobStore, LAYOUT_VERSION};
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// # std::fs::write(dir.path().join("old"), b"").unwrap();
+    /// // A blob store written before layouts were versioned
+    /// let migrations = BlobStore::migrate(&[dir.path()]).unwrap();
+    /// assert_eq!(migrations[0].from, 0);
+    /// assert_eq!(migrations[0].to, LAYOUT_VERSION);
+    ///
+    /// assert!(BlobStore::migrate(&[dir.path()]).unwrap().is_empty());
+    /// ```
+    pub fn migrate<P: AsRef<Path>>(roots: &[P]) -> Result<Vec<Migration>> {
+        let mut migrations = vec![];
+        for root in roots {
+            if let Some(migration) = layout::migrate(root.as_ref())? {
+                migrations.push(migration);
+            }
+        }
+        Ok(migrations)
     }
 
-    /// Add a file to the blob store given a path.
+    /// Moves the blobs of a blob store to the shard layout `target`, in place, and
+    /// records it in the blob store. The roots are migrated to the current layout
+    /// version first, see [`BlobStore::migrate`].
+    ///
+    /// If the conversion is interrupted, the blob store cannot be opened until it is
+    /// resumed by [`BlobStore::migrate`] or [`BlobStore::reshard`].
+    ///
+    /// # Errors
+    ///
+    /// It errors as [`BlobStore::migrate`], and if a blob cannot be moved.
     ///
     /// # Examples
     ///
     /// ```
-    /// use rstr::{BlobStore, BlobRef};
-    /// use std::path::PathBuf;
-    /// let blob_store = BlobStore::new("../tests/test_data_store/").unwrap();
+    /// use rstr::{BlobStore, ShardLayout};
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let blob_ref = BlobStore::new(dir.path())
+    ///     .unwrap()
+    ///     .add_bytes(b"hello world", "hello.txt")
+    ///     .unwrap();
     ///
-    /// let blob_ref: BlobRef = blob_store.add("../tests/test_file.txt").unwrap();
-    /// assert!(blob_store.exists(&blob_ref));
-    /// assert_eq!(blob_ref.reference(), "f29bc64a9d3732b4b9035125fdb3285f5b6455778edca72414671e0ca3b2e0de");
+    /// let layout = ShardLayout::new(1, 2).unwrap();
+    /// let migrations = BlobStore::reshard(&[dir.path()], layout).unwrap();
+    /// assert_eq!(migrations[0].moved, 1);
+    /// assert!(dir.path().join("b/9").exists());
+    ///
+    /// let blob_store = BlobStore::new(dir.path()).unwrap();
+    /// assert_eq!(blob_store.shard_layout(), layout);
+    /// assert_eq!(blob_store.get(&blob_ref).unwrap(), b"hello world");
     /// ```
-    pub fn add<P: AsRef<Path>>(&self, path: P) -> Result<BlobRef> {
-        let mut file = File::open(&path)?;
-        let mut hasher = BlobStore::hasher();
+    pub fn reshard<P: AsRef<Path>>(roots: &[P], target: ShardLayout) -> Result<Vec<Migration>> {
+        let mut migrations = vec![];
+        for root in roots {
+            let root = root.as_ref();
+            let migrated = layout::migrate(root)?;
+            match layout::reshard(root, target)? {
+                Some(mut migration) => {
+                    if let Some(migrated) = migrated {
+                        migration.from = migrated.from;
+                        migration.moved += migrated.moved;
+                    }
+                    migrations.push(migration);
+                }
+                None => migrations.extend(migrated),
+            }
+        }
+        Ok(migrations)
+    }
+
+    /// Returns how the blob directories are spread over shard directories.
+    pub fn shard_layout(&self) -> ShardLayout {
+        self.shard_layout
+    }
 
-        io::copy(&mut file, &mut hasher)?;
-        let blob_ref = BlobRef::from(hasher);
+    /// Sets how many roots each new blob is written to, so that losing a disk loses no
+    /// data. The copies go to the roots chosen by the placement, see [`Placement`].
+    ///
+    /// When reading a blob stored more than once, a copy that cannot be read or whose
+    /// content does not match its reference is skipped in favour of the next one. Use
+    /// [`BlobStore::repair`] to restore the missing copies.
+    ///
+    /// # Errors
+    ///
+    /// It errors if `replicas` is zero or greater than the number of roots.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::{BlobStore, Placement};
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let roots = [dir.path().join("disk1"), dir.path().join("disk2")];
+    /// let blob_store = BlobStore::with_roots(&roots, Placement::HashRange)
+    ///     .unwrap()
+    ///     .with_replicas(2)
+    ///     .unwrap();
+    ///
+    /// let blob_ref = blob_store.add_bytes(b"hello world", "hello.txt").unwrap();
+    /// assert!(roots.iter().all(|root| root.join(blob_ref.to_path()).exists()));
+    ///
+    /// // Losing a disk loses nothing
+    /// std::fs::remove_dir_all(&roots[1]).unwrap();
+    /// assert_eq!(blob_store.get(&blob_ref).unwrap(), b"hello world");
+    ///
+    /// assert!(BlobStore::new(dir.path()).unwrap().with_replicas(2).is_err());
+    /// ```
+    pub fn with_replicas(mut self, replicas: usize) -> Result<BlobStore> {
+        if replicas == 0 || replicas > self.roots.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the number of replicas must be between 1 and the number of roots",
+            )
+            .into());
+        }
+        if replicas > 1 && self.pack_threshold.is_some() {
+            return Err(packing_conflict());
+        }
+        self.replicas = replicas;
+        Ok(self)
+    }
 
-        if !self.exists(&blob_ref) {
-            let save_path = self.get_blob_path(&blob_ref);
-            fs::create_dir_all(&save_path)?;
+    /// Stores the blobs of at least [`ErasureCoding::min_size`] bytes as erasure coded
+    /// shards, one per root, instead of whole copies. Such blobs survive the loss of
+    /// [`ErasureCoding::parity_shards`] roots, and are rebuilt from the remaining shards
+    /// when read. Smaller blobs are still stored whole, see [`BlobStore::with_replicas`].
+    ///
+    /// # Errors
+    ///
+    /// It errors if there are fewer roots than shards, if the layout is not supported
+    /// (e.g. no parity shards or more than 256 shards), or if packing is enabled.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::{BlobStore, ErasureCoding, Placement};
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let roots: Vec<_> = (0..3).map(|i| dir.path().join(format!("disk{i}"))).collect();
+    /// let coding = ErasureCoding {
+    ///     data_shards: 2,
+    ///     parity_shards: 1,
+    ///     min_size: 0,
+    /// };
+    /// let blob_store = BlobStore::with_roots(&roots, Placement::HashRange)
+    ///     .unwrap()
+    ///     .with_erasure_coding(coding)
+    ///     .unwrap();
+    ///
+    /// let blob_ref = blob_store.add_bytes(b"hello world", "hello.txt").unwrap();
+    ///
+    /// // Losing a disk loses nothing
+    /// std::fs::remove_dir_all(&roots[0]).unwrap();
+    /// assert_eq!(blob_store.get(&blob_ref).unwrap(), b"hello world");
+    /// assert_eq!(blob_store.metadata(&blob_ref).unwrap().filename, "hello.txt");
+    /// ```
+    pub fn with_erasure_coding(mut self, coding: ErasureCoding) -> Result<BlobStore> {
+        coding.codec()?;
+        if coding.shards() > self.roots.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the number of shards must not be greater than the number of roots",
+            )
+            .into());
+        }
+        if self.pack_threshold.is_some() {
+            return Err(packing_conflict());
+        }
+        self.erasure_coding = Some(coding);
+        Ok(self)
+    }
 
-            let filename = path.as_ref().file_name().unwrap();
-            let save_path = save_path.join(&filename);
-            fs::copy(path, save_path)?;
-        };
+    /// Returns the layout of the erasure coded blobs, if enabled.
+    pub fn erasure_coding(&self) -> Option<ErasureCoding> {
+        self.erasure_coding
+    }
 
-        Ok(blob_ref)
+    /// Returns the layout a blob of `size` bytes is written with, if it is erasure coded.
+    fn erasure_coding_for(&self, size: u64) -> Option<ErasureCoding> {
+        self.erasure_coding.filter(|coding| size >= coding.min_size)
     }
 
-    /// Given a list of paths to files/directories it adds them to the blob store. In the case
-    /// of a directory it adds all the files in its children recursively.
+    /// Appends the blobs smaller than `threshold` bytes to pack files, with an index,
+    /// instead of storing each of them in its own file. This saves inodes and speeds up
+    /// listing when the store holds many tiny blobs.
     ///
-    /// The function iterates over all paths in parallel and adds each file to the blob store.
+    /// Packed blobs are read, listed and deleted like the others. The content of deleted
+    /// blobs stays in the pack files until [`BlobStore::repack`] is called. Pack files are
+    /// kept in the first root: packed blobs are neither replicated nor erasure coded.
     ///
-    /// It returns two vectors: one containing the paths to the files that were successfully
-    /// added together with their generated `BlobRef` and the other containing the list of
-    /// paths that errored together with the error.
+    /// # Errors
+    ///
+    /// It errors if the blobs are replicated or erasure coded, since packing them would
+    /// silently lower their durability.
     ///
     /// # Examples
     ///
     /// ```
-    /// # use std::path::Path;
-    /// use rstr::{BlobStore, BlobRef};
+    /// use rstr::{BlobStore, Placement};
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let blob_store = BlobStore::new(dir.path()).unwrap().with_packing(1024).unwrap();
     ///
-    /// let blob_store = BlobStore::new("../tests/test_data_store").unwrap();
+    /// let blob_ref = blob_store.add_bytes(b"hello world", "hello.txt").unwrap();
+    /// assert!(!dir.path().join(blob_ref.to_path()).exists());
+    /// assert!(blob_store.exists(&blob_ref));
+    /// assert_eq!(blob_store.get(&blob_ref).unwrap(), b"hello world");
     ///
-    /// let paths = [Path::new("../tests/test_file.txt")];
-    /// let threads: u8 = 8;
-    /// let (blob_refs_with_paths, errors) = blob_store.add_files(&paths[..], threads);
-    /// let blob_refs: Vec<BlobRef> = blob_refs_with_paths.into_iter().map(|(_, b)| b).collect();
+    /// blob_store.delete(&blob_ref).unwrap();
+    /// assert!(!blob_store.exists(&blob_ref));
+    /// assert_eq!(blob_store.repack().unwrap().reclaimed, 11);
     ///
-    /// assert_eq!(blob_refs[0].reference(), "f29bc64a9d3732b4b9035125fdb3285f5b6455778edca72414671e0ca3b2e0de")
+    /// let roots = [dir.path().join("disk1"), dir.path().join("disk2")];
+    /// let replicated = BlobStore::with_roots(&roots, Placement::HashRange)
+    ///     .unwrap()
+    ///     .with_replicas(2)
+    ///     .unwrap();
+    /// assert!(replicated.with_packing(1024).is_err());
     /// ```
-    pub fn add_files<P: AsRef<Path>>(
-        &self,
-        paths: &[P],
-        threads: u8,
-    ) -> (Vec<BlobRefAndPath>, Vec<(PathBuf, Error)>) {
-        let paths: Vec<PathBuf> = paths
-            .iter()
-            .flat_map(|p| collect_file_paths(p.as_ref()))
-            .collect();
+    pub fn with_packing(mut self, threshold: u64) -> Result<BlobStore> {
+        if self.replicas > 1 || self.erasure_coding.is_some() {
+            return Err(packing_conflict());
+        }
+        self.pack_threshold = Some(threshold);
+        Ok(self)
+    }
 
-        let (tx, rx) = mpsc::channel();
+    /// Returns the size under which blobs are packed, if packing is enabled.
+    pub fn pack_threshold(&self) -> Option<u64> {
+        self.pack_threshold
+    }
 
-        let chunk_size = std::cmp::max(paths.len() / threads as usize, 1_usize);
-        let chunks = paths.chunks(chunk_size);
+    /// Returns `true` if a blob of `size` bytes is written to a pack file.
+    fn is_packed_size(&self, size: u64) -> bool {
+        self.pack_threshold
+            .is_some_and(|threshold| size < threshold)
+    }
 
-        for chunk in chunks {
-            let tx = tx.clone();
-            let chunk = chunk.to_owned();
-            let blob_store = self.clone();
-            thread::spawn(move || {
-                for path in chunk {
-                    let blob_ref = blob_store.add(&path);
-                    tx.send((path, blob_ref)).expect("err")
-                }
-            });
-        }
+    /// Returns where the blob is stored if it is in a pack file.
+    fn find_packed(&self, blob_ref: &BlobRef) -> Option<PackEntry> {
+        pack::find(&self.root, blob_ref).ok().flatten()
+    }
 
-        drop(tx);
+    /// Keeps the references of all the blobs in an index, so that [`BlobStore::exists`],
+    /// [`BlobStore::blob_refs`] and the checks for duplicates when adding files do not
+    /// touch the disk. This matters on network file systems.
+    ///
+    /// The index is loaded in memory, together with a bloom filter, when the blob store
+    /// is opened. If there is no index yet it is built from the blobs on disk. The blob
+    /// stores using the index keep it up to date, and read the changes made by the others
+    /// when the blobs are listed. A blob not found in memory is reported as missing
+    /// without touching the disk, unless the changes were not read for a few seconds.
+    /// Blobs written or removed by other means are missed: use
+    /// [`BlobStore::rebuild_index`] then. Clones of the blob store share the index.
+    ///
+    /// # Errors
+    ///
+    /// It errors if the index cannot be read or, when there is none, if the blobs cannot
+    /// be listed or the index cannot be written.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::BlobStore;
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let blob_ref = BlobStore::new(dir.path())
+    ///     .unwrap()
+    ///     .add_bytes(b"hello world", "hello.txt")
+    ///     .unwrap();
+    ///
+    /// let blob_store = BlobStore::new(dir.path()).unwrap().with_index().unwrap();
+    /// assert!(blob_store.exists(&blob_ref));
+    ///
+    /// blob_store.delete(&blob_ref).unwrap();
+    /// let blob_store = BlobStore::new(dir.path()).unwrap().with_index().unwrap();
+    /// assert!(!blob_store.exists(&blob_ref));
+    ///
+    /// // Missing blobs are looked up in memory only, so the blobs added by another blob
+    /// // store using the index are found once the blobs are listed
+    /// let other = BlobStore::new(dir.path()).unwrap().with_index().unwrap();
+    /// let new = other.add_bytes(b"hello there", "hello.txt").unwrap();
+    /// assert!(!blob_store.exists(&new));
+    /// assert_eq!(blob_store.blob_refs().unwrap().len(), 1);
+    /// assert!(blob_store.exists(&new));
+    ///
+    /// // Even without an index on disk
+    /// std::fs::remove_file(dir.path().join("refs.index")).unwrap();
+    /// std::fs::create_dir(dir.path().join("refs.index")).unwrap();
+    /// assert!(!blob_store.exists(&blob_ref));
+    /// assert!(blob_store.blob_refs().is_err());
+    /// ```
+    pub fn with_index(mut self) -> Result<BlobStore> {
+        let index = match RefIndex::load(&self.root)? {
+            Some(index) => index,
+            None => RefIndex::rebuild(&self.root, &self.scan_blob_refs()?)?,
+        };
+        self.index = Some(Arc::new(RwLock::new(index)));
+        Ok(self)
+    }
 
-        let rx_iter = rx.iter();
+    /// Rebuilds the index of the blobs, see [`BlobStore::with_index`], from the blobs on
+    /// disk and returns the number of blobs. The index is written even if this blob store
+    /// does not use it.
+    ///
+    /// # Errors
+    ///
+    /// It errors if the blobs cannot be listed or the index cannot be written.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::BlobStore;
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let blob_store = BlobStore::new(dir.path()).unwrap().with_index().unwrap();
+    ///
+    /// // Written without the index
+    /// let blob_ref = BlobStore::new(dir.path())
+    ///     .unwrap()
+    ///     .add_bytes(b"hello world", "hello.txt")
+    ///     .unwrap();
+    /// assert!(!blob_store.exists(&blob_ref));
+    ///
+    /// assert_eq!(blob_store.rebuild_index().unwrap(), 1);
+    /// assert!(blob_store.exists(&blob_ref));
+    /// ```
+    pub fn rebuild_index(&self) -> Result<usize> {
+        let rebuilt = RefIndex::rebuild(&self.root, &self.scan_blob_refs()?)?;
+        let count = rebuilt.blob_refs().len();
+        if let Some(index) = &self.index {
+            *index.write().unwrap_or_else(PoisonError::into_inner) = rebuilt;
+        }
+        Ok(count)
+    }
 
-        #[cfg(feature = "progress_bar")]
-        let rx_iter = rx_iter.progress_with(progress_bar(paths.len() as u64));
+    /// Returns the index of the blobs, if the blob store uses one.
+    fn ref_index(&self) -> Option<RwLockReadGuard<'_, RefIndex>> {
+        self.index
+            .as_ref()
+            .map(|index| index.read().unwrap_or_else(PoisonError::into_inner))
+    }
 
-        let (success, errors): (Vec<_>, Vec<_>) = rx_iter.partition(|(_, b)| b.is_ok());
+    /// Same as [`BlobStore::ref_index`], but with the changes made by other processes
+    /// since the index was last read.
+    fn refreshed_index(&self) -> Result<Option<RwLockReadGuard<'_, RefIndex>>> {
+        if let Some(index) = &self.index {
+            index
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .refresh()?;
+        }
+        Ok(self.ref_index())
+    }
 
-        let success = success.into_iter().map(|(p, b)| (p, b.unwrap())).collect();
-        let errors = errors
-            .into_iter()
-            .map(|(p, b)| (p, b.unwrap_err()))
-            .collect();
-        (success, errors)
+    /// Records a new blob in the index, if the blob store uses one.
+    fn index_blob(&self, blob_ref: &BlobRef) -> Result<()> {
+        match &self.index {
+            Some(index) => index
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(blob_ref),
+            None => Ok(()),
+        }
     }
 
-    /// Given a [`BlobRef`] it retrieves the associated file and returns it as a byte-array.
+    /// Adds a secondary, cold, tier to the blob store, e.g. on HDD or archive storage,
+    /// while the blob store itself, e.g. on SSD, is the hot tier.
+    ///
+    /// The time each blob of the hot tier is last read is recorded, and
+    /// [`BlobStore::run_tiering`] moves the blobs which have not been read for a while to
+    /// the cold tier. Reading a blob of the cold tier moves it back to the hot tier. Blobs
+    /// are otherwise looked up, listed and deleted in both tiers.
     ///
     /// # Examples
     ///
     /// ```
-    /// use rstr::{BlobStore, BlobRef};
-    ///
-    /// let blob_store = BlobStore::new("../tests/test_data_store").unwrap();
-    /// let reference = "f29bc64a9d3732b4b9035125fdb3285f5b6455778edca72414671e0ca3b2e0de";
-    /// let blob_ref = BlobRef::new(reference).unwrap();
+    /// use rstr::{BlobStore, Tier};
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let cold = BlobStore::new(dir.path().join("hdd")).unwrap();
+    /// let blob_ref = cold.add_bytes(b"hello world", "hello.txt").unwrap();
     ///
-    /// let content = blob_store.get(&blob_ref).unwrap();
+    /// let blob_store = BlobStore::new(dir.path().join("ssd")).unwrap().with_cold_tier(cold);
+    /// assert_eq!(blob_store.metadata(&blob_ref).unwrap().tier, Tier::Cold);
     ///
-    /// assert_eq!(content, &[
-    ///     84, 104, 105, 115, 32, 105, 115, 32, 97, 32, 116, 101, 115, 116, 32, 102, 105, 108,
-    ///     101, 46,
-    /// ]);
+    /// // Reading the blob promotes it
+    /// assert_eq!(blob_store.get(&blob_ref).unwrap(), b"hello world");
+    /// assert_eq!(blob_store.metadata(&blob_ref).unwrap().tier, Tier::Hot);
     /// ```
-    pub fn get(&self, blob_ref: &BlobRef) -> Result<Vec<u8>> {
-        Ok(fs::read(&self.get_blob_file_path(blob_ref)?)?)
+    #[must_use]
+    pub fn with_cold_tier(mut self, cold: BlobStore) -> BlobStore {
+        self.cold_tier = Some(Box::new(cold));
+        self
     }
 
-    /// Returns `true` if there is a file associated with the [`BlobRef`] in the blob store
+    /// Returns the cold tier of the blob store, if it has one.
+    pub fn cold_tier(&self) -> Option<&BlobStore> {
+        self.cold_tier.as_deref()
+    }
+
+    /// Records that the blob is being read and, if it is in the cold tier or only
+    /// upstream, brings it to the blob store first. Blobs found nowhere are left to the
+    /// caller to report.
+    fn touch(&self, blob_ref: &BlobRef) -> Result<()> {
+        if self.cold_tier.is_none() && self.upstream.is_none() {
+            return Ok(());
+        }
+        if !self.exists_locally(blob_ref) {
+            match (&self.cold_tier, &self.upstream) {
+                (Some(cold), _) if cold.exists(blob_ref) => self.move_blob_from(cold, blob_ref)?,
+                (_, Some(upstream)) if upstream.exists(blob_ref)? => {
+                    self.fill(upstream.as_ref(), blob_ref)?;
+                }
+                _ => return Ok(()),
+            }
+        }
+        self.record_use(blob_ref)?;
+        tier::record_access(&self.root, blob_ref)
+    }
+
+    /// Stores a blob under `filename`, writing it to a temporary file with `copy` first.
+    /// The blob is stored only if the content of the temporary file matches `blob_ref`.
+    pub(crate) fn store_verified<F>(
+        &self,
+        blob_ref: &BlobRef,
+        filename: &str,
+        copy: F,
+    ) -> Result<()>
+    where
+        F: FnOnce(&Path) -> Result<()>,
+    {
+        let tmp_dir = self.tmp_dir(blob_ref);
+        fs::create_dir_all(&tmp_dir)?;
+        let tmp_file = tmp_dir.join(sanitize_filename(filename));
+        let result = copy(&tmp_file).and_then(|()| {
+            let (actual, _) = hash_file(&tmp_file)?;
+            if actual.reference() == blob_ref.reference() {
+                self.store_file(&tmp_file, blob_ref)
+            } else {
+                Err(Error::HashMismatch {
+                    expected: blob_ref.clone(),
+                    actual,
+                })
+            }
+        });
+        fs::remove_dir_all(&tmp_dir)?;
+        result
+    }
+
+    /// Returns a new temporary directory for the blob, distinct from those of concurrent
+    /// writes of the same blob.
+    fn tmp_dir(&self, blob_ref: &BlobRef) -> PathBuf {
+        self.root.join(format!(
+            ".{}-{}-{}.tmp",
+            blob_ref.reference(),
+            std::process::id(),
+            TMP_DIRS.fetch_add(1, Ordering::Relaxed)
+        ))
+    }
+
+    /// Moves a blob from `source` to this blob store, keeping its filename. The content
+    /// is verified before the blob is removed from `source`.
+    fn move_blob_from(&self, source: &BlobStore, blob_ref: &BlobRef) -> Result<()> {
+        let filename = source.metadata(blob_ref)?.filename;
+        self.store_verified(blob_ref, &filename, |tmp_file| {
+            source.copy_to(blob_ref, tmp_file)
+        })?;
+        source.delete_locally(blob_ref)
+    }
+
+    /// Puts the blob store in front of a slower `upstream` store, e.g. a remote rstr
+    /// server, as a read-through cache.
+    ///
+    /// Blobs missing from the blob store are fetched from `upstream` when they are read,
+    /// and stored only if their content matches their reference. When the blob store
+    /// grows over [`CacheOptions::max_size`], the least recently read blobs which are also
+    /// upstream are evicted, e.g. to keep blobs upstream only with a size of 0. With
+    /// [`CacheOptions::write_through`], new blobs are also uploaded to `upstream`, and
+    /// deleted blobs deleted from it. Blobs are otherwise looked up and listed in both
+    /// stores.
     ///
     /// # Examples
     ///
     /// ```
-    /// use rstr::{BlobStore, BlobRef};
+    /// use rstr::{BlobStore, CacheOptions};
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let central = BlobStore::new(dir.path().join("central")).unwrap();
+    /// let blob_ref = central.add_bytes(b"hello world", "hello.txt").unwrap();
     ///
-    /// let blob_store = BlobStore::new("../tests/test_data_store/").unwrap();
-    /// let blob_ref = BlobRef::new("f29bc64a9d3732b4b9035125fdb3285f5b6455778edca72414671e0ca3b2e0de").unwrap();
+    /// let options = CacheOptions {
+    ///     max_size: Some(1 << 30),
+    ///     write_through: true,
+    /// };
+    /// let cache = BlobStore::new(dir.path().join("cache"))
+    ///     .unwrap()
+    ///     .with_upstream(central.clone(), options);
+    /// assert_eq!(cache.get(&blob_ref).unwrap(), b"hello world");
+    /// assert!(dir.path().join("cache").join(blob_ref.to_path()).exists());
     ///
-    /// assert!(blob_store.exists(&blob_ref))
+    /// // New blobs are written through
+    /// let new_ref = cache.add_bytes(b"hello cache", "hello.txt").unwrap();
+    /// assert!(central.exists(&new_ref));
+    ///
+    /// // The least recently read blobs are evicted once the cache is full
+    /// let options = CacheOptions {
+    ///     max_size: Some(16),
+    ///     write_through: false,
+    /// };
+    /// let small = BlobStore::new(dir.path().join("small"))
+    ///     .unwrap()
+    ///     .with_upstream(central.clone(), options);
+    /// small.get(&blob_ref).unwrap();
+    /// small.get(&new_ref).unwrap();
+    /// assert!(!dir.path().join("small").join(blob_ref.to_path()).exists());
+    /// assert!(dir.path().join("small").join(new_ref.to_path()).exists());
     /// ```
-    pub fn exists(&self, blob_ref: &BlobRef) -> bool {
-        let dir = self.get_blob_path(blob_ref);
-        dir.exists() && dir.read_dir().unwrap().next().is_some()
+    #[must_use]
+    pub fn with_upstream<B: Backend + 'static>(
+        mut self,
+        upstream: B,
+        options: CacheOptions,
+    ) -> BlobStore {
+        self.upstream = Some(Arc::new(upstream));
+        self.cache = options;
+        self.cache_usage = Arc::default();
+        self
     }
 
-    /// Given a [`BlobRef`] it deletes the corresponding blob from the blob store
+    /// Returns the store the blob store reads through to, if it has one.
+    pub fn upstream(&self) -> Option<&dyn Backend> {
+        self.upstream.as_deref()
+    }
+
+    /// Makes [`BlobStore::get`] and [`BlobStore::open`] check that the content of the
+    /// blobs matches their reference, like [`BlobStore::get_verified`] and
+    /// [`BlobStore::open_verified`] do. This costs hashing every blob read.
     ///
     /// # Examples
     ///
-    /// ```no_run
-    /// # use rstr::{BlobStore, BlobRef};
-    /// let blob_store = BlobStore::new("/path/to/blob/store").unwrap();
+    /// ```
+    /// use rstr::{BlobStore, Error};
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let blob_store = BlobStore::new(dir.path()).unwrap().with_verify_on_read();
+    /// let blob_ref = blob_store.add_bytes(b"hello world", "hello.txt").unwrap();
+    /// assert_eq!(blob_store.get(&blob_ref).unwrap(), b"hello world");
     ///
-    /// let blob_ref = BlobRef::new("b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9").unwrap();
-    /// assert!(blob_store.exists(&blob_ref));
+    /// let path = blob_store.blob_dir(&blob_ref).join("hello.txt");
+    /// std::fs::write(path, b"hello w0rld").unwrap();
+    /// assert!(matches!(blob_store.get(&blob_ref), Err(Error::Corrupted(_))));
     ///
-    /// blob_store.delete(&blob_ref);
-    /// assert!(!blob_store.exists(&blob_ref));
+    /// let quarantine = blob_store.quarantine().unwrap();
+    /// assert_eq!(quarantine[0].blob_ref.reference(), blob_ref.reference());
     /// ```
-    /// # Errors
-    ///
-    /// See [`fs::remove_dir_all`].
-    pub fn delete(&self, blob_ref: &BlobRef) -> Result<()> {
-        Ok(fs::remove_dir_all(self.get_blob_path(blob_ref))?)
+    #[must_use]
+    pub fn with_verify_on_read(mut self) -> BlobStore {
+        self.verify_on_read = true;
+        self
     }
 
-    /// Given a [`BlobRef`] returns the metadata relative to the referenced blob. For more
-    /// details on the metadata returned see `BlobMetadata`.
-    ///
-    /// The mime type is inferred from the file's magic number as a string.
-    /// It defaults to "application/octet-stream" if it cannot determine the type.
-    /// We use the [`tree_magic_mini`] crate to infer the mime type.
+    /// Returns `true` if the blobs are verified whenever they are read, see
+    /// [`BlobStore::with_verify_on_read`].
+    pub fn verifies_on_read(&self) -> bool {
+        self.verify_on_read
+    }
+
+    /// Returns the blobs found corrupted when they were read, oldest first. A blob is
+    /// listed once per time it was found corrupted, even if it was repaired since.
     ///
     /// # Errors
     ///
-    /// Will return an error if the file cannot be found/opened or if [`std::fs::metadata`]
-    /// fails.
-    pub fn metadata(&self, blob_ref: &BlobRef) -> Result<BlobMetadata> {
-        let file_path = self.get_blob_file_path(blob_ref)?;
+    /// It errors if the quarantine list cannot be read.
+    pub fn quarantine(&self) -> Result<Vec<QuarantineEntry>> {
+        quarantine::read(&self.root)
+    }
 
-        let mime = magic::from_filepath(&file_path).unwrap_or("application/octet-stream");
+    /// Records that the blob was found corrupted in the quarantine list, and returns the
+    /// error to report.
+    fn corrupted(&self, blob_ref: &BlobRef) -> Error {
+        // Failing to record the event must not hide the corruption itself
+        quarantine::record(&self.root, blob_ref).ok();
+        Error::Corrupted(blob_ref.clone())
+    }
 
-        let filename = file_path.file_name().unwrap().to_str().unwrap().to_string();
+    /// Fetches a blob from `upstream`, then evicts blobs if the blob store is too large.
+    fn fill(&self, upstream: &dyn Backend, blob_ref: &BlobRef) -> Result<()> {
+        let filename = upstream.metadata(blob_ref)?.filename;
+        self.store_verified(blob_ref, &filename, |tmp_file| {
+            io::copy(&mut upstream.open(blob_ref)?, &mut File::create(tmp_file)?)?;
+            Ok(())
+        })?;
+        self.evict(upstream, blob_ref)
+    }
 
-        let metadata = fs::metadata(file_path)?;
-        Ok(BlobMetadata {
-            mime_type: String::from(mime),
-            filename,
-            size: metadata.len(),
-            created: metadata.created()?.into(),
+    /// Removes the least recently read blobs until the blob store is smaller than
+    /// [`CacheOptions::max_size`]. Only the blobs which are also upstream are removed, and
+    /// never `keep`, i.e. the blob being read.
+    fn evict(&self, upstream: &dyn Backend, keep: &BlobRef) -> Result<()> {
+        let Some(max_size) = self.cache.max_size else {
+            return Ok(());
+        };
+        self.record_use(keep)?;
+        let mut after = 0;
+        loop {
+            // The lock is not held while the blob is looked up upstream and deleted
+            let candidate = self.with_cache_usage(|usage| {
+                if usage.size() > max_size {
+                    usage.used_after(after)
+                } else {
+                    None
+                }
+            })?;
+            let Some((used, blob_ref)) = candidate else {
+                return Ok(());
+            };
+            after = used;
+            if blob_ref.reference() != keep.reference() && upstream.exists(&blob_ref)? {
+                self.delete_locally(&blob_ref)?;
+                tier::forget(&self.root, &blob_ref)?;
+            }
+        }
+    }
+
+    /// Records that a blob of the blob store was read or written last, if the blob store
+    /// is a cache with a size limit.
+    fn record_use(&self, blob_ref: &BlobRef) -> Result<()> {
+        if self.cache.max_size.is_none() || self.upstream.is_none() {
+            return Ok(());
+        }
+        let size = match self.with_cache_usage(|usage| usage.size_of(blob_ref))? {
+            Some(size) => size,
+            None => self.local_metadata(blob_ref)?.size,
+        };
+        self.with_cache_usage(|usage| usage.touch(blob_ref, size))
+    }
+
+    /// Runs `f` with the blobs of the cache by use. They are read from the blob store the
+    /// first time only, then kept up to date as blobs are read, written and deleted.
+    fn with_cache_usage<T>(&self, f: impl FnOnce(&mut CacheUsage) -> T) -> Result<T> {
+        let mut cache_usage = self
+            .cache_usage
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(usage) = cache_usage.as_mut() {
+            return Ok(f(usage));
+        }
+        let mut blobs = vec![];
+        for blob_ref in self.local_blob_refs()? {
+            let metadata = self.local_metadata(&blob_ref)?;
+            let last_used = tier::last_access(&self.root, &blob_ref)?.unwrap_or(metadata.created);
+            blobs.push((last_used, metadata.size, blob_ref));
+        }
+        blobs.sort_by_key(|(last_used, _, _)| *last_used);
+        let mut usage = CacheUsage::default();
+        for (_, size, blob_ref) in blobs {
+            usage.touch(&blob_ref, size);
+        }
+        let result = f(&mut usage);
+        *cache_usage = Some(usage);
+        Ok(result)
+    }
+
+    /// Uploads a new blob to the upstream store, if the blob store writes through to one.
+    /// The blob is read from `path`, or from the blob store if not given. Once uploaded,
+    /// the blob can be evicted, so blobs are evicted if the blob store is too large.
+    pub(crate) fn write_through(&self, blob_ref: &BlobRef, path: Option<&Path>) -> Result<()> {
+        let Some(upstream) = self.upstream.as_ref().filter(|_| self.cache.write_through) else {
+            return Ok(());
+        };
+        if !upstream.exists(blob_ref)? {
+            self.upload(upstream.as_ref(), blob_ref, path)?;
+        }
+        self.evict(upstream.as_ref(), blob_ref)
+    }
+
+    /// Uploads a blob of the blob store to `upstream`, reading it from `path` if given.
+    fn upload(
+        &self,
+        upstream: &dyn Backend,
+        blob_ref: &BlobRef,
+        path: Option<&Path>,
+    ) -> Result<()> {
+        if let Some(path) = path
+            .map(Path::to_path_buf)
+            .or_else(|| self.find_blob_files(blob_ref).into_iter().next())
+        {
+            return upstream.upload(&path, blob_ref);
+        }
+        let filename = self.local_metadata(blob_ref)?.filename;
+        let tmp_dir = self.tmp_dir(blob_ref);
+        fs::create_dir_all(&tmp_dir)?;
+        let tmp_file = tmp_dir.join(sanitize_filename(&filename));
+        let result = self
+            .copy_to(blob_ref, &tmp_file)
+            .and_then(|()| upstream.upload(&tmp_file, blob_ref));
+        fs::remove_dir_all(&tmp_dir)?;
+        result
+    }
+
+    /// Moves the blobs of the hot tier which have not been read for `unused_for` to the
+    /// cold tier, see [`BlobStore::with_cold_tier`]. Blobs never read since they were
+    /// added count as last used when they were added.
+    ///
+    /// # Errors
+    ///
+    /// It errors if the blob store has no cold tier, and if a blob cannot be moved.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chrono::Duration;
+    /// use rstr::{BlobStore, Tier};
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let cold = BlobStore::new(dir.path().join("hdd")).unwrap();
+    /// let blob_store = BlobStore::new(dir.path().join("ssd")).unwrap().with_cold_tier(cold);
+    /// let blob_ref = blob_store.add_bytes(b"hello world", "hello.txt").unwrap();
+    ///
+    /// assert!(blob_store.run_tiering(Duration::days(60)).unwrap().demoted.is_empty());
+    ///
+    /// let report = blob_store.run_tiering(Duration::zero()).unwrap();
+    /// assert_eq!(report.demoted.len(), 1);
+    /// assert_eq!(blob_store.metadata(&blob_ref).unwrap().tier, Tier::Cold);
+    /// ```
+    pub fn run_tiering(&self, unused_for: Duration) -> Result<TierReport> {
+        let cold = self.cold_tier.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the blob store has no cold tier",
+            )
+        })?;
+        let mut report = TierReport::default();
+        for blob_ref in self.local_blob_refs()? {
+            let last_used = match tier::last_access(&self.root, &blob_ref)? {
+                Some(last_used) => last_used,
+                None => self.local_metadata(&blob_ref)?.created,
+            };
+            if tier::is_stale(last_used, unused_for) {
+                cold.move_blob_from(self, &blob_ref)?;
+                tier::forget(&self.root, &blob_ref)?;
+                report.demoted.push(blob_ref);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Returns the roots the content of the blobs is stored in.
+    pub fn roots(&self) -> &[PathBuf] {
+        &self.roots
+    }
+
+    /// Returns how many roots each blob is written to.
+    pub fn replicas(&self) -> usize {
+        self.replicas
+    }
+
+    /// Returns an instance of the hasher used to compute the blob reference for a file
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::BlobStore;
+    /// use sha2::{Digest, Sha256};
+    ///
+    /// let mut hasher = BlobStore::hasher();
+    /// hasher.update(b"hello world");
+    /// let result = hasher.finalize();
+    /// assert_eq!(format!("{:x}", result), "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9")
+    /// ```
+    pub fn hasher() -> Sha256 {
+        Sha256::new()
+    }
+
+    /// Given a `BlobRef` it returns it's path inside the blob store: the directory holding
+    /// it if it is stored in any root, otherwise the one where it should be written.
+    fn get_blob_path(&self, blob_ref: &BlobRef) -> PathBuf {
+        self.find_blob_path(blob_ref)
+            .unwrap_or_else(|| self.placement_paths(blob_ref).remove(0))
+    }
+
+    /// Returns the directory holding the blob in the blob store or, if the blob is not
+    /// stored yet, the directory where it should be written. The directory contains a
+    /// single file, named after the original file.
+    pub fn blob_dir(&self, blob_ref: &BlobRef) -> PathBuf {
+        self.get_blob_path(blob_ref)
+    }
+
+    /// Returns the roots a blob is copied to according to the placement, in order of
+    /// preference. At most `n` roots are returned.
+    fn placement_roots(&self, blob_ref: &BlobRef, n: usize) -> Vec<&Path> {
+        let roots: Vec<&Path> = self.roots.iter().map(PathBuf::as_path).collect();
+        self.placement.choose_n(&roots, blob_ref, n)
+    }
+
+    /// Returns the directories where a new blob is written according to the placement,
+    /// one per replica.
+    fn placement_paths(&self, blob_ref: &BlobRef) -> Vec<PathBuf> {
+        self.placement_dirs(blob_ref, self.replicas)
+    }
+
+    /// Returns the blob directories in the roots returned by
+    /// [`BlobStore::placement_roots`].
+    fn placement_dirs(&self, blob_ref: &BlobRef, n: usize) -> Vec<PathBuf> {
+        self.placement_roots(blob_ref, n)
+            .into_iter()
+            .map(|root| root.join(self.shard_layout.blob_path(blob_ref)))
+            .collect()
+    }
+
+    /// Returns the directory holding the blob, searching all the roots.
+    fn find_blob_path(&self, blob_ref: &BlobRef) -> Option<PathBuf> {
+        self.roots
+            .iter()
+            .map(|root| root.join(self.shard_layout.blob_path(blob_ref)))
+            .find(|dir| roots::has_blob(dir))
+    }
+
+    /// Returns the file of every whole copy of the blob, searching all the roots.
+    fn find_blob_files(&self, blob_ref: &BlobRef) -> Vec<PathBuf> {
+        self.roots
+            .iter()
+            .filter_map(|root| roots::blob_file(&root.join(self.shard_layout.blob_path(blob_ref))))
+            .collect()
+    }
+
+    /// Returns the shard files of an erasure coded blob, searching all the roots.
+    fn find_shard_files(&self, blob_ref: &BlobRef) -> Vec<PathBuf> {
+        self.roots
+            .iter()
+            .filter_map(|root| {
+                erasure::shard_path(&root.join(self.shard_layout.blob_path(blob_ref)))
+            })
+            .collect()
+    }
+
+    /// Expands a prefix of a blob reference, like an abbreviated git hash, into the
+    /// reference of the only blob in the blob store starting with it. The prefix must be
+    /// at least 6 characters long.
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::InvalidRef`] if the prefix is too short or contains
+    /// invalid characters, with [`Error::PrefixNotFound`] if no blob matches and with
+    /// [`Error::AmbiguousRef`] if more than one blob matches.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::{BlobStore, Error};
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let blob_store = BlobStore::new(dir.path()).unwrap();
+    /// let blob_ref = blob_store.add("../tests/test_file.txt").unwrap();
+    ///
+    /// let resolved = blob_store.resolve_prefix("f29bc64a9d").unwrap();
+    /// assert_eq!(resolved.reference(), blob_ref.reference());
+    ///
+    /// assert!(matches!(blob_store.resolve_prefix("f29b"), Err(Error::InvalidRef)));
+    /// assert!(matches!(blob_store.resolve_prefix("f29bxyz"), Err(Error::InvalidRef)));
+    /// assert!(matches!(blob_store.resolve_prefix("000000"), Err(Error::PrefixNotFound(_))));
+    /// ```
+    pub fn resolve_prefix(&self, prefix: &str) -> Result<BlobRef> {
+        if !VALID_PREFIX_REGEX.is_match(prefix) {
+            return Err(Error::InvalidRef);
+        }
+
+        let mut candidates: Vec<BlobRef> = match self.refreshed_index()? {
+            Some(index) => index.find_prefix(prefix),
+            None => pack::find_prefix(&self.root, prefix)?,
+        };
+        let roots = if self.index.is_some() {
+            &[][..]
+        } else {
+            &self.roots[..]
+        };
+        for root in roots {
+            for blob_ref in roots::find_blobs(root, self.shard_layout, prefix)? {
+                let known = candidates
+                    .iter()
+                    .any(|c| c.reference() == blob_ref.reference());
+                if !known {
+                    candidates.push(blob_ref);
+                }
+            }
+        }
+
+        if let Some(cold) = &self.cold_tier {
+            match cold.resolve_prefix(prefix) {
+                Ok(blob_ref) => candidates.push(blob_ref),
+                Err(Error::AmbiguousRef(blob_refs)) => candidates.extend(blob_refs),
+                Err(Error::PrefixNotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+            candidates.sort_by(|a, b| a.reference().cmp(b.reference()));
+            candidates.dedup_by(|a, b| a.reference() == b.reference());
+        }
+
+        match candidates.len() {
+            0 => Err(Error::PrefixNotFound(prefix.to_string())),
+            1 => Ok(candidates.remove(0)),
+            _ => {
+                candidates.sort_by(|a, b| a.reference().cmp(b.reference()));
+                Err(Error::AmbiguousRef(candidates))
+            }
+        }
+    }
+
+    /// Add a file to the blob store given a path.
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::SourceNotFound`] if the file does not exist, and if it
+    /// cannot be read or copied into the blob store.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::{BlobStore, BlobRef};
+    /// use std::path::PathBuf;
+    /// let blob_store = BlobStore::new("../tests/test_data_store/").unwrap();
+    ///
+    /// let blob_ref: BlobRef = blob_store.add("../tests/test_file.txt").unwrap();
+    /// assert!(blob_store.exists(&blob_ref));
+    /// assert_eq!(blob_ref.reference(), "f29bc64a9d3732b4b9035125fdb3285f5b6455778edca72414671e0ca3b2e0de");
+    /// ```
+    pub fn add<P: AsRef<Path>>(&self, path: P) -> Result<BlobRef> {
+        let (blob_ref, _) = hash_file(path.as_ref())?;
+
+        if self.exists(&blob_ref) {
+            self.disown(&blob_ref)?;
+        } else {
+            self.write_file(path.as_ref(), &blob_ref)?;
+        }
+
+        Ok(blob_ref)
+    }
+
+    /// Adds the given content to the blob store, saving it under `filename`.
+    ///
+    /// # Errors
+    ///
+    /// It errors if the blob cannot be written to the blob store.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::BlobStore;
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let blob_store = BlobStore::new(dir.path()).unwrap();
+    ///
+    /// let blob_ref = blob_store.add_bytes(b"hello world", "hello.txt").unwrap();
+    /// assert_eq!(blob_ref.reference(), "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9");
+    /// assert_eq!(blob_store.metadata(&blob_ref).unwrap().filename, "hello.txt");
+    /// ```
+    pub fn add_bytes(&self, content: &[u8], filename: &str) -> Result<BlobRef> {
+        let (blob_ref, _) = self.store_bytes(content, filename)?;
+        self.disown(&blob_ref)?;
+        Ok(blob_ref)
+    }
+
+    /// Same as [`BlobStore::add_bytes`], but it also returns whether the content was
+    /// written, i.e. it was not in the blob store before.
+    pub(crate) fn store_bytes(&self, content: &[u8], filename: &str) -> Result<(BlobRef, bool)> {
+        let blob_ref = hash_bytes(content);
+
+        if self.exists(&blob_ref) {
+            return Ok((blob_ref, false));
+        }
+        let filename = sanitize_filename(filename);
+        if self.is_packed_size(content.len() as u64) {
+            pack::insert(&self.root, &blob_ref, content, &filename)?;
+        } else if let Some(coding) = self.erasure_coding_for(content.len() as u64) {
+            let dirs = self.placement_dirs(&blob_ref, coding.shards());
+            erasure::write_shards(content, content.len() as u64, &filename, coding, &dirs)?;
+        } else {
+            for save_path in self.placement_paths(&blob_ref) {
+                fs::create_dir_all(&save_path)
+                    .and_then(|()| fs::write(save_path.join(&filename), content))
+                    .map_err(|e| Error::from(e).with_path(&save_path))?;
+            }
+        }
+        self.index_blob(&blob_ref)?;
+        self.write_through(&blob_ref, None)?;
+
+        Ok((blob_ref, true))
+    }
+
+    /// Copies the file at `path` into the blob store under the given [`BlobRef`], once
+    /// per replica, as erasure coded shards or to a pack file.
+    ///
+    /// The caller is responsible for `blob_ref` being the actual hash of the file.
+    pub(crate) fn write_file(&self, path: &Path, blob_ref: &BlobRef) -> Result<()> {
+        self.store_file(path, blob_ref)?;
+        self.disown(blob_ref)?;
+        self.write_through(blob_ref, Some(path))
+    }
+
+    /// Drops the claim of the snapshots and the buckets on a blob which is added to the
+    /// blob store in another way, so that neither [`BlobStore::gc_snapshots`] nor
+    /// [`BlobStore::remove_from_bucket`] ever deletes it.
+    pub(crate) fn disown(&self, blob_ref: &BlobRef) -> Result<()> {
+        self.remove_owned_markers(&[
+            snapshot::owned_marker(&self.root, blob_ref),
+            bucket::owned_marker(&self.root, blob_ref),
+        ])
+    }
+
+    /// Removes those of the markers of the blobs owned by the snapshots and the buckets
+    /// which exist. Only the markers known to exist are removed from disk.
+    pub(crate) fn remove_owned_markers(&self, markers: &[PathBuf]) -> Result<()> {
+        let dirs = [
+            snapshot::owned_dir(&self.root),
+            bucket::owned_dir(&self.root),
+        ];
+        self.owned_markers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&dirs, markers)
+    }
+
+    /// Records a marker of a blob owned by the snapshots or the buckets, once created.
+    pub(crate) fn record_owned_marker(&self, marker: PathBuf) {
+        self.owned_markers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(marker);
+    }
+
+    /// Stores the file at `path` in this blob store only, see [`BlobStore::write_file`].
+    fn store_file(&self, path: &Path, blob_ref: &BlobRef) -> Result<()> {
+        let filename = path
+            .file_name()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let file = open_source(path)?;
+        let size = file.metadata()?.len();
+        if self.is_packed_size(size) {
+            pack::insert(&self.root, blob_ref, file, &filename.to_string_lossy())?;
+        } else if let Some(coding) = self.erasure_coding_for(size) {
+            let dirs = self.placement_dirs(blob_ref, coding.shards());
+            let filename = filename.to_string_lossy();
+            erasure::write_shards(file, size, &filename, coding, &dirs)?;
+        } else {
+            for save_path in self.placement_paths(blob_ref) {
+                fs::create_dir_all(&save_path)
+                    .and_then(|()| fs::copy(path, save_path.join(filename)))
+                    .map_err(|e| Error::from(e).with_path(&save_path))?;
+            }
+        }
+        self.index_blob(blob_ref)
+    }
+
+    /// Copies the blob to the roots chosen by the placement until it has as many copies
+    /// as [`BlobStore::replicas`]. It must be called after writing a blob directly into
+    /// [`BlobStore::blob_dir`], which also records the blob in the index, if any.
+    ///
+    /// Blobs which should be erasure coded, see [`BlobStore::with_erasure_coding`], are
+    /// split into shards instead, and the whole copies are removed. The missing shards of
+    /// erasure coded blobs are rebuilt from the others. Likewise, blobs which should be
+    /// packed, see [`BlobStore::with_packing`], are moved to a pack file. Packed blobs are
+    /// left untouched.
+    ///
+    /// The copied content is not verified, see [`BlobStore::repair`] for that.
+    ///
+    /// Returns the roots the blob or its shards were written to.
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::BlobNotFound`] if the blob is not in the blob store, or if
+    /// it cannot be copied.
+    pub fn replicate(&self, blob_ref: &BlobRef) -> Result<Vec<PathBuf>> {
+        let written = self.replicate_copies(blob_ref)?;
+        self.index_blob(blob_ref)?;
+        self.write_through(blob_ref, None)?;
+        Ok(written)
+    }
+
+    /// Writes the missing copies, or shards, of a blob, see [`BlobStore::replicate`].
+    fn replicate_copies(&self, blob_ref: &BlobRef) -> Result<Vec<PathBuf>> {
+        let path = self.shard_layout.blob_path(blob_ref);
+        let shards = self.find_shard_files(blob_ref);
+        if !shards.is_empty() {
+            // Rebuild the missing shards in the roots without one
+            let free: Vec<&Path> = self
+                .placement_roots(blob_ref, self.roots.len())
+                .into_iter()
+                .filter(|root| erasure::shard_path(&root.join(&path)).is_none())
+                .collect();
+            let dirs: Vec<PathBuf> = free.iter().map(|root| root.join(&path)).collect();
+            let written = erasure::rebuild_shards(&shards, blob_ref, &dirs)?;
+            return Ok(free[..written]
+                .iter()
+                .map(|root| root.to_path_buf())
+                .collect());
+        }
+
+        let files = self.find_blob_files(blob_ref);
+        let Some(source) = files.first() else {
+            return match self.find_packed(blob_ref) {
+                Some(_) => Ok(vec![]),
+                None => Err(Error::BlobNotFound(blob_ref.clone())),
+            };
+        };
+        let size = fs::metadata(source)?.len();
+        if self.is_packed_size(size) {
+            let filename = source.file_name().unwrap_or_default().to_string_lossy();
+            pack::insert(&self.root, blob_ref, File::open(source)?, &filename)?;
+            self.remove_loose(blob_ref)?;
+            return Ok(vec![self.root.clone()]);
+        }
+        if let Some(coding) = self.erasure_coding_for(size) {
+            let roots = self.placement_roots(blob_ref, coding.shards());
+            let dirs: Vec<PathBuf> = roots.iter().map(|root| root.join(&path)).collect();
+            let filename = source.file_name().unwrap_or_default().to_string_lossy();
+            erasure::write_shards(File::open(source)?, size, &filename, coding, &dirs)?;
+            for file in &files {
+                fs::remove_file(file)?;
+            }
+            return Ok(roots.iter().map(|root| root.to_path_buf()).collect());
+        }
+
+        let source = source.parent().unwrap_or(source);
+        let (holders, free): (Vec<&Path>, Vec<&Path>) = self
+            .placement_roots(blob_ref, self.roots.len())
+            .into_iter()
+            .partition(|root| roots::has_blob(&root.join(&path)));
+
+        let mut copied = vec![];
+        for root in free
+            .into_iter()
+            .take(self.replicas.saturating_sub(holders.len()))
+        {
+            roots::copy_blob(source, &root.join(&path))?;
+            copied.push(root.to_path_buf());
+        }
+        Ok(copied)
+    }
+
+    /// Given a list of paths to files/directories it adds them to the blob store. In the case
+    /// of a directory it adds all the files in its children recursively.
+    ///
+    /// This is a shorthand for [`BlobStore::add_files_with_options`] using the default
+    /// [`AddOptions`] with `threads` hashing threads.
+    ///
+    /// It returns two vectors: one containing the paths to the files that were successfully
+    /// added together with their generated `BlobRef` and the other containing the list of
+    /// paths that errored together with the error.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use std::path::Path;
+    /// use rstr::{BlobStore, BlobRef};
+    ///
+    /// let blob_store = BlobStore::new("../tests/test_data_store").unwrap();
+    ///
+    /// let paths = [Path::new("../tests/test_file.txt")];
+    /// let threads = 8;
+    /// let (blob_refs_with_paths, errors) = blob_store.add_files(&paths[..], threads);
+    /// let blob_refs: Vec<BlobRef> = blob_refs_with_paths.into_iter().map(|(_, b)| b).collect();
+    ///
+    /// assert_eq!(blob_refs[0].reference(), "f29bc64a9d3732b4b9035125fdb3285f5b6455778edca72414671e0ca3b2e0de")
+    /// ```
+    pub fn add_files<P: AsRef<Path> + Sync>(
+        &self,
+        paths: &[P],
+        threads: usize,
+    ) -> (Vec<BlobRefAndPath>, Vec<(PathBuf, Error)>) {
+        self.add_files_with_options(paths, &AddOptions::with_hash_threads(threads))
+    }
+
+    /// Given a list of paths to files/directories it adds them to the blob store. In the case
+    /// of a directory it adds all the files in its children recursively.
+    ///
+    /// Files are listed, hashed and copied into the blob store by separate pools of
+    /// threads, see [`AddOptions`] for the details.
+    ///
+    /// It returns two vectors: one containing the paths to the files that were successfully
+    /// added together with their generated `BlobRef` and the other containing the list of
+    /// paths that errored together with the error.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use std::path::Path;
+    /// use rstr::{AddOptions, BlobStore};
+    ///
+    /// let blob_store = BlobStore::new("../tests/test_data_store").unwrap();
+    ///
+    /// let options = AddOptions {
+    ///     hash_threads: 8,
+    ///     write_threads: 2,
+    ///     queue_capacity: 64,
+    /// };
+    /// let (blob_refs_with_paths, errors) =
+    ///     blob_store.add_files_with_options(&[Path::new("../tests/")], &options);
+    ///
+    /// assert!(errors.is_empty());
+    /// assert!(blob_refs_with_paths
+    ///     .iter()
+    ///     .all(|(_, blob_ref)| blob_store.exists(blob_ref)));
+    /// ```
+    pub fn add_files_with_options<P: AsRef<Path> + Sync>(
+        &self,
+        paths: &[P],
+        options: &AddOptions,
+    ) -> (Vec<BlobRefAndPath>, Vec<(PathBuf, Error)>) {
+        let mut success = vec![];
+        let mut errors = vec![];
+        for (path, result) in pipeline::add_files(self, paths, options) {
+            match result.and_then(|(blob_ref, _)| self.disown(&blob_ref).map(|()| blob_ref)) {
+                Ok(blob_ref) => success.push((path, blob_ref)),
+                Err(e) => errors.push((path, e)),
+            }
+        }
+        (success, errors)
+    }
+
+    /// Given a list of paths to files/directories it hashes all the files like
+    /// [`BlobStore::add_files`] would, but without writing anything to the blob store.
+    ///
+    /// The returned [`AddPlan`] tells which files would be added, which are already present
+    /// and which are duplicated in the input, as well as how much space the new blobs would
+    /// take.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use std::fs;
+    /// use rstr::BlobStore;
+    ///
+    /// let blob_store = BlobStore::new("../tests/test_data_store").unwrap();
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// # fs::copy("../tests/test_file.txt", dir.path().join("a.txt")).unwrap();
+    /// # fs::copy("../tests/test_file.txt", dir.path().join("b.txt")).unwrap();
+    /// # fs::write(dir.path().join("c.txt"), b"hello world").unwrap();
+    ///
+    /// // `dir` contains two copies of `test_file.txt` and a new file.
+    /// let plan = blob_store.plan_add(&[dir.path()]);
+    ///
+    /// assert_eq!(plan.existing.len(), 1);
+    /// assert_eq!(plan.duplicates.len(), 1);
+    /// assert_eq!(plan.new.len(), 1);
+    /// assert_eq!(plan.new_bytes(), 11);
+    /// assert!(!blob_store.exists(&plan.new[0].blob_ref));
+    /// ```
+    pub fn plan_add<P: AsRef<Path> + Sync>(&self, paths: &[P]) -> AddPlan {
+        self.plan_add_with_options(paths, &AddOptions::default())
+    }
+
+    /// Same as [`BlobStore::plan_add`] but with custom [`AddOptions`]. Note that no write
+    /// threads are used.
+    pub fn plan_add_with_options<P: AsRef<Path> + Sync>(
+        &self,
+        paths: &[P],
+        options: &AddOptions,
+    ) -> AddPlan {
+        pipeline::plan_add(self, paths, options)
+    }
+
+    /// Given a [`BlobRef`] it retrieves the associated file and returns it as a byte-array.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::{BlobStore, BlobRef};
+    ///
+    /// let blob_store = BlobStore::new("../tests/test_data_store").unwrap();
+    /// let reference = "f29bc64a9d3732b4b9035125fdb3285f5b6455778edca72414671e0ca3b2e0de";
+    /// let blob_ref = BlobRef::new(reference).unwrap();
+    ///
+    /// let content = blob_store.get(&blob_ref).unwrap();
+    ///
+    /// assert_eq!(content, &[
+    ///     84, 104, 105, 115, 32, 105, 115, 32, 97, 32, 116, 101, 115, 116, 32, 102, 105, 108,
+    ///     101, 46,
+    /// ]);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::BlobNotFound`] if the blob is not in the blob store.
+    ///
+    /// If the blob is stored more than once, copies which cannot be read or whose content
+    /// does not match the reference are skipped. Erasure coded blobs are rebuilt from
+    /// their shards. If no copy is healthy it errors with [`Error::Corrupted`].
+    pub fn get(&self, blob_ref: &BlobRef) -> Result<Vec<u8>> {
+        if self.verify_on_read {
+            self.get_verified(blob_ref)
+        } else {
+            self.read_content(blob_ref)
+        }
+    }
+
+    /// Like [`BlobStore::get`], but it also checks the content of a blob stored only once.
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::Corrupted`] if the content does not match the reference,
+    /// adding the blob to the quarantine list, see [`BlobStore::quarantine`]. It errors
+    /// like [`BlobStore::get`] otherwise.
+    pub fn get_verified(&self, blob_ref: &BlobRef) -> Result<Vec<u8>> {
+        let content = self.read_content(blob_ref)?;
+        if hash_bytes(&content).reference() == blob_ref.reference() {
+            Ok(content)
+        } else {
+            Err(self.corrupted(blob_ref))
+        }
+    }
+
+    /// Reads the content of a blob, see [`BlobStore::get`].
+    fn read_content(&self, blob_ref: &BlobRef) -> Result<Vec<u8>> {
+        self.touch(blob_ref)?;
+        let files = self.find_blob_files(blob_ref);
+        let mut error = Error::BlobNotFound(blob_ref.clone());
+        for file in &files {
+            match fs::read(file) {
+                // With a single copy there is nothing to fall back to
+                Ok(content)
+                    if files.len() == 1
+                        || hash_bytes(&content).reference() == blob_ref.reference() =>
+                {
+                    return Ok(content)
+                }
+                Ok(_) => error = self.corrupted(blob_ref),
+                Err(e) => error = e.into(),
+            }
+        }
+        if let Some(entry) = self.find_packed(blob_ref) {
+            let mut content = vec![];
+            pack::open(&self.root, &entry)?.read_to_end(&mut content)?;
+            return Ok(content);
+        }
+
+        let read = |mut shards: ShardReader| {
+            let mut content = vec![];
+            shards.read_to_end(&mut content)?;
+            Ok(content)
+        };
+        let is_valid = |content: &Vec<u8>| hash_bytes(content).reference() == blob_ref.reference();
+        match self.read_shards(blob_ref, read, is_valid) {
+            Err(Error::BlobNotFound(_)) => Err(error),
+            result => result,
+        }
+    }
+
+    /// Returns a reader over the content of the blob, which is not loaded in memory.
+    ///
+    /// Unlike [`BlobStore::get`], the content is not verified while it is read, unless the
+    /// blob store verifies on read, see [`BlobStore::open_verified`]: the first copy which
+    /// can be opened is read. Erasure coded blobs are rebuilt from their shards while they
+    /// are read.
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::BlobNotFound`] if the blob is not in the blob store, or if
+    /// it cannot be opened.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::BlobStore;
+    /// use std::io::Read;
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let blob_store = BlobStore::new(dir.path()).unwrap();
+    /// let blob_ref = blob_store.add_bytes(b"hello world", "hello.txt").unwrap();
+    ///
+    /// let mut content = String::new();
+    /// blob_store.open(&blob_ref).unwrap().read_to_string(&mut content).unwrap();
+    /// assert_eq!(content, "hello world");
+    /// ```
+    pub fn open(&self, blob_ref: &BlobRef) -> Result<BlobReader> {
+        if self.verify_on_read {
+            self.open_verified(blob_ref)
+        } else {
+            self.open_reader(blob_ref, false)
+        }
+    }
+
+    /// Like [`BlobStore::open`], but the content is hashed while it is read. If it does
+    /// not match the reference, reading the end of the content fails with an
+    /// [`io::Error`] wrapping [`Error::Corrupted`], and the blob is added to the
+    /// quarantine list, see [`BlobStore::quarantine`]. When the blob has several replicas,
+    /// the first copy matching its hash is read, which costs hashing the copies first.
+    ///
+    /// As the content is only known to be corrupted once it has all been read, callers
+    /// which must not pass on corrupted content should read it all first, or call
+    /// [`BlobStore::verify`] beforehand.
+    ///
+    /// # Errors
+    ///
+    /// It errors like [`BlobStore::open`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::{BlobStore, Error};
+    /// use std::io::Read;
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let blob_store = BlobStore::new(dir.path()).unwrap();
+    /// let blob_ref = blob_store.add_bytes(b"hello world", "hello.txt").unwrap();
+    /// let path = blob_store.blob_dir(&blob_ref).join("hello.txt");
+    /// std::fs::write(path, b"hello w0rld").unwrap();
+    ///
+    /// let mut content = vec![];
+    /// let err = blob_store
+    ///     .open_verified(&blob_ref)
+    ///     .unwrap()
+    ///     .read_to_end(&mut content)
+    ///     .unwrap_err();
+    /// assert!(matches!(Error::from(err), Error::Corrupted(_)));
+    /// ```
+    pub fn open_verified(&self, blob_ref: &BlobRef) -> Result<BlobReader> {
+        Ok(self
+            .open_reader(blob_ref, true)?
+            .verified(blob_ref, &self.root))
+    }
+
+    /// Reads the whole content of a blob, without keeping it, to check that it matches
+    /// the reference.
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::Corrupted`] if the content does not match, see
+    /// [`BlobStore::open_verified`], and like [`BlobStore::open`] otherwise.
+    pub fn verify(&self, blob_ref: &BlobRef) -> Result<()> {
+        io::copy(&mut self.open_verified(blob_ref)?, &mut io::sink())?;
+        Ok(())
+    }
+
+    /// Checks the content of a blob like [`BlobStore::verify`], and returns a reader over
+    /// a copy known to match the reference, whose content is not hashed again. Unlike
+    /// with [`BlobStore::open_verified`], corrupted content is reported before any of it
+    /// is read, e.g. before responding to a request.
+    ///
+    /// # Errors
+    ///
+    /// See [`BlobStore::verify`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::{BlobStore, Error};
+    /// use std::io::Read;
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let blob_store = BlobStore::new(dir.path()).unwrap();
+    /// let blob_ref = blob_store.add_bytes(b"hello world", "hello.txt").unwrap();
+    ///
+    /// let mut content = String::new();
+    /// let mut reader = blob_store.verify_and_open(&blob_ref).unwrap();
+    /// reader.read_to_string(&mut content).unwrap();
+    /// assert_eq!(content, "hello world");
+    ///
+    /// let path = blob_store.blob_dir(&blob_ref).join("hello.txt");
+    /// std::fs::write(path, b"hello w0rld").unwrap();
+    /// let result = blob_store.verify_and_open(&blob_ref);
+    /// assert!(matches!(result, Err(Error::Corrupted(_))));
+    /// ```
+    pub fn verify_and_open(&self, blob_ref: &BlobRef) -> Result<BlobReader> {
+        self.touch(blob_ref)?;
+        let files = self.find_blob_files(blob_ref);
+        // Finding a healthy replica already hashes it
+        if let [_, _, ..] = files.as_slice() {
+            if let Some(file) = files.iter().find(|file| fsck::verify_file(file, blob_ref)) {
+                return Ok(BlobReader::from_file(File::open(file)?));
+            }
+        }
+        self.verify(blob_ref)?;
+        self.open_reader(blob_ref, false)
+    }
+
+    /// Opens a reader over the content of a blob, see [`BlobStore::open`]. With
+    /// `healthy_first`, a replica matching the hash of the blob is preferred.
+    fn open_reader(&self, blob_ref: &BlobRef, healthy_first: bool) -> Result<BlobReader> {
+        self.touch(blob_ref)?;
+        let files = self.find_blob_files(blob_ref);
+        // With a single copy there is nothing to fall back to
+        let healthy = match files.as_slice() {
+            [_, _, ..] if healthy_first => {
+                files.iter().find(|file| fsck::verify_file(file, blob_ref))
+            }
+            _ => None,
+        };
+        if let Some(file) = healthy
+            .into_iter()
+            .chain(&files)
+            .find_map(|file| File::open(file).ok())
+        {
+            return Ok(BlobReader::from_file(file));
+        }
+        if let Some(entry) = self.find_packed(blob_ref) {
+            return Ok(BlobReader::from_pack(pack::open(&self.root, &entry)?));
+        }
+        let shards = self.find_shard_files(blob_ref);
+        if shards.is_empty() {
+            return Err(Error::BlobNotFound(blob_ref.clone()));
+        }
+        Ok(BlobReader::from_shards(ShardReader::open(
+            &shards, blob_ref,
+        )?))
+    }
+
+    /// Rebuilds an erasure coded blob from its shards with `read`, checking the result
+    /// with `is_valid`. As the shards are not verified while they are read, a corrupted
+    /// shard gives an invalid result: the blob is then rebuilt again from the shards
+    /// matching their hash only.
+    fn read_shards<T, R, V>(&self, blob_ref: &BlobRef, read: R, is_valid: V) -> Result<T>
+    where
+        R: Fn(ShardReader) -> Result<T>,
+        V: Fn(&T) -> bool,
+    {
+        let shards = self.find_shard_files(blob_ref);
+        if shards.is_empty() {
+            return Err(Error::BlobNotFound(blob_ref.clone()));
+        }
+        let result = read(ShardReader::open(&shards, blob_ref)?)?;
+        if is_valid(&result) {
+            return Ok(result);
+        }
+        let shards: Vec<PathBuf> = shards
+            .into_iter()
+            .filter(|shard| erasure::verify_shard(shard).is_some())
+            .collect();
+        let result = read(ShardReader::open(&shards, blob_ref)?)?;
+        if is_valid(&result) {
+            Ok(result)
+        } else {
+            Err(self.corrupted(blob_ref))
+        }
+    }
+
+    /// Copies the content of the blob to the file at `dest`, replacing it if it exists.
+    ///
+    /// Like [`BlobStore::get`], it falls back to another copy if there is one.
+    pub(crate) fn copy_to(&self, blob_ref: &BlobRef, dest: &Path) -> Result<()> {
+        self.touch(blob_ref)?;
+        let files = self.find_blob_files(blob_ref);
+        let mut error = Error::BlobNotFound(blob_ref.clone());
+        for file in &files {
+            match fs::copy(file, dest) {
+                Ok(_) if files.len() == 1 || fsck::verify_file(dest, blob_ref) => return Ok(()),
+                Ok(_) => error = self.corrupted(blob_ref),
+                Err(e) => error = e.into(),
+            }
+        }
+        if let Some(entry) = self.find_packed(blob_ref) {
+            io::copy(
+                &mut pack::open(&self.root, &entry)?,
+                &mut File::create(dest)?,
+            )?;
+            return Ok(());
+        }
+
+        let read = |mut shards: ShardReader| {
+            io::copy(&mut shards, &mut File::create(dest)?)?;
+            Ok(())
+        };
+        let is_valid = |(): &()| fsck::verify_file(dest, blob_ref);
+        match self.read_shards(blob_ref, read, is_valid) {
+            Err(Error::BlobNotFound(_)) => Err(error),
+            result => result,
+        }
+    }
+
+    /// Stores a snapshot of the directory at `path` as a [`Tree`] and returns the
+    /// reference to its manifest.
+    ///
+    /// All files in the directory and its children are added to the blob store, including
+    /// hidden files. The manifest records the relative path, permissions and modification
+    /// time of every file, directory and symlink so that the directory can be recreated
+    /// with [`BlobStore::restore_tree`].
+    ///
+    /// # Errors
+    ///
+    /// It errors if `path` is not a directory or if any of its entries cannot be read or
+    /// stored.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use std::fs;
+    /// use rstr::BlobStore;
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// # let source = dir.path().join("source");
+    /// # fs::create_dir_all(source.join("sub")).unwrap();
+    /// # fs::write(source.join("sub/hello.txt"), b"hello world").unwrap();
+    /// let blob_store = BlobStore::new(dir.path().join("store")).unwrap();
+    ///
+    /// let tree_ref = blob_store.add_tree(&source).unwrap();
+    /// let tree = blob_store.tree(&tree_ref).unwrap();
+    /// assert_eq!(tree.entries.len(), 3); // ".", "sub" and "sub/hello.txt"
+    ///
+    /// let dest = dir.path().join("restored");
+    /// blob_store.restore_tree(&tree_ref, &dest).unwrap();
+    /// assert_eq!(fs::read(dest.join("sub/hello.txt")).unwrap(), b"hello world");
+    /// ```
+    pub fn add_tree<P: AsRef<Path>>(&self, path: P) -> Result<BlobRef> {
+        let (tree_ref, tree, _) = tree::store_tree(self, path.as_ref())?;
+        for blob_ref in tree.blob_refs().chain(Some(&tree_ref)) {
+            self.disown(blob_ref)?;
+        }
+        Ok(tree_ref)
+    }
+
+    /// Given the [`BlobRef`] of a tree manifest it returns the parsed [`Tree`].
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::BlobNotFound`] if the blob is not in the blob store and with
+    /// [`Error::InvalidTree`] if the blob is not a tree manifest.
+    pub fn tree(&self, blob_ref: &BlobRef) -> Result<Tree> {
+        Tree::from_manifest(&self.get(blob_ref)?)
+    }
+
+    /// Recreates the directory stored as the tree `tree_ref` at the path `dest`,
+    /// restoring the content, permissions and modification times of all its entries.
+    ///
+    /// The destination directory is created if it does not exist. Existing files
+    /// with the same path as an entry of the tree are overwritten.
+    ///
+    /// # Errors
+    ///
+    /// It errors if the tree or any of its blobs cannot be found, if the manifest
+    /// contains paths escaping the destination, e.g. through a symlink, or if the files
+    /// cannot be written.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use std::fs;
+    /// use rstr::{BlobStore, Error};
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let blob_store = BlobStore::new(dir.path().join("store")).unwrap();
+    /// let outside = dir.path().join("outside");
+    /// fs::create_dir(&outside).unwrap();
+    ///
+    /// // A crafted manifest restoring a symlink to `outside`, then a file through it
+    /// let target = blob_store.add_bytes(outside.to_str().unwrap().as_bytes(), "link").unwrap();
+    /// let content = blob_store.add_bytes(b"pwned", "passwd").unwrap();
+    /// let manifest = format!(
+    ///     "rstr-tree 1\n\
+    ///      dir\t755\t0.000000000\t0\t-\t.\n\
+    ///      link\t777\t0.000000000\t0\t{}\ta\n\
+    ///      file\t644\t0.000000000\t5\t{}\ta/passwd\n",
+    ///     target.reference(),
+    ///     content.reference()
+    /// );
+    /// let tree_ref = blob_store.add_bytes(manifest.as_bytes(), "tree.rstr").unwrap();
+    ///
+    /// let dest = dir.path().join("restored");
+    /// let result = blob_store.restore_tree(&tree_ref, &dest);
+    /// assert!(matches!(result, Err(Error::InvalidTree)));
+    /// assert!(!outside.join("passwd").exists());
+    /// ```
+    pub fn restore_tree<P: AsRef<Path>>(&self, tree_ref: &BlobRef, dest: P) -> Result<()> {
+        let tree = self.tree(tree_ref)?;
+        tree::restore_tree(self, &tree, dest.as_ref())
+    }
+
+    /// Takes a snapshot of the directory at `path`, stored as a [`Tree`], and records it
+    /// under the label `name` together with the current time.
+    ///
+    /// Files already in the blob store, e.g. from previous snapshots of the same
+    /// directory, are not stored again.
+    ///
+    /// # Errors
+    ///
+    /// It errors if `name` is empty or contains control characters, or if the tree cannot
+    /// be created (see [`BlobStore::add_tree`]).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use std::fs;
+    /// use rstr::{BlobStore, RetentionPolicy};
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// # let source = dir.path().join("documents");
+    /// # fs::create_dir_all(&source).unwrap();
+    /// # fs::write(source.join("a.txt"), b"first version").unwrap();
+    /// let blob_store = BlobStore::new(dir.path().join("store")).unwrap();
+    ///
+    /// let first = blob_store.create_snapshot(&source, "documents").unwrap();
+    /// fs::write(source.join("a.txt"), b"second version").unwrap();
+    /// let second = blob_store.create_snapshot(&source, "documents").unwrap();
+    ///
+    /// assert_eq!(blob_store.snapshots().unwrap().len(), 2);
+    /// let diff = blob_store.diff_snapshots(&first.id, &second.id).unwrap();
+    /// assert_eq!(diff.modified.len(), 1);
+    ///
+    /// // Only keep the latest snapshot and delete the blobs no longer needed.
+    /// let policy = RetentionPolicy { keep_last: 1, ..RetentionPolicy::default() };
+    /// let forgotten = blob_store.forget_snapshots(&policy, None).unwrap();
+    /// assert_eq!(forgotten[0].id, first.id);
+    /// let deleted = blob_store.gc_snapshots(&forgotten).unwrap();
+    /// assert_eq!(deleted.len(), 2); // The old version of "a.txt" and the old tree
+    /// ```
+    pub fn create_snapshot<P: AsRef<Path>>(&self, path: P, name: &str) -> Result<Snapshot> {
+        snapshot::create(self, &self.root, path.as_ref(), name)
+    }
+
+    /// Returns all the snapshots in the blob store, sorted from oldest to newest.
+    ///
+    /// # Errors
+    ///
+    /// It errors if the snapshot records cannot be read or parsed.
+    pub fn snapshots(&self) -> Result<Vec<Snapshot>> {
+        snapshot::list(&self.root)
+    }
+
+    /// Returns the snapshot whose id starts with `id`.
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::SnapshotNotFound`] unless exactly one snapshot matches.
+    pub fn snapshot(&self, id: &str) -> Result<Snapshot> {
+        snapshot::find(&self.root, id)
+    }
+
+    /// Compares the trees of two snapshots, identified by (a prefix of) their ids.
+    ///
+    /// # Errors
+    ///
+    /// It errors if either snapshot or tree cannot be found.
+    pub fn diff_snapshots(&self, old_id: &str, new_id: &str) -> Result<TreeDiff> {
+        let old = self.tree(&self.snapshot(old_id)?.tree)?;
+        let new = self.tree(&self.snapshot(new_id)?.tree)?;
+        Ok(old.diff(&new))
+    }
+
+    /// Recreates the directory stored in the snapshot `id` at the path `dest`. See
+    /// [`BlobStore::restore_tree`].
+    ///
+    /// # Errors
+    ///
+    /// It errors if the snapshot cannot be found or restored.
+    pub fn restore_snapshot<P: AsRef<Path>>(&self, id: &str, dest: P) -> Result<()> {
+        self.restore_tree(&self.snapshot(id)?.tree, dest)
+    }
+
+    /// Removes the snapshots not kept by the retention `policy` and returns them. If
+    /// `name` is given, only the snapshots with that name are considered.
+    ///
+    /// The blobs of the removed snapshots are left in the blob store, use
+    /// [`BlobStore::gc_snapshots`] to delete them.
+    ///
+    /// # Errors
+    ///
+    /// It errors if the snapshot records cannot be read or removed.
+    pub fn forget_snapshots(
+        &self,
+        policy: &RetentionPolicy,
+        name: Option<&str>,
+    ) -> Result<Vec<Snapshot>> {
+        snapshot::forget(&self.root, policy, name)
+    }
+
+    /// Deletes the blobs (files and trees) written by the `forgotten` snapshots which are
+    /// no longer referenced, and returns their references. A blob is still referenced if
+    /// a remaining snapshot, an alias (including its history), a bucket or a tree which
+    /// is not part of a forgotten snapshot uses it.
+    ///
+    /// Only the blobs which were not in the blob store before a snapshot was taken are
+    /// deleted. Blobs added to the blob store in any other way, before or after, are never
+    /// deleted.
+    ///
+    /// # Errors
+    ///
+    /// It errors if the remaining snapshots cannot be read or a blob cannot be deleted.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::{BlobStore, RetentionPolicy};
+    /// use std::fs;
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let blob_store = BlobStore::new(dir.path().join("store")).unwrap();
+    /// let release = blob_store.add_bytes(b"version 1", "release.txt").unwrap();
+    /// blob_store.set_alias("releases/latest", &release).unwrap();
+    ///
+    /// let source = dir.path().join("source");
+    /// fs::create_dir(&source).unwrap();
+    /// fs::write(source.join("release.txt"), b"version 1").unwrap();
+    /// fs::write(source.join("notes.txt"), b"first draft").unwrap();
+    /// blob_store.create_snapshot(&source, "documents").unwrap();
+    /// fs::remove_file(source.join("release.txt")).unwrap();
+    /// fs::remove_file(source.join("notes.txt")).unwrap();
+    /// blob_store.create_snapshot(&source, "documents").unwrap();
+    ///
+    /// let policy = RetentionPolicy { keep_last: 1, ..RetentionPolicy::default() };
+    /// let forgotten = blob_store.forget_snapshots(&policy, None).unwrap();
+    /// let deleted = blob_store.gc_snapshots(&forgotten).unwrap();
+    /// assert_eq!(deleted.len(), 2); // "notes.txt" and the old tree
+    /// assert_eq!(blob_store.get(&release).unwrap(), b"version 1");
+    /// ```
+    pub fn gc_snapshots(&self, forgotten: &[Snapshot]) -> Result<Vec<BlobRef>> {
+        snapshot::gc(self, &self.root, forgotten)
+    }
+
+    /// Points the alias `name` (e.g. `releases/latest`) to the blob `target`, creating the
+    /// alias if it does not exist. The previous targets are kept in the alias history.
+    ///
+    /// Alias names are made of `/` separated segments of ASCII letters, digits, `.`, `_`
+    /// and `-`, and segments cannot start with a `.`. A name cannot be both an alias and
+    /// the prefix of another one (e.g. `releases` and `releases/latest`).
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::InvalidAlias`] if the name is not valid and with
+    /// [`Error::BlobNotFound`] if `target` is not in the blob store.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::BlobStore;
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let blob_store = BlobStore::new(dir.path()).unwrap();
+    /// let v1 = blob_store.add_bytes(b"version 1", "release.txt").unwrap();
+    /// let v2 = blob_store.add_bytes(b"version 2", "release.txt").unwrap();
+    ///
+    /// blob_store.set_alias("releases/latest", &v1).unwrap();
+    /// blob_store.set_alias("releases/latest", &v2).unwrap();
+    ///
+    /// assert_eq!(blob_store.resolve("releases/latest").unwrap().reference(), v2.reference());
+    /// let history = blob_store.alias_history("releases/latest").unwrap();
+    /// assert_eq!(history[0].target.reference(), v1.reference());
+    /// ```
+    pub fn set_alias(&self, name: &str, target: &BlobRef) -> Result<()> {
+        self.check_alias_target(target)?;
+        alias::set(&self.root, name, target, &Precondition::Any)?;
+        self.disown(target)
+    }
+
+    /// Atomically points the alias `name` to `target`, but only if it currently points to
+    /// `expected`. If `expected` is `None`, the alias is only created if it does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::AliasConflict`] if the alias does not point to `expected`,
+    /// and otherwise as [`BlobStore::set_alias`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::{BlobStore, Error};
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let blob_store = BlobStore::new(dir.path()).unwrap();
+    /// let v1 = blob_store.add_bytes(b"version 1", "release.txt").unwrap();
+    /// let v2 = blob_store.add_bytes(b"version 2", "release.txt").unwrap();
+    ///
+    /// blob_store.compare_and_swap_alias("latest", None, &v1).unwrap();
+    /// // Somebody else already created the alias
+    /// let err = blob_store.compare_and_swap_alias("latest", None, &v2).unwrap_err();
+    /// assert!(matches!(err, Error::AliasConflict));
+    ///
+    /// blob_store.compare_and_swap_alias("latest", Some(&v1), &v2).unwrap();
+    /// assert_eq!(blob_store.resolve("latest").unwrap().reference(), v2.reference());
+    /// ```
+    pub fn compare_and_swap_alias(
+        &self,
+        name: &str,
+        expected: Option<&BlobRef>,
+        target: &BlobRef,
+    ) -> Result<()> {
+        self.check_alias_target(target)?;
+        let precondition = match expected {
+            Some(blob_ref) => Precondition::Target(blob_ref),
+            None => Precondition::Missing,
+        };
+        alias::set(&self.root, name, target, &precondition)?;
+        self.disown(target)
+    }
+
+    fn check_alias_target(&self, target: &BlobRef) -> Result<()> {
+        if self.exists(target) {
+            Ok(())
+        } else {
+            Err(Error::BlobNotFound(target.clone()))
+        }
+    }
+
+    /// Returns the blob the alias `name` currently points to.
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::AliasNotFound`] if the alias does not exist.
+    pub fn resolve(&self, name: &str) -> Result<BlobRef> {
+        alias::resolve(&self.root, name)
+    }
+
+    /// Returns all the blobs the alias `name` pointed to, oldest first. The last entry is
+    /// the current target.
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::AliasNotFound`] if the alias does not exist.
+    pub fn alias_history(&self, name: &str) -> Result<Vec<AliasEntry>> {
+        alias::history(&self.root, name)
+    }
+
+    /// Returns the names of all the aliases together with their current target, sorted
+    /// by name.
+    ///
+    /// # Errors
+    ///
+    /// It errors if the aliases cannot be read.
+    pub fn aliases(&self) -> Result<Vec<(String, BlobRef)>> {
+        alias::list(&self.root)
+    }
+
+    /// Creates a new bucket: a named collection of blobs with its own listing, quota and
+    /// metadata. The content of the blobs is still deduplicated across the whole store.
+    ///
+    /// The `quota` is the maximum number of bytes the blobs in the bucket can take. Blobs
+    /// shared with other buckets count in full towards the quota of each bucket.
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::InvalidBucket`] if the name is not made of ASCII letters,
+    /// digits, `.`, `_` and `-`, and with [`Error::BucketExists`] if the bucket exists.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::BlobStore;
+    /// use std::collections::BTreeMap;
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let blob_store = BlobStore::new(dir.path()).unwrap();
+    ///
+    /// let mut metadata = BTreeMap::new();
+    /// metadata.insert("owner".to_string(), "data-team".to_string());
+    /// blob_store.create_bucket("datasets", Some(1 << 30), metadata).unwrap();
+    ///
+    /// let blob_ref = blob_store.add_to_bucket("datasets", "../tests/test_file.txt").unwrap();
+    /// let bucket = blob_store.bucket("datasets").unwrap();
+    /// assert_eq!(bucket.blob_count, 1);
+    /// assert_eq!(bucket.usage, 20);
+    /// assert_eq!(bucket.metadata["owner"], "data-team");
+    /// assert!(blob_store.exists(&blob_ref));
+    /// ```
+    pub fn create_bucket(
+        &self,
+        name: &str,
+        quota: Option<u64>,
+        metadata: BTreeMap<String, String>,
+    ) -> Result<Bucket> {
+        bucket::create(&self.root, name, quota, metadata)
+    }
+
+    /// Returns the bucket `name`.
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::BucketNotFound`] if the bucket does not exist.
+    pub fn bucket(&self, name: &str) -> Result<Bucket> {
+        bucket::get(&self.root, name)
+    }
+
+    /// Returns all the buckets, sorted by name.
+    ///
+    /// # Errors
+    ///
+    /// It errors if the buckets cannot be read.
+    pub fn buckets(&self) -> Result<Vec<Bucket>> {
+        bucket::list(&self.root)
+    }
+
+    /// Replaces the quota and the metadata of the bucket `name`. Lowering the quota below
+    /// the current usage does not remove any blob, it only prevents adding new ones.
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::BucketNotFound`] if the bucket does not exist.
+    pub fn update_bucket(
+        &self,
+        name: &str,
+        quota: Option<u64>,
+        metadata: BTreeMap<String, String>,
+    ) -> Result<()> {
+        bucket::update(&self.root, name, quota, metadata)
+    }
+
+    /// Deletes the bucket `name` after removing all of its blobs as in
+    /// [`BlobStore::remove_from_bucket`]. Returns the blobs whose content was deleted.
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::BucketNotFound`] if the bucket does not exist.
+    pub fn delete_bucket(&self, name: &str) -> Result<Vec<BlobRef>> {
+        bucket::delete(self, &self.root, name)
+    }
+
+    /// Adds the file at `path` to the bucket `name`, storing its content in the blob
+    /// store if it is not there yet. Adding a blob already in the bucket does nothing.
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::QuotaExceeded`] if the blob would take the bucket over its
+    /// quota, with [`Error::BucketNotFound`] if the bucket does not exist, and if the file
+    /// cannot be read.
+    pub fn add_to_bucket<P: AsRef<Path>>(&self, name: &str, path: P) -> Result<BlobRef> {
+        bucket::add(self, &self.root, name, path.as_ref())
+    }
+
+    /// Same as [`BlobStore::add_files_with_options`], but also adds the files to the
+    /// bucket `name`. Files that would take the bucket over its quota are reported with
+    /// [`Error::QuotaExceeded`].
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::BucketNotFound`] if the bucket does not exist.
+    #[allow(clippy::type_complexity)]
+    pub fn add_files_to_bucket<P: AsRef<Path> + Sync>(
+        &self,
+        name: &str,
+        paths: &[P],
+        options: &AddOptions,
+    ) -> Result<(Vec<(PathBuf, BlobRef)>, Vec<(PathBuf, Error)>)> {
+        let mut blob_refs = vec![];
+        let mut errors = vec![];
+        for (path, result) in bucket::add_files(self, &self.root, name, paths, options)? {
+            match result {
+                Ok(blob_ref) => blob_refs.push((path, blob_ref)),
+                Err(e) => errors.push((path, e)),
+            }
+        }
+        Ok((blob_refs, errors))
+    }
+
+    /// Returns the blobs in the bucket `name`, from the oldest to the newest added.
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::BucketNotFound`] if the bucket does not exist.
+    pub fn bucket_entries(&self, name: &str) -> Result<Vec<BucketEntry>> {
+        bucket::entries(&self.root, name)
+    }
+
+    /// Returns the entry of the blob `blob_ref` in the bucket `name`.
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::BlobNotFound`] if the blob is not in the bucket and with
+    /// [`Error::BucketNotFound`] if the bucket does not exist.
+    pub fn bucket_entry(&self, name: &str, blob_ref: &BlobRef) -> Result<BucketEntry> {
+        bucket::entry(&self.root, name, blob_ref)
+    }
+
+    /// Removes the blob `blob_ref` from the bucket `name`.
+    ///
+    /// The content of the blob is deleted from the blob store only if it was stored
+    /// through a bucket and no other bucket contains it; blobs added directly to the
+    /// blob store, before or after, or used by a snapshot, a tree or an alias are never
+    /// deleted. Returns `true` if the content was deleted.
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::BlobNotFound`] if the blob is not in the bucket and with
+    /// [`Error::BucketNotFound`] if the bucket does not exist.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::BlobStore;
+    /// use std::collections::BTreeMap;
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let blob_store = BlobStore::new(dir.path()).unwrap();
+    /// blob_store.create_bucket("team-a", None, BTreeMap::new()).unwrap();
+    /// blob_store.create_bucket("team-b", None, BTreeMap::new()).unwrap();
+    ///
+    /// let blob_ref = blob_store.add_to_bucket("team-a", "../tests/test_file.txt").unwrap();
+    /// blob_store.add_to_bucket("team-b", "../tests/test_file.txt").unwrap();
+    ///
+    /// // The content is still used by `team-b`
+    /// assert!(!blob_store.remove_from_bucket("team-a", &blob_ref).unwrap());
+    /// assert!(blob_store.exists(&blob_ref));
+    ///
+    /// assert!(blob_store.remove_from_bucket("team-b", &blob_ref).unwrap());
+    /// assert!(!blob_store.exists(&blob_ref));
+    ///
+    /// // The content was also added directly
+    /// blob_store.add_to_bucket("team-a", "../tests/test_file.txt").unwrap();
+    /// blob_store.add("../tests/test_file.txt").unwrap();
+    /// assert!(!blob_store.remove_from_bucket("team-a", &blob_ref).unwrap());
+    /// assert!(blob_store.exists(&blob_ref));
+    /// ```
+    pub fn remove_from_bucket(&self, name: &str, blob_ref: &BlobRef) -> Result<bool> {
+        bucket::remove(self, &self.root, name, blob_ref)
+    }
+
+    /// Returns `true` if there is a file associated with the [`BlobRef`] in the blob store
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::{BlobStore, BlobRef};
+    ///
+    /// let blob_store = BlobStore::new("../tests/test_data_store/").unwrap();
+    /// let blob_ref = BlobRef::new("f29bc64a9d3732b4b9035125fdb3285f5b6455778edca72414671e0ca3b2e0de").unwrap();
+    ///
+    /// assert!(blob_store.exists(&blob_ref))
+    /// ```
+    pub fn exists(&self, blob_ref: &BlobRef) -> bool {
+        self.exists_locally(blob_ref)
+            || self
+                .cold_tier
+                .as_ref()
+                .is_some_and(|cold| cold.exists(blob_ref))
+            || self
+                .upstream
+                .as_ref()
+                .is_some_and(|upstream| upstream.exists(blob_ref).unwrap_or(false))
+    }
+
+    /// Returns `true` if the blob is stored in this tier.
+    fn exists_locally(&self, blob_ref: &BlobRef) -> bool {
+        match self.ref_index() {
+            Some(index) if index.contains(blob_ref) => true,
+            // Another process may have added it since the index was read
+            Some(index) if index.is_stale() => {
+                drop(index);
+                matches!(self.refreshed_index(), Ok(Some(index)) if index.contains(blob_ref))
+            }
+            Some(_) => false,
+            None => self.find_blob_path(blob_ref).is_some() || self.find_packed(blob_ref).is_some(),
+        }
+    }
+
+    /// Returns the references of all the blobs in the blob store, sorted.
+    ///
+    /// # Errors
+    ///
+    /// It errors if any of the roots cannot be read.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::BlobStore;
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let blob_store = BlobStore::new(dir.path()).unwrap();
+    /// let blob_ref = blob_store.add_bytes(b"hello world", "hello.txt").unwrap();
+    /// blob_store.set_alias("hello", &blob_ref).unwrap();
+    ///
+    /// let blob_refs = blob_store.blob_refs().unwrap();
+    /// assert_eq!(blob_refs.len(), 1);
+    /// assert_eq!(blob_refs[0].reference(), blob_ref.reference());
+    /// ```
+    pub fn blob_refs(&self) -> Result<Vec<BlobRef>> {
+        let mut blob_refs = self.local_blob_refs()?;
+        if let Some(cold) = &self.cold_tier {
+            blob_refs.extend(cold.blob_refs()?);
+        }
+        if let Some(upstream) = &self.upstream {
+            blob_refs.extend(upstream.blob_refs()?);
+        }
+        blob_refs.sort_by(|a, b| a.reference().cmp(b.reference()));
+        blob_refs.dedup_by(|a, b| a.reference() == b.reference());
+        Ok(blob_refs)
+    }
+
+    /// Returns the references of the blobs stored in this tier, sorted.
+    pub(crate) fn local_blob_refs(&self) -> Result<Vec<BlobRef>> {
+        match self.refreshed_index()? {
+            Some(index) => Ok(index.blob_refs()),
+            None => self.scan_blob_refs(),
+        }
+    }
+
+    /// Lists the blobs stored on disk, sorted, regardless of the index.
+    fn scan_blob_refs(&self) -> Result<Vec<BlobRef>> {
+        let mut blob_refs = vec![];
+        for root in &self.roots {
+            blob_refs.extend(roots::list_blobs(root, self.shard_layout)?);
+        }
+        blob_refs.extend(
+            pack::list(&self.root)?
+                .into_iter()
+                .map(|entry| entry.blob_ref),
+        );
+        blob_refs.sort_by(|a, b| a.reference().cmp(b.reference()));
+        blob_refs.dedup_by(|a, b| a.reference() == b.reference());
+        Ok(blob_refs)
+    }
+
+    /// Moves the blobs to the root chosen for them by the placement, e.g. after adding a
+    /// root. The blobs stored in the `drain` roots are moved to the other roots, so that
+    /// the drained roots can be removed from the store afterwards. Drained roots which
+    /// are not part of the store are emptied as well.
+    ///
+    /// With [`Placement::FreeSpace`] only the blobs in the drained roots are moved.
+    ///
+    /// When the blobs are replicated, each of them ends up with a copy in each of the
+    /// [`BlobStore::replicas`] roots the placement chooses, and the copies stored
+    /// elsewhere are removed.
+    ///
+    /// The shards of erasure coded blobs are only moved out of the drained roots, to
+    /// roots not holding another shard of the same blob.
+    ///
+    /// Returns the blobs that were moved or copied, together with their new root.
+    ///
+    /// # Errors
+    ///
+    /// It errors if all the roots are drained, or if a blob cannot be moved.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::{BlobStore, Placement};
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let old_disk = dir.path().join("disk1");
+    /// let new_disk = dir.path().join("disk2");
+    /// let blob_ref = BlobStore::new(&old_disk)
+    ///     .unwrap()
+    ///     .add_bytes(b"hello world", "hello.txt")
+    ///     .unwrap();
+    ///
+    /// // Replace `disk1` with `disk2`
+    /// let blob_store = BlobStore::with_roots(&[&new_disk], Placement::HashRange).unwrap();
+    /// let moved = blob_store.rebalance(&[&old_disk]).unwrap();
+    ///
+    /// assert_eq!(moved.len(), 1);
+    /// assert!(new_disk.join(blob_ref.to_path()).exists());
+    /// assert!(!old_disk.join(blob_ref.to_path()).exists());
+    /// ```
+    pub fn rebalance<P: AsRef<Path>>(&self, drain: &[P]) -> Result<Vec<(BlobRef, PathBuf)>> {
+        let drained: Vec<&Path> = drain.iter().map(AsRef::as_ref).collect();
+        let targets: Vec<&Path> = self
+            .roots
+            .iter()
+            .map(PathBuf::as_path)
+            .filter(|root| !drained.contains(root))
+            .collect();
+        if targets.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "all roots drained").into());
+        }
+
+        let mut sources = targets.clone();
+        sources.extend(drained.iter().filter(|root| !targets.contains(root)));
+
+        let mut blob_refs = vec![];
+        for source in &sources {
+            if self.placement == Placement::FreeSpace && !drained.contains(source) {
+                continue;
+            }
+            blob_refs.extend(roots::list_blobs(source, self.shard_layout)?);
+        }
+        blob_refs.sort_by(|a, b| a.reference().cmp(b.reference()));
+        blob_refs.dedup_by(|a, b| a.reference() == b.reference());
+
+        let mut moved = vec![];
+        for blob_ref in blob_refs {
+            let path = self.shard_layout.blob_path(&blob_ref);
+            let holders: Vec<&Path> = sources
+                .iter()
+                .copied()
+                .filter(|root| roots::has_blob(&root.join(&path)))
+                .collect();
+            if holders
+                .iter()
+                .any(|root| erasure::shard_path(&root.join(&path)).is_some())
+            {
+                // Each shard must stay in its own root, only drain them
+                let mut free = self
+                    .placement
+                    .choose_n(&targets, &blob_ref, targets.len())
+                    .into_iter()
+                    .filter(|root| !holders.contains(root));
+                for source in holders.iter().filter(|root| drained.contains(root)) {
+                    let Some(dest) = free.next() else {
+                        break;
+                    };
+                    roots::move_blob(&source.join(&path), &dest.join(&path))?;
+                    roots::prune_shards(source, self.shard_layout, &blob_ref);
+                    moved.push((blob_ref.clone(), dest.to_path_buf()));
+                }
+                continue;
+            }
+            let wanted = match self.placement {
+                Placement::HashRange => self.placement.choose_n(&targets, &blob_ref, self.replicas),
+                Placement::FreeSpace => {
+                    // Keep the copies outside of the drained roots where they are
+                    let mut wanted: Vec<&Path> = holders
+                        .iter()
+                        .copied()
+                        .filter(|root| !drained.contains(root))
+                        .collect();
+                    let free: Vec<&Path> = targets
+                        .iter()
+                        .copied()
+                        .filter(|root| !wanted.contains(root))
+                        .collect();
+                    if wanted.len() < self.replicas && !free.is_empty() {
+                        let missing = self.replicas - wanted.len();
+                        wanted.extend(self.placement.choose_n(&free, &blob_ref, missing));
+                    }
+                    wanted
+                }
+            };
+
+            let mut unwanted: Vec<&Path> = holders
+                .iter()
+                .copied()
+                .filter(|root| !wanted.contains(root))
+                .collect();
+            for dest in wanted.iter().filter(|root| !holders.contains(root)) {
+                let dest_dir = dest.join(&path);
+                // Move the copies which are in the wrong place, copy the others
+                if let Some(source) = unwanted.pop() {
+                    roots::move_blob(&source.join(&path), &dest_dir)?;
+                    roots::prune_shards(source, self.shard_layout, &blob_ref);
+                } else {
+                    roots::copy_blob(&holders[0].join(&path), &dest_dir)?;
+                }
+                moved.push((blob_ref.clone(), dest.to_path_buf()));
+            }
+            for source in unwanted {
+                fs::remove_dir_all(source.join(&path))?;
+                roots::prune_shards(source, self.shard_layout, &blob_ref);
+            }
+        }
+        Ok(moved)
+    }
+
+    /// Hashes every copy of the blob and reports which ones are healthy and which ones
+    /// are corrupted. This is the check [`BlobStore::fsck`] performs on every blob.
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::BlobNotFound`] if the blob is not in the blob store.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::BlobStore;
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let blob_store = BlobStore::new(dir.path()).unwrap();
+    /// let blob_ref = blob_store.add_bytes(b"hello world", "hello.txt").unwrap();
+    /// assert!(blob_store.check_blob(&blob_ref).unwrap().is_healthy());
+    ///
+    /// let file = blob_store.blob_dir(&blob_ref).join("hello.txt");
+    /// std::fs::write(file, b"hello w0rld").unwrap();
+    ///
+    /// let check = blob_store.check_blob(&blob_ref).unwrap();
+    /// assert!(check.is_lost());
+    /// assert_eq!(check.corrupted, [dir.path()]);
+    /// ```
+    pub fn check_blob(&self, blob_ref: &BlobRef) -> Result<BlobCheck> {
+        let check = fsck::check(self, blob_ref);
+        if check.healthy.is_empty() && check.corrupted.is_empty() {
+            return Err(Error::BlobNotFound(blob_ref.clone()));
+        }
+        Ok(check)
+    }
+
+    /// Checks every blob in the blob store with [`BlobStore::check_blob`] and returns the
+    /// ones which are not healthy: blobs with corrupted copies or with fewer copies than
+    /// [`BlobStore::replicas`].
+    ///
+    /// Erasure coded blobs missing some shards are degraded: they can still be read as
+    /// long as [`ErasureCoding::data_shards`] shards are healthy.
+    ///
+    /// # Errors
+    ///
+    /// It errors if a root cannot be read.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::{BlobStore, ErasureCoding, Placement};
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let roots: Vec<_> = (0..3).map(|i| dir.path().join(format!("disk{i}"))).collect();
+    /// let coding = ErasureCoding {
+    ///     data_shards: 2,
+    ///     parity_shards: 1,
+    ///     min_size: 0,
+    /// };
+    /// let blob_store = BlobStore::with_roots(&roots, Placement::HashRange)
+    ///     .unwrap()
+    ///     .with_erasure_coding(coding)
+    ///     .unwrap();
+    /// let blob_ref = blob_store.add_bytes(b"hello world", "hello.txt").unwrap();
+    ///
+    /// std::fs::remove_dir_all(&roots[2]).unwrap();
+    /// let checks = blob_store.fsck().unwrap();
+    /// assert_eq!(checks[0].data_shards, Some(2));
+    /// assert_eq!(checks[0].healthy.len(), 2);
+    /// assert!(!checks[0].is_lost());
+    ///
+    /// // The missing shard is rebuilt from the other two
+    /// blob_store.repair().unwrap();
+    /// assert!(blob_store.fsck().unwrap().is_empty());
+    /// ```
+    pub fn fsck(&self) -> Result<Vec<BlobCheck>> {
+        Ok(self
+            .local_blob_refs()?
+            .iter()
+            .map(|blob_ref| fsck::check(self, blob_ref))
+            .filter(|check| !check.is_healthy())
+            .collect())
+    }
+
+    /// Repairs the blobs [`BlobStore::fsck`] reports: the corrupted copies are removed and
+    /// the blobs are copied from a healthy copy until they have [`BlobStore::replicas`]
+    /// copies again, see [`BlobStore::replicate`].
+    ///
+    /// Blobs without any healthy copy cannot be repaired: they are left untouched and
+    /// reported as lost.
+    ///
+    /// # Errors
+    ///
+    /// It errors if a root cannot be read or a copy cannot be written or removed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::{BlobStore, Placement};
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let roots = [dir.path().join("disk1"), dir.path().join("disk2")];
+    /// let blob_store = BlobStore::with_roots(&roots, Placement::HashRange)
+    ///     .unwrap()
+    ///     .with_replicas(2)
+    ///     .unwrap();
+    /// let blob_ref = blob_store.add_bytes(b"hello world", "hello.txt").unwrap();
+    ///
+    /// // A disk is replaced
+    /// std::fs::remove_dir_all(&roots[0]).unwrap();
+    /// assert_eq!(blob_store.fsck().unwrap().len(), 1);
+    ///
+    /// let report = blob_store.repair().unwrap();
+    /// assert_eq!(report.copied.len(), 1);
+    /// assert!(roots[0].join(blob_ref.to_path()).exists());
+    /// assert!(blob_store.fsck().unwrap().is_empty());
+    /// ```
+    pub fn repair(&self) -> Result<RepairReport> {
+        let mut report = RepairReport::default();
+        for check in self.fsck()? {
+            fsck::repair(self, &check, &mut report)?;
+        }
+        Ok(report)
+    }
+
+    /// Given a [`BlobRef`] it deletes the corresponding blob from the blob store
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use rstr::{BlobStore, BlobRef};
+    /// let blob_store = BlobStore::new("/path/to/blob/store").unwrap();
+    ///
+    /// let blob_ref = BlobRef::new("b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9").unwrap();
+    /// assert!(blob_store.exists(&blob_ref));
+    ///
+    /// blob_store.delete(&blob_ref);
+    /// assert!(!blob_store.exists(&blob_ref));
+    /// ```
+    /// # Errors
+    ///
+    /// See [`fs::remove_dir_all`].
+    pub fn delete(&self, blob_ref: &BlobRef) -> Result<()> {
+        let mut result = self.delete_locally(blob_ref);
+        if self.cold_tier.is_some() || self.upstream.is_some() {
+            tier::forget(&self.root, blob_ref)?;
+        }
+        if let Some(cold) = &self.cold_tier {
+            result = either_deleted(result, cold.delete(blob_ref));
+        }
+        if let Some(upstream) = self.upstream.as_ref().filter(|_| self.cache.write_through) {
+            result = either_deleted(result, upstream.delete(blob_ref));
+        }
+        result
+    }
+
+    /// Removes the blob from this tier.
+    fn delete_locally(&self, blob_ref: &BlobRef) -> Result<()> {
+        if let Some(usage) = self
+            .cache_usage
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_mut()
+        {
+            usage.remove(blob_ref);
+        }
+        if let Some(index) = &self.index {
+            index
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .remove(blob_ref)?;
+        }
+        let removed = self.remove_loose(blob_ref)?;
+        if pack::remove(&self.root, blob_ref)? || removed {
+            Ok(())
+        } else {
+            Err(Error::BlobNotFound(blob_ref.clone()))
+        }
+    }
+
+    /// Removes the blob directories of the blob from all the roots, i.e. everything but
+    /// its packed copy. Returns `false` if there was none.
+    fn remove_loose(&self, blob_ref: &BlobRef) -> Result<bool> {
+        let mut found = false;
+        for root in &self.roots {
+            let dir = root.join(self.shard_layout.blob_path(blob_ref));
+            if dir.exists() {
+                fs::remove_dir_all(dir)?;
+                roots::prune_shards(root, self.shard_layout, blob_ref);
+                found = true;
+            }
+        }
+        Ok(found)
+    }
+
+    /// Moves the blobs smaller than the packing threshold, see [`BlobStore::with_packing`],
+    /// to a pack file and rewrites the pack files holding the content of deleted blobs.
+    ///
+    /// # Errors
+    ///
+    /// It errors if a root or a pack file cannot be read, or a pack file cannot be
+    /// written.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::BlobStore;
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let blob_ref = BlobStore::new(dir.path())
+    ///     .unwrap()
+    ///     .add_bytes(b"hello world", "hello.txt")
+    ///     .unwrap();
+    ///
+    /// let blob_store = BlobStore::new(dir.path()).unwrap().with_packing(1024).unwrap();
+    /// let report = blob_store.repack().unwrap();
+    /// assert_eq!(report.packed.len(), 1);
+    /// assert!(!dir.path().join(blob_ref.to_path()).exists());
+    /// assert_eq!(blob_store.metadata(&blob_ref).unwrap().filename, "hello.txt");
+    /// ```
+    pub fn repack(&self) -> Result<RepackReport> {
+        let mut loose = vec![];
+        if self.pack_threshold.is_some() {
+            for root in &self.roots {
+                for blob_ref in roots::list_blobs(root, self.shard_layout)? {
+                    let Some(file) =
+                        roots::blob_file(&root.join(self.shard_layout.blob_path(&blob_ref)))
+                    else {
+                        continue;
+                    };
+                    // Corrupted copies are left for `repair`
+                    if self.is_packed_size(fs::metadata(&file)?.len())
+                        && fsck::verify_file(&file, &blob_ref)
+                    {
+                        loose.push((blob_ref, file));
+                    }
+                }
+            }
+        }
+        let report = pack::repack(&self.root, &loose)?;
+        for blob_ref in &report.packed {
+            self.remove_loose(blob_ref)?;
+        }
+        Ok(report)
+    }
+
+    /// Returns the blobs whose metadata satisfies all the given filters, see [`Filter`].
+    ///
+    /// # Errors
+    ///
+    /// It errors if the blobs cannot be listed or their metadata cannot be read.
+    pub fn query(&self, filters: &[Filter]) -> Result<Vec<BlobRef>> {
+        let mut blob_refs = vec![];
+        for blob_ref in self.blob_refs()? {
+            let metadata = self.metadata(&blob_ref)?;
+            if filters.iter().all(|filter| filter.matches(&metadata)) {
+                blob_refs.push(blob_ref);
+            }
+        }
+        Ok(blob_refs)
+    }
+
+    /// Writes the given blobs to `writer` as a bundle, a tar archive which can be imported
+    /// into another blob store with [`BlobStore::import_bundle`], e.g. to move blobs
+    /// between machines which are not connected. The archive starts with a manifest
+    /// listing the blobs with their size, creation time and mime type, followed by the
+    /// blobs as `blobs/{reference}/{filename}`.
+    ///
+    /// The blobs are streamed, so that the archive can be larger than the memory.
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::BlobNotFound`] if a blob is not in the blob store, or if the
+    /// archive cannot be written.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::BlobStore;
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let blob_store = BlobStore::new(dir.path().join("source")).unwrap();
+    /// let blob_ref = blob_store.add_bytes(b"hello world", "hello.txt").unwrap();
+    ///
+    /// let mut archive = vec![];
+    /// blob_store.export_bundle(&blob_store.blob_refs().unwrap(), &mut archive).unwrap();
+    ///
+    /// let other = BlobStore::new(dir.path().join("other")).unwrap();
+    /// let report = other.import_bundle(archive.as_slice()).unwrap();
+    /// assert_eq!(report.imported, vec![blob_ref.clone()]);
+    /// assert_eq!(other.metadata(&blob_ref).unwrap().filename, "hello.txt");
+    ///
+    /// // Blobs already in the blob store are skipped
+    /// let report = other.import_bundle(archive.as_slice()).unwrap();
+    /// assert_eq!(report.present, vec![blob_ref]);
+    /// ```
+    pub fn export_bundle<W: io::Write>(&self, blob_refs: &[BlobRef], writer: W) -> Result<()> {
+        bundle::write(self, blob_refs, writer)
+    }
+
+    /// Adds the blobs of a bundle read from `reader` to the blob store, see
+    /// [`BlobStore::export_bundle`]. The blobs already in the blob store are skipped, and
+    /// the content of the others is verified against their reference while it is read.
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::InvalidBundle`] if the archive cannot be parsed or does not
+    /// contain all the blobs of its manifest, and with [`Error::HashMismatch`] if the
+    /// content of a blob does not match its reference. The blobs before it are still
+    /// added.
+    pub fn import_bundle<R: Read>(&self, reader: R) -> Result<ImportReport> {
+        let report = bundle::read(self, reader)?;
+        self.disown_imported(&report)?;
+        Ok(report)
+    }
+
+    /// Writes the given blobs to `writer` as a CAR file (content-addressed archive,
+    /// version 1), as used by IPFS. Each blob is a block identified by its CID, see
+    /// [`BlobRef::to_cid`], and the blobs are also the roots of the archive.
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::BlobNotFound`] if a blob is not in the blob store, or if the
+    /// archive cannot be written.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::BlobStore;
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let blob_store = BlobStore::new(dir.path().join("source")).unwrap();
+    /// let blob_ref = blob_store.add_bytes(b"hello world", "hello.txt").unwrap();
+    ///
+    /// let mut archive = vec![];
+    /// blob_store.export_car(&[blob_ref.clone()], &mut archive).unwrap();
+    ///
+    /// let other = BlobStore::new(dir.path().join("other")).unwrap();
+    /// let report = other.import_car(archive.as_slice()).unwrap();
+    /// assert_eq!(report.imported, vec![blob_ref.clone()]);
+    /// assert_eq!(other.get(&blob_ref).unwrap(), b"hello world");
+    /// ```
+    pub fn export_car<W: io::Write>(&self, blob_refs: &[BlobRef], writer: W) -> Result<()> {
+        car::write(self, blob_refs, writer)
+    }
+
+    /// Adds the blocks of a CAR file (version 1) read from `reader` to the blob store, see
+    /// [`BlobStore::export_car`]. The content of each block is verified against its CID
+    /// while it is read.
+    ///
+    /// Only the blocks of raw content with a sha2-256 multihash are blobs, the others, e.g.
+    /// the `dag-pb` nodes of files added to IPFS with the default options, are skipped.
+    /// The blobs are saved under their CID as filename.
+    ///
+    /// # Errors
+    ///
+    /// It errors with [`Error::InvalidCar`] if the archive cannot be parsed, and with
+    /// [`Error::HashMismatch`] if the content of a block does not match its CID. The
+    /// blocks before it are still added.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::{BlobStore, Error};
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let blob_store = BlobStore::new(dir.path()).unwrap();
+    ///
+    /// // A header with its roots nested in 100 000 arrays, i.e. `{"roots": [[[...]]]}`
+    /// let mut header = vec![0xa1, 0x65];
+    /// header.extend(b"roots");
+    /// header.extend(vec![0x81; 100_000]);
+    /// header.push(0x80);
+    /// let mut archive = vec![];
+    /// let mut length = header.len();
+    /// while length >= 0x80 {
+    ///     archive.push(length as u8 | 0x80);
+    ///     length >>= 7;
+    /// }
+    /// archive.push(length as u8);
+    /// archive.extend(header);
+    ///
+    /// let err = blob_store.import_car(archive.as_slice()).unwrap_err();
+    /// assert!(matches!(err, Error::InvalidCar(_)));
+    /// assert_eq!(err.to_string(), "Error: Invalid CAR file, header nested too deep.");
+    /// ```
+    pub fn import_car<R: Read>(&self, reader: R) -> Result<ImportReport> {
+        let report = car::read(self, reader)?;
+        self.disown_imported(&report)?;
+        Ok(report)
+    }
+
+    fn disown_imported(&self, report: &ImportReport) -> Result<()> {
+        for blob_ref in report.imported.iter().chain(&report.present) {
+            self.disown(blob_ref)?;
+        }
+        Ok(())
+    }
+
+    /// Given a [`BlobRef`] returns the metadata relative to the referenced blob. For more
+    /// details on the metadata returned see `BlobMetadata`.
+    ///
+    /// The mime type is inferred from the file's magic number as a string.
+    /// It defaults to "application/octet-stream" if it cannot determine the type.
+    /// We use the [`tree_magic_mini`] crate to infer the mime type.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if the file cannot be found/opened or if [`std::fs::metadata`]
+    /// fails.
+    pub fn metadata(&self, blob_ref: &BlobRef) -> Result<BlobMetadata> {
+        let mut result = self.local_metadata(blob_ref);
+        if let (Err(Error::BlobNotFound(_)), Some(cold)) = (&result, &self.cold_tier) {
+            result = cold.metadata(blob_ref).map(|metadata| BlobMetadata {
+                tier: Tier::Cold,
+                ..metadata
+            });
+        }
+        match (result, &self.upstream) {
+            (Err(Error::BlobNotFound(_)), Some(upstream)) => upstream.metadata(blob_ref),
+            (result, _) => result,
+        }
+    }
+
+    /// Returns the metadata of a blob of this tier.
+    fn local_metadata(&self, blob_ref: &BlobRef) -> Result<BlobMetadata> {
+        let Some(file_path) = self
+            .find_blob_files(blob_ref)
+            .into_iter()
+            .find(|file| file.is_file())
+        else {
+            if let Some(entry) = self.find_packed(blob_ref) {
+                return self.packed_metadata(&entry);
+            }
+            return self.shards_metadata(blob_ref);
+        };
+
+        let mime = magic::from_filepath(&file_path).unwrap_or("application/octet-stream");
+
+        let filename = file_path
+            .file_name()
+            .map(|f| f.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let metadata = fs::metadata(file_path)?;
+        Ok(BlobMetadata {
+            mime_type: String::from(mime),
+            filename,
+            size: metadata.len(),
+            created: metadata.created()?.into(),
+            tier: Tier::Hot,
+        })
+    }
+
+    /// Returns the metadata of a packed blob.
+    fn packed_metadata(&self, entry: &PackEntry) -> Result<BlobMetadata> {
+        let mut content = vec![];
+        pack::open(&self.root, entry)?.read_to_end(&mut content)?;
+        Ok(BlobMetadata {
+            mime_type: String::from(magic::from_u8(&content)),
+            filename: entry.filename.clone(),
+            size: entry.size,
+            created: entry.created,
+            tier: Tier::Hot,
+        })
+    }
+
+    /// Returns the metadata of an erasure coded blob. The mime type is inferred from the
+    /// beginning of the content, which is rebuilt if needed.
+    fn shards_metadata(&self, blob_ref: &BlobRef) -> Result<BlobMetadata> {
+        let shards = self.find_shard_files(blob_ref);
+        let first = shards
+            .first()
+            .ok_or_else(|| Error::BlobNotFound(blob_ref.clone()))?;
+        let created = fs::metadata(first)?.created()?.into();
+
+        let reader = ShardReader::open(&shards, blob_ref)?;
+        let filename = reader.filename().to_string();
+        let size = reader.size();
+        let mut head = vec![];
+        reader.take(8192).read_to_end(&mut head)?;
+        Ok(BlobMetadata {
+            mime_type: String::from(magic::from_u8(&head)),
+            filename,
+            size,
+            created,
+            tier: Tier::Hot,
         })
     }
 }
 
+/// Returns a filename that can be safely used inside a blob directory.
+pub(crate) fn sanitize_filename(filename: &str) -> String {
+    let filename: String = filename
+        .chars()
+        .map(|c| if c == '/' || c == '\\' { '_' } else { c })
+        .collect();
+    match filename.as_str() {
+        "" | "." | ".." => String::from("file"),
+        _ => filename,
+    }
+}
+
+/// The error returned when packing is combined with replicas or erasure coding.
+fn packing_conflict() -> Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "packed blobs are neither replicated nor erasure coded",
+    )
+    .into()
+}
+
+/// Computes the [`BlobRef`] of the given content.
+fn hash_bytes(content: &[u8]) -> BlobRef {
+    let mut hasher = BlobStore::hasher();
+    hasher.update(content);
+    BlobRef::from(hasher)
+}
+
+/// Combines the results of deleting a blob from two stores: it is deleted if it was in
+/// either of them.
+fn either_deleted(first: Result<()>, second: Result<()>) -> Result<()> {
+    match (first, second) {
+        (Err(Error::BlobNotFound(_)), second) => second,
+        (first, Err(Error::BlobNotFound(_))) => first,
+        (first, second) => first.and(second),
+    }
+}
+
+/// Computes the [`BlobRef`] of the file at the given path. It also returns the size of
+/// the file in bytes.
+pub(crate) fn hash_file(path: &Path) -> Result<(BlobRef, u64)> {
+    let mut file = open_source(path)?;
+    let mut hasher = BlobStore::hasher();
+
+    let size = io::copy(&mut file, &mut hasher)?;
+    Ok((BlobRef::from(hasher), size))
+}
+
+/// Opens a file to add to the blob store, reporting a missing file as
+/// [`Error::SourceNotFound`].
+pub(crate) fn open_source(path: &Path) -> Result<File> {
+    File::open(path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => Error::SourceNotFound(path.to_path_buf()),
+        _ => Error::from(e).with_path(path),
+    })
+}
+
 impl BlobMetadata {
     pub fn created_str(&self) -> String {
         self.created
@@ -0,0 +1,72 @@
+use super::error::Result;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How long the markers read from disk are trusted before they are read again, to pick up
+/// the blobs claimed by other processes since.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The markers of the blobs owned by the snapshots and the buckets, so that adding a blob
+/// already in the blob store only removes the markers which exist.
+///
+/// The markers are read from their directories the first time they are needed, and read
+/// again at most every [`RELOAD_INTERVAL`]. The markers created by this process are
+/// recorded as they are.
+#[derive(Debug, Default)]
+pub(crate) struct OwnedMarkers {
+    markers: HashSet<PathBuf>,
+    loaded: Option<Instant>,
+}
+
+impl OwnedMarkers {
+    /// Records a marker created by this process.
+    pub(crate) fn insert(&mut self, marker: PathBuf) {
+        self.markers.insert(marker);
+    }
+
+    /// Removes the markers among `markers` which exist. The markers are read from `dirs`
+    /// first if they were not read recently.
+    pub(crate) fn remove(&mut self, dirs: &[PathBuf], markers: &[PathBuf]) -> Result<()> {
+        if self
+            .loaded
+            .map_or(true, |loaded| loaded.elapsed() > RELOAD_INTERVAL)
+        {
+            self.load(dirs)?;
+        }
+        for marker in markers {
+            if self.markers.remove(marker) {
+                match fs::remove_file(marker) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, dirs: &[PathBuf]) -> Result<()> {
+        let mut markers = HashSet::new();
+        for dir in dirs {
+            markers.extend(read_markers(dir)?);
+        }
+        self.markers = markers;
+        self.loaded = Some(Instant::now());
+        Ok(())
+    }
+}
+
+fn read_markers(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut markers = vec![];
+    for entry in entries {
+        markers.push(entry?.path());
+    }
+    Ok(markers)
+}
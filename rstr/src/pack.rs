@@ -0,0 +1,366 @@
+use super::error::Result;
+use super::models::{BlobRef, BlobStore};
+use super::tree::{decode_path, encode_path};
+use chrono::{offset::Utc, DateTime};
+use fs2::FileExt;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the directory, inside the blob store root, holding the pack files.
+const PACKS_DIR: &str = "packs";
+
+/// Name of the directory, inside the packs directory, holding the index. The index is
+/// split in one file per first byte of the references, so that a lookup only reads a
+/// small part of it.
+const INDEX_DIR: &str = "index";
+
+/// Name of the file used to serialise updates to the packs.
+const LOCK_FILE: &str = ".lock";
+
+/// Extension of the pack files, which are named after their sequence number.
+const PACK_EXTENSION: &str = "pack";
+
+/// Size from which no more blobs are appended to a pack file and a new one is started.
+const MAX_PACK_SIZE: u64 = 256 * 1024 * 1024;
+
+/// What [`BlobStore::repack`] did.
+///
+/// [`BlobStore::repack`]: crate::BlobStore::repack
+#[derive(Debug, Default)]
+pub struct RepackReport {
+    /// The blobs which were stored in their own file and have been moved to a pack
+    pub packed: Vec<BlobRef>,
+    /// The number of bytes freed by dropping the content of deleted blobs from the packs
+    pub reclaimed: u64,
+}
+
+/// Where a blob is stored in a pack file.
+#[derive(Debug, Clone)]
+pub(crate) struct PackEntry {
+    pub blob_ref: BlobRef,
+    /// The sequence number of the pack file
+    pub pack: u64,
+    /// The position of the blob in the pack file
+    pub offset: u64,
+    /// The size of the blob in bytes
+    pub size: u64,
+    /// When the blob was added to the blob store
+    pub created: DateTime<Utc>,
+    /// The filename of the blob
+    pub filename: String,
+}
+
+impl PackEntry {
+    fn to_line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\n",
+            self.blob_ref.reference(),
+            self.pack,
+            self.offset,
+            self.size,
+            self.created
+                .to_rfc3339_opts(chrono::SecondsFormat::Nanos, true),
+            encode_path(Path::new(&self.filename))
+        )
+    }
+
+    fn from_line(line: &str) -> Option<PackEntry> {
+        let mut fields = line.split('\t');
+        let mut field = || fields.next();
+        Some(PackEntry {
+            blob_ref: BlobRef::new(field()?).ok()?,
+            pack: field()?.parse().ok()?,
+            offset: field()?.parse().ok()?,
+            size: field()?.parse().ok()?,
+            created: DateTime::parse_from_rfc3339(field()?).ok()?.into(),
+            filename: decode_path(field()?)?.to_string_lossy().into_owned(),
+        })
+    }
+}
+
+fn packs_dir(root: &Path) -> PathBuf {
+    root.join(PACKS_DIR)
+}
+
+fn pack_path(root: &Path, pack: u64) -> PathBuf {
+    packs_dir(root).join(format!("{pack:08}.{PACK_EXTENSION}"))
+}
+
+fn index_path(root: &Path, reference: &str) -> PathBuf {
+    packs_dir(root).join(INDEX_DIR).join(&reference[..2])
+}
+
+/// Takes the lock serialising all the updates to the packs. It is released when the
+/// returned file is dropped.
+fn lock(root: &Path) -> Result<File> {
+    let dir = packs_dir(root);
+    fs::create_dir_all(&dir)?;
+    let lock = File::create(dir.join(LOCK_FILE))?;
+    lock.lock_exclusive()?;
+    Ok(lock)
+}
+
+/// Returns the sequence numbers of the pack files, in increasing order.
+fn packs(root: &Path) -> Result<Vec<u64>> {
+    let entries = match fs::read_dir(packs_dir(root)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut packs = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == PACK_EXTENSION) {
+            if let Some(pack) = path.file_stem().and_then(|s| s.to_str()?.parse().ok()) {
+                packs.push(pack);
+            }
+        }
+    }
+    packs.sort_unstable();
+    Ok(packs)
+}
+
+/// Reads an index file, skipping the lines which cannot be parsed.
+fn read_index(path: &Path) -> Result<Vec<PackEntry>> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(content.lines().filter_map(PackEntry::from_line).collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Replaces the content of an index file atomically, removing it if `entries` is empty.
+fn write_index(path: &Path, entries: &[&PackEntry]) -> Result<()> {
+    if entries.is_empty() {
+        return match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        };
+    }
+    let content: String = entries.iter().map(|entry| entry.to_line()).collect();
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    fs::create_dir_all(path.parent().unwrap_or(path))?;
+    fs::write(&tmp_path, content)?;
+    fs::rename(tmp_path, path)?;
+    Ok(())
+}
+
+/// Returns where `blob_ref` is stored, if it is in a pack.
+pub(crate) fn find(root: &Path, blob_ref: &BlobRef) -> Result<Option<PackEntry>> {
+    Ok(read_index(&index_path(root, blob_ref.reference()))?
+        .into_iter()
+        .find(|entry| entry.blob_ref.reference() == blob_ref.reference()))
+}
+
+/// Returns the packed blobs whose reference starts with `prefix`, which must be at least
+/// two characters long.
+pub(crate) fn find_prefix(root: &Path, prefix: &str) -> Result<Vec<BlobRef>> {
+    Ok(read_index(&index_path(root, prefix))?
+        .into_iter()
+        .filter(|entry| entry.blob_ref.reference().starts_with(prefix))
+        .map(|entry| entry.blob_ref)
+        .collect())
+}
+
+/// Returns all the packed blobs.
+pub(crate) fn list(root: &Path) -> Result<Vec<PackEntry>> {
+    let entries = match fs::read_dir(packs_dir(root).join(INDEX_DIR)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut packed = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_none() {
+            packed.extend(read_index(&path)?);
+        }
+    }
+    Ok(packed)
+}
+
+/// Returns `true` if the content of a packed blob hashes to its reference.
+pub(crate) fn verify(root: &Path, entry: &PackEntry) -> bool {
+    let mut hasher = BlobStore::hasher();
+    open(root, entry).is_ok_and(|mut reader| io::copy(&mut reader, &mut hasher).is_ok())
+        && BlobRef::from(hasher).reference() == entry.blob_ref.reference()
+}
+
+/// Returns a reader over the content of a packed blob.
+pub(crate) fn open(root: &Path, entry: &PackEntry) -> Result<io::Take<File>> {
+    let mut file = File::open(pack_path(root, entry.pack))?;
+    file.seek(SeekFrom::Start(entry.offset))?;
+    Ok(file.take(entry.size))
+}
+
+/// Appends blobs to the pack files, starting a new pack file when the current one is
+/// full.
+struct PackWriter<'a> {
+    root: &'a Path,
+    pack: u64,
+    file: File,
+    offset: u64,
+}
+
+impl<'a> PackWriter<'a> {
+    /// Opens the pack file `pack` for appending.
+    fn open(root: &'a Path, pack: u64) -> Result<PackWriter<'a>> {
+        fs::create_dir_all(packs_dir(root))?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(pack_path(root, pack))?;
+        let offset = file.metadata()?.len();
+        Ok(PackWriter {
+            root,
+            pack,
+            file,
+            offset,
+        })
+    }
+
+    /// Appends the content read from `reader` and returns its entry.
+    fn append<R: Read>(
+        &mut self,
+        blob_ref: &BlobRef,
+        mut reader: R,
+        created: DateTime<Utc>,
+        filename: &str,
+    ) -> Result<PackEntry> {
+        if self.offset >= MAX_PACK_SIZE {
+            *self = PackWriter::open(self.root, self.pack + 1)?;
+        }
+        let size = io::copy(&mut reader, &mut self.file)?;
+        let entry = PackEntry {
+            blob_ref: blob_ref.clone(),
+            pack: self.pack,
+            offset: self.offset,
+            size,
+            created,
+            filename: filename.to_string(),
+        };
+        self.offset += size;
+        Ok(entry)
+    }
+}
+
+/// Appends the content read from `reader` to the current pack file, under `blob_ref`.
+/// Nothing is written if the blob is already packed.
+pub(crate) fn insert<R: Read>(
+    root: &Path,
+    blob_ref: &BlobRef,
+    reader: R,
+    filename: &str,
+) -> Result<()> {
+    let _lock = lock(root)?;
+    if find(root, blob_ref)?.is_some() {
+        return Ok(());
+    }
+    let pack = packs(root)?.last().copied().unwrap_or(1);
+    let entry = PackWriter::open(root, pack)?.append(blob_ref, reader, Utc::now(), filename)?;
+
+    let index = index_path(root, blob_ref.reference());
+    fs::create_dir_all(index.parent().unwrap_or(&index))?;
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(index)?
+        .write_all(entry.to_line().as_bytes())?;
+    Ok(())
+}
+
+/// Removes a blob from the index. Its content stays in the pack file until it is
+/// repacked.
+///
+/// Returns `false` if the blob was not packed.
+pub(crate) fn remove(root: &Path, blob_ref: &BlobRef) -> Result<bool> {
+    let path = index_path(root, blob_ref.reference());
+    if !path.exists() {
+        return Ok(false);
+    }
+    let _lock = lock(root)?;
+    let entries = read_index(&path)?;
+    let kept: Vec<&PackEntry> = entries
+        .iter()
+        .filter(|entry| entry.blob_ref.reference() != blob_ref.reference())
+        .collect();
+    if kept.len() == entries.len() {
+        return Ok(false);
+    }
+    write_index(&path, &kept)?;
+    Ok(true)
+}
+
+/// Rewrites the pack files holding the content of deleted blobs, and appends the `loose`
+/// blobs, given with the file holding their content, to a new pack file.
+pub(crate) fn repack(root: &Path, loose: &[(BlobRef, PathBuf)]) -> Result<RepackReport> {
+    let _lock = lock(root)?;
+    let entries = list(root)?;
+    let packs = packs(root)?;
+
+    let mut live: BTreeMap<u64, u64> = BTreeMap::new();
+    for entry in &entries {
+        *live.entry(entry.pack).or_default() += entry.size;
+    }
+    let mut report = RepackReport::default();
+    let mut rewritten = HashSet::new();
+    for pack in &packs {
+        let size = fs::metadata(pack_path(root, *pack))?.len();
+        let live = live.get(pack).copied().unwrap_or(0);
+        if size > live {
+            report.reclaimed += size - live;
+            rewritten.insert(*pack);
+        }
+    }
+
+    // A blob can have loose copies in several roots, only one of them is packed
+    let mut packed: HashSet<&str> = entries
+        .iter()
+        .map(|entry| entry.blob_ref.reference())
+        .collect();
+    let loose: Vec<&(BlobRef, PathBuf)> = loose
+        .iter()
+        .filter(|(blob_ref, _)| packed.insert(blob_ref.reference()))
+        .collect();
+    if rewritten.is_empty() && loose.is_empty() {
+        return Ok(report);
+    }
+
+    let mut writer = PackWriter::open(root, packs.last().map_or(1, |pack| pack + 1))?;
+    let mut index: BTreeMap<String, Vec<PackEntry>> = BTreeMap::new();
+    for entry in entries {
+        let entry = if rewritten.contains(&entry.pack) {
+            let reader = open(root, &entry)?;
+            writer.append(&entry.blob_ref, reader, entry.created, &entry.filename)?
+        } else {
+            entry
+        };
+        let shard = entry.blob_ref.reference()[..2].to_string();
+        index.entry(shard).or_default().push(entry);
+    }
+    for (blob_ref, path) in loose {
+        let created = fs::metadata(path)?
+            .created()
+            .map_or_else(|_| Utc::now(), DateTime::from);
+        let filename = path.file_name().unwrap_or_default().to_string_lossy();
+        let entry = writer.append(blob_ref, File::open(path)?, created, &filename)?;
+        let shard = blob_ref.reference()[..2].to_string();
+        index.entry(shard).or_default().push(entry);
+        report.packed.push(blob_ref.clone());
+    }
+    writer.file.sync_all()?;
+
+    // The new index only refers to the new pack files, the old ones can go
+    for (shard, entries) in &index {
+        let entries: Vec<&PackEntry> = entries.iter().collect();
+        write_index(&index_path(root, shard), &entries)?;
+    }
+    for pack in rewritten {
+        fs::remove_file(pack_path(root, pack))?;
+    }
+    Ok(report)
+}
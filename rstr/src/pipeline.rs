@@ -0,0 +1,308 @@
+use super::error::{Error, Result};
+use super::models::{hash_file, BlobRef, BlobStore};
+#[cfg(feature = "progress_bar")]
+use super::utils::progress_bar;
+use super::utils::send_file_paths;
+use crossbeam_channel::bounded;
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+
+/// Options controlling how [`BlobStore::add_files_with_options`] spreads the work
+/// across threads.
+///
+/// Adding files is split in three stages connected by bounded queues:
+/// - a *walk* stage listing the files to add,
+/// - a *hash* stage computing the [`BlobRef`] of each file,
+/// - a *write* stage copying the new blobs into the blob store.
+///
+/// Workers of each stage pull the next item from a shared queue as soon as they are
+/// idle, so a few large files never keep the other workers waiting. The size of the
+/// queues bounds the memory used regardless of the number of files being added.
+#[derive(Clone, Debug)]
+pub struct AddOptions {
+    /// Number of threads hashing the content of the files (CPU bound).
+    pub hash_threads: usize,
+    /// Number of threads copying new blobs into the blob store (disk bound).
+    pub write_threads: usize,
+    /// Maximum number of items waiting in each of the queues between the stages.
+    pub queue_capacity: usize,
+}
+
+impl Default for AddOptions {
+    /// Uses one hashing thread per available CPU and 4 writing threads.
+    fn default() -> Self {
+        AddOptions {
+            hash_threads: thread::available_parallelism().map_or(4, NonZeroUsize::get),
+            write_threads: 4,
+            queue_capacity: 1024,
+        }
+    }
+}
+
+impl AddOptions {
+    /// Returns the default options but with the given number of hashing threads.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::AddOptions;
+    ///
+    /// let options = AddOptions::with_hash_threads(8);
+    /// assert_eq!(options.hash_threads, 8);
+    /// assert_eq!(options.write_threads, AddOptions::default().write_threads);
+    /// ```
+    pub fn with_hash_threads(hash_threads: usize) -> Self {
+        AddOptions {
+            hash_threads,
+            ..AddOptions::default()
+        }
+    }
+}
+
+/// A file of the input of [`BlobStore::plan_add`] together with its [`BlobRef`].
+#[derive(Debug, Clone)]
+pub struct PlannedFile {
+    /// Path to the file
+    pub path: PathBuf,
+    /// Reference the file would be stored under
+    pub blob_ref: BlobRef,
+    /// Size of the file in bytes
+    pub size: u64,
+}
+
+/// Report of what adding a list of files/directories to the blob store would do, as
+/// returned by [`BlobStore::plan_add`].
+///
+/// Each file of the input ends up in exactly one of `new`, `existing`, `duplicates` or
+/// `errors`. All lists are sorted by path.
+#[derive(Debug, Default)]
+pub struct AddPlan {
+    /// Files whose content is not in the blob store yet (one per distinct content).
+    pub new: Vec<PlannedFile>,
+    /// Files whose content is already in the blob store (one per distinct content).
+    pub existing: Vec<PlannedFile>,
+    /// Files whose content is the same as another file of the input listed in `new` or
+    /// `existing`.
+    pub duplicates: Vec<PlannedFile>,
+    /// Files that could not be read, together with the error.
+    pub errors: Vec<(PathBuf, Error)>,
+}
+
+impl AddPlan {
+    /// Total number of bytes the new blobs would take in the blob store.
+    pub fn new_bytes(&self) -> u64 {
+        self.new.iter().map(|f| f.size).sum()
+    }
+
+    /// Total number of bytes of the input that would not be stored again, either because
+    /// the content is already in the blob store or because it is duplicated in the input.
+    pub fn deduplicated_bytes(&self) -> u64 {
+        self.existing
+            .iter()
+            .chain(self.duplicates.iter())
+            .map(|f| f.size)
+            .sum()
+    }
+}
+
+/// Runs the walk, hash and write stages over `paths` and returns the outcome for each
+/// file found, in the order in which they completed, together with whether its content
+/// was written by this run rather than already in the blob store.
+pub(crate) fn add_files<P: AsRef<Path> + Sync>(
+    blob_store: &BlobStore,
+    paths: &[P],
+    options: &AddOptions,
+) -> Vec<(PathBuf, Result<(BlobRef, bool)>)> {
+    run(Some(blob_store), paths, options)
+        .into_iter()
+        .map(|(path, result)| {
+            (
+                path,
+                result.map(|(blob_ref, _, written)| (blob_ref, written)),
+            )
+        })
+        .collect()
+}
+
+/// Runs only the walk and hash stages over `paths` and sorts the files according to
+/// whether their content is new, already stored or duplicated in the input.
+pub(crate) fn plan_add<P: AsRef<Path> + Sync>(
+    blob_store: &BlobStore,
+    paths: &[P],
+    options: &AddOptions,
+) -> AddPlan {
+    let mut plan = AddPlan::default();
+    let mut seen = HashSet::new();
+    for (path, result) in hash_files(paths, options) {
+        let (blob_ref, size) = match result {
+            Ok(hashed) => hashed,
+            Err(e) => {
+                plan.errors.push((path, e));
+                continue;
+            }
+        };
+        let is_first = seen.insert(blob_ref.reference().to_string());
+        let file = PlannedFile {
+            path,
+            blob_ref,
+            size,
+        };
+        if !is_first {
+            plan.duplicates.push(file);
+        } else if blob_store.exists(&file.blob_ref) {
+            plan.existing.push(file);
+        } else {
+            plan.new.push(file);
+        }
+    }
+    plan
+}
+
+/// Runs only the walk and hash stages over `paths`, and returns the [`BlobRef`] and the
+/// size of each file found, sorted by path.
+pub(crate) fn hash_files<P: AsRef<Path> + Sync>(
+    paths: &[P],
+    options: &AddOptions,
+) -> Vec<(PathBuf, Result<(BlobRef, u64)>)> {
+    let mut hashed: Vec<_> = run(None, paths, options)
+        .into_iter()
+        .map(|(path, result)| (path, result.map(|(blob_ref, size, _)| (blob_ref, size))))
+        .collect();
+    hashed.sort_by(|(a, _), (b, _)| a.cmp(b));
+    hashed
+}
+
+/// The [`BlobRef`] and the size of a file, and whether its content was written by the
+/// pipeline.
+type Hashed = (BlobRef, u64, bool);
+
+/// Runs the pipeline over `paths`, writing the new blobs to `blob_store` if given, or
+/// skipping the write stage otherwise.
+///
+/// It returns the [`BlobRef`] and the size of each file found, and whether its content
+/// was written by the write stage, in the order in which they completed.
+fn run<P: AsRef<Path> + Sync>(
+    blob_store: Option<&BlobStore>,
+    paths: &[P],
+    options: &AddOptions,
+) -> Vec<(PathBuf, Result<Hashed>)> {
+    let capacity = options.queue_capacity.max(1);
+    let (path_tx, path_rx) = bounded::<PathBuf>(capacity);
+    let (write_tx, write_rx) = bounded::<(PathBuf, BlobRef, u64)>(capacity);
+    let (result_tx, result_rx) = bounded::<(PathBuf, Result<Hashed>)>(capacity);
+
+    // Refs handed over to the write stage during this run, with the identical files found
+    // while they are written. Used so that identical files found in the input are only
+    // written once, and only reported once the write is done.
+    let claimed = &Mutex::new(HashMap::<String, Vec<PathBuf>>::new());
+
+    #[cfg(feature = "progress_bar")]
+    let pb = &progress_bar(0);
+
+    thread::scope(|s| {
+        s.spawn(move || {
+            for path in paths {
+                send_file_paths(path.as_ref(), &path_tx);
+            }
+        });
+
+        for _ in 0..options.hash_threads.max(1) {
+            let path_rx = path_rx.clone();
+            let write_tx = write_tx.clone();
+            let result_tx = result_tx.clone();
+            s.spawn(move || {
+                for path in path_rx {
+                    #[cfg(feature = "progress_bar")]
+                    pb.inc_length(1);
+
+                    match (hash_file(&path), blob_store) {
+                        (Ok((blob_ref, size)), Some(blob_store)) => {
+                            let mut claimed = claimed.lock().unwrap();
+                            if blob_store.exists(&blob_ref) {
+                                drop(claimed);
+                                result_tx
+                                    .send((path, Ok((blob_ref, size, false))))
+                                    .expect("err");
+                            } else if let Some(waiting) = claimed.get_mut(blob_ref.reference()) {
+                                // Another file with the same content is being written
+                                waiting.push(path);
+                            } else {
+                                claimed.insert(blob_ref.reference().to_string(), vec![]);
+                                drop(claimed);
+                                write_tx.send((path, blob_ref, size)).expect("err");
+                            }
+                        }
+                        (result, _) => {
+                            let result = result.map(|(blob_ref, size)| (blob_ref, size, false));
+                            result_tx.send((path, result)).expect("err");
+                        }
+                    }
+                }
+            });
+        }
+        drop(path_rx);
+        drop(write_tx);
+
+        if let Some(blob_store) = blob_store {
+            for _ in 0..options.write_threads.max(1) {
+                let write_rx = write_rx.clone();
+                let result_tx = result_tx.clone();
+                s.spawn(move || {
+                    for (path, blob_ref, size) in write_rx {
+                        let result = blob_store.write_file(&path, &blob_ref);
+                        let mut stored = result.is_ok();
+                        let outcome = result.map(|()| (blob_ref.clone(), size, true));
+                        result_tx.send((path, outcome)).expect("err");
+                        // The identical files found while writing share its outcome. If it
+                        // failed, each of them is written in turn instead.
+                        while let Some(waiting) = take_waiting(claimed, &blob_ref) {
+                            for duplicate in waiting {
+                                let result = if stored {
+                                    Ok(())
+                                } else {
+                                    blob_store.write_file(&duplicate, &blob_ref)
+                                };
+                                stored = result.is_ok();
+                                let outcome = result.map(|()| (blob_ref.clone(), size, true));
+                                result_tx.send((duplicate, outcome)).expect("err");
+                            }
+                        }
+                    }
+                });
+            }
+        }
+        drop(write_rx);
+        drop(result_tx);
+
+        let results = result_rx.iter();
+
+        #[cfg(feature = "progress_bar")]
+        let results = results.inspect(|_| pb.inc(1));
+
+        let results = results.collect();
+
+        #[cfg(feature = "progress_bar")]
+        pb.finish();
+
+        results
+    })
+}
+
+/// Takes the files waiting for the write of `blob_ref`, or releases its claim if there are
+/// none left.
+fn take_waiting(
+    claimed: &Mutex<HashMap<String, Vec<PathBuf>>>,
+    blob_ref: &BlobRef,
+) -> Option<Vec<PathBuf>> {
+    let mut claimed = claimed.lock().unwrap();
+    match claimed.get_mut(blob_ref.reference()) {
+        Some(waiting) if !waiting.is_empty() => Some(std::mem::take(waiting)),
+        _ => {
+            claimed.remove(blob_ref.reference());
+            None
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use super::error::Result;
+use super::models::BlobRef;
+use chrono::{offset::Utc, DateTime};
+use fs2::FileExt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Name of the file, inside the blob store root, listing the blobs found corrupted when
+/// they were read. It has one `reference<TAB>detected` line per event.
+const QUARANTINE_FILE: &str = "quarantine";
+
+/// A blob found corrupted when it was read, see [`BlobStore::quarantine`].
+///
+/// [`BlobStore::quarantine`]: crate::BlobStore::quarantine
+#[derive(Debug, Clone)]
+pub struct QuarantineEntry {
+    /// The corrupted blob
+    pub blob_ref: BlobRef,
+    /// When the corruption was detected
+    pub detected: DateTime<Utc>,
+}
+
+/// Appends the blob to the quarantine list of the blob store at `root`.
+pub(crate) fn record(root: &Path, blob_ref: &BlobRef) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(root.join(QUARANTINE_FILE))?;
+    file.lock_exclusive()?;
+    let line = format!(
+        "{}\t{}\n",
+        blob_ref.reference(),
+        Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+    );
+    let result = file.write_all(line.as_bytes());
+    FileExt::unlock(&file)?;
+    Ok(result?)
+}
+
+/// Reads the quarantine list of the blob store at `root`, oldest first, skipping the lines
+/// which cannot be parsed.
+pub(crate) fn read(root: &Path) -> Result<Vec<QuarantineEntry>> {
+    let content = match fs::read_to_string(root.join(QUARANTINE_FILE)) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(content
+        .lines()
+        .filter_map(|line| {
+            let (reference, detected) = line.split_once('\t')?;
+            Some(QuarantineEntry {
+                blob_ref: BlobRef::new(reference).ok()?,
+                detected: DateTime::parse_from_rfc3339(detected)
+                    .ok()?
+                    .with_timezone(&Utc),
+            })
+        })
+        .collect())
+}
@@ -0,0 +1,219 @@
+use super::erasure::SHARD_FILENAME;
+use super::error::{Error, Result};
+use super::layout::ShardLayout;
+use super::models::BlobRef;
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Strategy used to choose which root of a [`BlobStore`] stores a new blob.
+///
+/// Blobs are looked up in all the roots regardless of the placement, so changing it
+/// never makes blobs unreachable. [`BlobStore::rebalance`] moves existing blobs to the
+/// root the placement chooses for them.
+///
+/// [`BlobStore`]: crate::BlobStore
+/// [`BlobStore::rebalance`]: crate::BlobStore::rebalance
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Placement {
+    /// The range of hashes is split in equal parts, one per root. The same blob always
+    /// goes to the same root, which makes rebalancing deterministic.
+    #[default]
+    HashRange,
+    /// New blobs go to the root with the most available space.
+    FreeSpace,
+}
+
+/// Parses `hash-range` or `free-space`.
+///
+/// # Examples
+///
+/// ```
+/// use rstr::Placement;
+///
+/// assert_eq!("free-space".parse::<Placement>().unwrap(), Placement::FreeSpace);
+/// assert!("random".parse::<Placement>().is_err());
+/// ```
+impl FromStr for Placement {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Placement> {
+        match s {
+            "hash-range" => Ok(Placement::HashRange),
+            "free-space" => Ok(Placement::FreeSpace),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "unknown placement").into()),
+        }
+    }
+}
+
+impl Placement {
+    /// Chooses `n` distinct roots, among `roots`, in which to store copies of `blob_ref`,
+    /// in order of preference.
+    ///
+    /// With [`Placement::HashRange`] the copies go to the roots following the chosen
+    /// one, wrapping around, so that the layout stays deterministic.
+    pub(crate) fn choose_n<'a>(
+        self,
+        roots: &[&'a Path],
+        blob_ref: &BlobRef,
+        n: usize,
+    ) -> Vec<&'a Path> {
+        let n = n.clamp(1, roots.len());
+        match self {
+            Placement::HashRange => {
+                let bucket = u64::from_str_radix(&blob_ref.reference()[..4], 16).unwrap_or(0);
+                let index = (bucket * roots.len() as u64) >> 16;
+                let index = usize::try_from(index).unwrap_or(0);
+                roots.iter().cycle().skip(index).take(n).copied().collect()
+            }
+            Placement::FreeSpace => {
+                let mut roots = roots.to_vec();
+                // The sort is stable, so ties go to the first root
+                roots
+                    .sort_by_key(|root| std::cmp::Reverse(fs2::available_space(root).unwrap_or(0)));
+                roots.truncate(n);
+                roots
+            }
+        }
+    }
+}
+
+/// Returns `true` if `name` is the name of a shard directory, i.e. `width` hex
+/// characters.
+fn is_shard(name: &str, width: usize) -> bool {
+    name.len() == width && name.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Returns `true` if a reference starting with `start` can start with `prefix`.
+fn may_match(start: &str, prefix: &str) -> bool {
+    start.starts_with(prefix) || prefix.starts_with(start)
+}
+
+/// Returns the names of the entries of `dir`, which may not exist.
+fn entry_names(dir: &Path) -> Result<Vec<String>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut names = vec![];
+    for entry in entries {
+        names.push(entry?.file_name().to_string_lossy().into_owned());
+    }
+    Ok(names)
+}
+
+/// Collects the blobs starting with `prefix` stored under the shard directory `dir`,
+/// whose name, together with the names of its parents, is `start`.
+fn collect_blobs(
+    dir: &Path,
+    layout: ShardLayout,
+    level: usize,
+    start: &str,
+    prefix: &str,
+    blob_refs: &mut Vec<BlobRef>,
+) -> Result<()> {
+    for name in entry_names(dir)? {
+        let start = format!("{start}{name}");
+        if !may_match(&start, prefix) {
+            continue;
+        }
+        if level < layout.depth() {
+            if is_shard(&name, layout.width()) {
+                collect_blobs(
+                    &dir.join(name),
+                    layout,
+                    level + 1,
+                    &start,
+                    prefix,
+                    blob_refs,
+                )?;
+            }
+        } else if let Ok(blob_ref) = BlobRef::new(&start) {
+            if has_blob(&dir.join(name)) {
+                blob_refs.push(blob_ref);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns all the blobs stored under `root`, skipping empty blob directories and
+/// anything which is not part of the blob layout (snapshots, aliases...).
+pub(crate) fn list_blobs(root: &Path, layout: ShardLayout) -> Result<Vec<BlobRef>> {
+    find_blobs(root, layout, "")
+}
+
+/// Returns the blobs stored under `root` whose reference starts with `prefix`. Only the
+/// shard directories matching the prefix are read.
+pub(crate) fn find_blobs(root: &Path, layout: ShardLayout, prefix: &str) -> Result<Vec<BlobRef>> {
+    let mut blob_refs = vec![];
+    collect_blobs(root, layout, 0, "", prefix, &mut blob_refs)?;
+    Ok(blob_refs)
+}
+
+/// Returns `true` if the blob directory `dir` exists and holds a file.
+pub(crate) fn has_blob(dir: &Path) -> bool {
+    dir.read_dir()
+        .is_ok_and(|mut entries| entries.next().is_some())
+}
+
+/// Returns the file in the blob directory `dir` holding the whole content of the blob,
+/// if any, as opposed to an erasure coded shard of it.
+pub(crate) fn blob_file(dir: &Path) -> Option<PathBuf> {
+    dir.read_dir()
+        .ok()?
+        .filter_map(std::result::Result::ok)
+        .find(|entry| entry.file_name() != SHARD_FILENAME)
+        .map(|entry| entry.path())
+}
+
+/// Moves the blob directory `src` to `dest`, which can be on a different file system.
+///
+/// When the directory cannot simply be renamed, it is copied with [`copy_blob`] and then
+/// removed.
+pub(crate) fn move_blob(src: &Path, dest: &Path) -> Result<()> {
+    let parent = dest.parent().unwrap_or(dest);
+    fs::create_dir_all(parent)?;
+    if fs::rename(src, dest).is_ok() {
+        return Ok(());
+    }
+    copy_blob(src, dest)?;
+    fs::remove_dir_all(src)?;
+    Ok(())
+}
+
+/// Copies the blob directory `src` to `dest`, which can be on a different file system.
+///
+/// The content is copied to a temporary directory next to `dest` which is then renamed,
+/// so that `dest` is never partially written.
+pub(crate) fn copy_blob(src: &Path, dest: &Path) -> Result<()> {
+    let parent = dest.parent().unwrap_or(dest);
+    fs::create_dir_all(parent)?;
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(dest.file_name().unwrap_or_default());
+    tmp_name.push(".tmp");
+    let tmp_dir: PathBuf = parent.join(tmp_name);
+    fs::create_dir_all(&tmp_dir)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        fs::copy(entry.path(), tmp_dir.join(entry.file_name()))?;
+    }
+    fs::rename(&tmp_dir, dest)?;
+    Ok(())
+}
+
+/// Removes the shard directories of `blob_ref` under `root` that became empty, e.g.
+/// after the blob was moved away.
+pub(crate) fn prune_shards(root: &Path, layout: ShardLayout, blob_ref: &BlobRef) {
+    let mut dir = root.join(layout.blob_path(blob_ref));
+    for _ in 0..layout.depth() {
+        dir.pop();
+        // Fails, as expected, as soon as a directory is not empty
+        if fs::remove_dir(&dir).is_err() {
+            break;
+        }
+    }
+}
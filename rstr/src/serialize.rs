@@ -0,0 +1,71 @@
+use super::models::BlobRef;
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde::ser::{Serialize, Serializer};
+use std::convert::TryFrom;
+use std::fmt;
+
+/// Serializes a reference as its hex representation in human readable formats, and as
+/// its 32 bytes otherwise.
+///
+/// # Examples
+///
+/// ```
+/// # use rstr::BlobRef;
+/// let hash = "f29bc64a9d3732b4b9035125fdb3285f5b6455778edca72414671e0ca3b2e0de";
+/// let blob_ref = BlobRef::new(hash).unwrap();
+///
+/// let json = serde_json::to_string(&blob_ref).unwrap();
+/// assert_eq!(json, format!("\"{hash}\""));
+/// assert_eq!(serde_json::from_str::<BlobRef>(&json).unwrap(), blob_ref);
+/// assert!(serde_json::from_str::<BlobRef>("\"f29bc64a9d\"").is_err());
+/// ```
+impl Serialize for BlobRef {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(self.reference())
+        } else {
+            serializer.serialize_bytes(&self.to_bytes())
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for BlobRef {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<BlobRef, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(BlobRefVisitor)
+        } else {
+            deserializer.deserialize_bytes(BlobRefVisitor)
+        }
+    }
+}
+
+/// Parses a reference from either of its serialized forms.
+struct BlobRefVisitor;
+
+impl<'de> Visitor<'de> for BlobRefVisitor {
+    type Value = BlobRef;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a sha256 hash, as 64 hexadecimal characters or 32 bytes")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<BlobRef, E> {
+        BlobRef::new(value).map_err(E::custom)
+    }
+
+    fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<BlobRef, E> {
+        let bytes =
+            <&[u8; 32]>::try_from(value).map_err(|_| E::invalid_length(value.len(), &self))?;
+        Ok(BlobRef::from_bytes(bytes))
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<BlobRef, A::Error> {
+        let mut bytes = [0; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = seq
+                .next_element()?
+                .ok_or_else(|| de::Error::invalid_length(i, &self))?;
+        }
+        Ok(BlobRef::from_bytes(&bytes))
+    }
+}
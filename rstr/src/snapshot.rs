@@ -0,0 +1,366 @@
+use super::alias;
+use super::bucket;
+use super::error::{Error, Result};
+use super::models::{BlobRef, BlobStore};
+use super::tree::{self, decode_path, encode_path, Tree};
+use chrono::{offset::Utc, DateTime, Datelike, Timelike};
+use sha2::Digest;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// First line of every snapshot record, used to recognise them and their version.
+const SNAPSHOT_HEADER: &str = "rstr-snapshot 1";
+
+/// Name of the directory, inside the blob store root, holding the snapshot records.
+const SNAPSHOTS_DIR: &str = "snapshots";
+
+/// Name of the directory, inside the snapshots directory, with an empty file for each blob
+/// written by a snapshot. Only those blobs are deleted by [`gc`].
+const OWNED_DIR: &str = ".owned";
+
+/// Struct representing a timestamped snapshot of a directory.
+///
+/// The content of the directory is stored as a [`Tree`](crate::Tree); the snapshot
+/// records when it was taken and under which name. See [`BlobStore::create_snapshot`].
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    /// Unique identifier of the snapshot (64 hex characters)
+    pub id: String,
+    /// The label given to the snapshot. Snapshots of the same directory usually share it.
+    pub name: String,
+    /// When the snapshot was taken
+    pub created: DateTime<Utc>,
+    /// The path of the directory the snapshot was taken from
+    pub source: PathBuf,
+    /// The reference to the tree manifest holding the content of the directory
+    pub tree: BlobRef,
+}
+
+impl Snapshot {
+    /// Returns the first 10 characters of the id, which is how snapshots are displayed.
+    pub fn short_id(&self) -> &str {
+        &self.id[..10]
+    }
+
+    fn to_record(&self) -> String {
+        format!(
+            "{}\nname\t{}\ncreated\t{}\nsource\t{}\ntree\t{}\n",
+            SNAPSHOT_HEADER,
+            self.name,
+            self.created
+                .to_rfc3339_opts(chrono::SecondsFormat::Nanos, true),
+            encode_path(&self.source),
+            self.tree.reference()
+        )
+    }
+
+    fn from_record(id: &str, record: &str) -> Option<Snapshot> {
+        let mut lines = record.lines();
+        if lines.next() != Some(SNAPSHOT_HEADER) {
+            return None;
+        }
+        let mut field = |name: &str| {
+            let (key, value) = lines.next()?.split_once('\t')?;
+            if key == name {
+                Some(value.to_string())
+            } else {
+                None
+            }
+        };
+        let name = field("name")?;
+        let created = DateTime::parse_from_rfc3339(&field("created")?).ok()?;
+        let source = decode_path(&field("source")?)?;
+        let tree = BlobRef::new(&field("tree")?).ok()?;
+        Some(Snapshot {
+            id: id.to_string(),
+            name,
+            created: created.into(),
+            source,
+            tree,
+        })
+    }
+}
+
+/// Maps a timestamp to the period (hour, day, week...) it belongs to.
+type Period = fn(&DateTime<Utc>) -> (i32, u32, u32);
+
+/// Policy deciding which snapshots to keep when calling [`BlobStore::forget_snapshots`].
+///
+/// The policy is applied separately to each group of snapshots with the same name. For
+/// each rule, the newest snapshot of each of the last `n` periods (days, weeks...) that
+/// have at least one snapshot is kept. A snapshot is kept if any of the rules keeps it.
+///
+/// A policy without any rule keeps all snapshots.
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPolicy {
+    /// Keep the `n` most recent snapshots
+    pub keep_last: usize,
+    /// Keep the most recent snapshot of each of the last `n` hours
+    pub keep_hourly: usize,
+    /// Keep the most recent snapshot of each of the last `n` days
+    pub keep_daily: usize,
+    /// Keep the most recent snapshot of each of the last `n` (ISO) weeks
+    pub keep_weekly: usize,
+    /// Keep the most recent snapshot of each of the last `n` months
+    pub keep_monthly: usize,
+    /// Keep the most recent snapshot of each of the last `n` years
+    pub keep_yearly: usize,
+}
+
+impl RetentionPolicy {
+    /// Returns `true` if the policy has no rules, i.e. it keeps everything.
+    pub fn is_empty(&self) -> bool {
+        self.keep_last == 0
+            && self.keep_hourly == 0
+            && self.keep_daily == 0
+            && self.keep_weekly == 0
+            && self.keep_monthly == 0
+            && self.keep_yearly == 0
+    }
+
+    /// Given snapshots sorted from newest to oldest, returns which of them to keep.
+    fn keep(&self, snapshots: &[&Snapshot]) -> Vec<bool> {
+        let mut keep = vec![self.is_empty(); snapshots.len()];
+
+        for k in keep.iter_mut().take(self.keep_last) {
+            *k = true;
+        }
+
+        let rules: [(usize, Period); 5] = [
+            (self.keep_hourly, |t| (t.year(), t.ordinal(), t.hour())),
+            (self.keep_daily, |t| (t.year(), t.ordinal(), 0)),
+            (self.keep_weekly, |t| {
+                (t.iso_week().year(), t.iso_week().week(), 0)
+            }),
+            (self.keep_monthly, |t| (t.year(), t.month(), 0)),
+            (self.keep_yearly, |t| (t.year(), 0, 0)),
+        ];
+        for (n, period) in rules {
+            let mut periods = HashSet::new();
+            for (i, snapshot) in snapshots.iter().enumerate() {
+                if periods.len() == n {
+                    break;
+                }
+                if periods.insert(period(&snapshot.created)) {
+                    keep[i] = true;
+                }
+            }
+        }
+        keep
+    }
+}
+
+fn snapshots_dir(root: &Path) -> PathBuf {
+    root.join(SNAPSHOTS_DIR)
+}
+
+pub(crate) fn owned_dir(root: &Path) -> PathBuf {
+    snapshots_dir(root).join(OWNED_DIR)
+}
+
+pub(crate) fn owned_marker(root: &Path, blob_ref: &BlobRef) -> PathBuf {
+    owned_dir(root).join(blob_ref.reference())
+}
+
+/// Records that the blobs were written by a snapshot.
+fn claim(blob_store: &BlobStore, root: &Path, blob_refs: &[BlobRef]) -> Result<()> {
+    fs::create_dir_all(owned_dir(root))?;
+    for blob_ref in blob_refs {
+        let marker = owned_marker(root, blob_ref);
+        fs::File::create(&marker)?;
+        blob_store.record_owned_marker(marker);
+    }
+    Ok(())
+}
+
+/// Records that the blob is no longer owned by the snapshots, once [`gc`] deleted it.
+fn disown(root: &Path, blob_ref: &BlobRef) -> Result<()> {
+    match fs::remove_file(owned_marker(root, blob_ref)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Creates a tree from `path` and records it as a new snapshot named `name`.
+pub(crate) fn create(
+    blob_store: &BlobStore,
+    root: &Path,
+    path: &Path,
+    name: &str,
+) -> Result<Snapshot> {
+    if name.is_empty() || name.chars().any(char::is_control) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid snapshot name").into());
+    }
+
+    let created = Utc::now();
+    let (tree, content, written) = tree::store_tree(blob_store, path)?;
+    claim(blob_store, root, &written)?;
+    // The blobs the snapshot uses must not be deleted with a bucket
+    let markers: Vec<_> = content
+        .blob_refs()
+        .chain(Some(&tree))
+        .map(|blob_ref| bucket::owned_marker(root, blob_ref))
+        .collect();
+    blob_store.remove_owned_markers(&markers)?;
+    let source = fs::canonicalize(path)?;
+
+    let mut snapshot = Snapshot {
+        id: String::new(),
+        name: name.to_string(),
+        created,
+        source,
+        tree,
+    };
+    let record = snapshot.to_record();
+    snapshot.id = format!("{:x}", sha2::Sha256::digest(record.as_bytes()));
+
+    let dir = snapshots_dir(root);
+    fs::create_dir_all(&dir)?;
+    let tmp_path = dir.join(format!(".{}.tmp", snapshot.id));
+    fs::write(&tmp_path, record)?;
+    fs::rename(tmp_path, dir.join(&snapshot.id))?;
+
+    Ok(snapshot)
+}
+
+/// Returns all the snapshots, sorted from oldest to newest.
+pub(crate) fn list(root: &Path) -> Result<Vec<Snapshot>> {
+    let entries = match fs::read_dir(snapshots_dir(root)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut snapshots = vec![];
+    for entry in entries {
+        let entry = entry?;
+        let id = entry.file_name().to_string_lossy().into_owned();
+        if id.starts_with('.') {
+            continue;
+        }
+        let record = fs::read_to_string(entry.path())?;
+        snapshots.push(Snapshot::from_record(&id, &record).ok_or(Error::InvalidSnapshot)?);
+    }
+    snapshots.sort_by(|a, b| a.created.cmp(&b.created).then(a.id.cmp(&b.id)));
+    Ok(snapshots)
+}
+
+/// Finds the snapshot whose id starts with `id`.
+pub(crate) fn find(root: &Path, id: &str) -> Result<Snapshot> {
+    let mut matches = list(root)?
+        .into_iter()
+        .filter(|s| !id.is_empty() && s.id.starts_with(id));
+    match (matches.next(), matches.next()) {
+        (Some(snapshot), None) => Ok(snapshot),
+        _ => Err(Error::SnapshotNotFound(id.to_string())),
+    }
+}
+
+/// Removes the snapshots not kept by `policy` and returns them.
+pub(crate) fn forget(
+    root: &Path,
+    policy: &RetentionPolicy,
+    name: Option<&str>,
+) -> Result<Vec<Snapshot>> {
+    let mut snapshots = list(root)?;
+    snapshots.reverse();
+
+    let mut names: Vec<&str> = snapshots.iter().map(|s| s.name.as_str()).collect();
+    names.sort_unstable();
+    names.dedup();
+
+    let mut forgotten = vec![];
+    for group_name in names {
+        if name.is_some_and(|n| n != group_name) {
+            continue;
+        }
+        let group: Vec<&Snapshot> = snapshots.iter().filter(|s| s.name == group_name).collect();
+        for (snapshot, keep) in group.iter().zip(policy.keep(&group)) {
+            if !keep {
+                fs::remove_file(snapshots_dir(root).join(&snapshot.id))?;
+                forgotten.push((*snapshot).clone());
+            }
+        }
+    }
+    forgotten.sort_by_key(|s| s.created);
+    Ok(forgotten)
+}
+
+/// Deletes the blobs written by the `forgotten` snapshots which are no longer referenced
+/// by a remaining snapshot, an alias, a bucket or a tree added independently of the
+/// snapshots, and returns them.
+pub(crate) fn gc(
+    blob_store: &BlobStore,
+    root: &Path,
+    forgotten: &[Snapshot],
+) -> Result<Vec<BlobRef>> {
+    let is_owned = |blob_ref: &BlobRef| owned_marker(root, blob_ref).exists();
+
+    let mut used = vec![];
+    for snapshot in list(root)? {
+        used.push(snapshot.tree);
+    }
+    for (name, _) in alias::list(root)? {
+        used.extend(
+            alias::history(root, &name)?
+                .into_iter()
+                .map(|entry| entry.target),
+        );
+    }
+    for bucket in bucket::list(root)? {
+        let entries = bucket::entries(root, &bucket.name)?;
+        used.extend(entries.into_iter().map(|entry| entry.blob_ref));
+    }
+    let mut stored = blob_store.local_blob_refs()?;
+    if let Some(cold) = blob_store.cold_tier() {
+        stored.extend(cold.local_blob_refs()?);
+    }
+    // The trees written by a snapshot are only used through it, or through another
+    // reference found above.
+    used.extend(stored.into_iter().filter(|blob_ref| !is_owned(blob_ref)));
+
+    let mut referenced = HashSet::new();
+    for blob_ref in used {
+        if referenced.insert(blob_ref.reference().to_string()) {
+            if let Some(tree) = read_tree(blob_store, &blob_ref) {
+                for blob_ref in tree.blob_refs() {
+                    referenced.insert(blob_ref.reference().to_string());
+                }
+            }
+        }
+    }
+
+    let mut candidates = vec![];
+    for snapshot in forgotten {
+        // Skip trees which are already missing from the blob store.
+        if let Ok(tree) = blob_store.tree(&snapshot.tree) {
+            candidates.extend(tree.blob_refs().cloned());
+        }
+        candidates.push(snapshot.tree.clone());
+    }
+
+    let mut deleted = vec![];
+    for blob_ref in candidates {
+        if !is_owned(&blob_ref) || !referenced.insert(blob_ref.reference().to_string()) {
+            continue;
+        }
+        if blob_store.exists(&blob_ref) {
+            blob_store.delete(&blob_ref)?;
+            deleted.push(blob_ref.clone());
+        }
+        disown(root, &blob_ref)?;
+    }
+    Ok(deleted)
+}
+
+/// Returns the tree stored in the blob, if it is a tree manifest.
+fn read_tree(blob_store: &BlobStore, blob_ref: &BlobRef) -> Option<Tree> {
+    let metadata = blob_store.metadata(blob_ref).ok()?;
+    if metadata.filename == tree::MANIFEST_FILENAME {
+        blob_store.tree(blob_ref).ok()
+    } else {
+        None
+    }
+}
@@ -0,0 +1,210 @@
+use super::backend::Backend;
+use super::error::{Error, Result};
+use super::models::{hash_file, sanitize_filename, BlobRef};
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Counter making the names of the temporary directories unique within the process
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// How [`sync`] reconciles two stores.
+#[derive(Debug, Clone, Default)]
+pub struct SyncOptions {
+    /// Whether the blobs missing from the source are also copied to it from the
+    /// destination
+    pub bidirectional: bool,
+    /// Whether deletions are propagated. In one direction, the blobs of the destination
+    /// which are not in the source are deleted. In both directions, the blobs deleted
+    /// from either store since the last sync, see [`SyncOptions::state`], are deleted
+    /// from the other one.
+    pub delete: bool,
+    /// File recording the blobs in both stores after each sync. It is needed to tell the
+    /// blobs deleted from a store from the blobs added to the other one when syncing in
+    /// both directions: without it, or on the first sync, nothing is deleted.
+    pub state: Option<PathBuf>,
+    /// Only report what would be copied and deleted
+    pub dry_run: bool,
+}
+
+/// What [`sync`] did.
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// The blobs copied from the source to the destination
+    pub to_destination: Vec<BlobRef>,
+    /// The blobs copied from the destination to the source
+    pub to_source: Vec<BlobRef>,
+    /// The blobs deleted from the destination
+    pub deleted_from_destination: Vec<BlobRef>,
+    /// The blobs deleted from the source
+    pub deleted_from_source: Vec<BlobRef>,
+    /// The blobs which could not be copied or deleted, with the reason
+    pub failed: Vec<(BlobRef, Error)>,
+}
+
+/// Copies the blobs missing from `destination` from `source`, and the other way around if
+/// [`SyncOptions::bidirectional`], e.g. to keep a backup of a blob store, local or behind
+/// an rstr server. The blobs of each store are listed to find the missing ones, and only
+/// those are copied. Their content is verified against their reference before it is
+/// stored.
+///
+/// A blob which cannot be copied or deleted does not stop the sync, it is reported in
+/// [`SyncReport::failed`] instead. The blobs are copied through a temporary file, in
+/// [`std::env::temp_dir`].
+///
+/// # Errors
+///
+/// It errors if the blobs of either store cannot be listed, or if the state file cannot
+/// be read or written.
+///
+/// # Examples
+///
+/// ```
+/// use rstr::{sync, BlobStore, SyncOptions};
+/// # let dir = tempfile::tempdir().unwrap();
+/// let primary = BlobStore::new(dir.path().join("primary")).unwrap();
+/// let backup = BlobStore::new(dir.path().join("backup")).unwrap();
+/// let blob_ref = primary.add_bytes(b"hello world", "hello.txt").unwrap();
+/// let stale = backup.add_bytes(b"hello backup", "hello.txt").unwrap();
+///
+/// let options = SyncOptions {
+///     delete: true,
+///     ..SyncOptions::default()
+/// };
+/// let report = sync(&primary, &backup, &options).unwrap();
+/// assert_eq!(report.to_destination, vec![blob_ref.clone()]);
+/// assert_eq!(report.deleted_from_destination, vec![stale.clone()]);
+/// assert!(backup.exists(&blob_ref));
+/// assert!(!backup.exists(&stale));
+/// ```
+pub fn sync(
+    source: &dyn Backend,
+    destination: &dyn Backend,
+    options: &SyncOptions,
+) -> Result<SyncReport> {
+    let source_refs: BTreeSet<BlobRef> = source.blob_refs()?.into_iter().collect();
+    let destination_refs: BTreeSet<BlobRef> = destination.blob_refs()?.into_iter().collect();
+    let synced = match &options.state {
+        Some(path) if options.bidirectional => read_state(path)?,
+        _ => BTreeSet::new(),
+    };
+    // A blob synced before and now missing from one of the stores was deleted from it
+    let was_deleted =
+        |blob_ref| options.bidirectional && options.delete && synced.contains(blob_ref);
+
+    let mut report = SyncReport::default();
+    let mut in_both: BTreeSet<BlobRef> = source_refs
+        .intersection(&destination_refs)
+        .cloned()
+        .collect();
+    for blob_ref in source_refs.difference(&destination_refs) {
+        if was_deleted(blob_ref) {
+            let result = apply(options, || source.delete(blob_ref));
+            report.record(blob_ref, result, |report| &mut report.deleted_from_source);
+        } else {
+            let result = apply(options, || copy(source, destination, blob_ref));
+            if report.record(blob_ref, result, |report| &mut report.to_destination) {
+                in_both.insert(blob_ref.clone());
+            }
+        }
+    }
+    for blob_ref in destination_refs.difference(&source_refs) {
+        if was_deleted(blob_ref) || (!options.bidirectional && options.delete) {
+            let result = apply(options, || destination.delete(blob_ref));
+            report.record(blob_ref, result, |report| {
+                &mut report.deleted_from_destination
+            });
+        } else if options.bidirectional {
+            let result = apply(options, || copy(destination, source, blob_ref));
+            if report.record(blob_ref, result, |report| &mut report.to_source) {
+                in_both.insert(blob_ref.clone());
+            }
+        }
+    }
+
+    if let (Some(path), false) = (&options.state, options.dry_run) {
+        write_state(path, &in_both)?;
+    }
+    Ok(report)
+}
+
+impl SyncReport {
+    /// Records the outcome of copying or deleting a blob, in the list returned by `list` if
+    /// it succeeded. Returns whether it succeeded.
+    fn record<F>(&mut self, blob_ref: &BlobRef, result: Result<()>, list: F) -> bool
+    where
+        F: FnOnce(&mut SyncReport) -> &mut Vec<BlobRef>,
+    {
+        match result {
+            Ok(()) => {
+                list(self).push(blob_ref.clone());
+                true
+            }
+            Err(e) => {
+                self.failed.push((blob_ref.clone(), e));
+                false
+            }
+        }
+    }
+}
+
+/// Runs `action`, unless this is a dry run.
+fn apply<F: FnOnce() -> Result<()>>(options: &SyncOptions, action: F) -> Result<()> {
+    if options.dry_run {
+        Ok(())
+    } else {
+        action()
+    }
+}
+
+/// Copies a blob from `source` to `destination`, through a temporary file named after the
+/// blob, so that its content is verified before it is stored.
+fn copy(source: &dyn Backend, destination: &dyn Backend, blob_ref: &BlobRef) -> Result<()> {
+    let filename = sanitize_filename(&source.metadata(blob_ref)?.filename);
+    let tmp_dir = std::env::temp_dir().join(format!(
+        ".rstr-sync-{}-{}",
+        std::process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::create_dir_all(&tmp_dir)?;
+    let tmp_file = tmp_dir.join(filename);
+    let result = (|| {
+        io::copy(&mut source.open(blob_ref)?, &mut File::create(&tmp_file)?)?;
+        let (actual, _) = hash_file(&tmp_file)?;
+        if actual != *blob_ref {
+            return Err(Error::HashMismatch {
+                expected: blob_ref.clone(),
+                actual,
+            });
+        }
+        destination.upload(&tmp_file, blob_ref)
+    })();
+    fs::remove_dir_all(&tmp_dir)?;
+    result
+}
+
+/// Reads the blobs recorded by the last sync, one reference per line.
+fn read_state(path: &Path) -> Result<BTreeSet<BlobRef>> {
+    match fs::read_to_string(path) {
+        Ok(content) => content.lines().map(BlobRef::new).collect(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+        Err(e) => Err(Error::from(e).with_path(path)),
+    }
+}
+
+/// Records the blobs in both stores, replacing the state file atomically.
+fn write_state(path: &Path, blob_refs: &BTreeSet<BlobRef>) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    for blob_ref in blob_refs {
+        writeln!(file, "{}", blob_ref.reference())?;
+    }
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
@@ -0,0 +1,154 @@
+use super::error::Result;
+use super::models::BlobRef;
+use chrono::{offset::Utc, DateTime, Duration, TimeZone};
+use fs2::FileExt;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the directory, inside the blob store root, recording when the blobs were
+/// last read. It holds one file per first byte of the references.
+const ACCESS_DIR: &str = "access";
+
+/// Name of the file used to serialise updates to the access records.
+const LOCK_FILE: &str = ".lock";
+
+/// How often the access time of a blob is recorded: reading a blob again within this
+/// time does not update its record, so that hot blobs do not cost a write per read.
+const ACCESS_RESOLUTION_SECS: i64 = 60 * 60;
+
+/// The tier a blob is stored in, see [`BlobStore::with_cold_tier`].
+///
+/// [`BlobStore::with_cold_tier`]: crate::BlobStore::with_cold_tier
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Tier {
+    /// The blob store itself, e.g. on SSD
+    Hot,
+    /// The secondary blob store, e.g. on HDD or archive storage
+    Cold,
+}
+
+impl fmt::Display for Tier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Tier::Hot => write!(f, "hot"),
+            Tier::Cold => write!(f, "cold"),
+        }
+    }
+}
+
+/// What [`BlobStore::run_tiering`] did.
+///
+/// [`BlobStore::run_tiering`]: crate::BlobStore::run_tiering
+#[derive(Debug, Default)]
+pub struct TierReport {
+    /// The blobs moved to the cold tier
+    pub demoted: Vec<BlobRef>,
+}
+
+fn access_path(root: &Path, reference: &str) -> PathBuf {
+    root.join(ACCESS_DIR).join(&reference[..2])
+}
+
+/// Takes the lock serialising all the updates to the access records. It is released
+/// when the returned file is dropped.
+fn lock(root: &Path) -> Result<File> {
+    let dir = root.join(ACCESS_DIR);
+    fs::create_dir_all(&dir)?;
+    let lock = File::create(dir.join(LOCK_FILE))?;
+    lock.lock_exclusive()?;
+    Ok(lock)
+}
+
+/// Reads an access file, as `(reference, last access)` pairs, skipping the lines which
+/// cannot be parsed.
+fn read_records(path: &Path) -> Result<Vec<(String, i64)>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(content
+        .lines()
+        .filter_map(|line| {
+            let (reference, timestamp) = line.split_once('\t')?;
+            Some((reference.to_string(), timestamp.parse().ok()?))
+        })
+        .collect())
+}
+
+/// Replaces the content of an access file atomically, removing it if `records` is empty.
+fn write_records(path: &Path, records: &[(String, i64)]) -> Result<()> {
+    if records.is_empty() {
+        return match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        };
+    }
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    let mut file = File::create(&tmp_path)?;
+    for (reference, timestamp) in records {
+        writeln!(file, "{reference}\t{timestamp}")?;
+    }
+    fs::rename(tmp_path, path)?;
+    Ok(())
+}
+
+/// Returns when the blob was last read, if it was since access times are recorded.
+pub(crate) fn last_access(root: &Path, blob_ref: &BlobRef) -> Result<Option<DateTime<Utc>>> {
+    let records = read_records(&access_path(root, blob_ref.reference()))?;
+    Ok(records
+        .into_iter()
+        .find(|(reference, _)| reference == blob_ref.reference())
+        .and_then(|(_, timestamp)| Utc.timestamp_opt(timestamp, 0).single()))
+}
+
+/// Records that the blob was read now.
+pub(crate) fn record_access(root: &Path, blob_ref: &BlobRef) -> Result<()> {
+    let now = Utc::now().timestamp();
+    let path = access_path(root, blob_ref.reference());
+    // Checked without the lock first, as it is the common case
+    let recent = |records: &[(String, i64)]| {
+        records.iter().any(|(reference, timestamp)| {
+            reference == blob_ref.reference() && now - timestamp < ACCESS_RESOLUTION_SECS
+        })
+    };
+    if recent(&read_records(&path)?) {
+        return Ok(());
+    }
+    let _lock = lock(root)?;
+    let mut records = read_records(&path)?;
+    if recent(&records) {
+        return Ok(());
+    }
+    records.retain(|(reference, _)| reference != blob_ref.reference());
+    records.push((blob_ref.reference().to_string(), now));
+    write_records(&path, &records)
+}
+
+/// Removes the access record of the blob, e.g. when it leaves the tier.
+pub(crate) fn forget(root: &Path, blob_ref: &BlobRef) -> Result<()> {
+    let path = access_path(root, blob_ref.reference());
+    if !path.exists() {
+        return Ok(());
+    }
+    let _lock = lock(root)?;
+    let mut records = read_records(&path)?;
+    let count = records.len();
+    records.retain(|(reference, _)| reference != blob_ref.reference());
+    if records.len() == count {
+        return Ok(());
+    }
+    write_records(&path, &records)
+}
+
+/// Returns `true` if a blob last used at `last_used` should be demoted now, given the
+/// policy of demoting the blobs unused for `unused_for`.
+pub(crate) fn is_stale(last_used: DateTime<Utc>, unused_for: Duration) -> bool {
+    Utc::now() - last_used >= unused_for
+}
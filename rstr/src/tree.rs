@@ -0,0 +1,527 @@
+use super::error::{Error, Result};
+use super::models::{BlobRef, BlobStore};
+use super::pipeline::{self, AddOptions};
+use chrono::{offset::Utc, DateTime, TimeZone};
+use filetime::FileTime;
+use ignore::WalkBuilder;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// First line of every tree manifest, used to recognise them and their version.
+const MANIFEST_HEADER: &str = "rstr-tree 1";
+
+/// Filename under which tree manifests are stored in the blob store.
+pub(crate) const MANIFEST_FILENAME: &str = "tree.rstr";
+
+/// The kind of filesystem object described by a [`TreeEntry`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    /// A regular file, whose content is stored as a blob.
+    File,
+    /// A directory.
+    Directory,
+    /// A symbolic link, whose target is stored as a blob.
+    Symlink,
+}
+
+/// An entry of a [`Tree`], i.e. a file, directory or symlink found when the tree was
+/// created.
+#[derive(Debug, Clone)]
+pub struct TreeEntry {
+    /// The path of the entry relative to the root of the tree. The root itself is `.`.
+    pub path: PathBuf,
+    /// What kind of entry this is
+    pub kind: EntryKind,
+    /// The permission bits of the entry (e.g. `0o644`)
+    pub mode: u32,
+    /// The last modification time of the entry
+    pub mtime: DateTime<Utc>,
+    /// The size in bytes of the file content or of the symlink target (0 for directories)
+    pub size: u64,
+    /// The blob holding the file content or the symlink target (`None` for directories)
+    pub blob_ref: Option<BlobRef>,
+}
+
+/// Struct representing a directory snapshot stored in the blob store.
+///
+/// A tree is stored as a manifest blob listing all the entries of the directory. The
+/// content of each file is stored as a separate blob, so identical files are only stored
+/// once across all trees. See [`BlobStore::add_tree`] and [`BlobStore::restore_tree`].
+#[derive(Debug, Clone, Default)]
+pub struct Tree {
+    /// The entries of the tree, sorted by path. Parents always come before children.
+    pub entries: Vec<TreeEntry>,
+}
+
+/// The differences between two [`Tree`]s, as returned by [`Tree::diff`].
+#[derive(Debug, Clone, Default)]
+pub struct TreeDiff {
+    /// Entries only present in the newer tree
+    pub added: Vec<TreeEntry>,
+    /// Entries only present in the older tree
+    pub removed: Vec<TreeEntry>,
+    /// Entries present in both trees that changed, as `(old, new)` pairs
+    pub modified: Vec<(TreeEntry, TreeEntry)>,
+}
+
+impl Tree {
+    /// Returns the files (and symlinks) of the tree, i.e. all the entries pointing to a blob
+    pub fn blob_refs(&self) -> impl Iterator<Item = &BlobRef> {
+        self.entries.iter().filter_map(|e| e.blob_ref.as_ref())
+    }
+
+    /// Compares this tree with a newer one and returns the entries that were added,
+    /// removed or modified. An entry is modified if its kind, content or permissions
+    /// changed; changes to the modification time alone are ignored.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use std::fs;
+    /// use rstr::BlobStore;
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// # let source = dir.path().join("source");
+    /// # fs::create_dir_all(&source).unwrap();
+    /// let blob_store = BlobStore::new(dir.path().join("store")).unwrap();
+    ///
+    /// fs::write(source.join("a.txt"), b"a").unwrap();
+    /// fs::write(source.join("b.txt"), b"b").unwrap();
+    /// let old = blob_store.tree(&blob_store.add_tree(&source).unwrap()).unwrap();
+    ///
+    /// fs::write(source.join("b.txt"), b"changed").unwrap();
+    /// fs::write(source.join("c.txt"), b"c").unwrap();
+    /// fs::remove_file(source.join("a.txt")).unwrap();
+    /// let new = blob_store.tree(&blob_store.add_tree(&source).unwrap()).unwrap();
+    ///
+    /// let diff = old.diff(&new);
+    /// assert_eq!(diff.added[0].path.to_str(), Some("c.txt"));
+    /// assert_eq!(diff.removed[0].path.to_str(), Some("a.txt"));
+    /// assert_eq!(diff.modified[0].1.path.to_str(), Some("b.txt"));
+    /// ```
+    pub fn diff(&self, newer: &Tree) -> TreeDiff {
+        let old: HashMap<&PathBuf, &TreeEntry> =
+            self.entries.iter().map(|e| (&e.path, e)).collect();
+        let new: HashMap<&PathBuf, &TreeEntry> =
+            newer.entries.iter().map(|e| (&e.path, e)).collect();
+
+        let mut diff = TreeDiff::default();
+        for entry in &newer.entries {
+            match old.get(&entry.path) {
+                None => diff.added.push(entry.clone()),
+                Some(old_entry)
+                    if old_entry.kind != entry.kind
+                        || old_entry.mode != entry.mode
+                        || old_entry.blob_ref.as_ref().map(BlobRef::reference)
+                            != entry.blob_ref.as_ref().map(BlobRef::reference) =>
+                {
+                    diff.modified.push(((*old_entry).clone(), entry.clone()));
+                }
+                Some(_) => {}
+            }
+        }
+        diff.removed = self
+            .entries
+            .iter()
+            .filter(|e| !new.contains_key(&e.path))
+            .cloned()
+            .collect();
+        diff
+    }
+
+    /// Serializes the tree in the manifest format stored in the blob store.
+    ///
+    /// The manifest is a text file whose first line is a header. Each following line
+    /// describes an entry with tab separated fields: kind, mode (octal), modification
+    /// time (`seconds.nanoseconds` since the unix epoch), size, blob reference (`-` for
+    /// directories) and path.
+    pub fn to_manifest(&self) -> String {
+        let mut manifest = String::from(MANIFEST_HEADER);
+        manifest.push('\n');
+        for entry in &self.entries {
+            let kind = match entry.kind {
+                EntryKind::File => "file",
+                EntryKind::Directory => "dir",
+                EntryKind::Symlink => "link",
+            };
+            let blob_ref = entry.blob_ref.as_ref().map_or("-", BlobRef::reference);
+            writeln!(
+                manifest,
+                "{}\t{:o}\t{}.{:09}\t{}\t{}\t{}",
+                kind,
+                entry.mode,
+                entry.mtime.timestamp(),
+                entry.mtime.timestamp_subsec_nanos(),
+                entry.size,
+                blob_ref,
+                encode_path(&entry.path)
+            )
+            .unwrap();
+        }
+        manifest
+    }
+
+    /// Parses a tree manifest, see [`Tree::to_manifest`] for the format.
+    ///
+    /// # Errors
+    ///
+    /// It returns [`Error::InvalidTree`] if the content is not a valid manifest.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rstr::{EntryKind, Tree};
+    ///
+    /// let manifest = "rstr-tree 1\n\
+    ///     dir\t755\t1623267000.000000000\t0\t-\t.\n\
+    ///     file\t644\t1623267000.000000000\t20\tf29bc64a9d3732b4b9035125fdb3285f5b6455778edca72414671e0ca3b2e0de\ttest_file.txt\n";
+    /// let tree = Tree::from_manifest(manifest.as_bytes()).unwrap();
+    ///
+    /// assert_eq!(tree.entries.len(), 2);
+    /// assert_eq!(tree.entries[1].kind, EntryKind::File);
+    /// assert_eq!(tree.entries[1].mode, 0o644);
+    /// assert_eq!(tree.to_manifest(), manifest);
+    ///
+    /// assert!(Tree::from_manifest(b"This is a test file.").is_err());
+    /// ```
+    pub fn from_manifest(content: &[u8]) -> Result<Tree> {
+        let content = std::str::from_utf8(content).map_err(|_| Error::InvalidTree)?;
+        let mut lines = content.lines();
+        if lines.next() != Some(MANIFEST_HEADER) {
+            return Err(Error::InvalidTree);
+        }
+        let entries = lines.map(parse_entry).collect::<Result<_>>()?;
+        Ok(Tree { entries })
+    }
+}
+
+/// Parses a single line of a tree manifest.
+fn parse_entry(line: &str) -> Result<TreeEntry> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    if fields.len() != 6 {
+        return Err(Error::InvalidTree);
+    }
+
+    let kind = match fields[0] {
+        "file" => EntryKind::File,
+        "dir" => EntryKind::Directory,
+        "link" => EntryKind::Symlink,
+        _ => return Err(Error::InvalidTree),
+    };
+    let mode = u32::from_str_radix(fields[1], 8).map_err(|_| Error::InvalidTree)?;
+    let mtime = parse_mtime(fields[2]).ok_or(Error::InvalidTree)?;
+    let size = fields[3].parse().map_err(|_| Error::InvalidTree)?;
+    let blob_ref = match (kind, fields[4]) {
+        (EntryKind::Directory, "-") => None,
+        (EntryKind::Directory, _) | (_, "-") => return Err(Error::InvalidTree),
+        (_, reference) => Some(BlobRef::new(reference).map_err(|_| Error::InvalidTree)?),
+    };
+    let path = decode_path(fields[5]).ok_or(Error::InvalidTree)?;
+
+    Ok(TreeEntry {
+        path,
+        kind,
+        mode,
+        mtime,
+        size,
+        blob_ref,
+    })
+}
+
+fn parse_mtime(value: &str) -> Option<DateTime<Utc>> {
+    let (secs, nanos) = value.split_once('.')?;
+    Utc.timestamp_opt(secs.parse().ok()?, nanos.parse().ok()?)
+        .single()
+}
+
+/// Returns the raw bytes of a path. On non-unix systems the separators are normalised
+/// to `/`.
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    #[cfg(unix)]
+    let bytes = std::os::unix::ffi::OsStrExt::as_bytes(path.as_os_str()).to_vec();
+    #[cfg(not(unix))]
+    let bytes = path.to_string_lossy().replace('\\', "/").into_bytes();
+    bytes
+}
+
+/// Inverse of [`path_to_bytes`]. It can only fail on non-unix systems.
+#[allow(clippy::unnecessary_wraps)]
+fn path_from_bytes(bytes: Vec<u8>) -> Option<PathBuf> {
+    #[cfg(unix)]
+    let path =
+        PathBuf::from(<std::ffi::OsString as std::os::unix::ffi::OsStringExt>::from_vec(bytes));
+    #[cfg(not(unix))]
+    let path = PathBuf::from(String::from_utf8(bytes).ok()?);
+    Some(path)
+}
+
+/// Encodes a path for the manifest. Bytes that would break the manifest format (control
+/// characters, `%` and, for paths which are not valid UTF-8, non-ASCII bytes) are
+/// percent-encoded.
+pub(crate) fn encode_path(path: &Path) -> String {
+    let bytes = path_to_bytes(path);
+    let is_utf8 = std::str::from_utf8(&bytes).is_ok();
+
+    let mut encoded = Vec::with_capacity(bytes.len());
+    for b in bytes {
+        if b == b'%' || b.is_ascii_control() || (!is_utf8 && !b.is_ascii()) {
+            encoded.extend(format!("%{b:02X}").bytes());
+        } else {
+            encoded.push(b);
+        }
+    }
+    // Only valid UTF-8 sequences or ASCII characters are left unencoded.
+    String::from_utf8(encoded).unwrap_or_default()
+}
+
+/// Decodes a path encoded with [`encode_path`].
+pub(crate) fn decode_path(encoded: &str) -> Option<PathBuf> {
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut chars = encoded.bytes();
+    while let Some(b) = chars.next() {
+        if b == b'%' {
+            let hex = [chars.next()?, chars.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    path_from_bytes(bytes)
+}
+
+/// Returns the path without its `.` components, e.g. `a` for `./a/.`.
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| *c != Component::CurDir)
+        .collect()
+}
+
+/// Checks that none of the parents of the entry at `path`, below `dest`, is a symlink,
+/// so that restoring the entry does not write outside `dest`.
+fn check_parents(dest: &Path, path: &Path) -> Result<()> {
+    let path = normalize(path);
+    // `dest` itself may be a symlink
+    for parent in path
+        .ancestors()
+        .skip(1)
+        .filter(|p| !p.as_os_str().is_empty())
+    {
+        match dest.join(parent).symlink_metadata() {
+            Ok(metadata) if metadata.file_type().is_symlink() => return Err(Error::InvalidTree),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Returns `true` if the path is relative and does not escape the root of the tree.
+fn is_safe_relative_path(path: &Path) -> bool {
+    path.components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+#[cfg(unix)]
+fn mode_of(metadata: &fs::Metadata) -> u32 {
+    std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()) & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode_of(metadata: &fs::Metadata) -> u32 {
+    match (metadata.is_dir(), metadata.permissions().readonly()) {
+        (true, _) => 0o755,
+        (false, true) => 0o444,
+        (false, false) => 0o644,
+    }
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    Ok(fs::set_permissions(path, fs::Permissions::from_mode(mode))?)
+}
+
+#[cfg(not(unix))]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o222 == 0);
+    Ok(fs::set_permissions(path, permissions)?)
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &Path, path: &Path) -> Result<()> {
+    Ok(std::os::unix::fs::symlink(target, path)?)
+}
+
+#[cfg(not(unix))]
+fn create_symlink(target: &Path, path: &Path) -> Result<()> {
+    Ok(std::os::windows::fs::symlink_file(target, path)?)
+}
+
+fn file_time(mtime: &DateTime<Utc>) -> FileTime {
+    FileTime::from_unix_time(mtime.timestamp(), mtime.timestamp_subsec_nanos())
+}
+
+/// Stores the directory at `root` and its manifest, see [`build_tree`]. It returns the
+/// reference of the manifest, the tree, and the blobs which were not in the blob store
+/// before.
+pub(crate) fn store_tree(
+    blob_store: &BlobStore,
+    root: &Path,
+) -> Result<(BlobRef, Tree, Vec<BlobRef>)> {
+    let (tree, mut written) = build_tree(blob_store, root)?;
+    let (tree_ref, is_new) =
+        blob_store.store_bytes(tree.to_manifest().as_bytes(), MANIFEST_FILENAME)?;
+    if is_new {
+        written.push(tree_ref.clone());
+    }
+    Ok((tree_ref, tree, written))
+}
+
+/// Walks `root` (including hidden and ignored files, without following symlinks) and
+/// stores every file it contains. It returns the resulting [`Tree`], and the blobs which
+/// were not in the blob store before.
+fn build_tree(blob_store: &BlobStore, root: &Path) -> Result<(Tree, Vec<BlobRef>)> {
+    if !fs::metadata(root)?.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a directory").into());
+    }
+
+    let mut found = vec![];
+    for entry in WalkBuilder::new(root).standard_filters(false).build() {
+        let entry = entry.map_err(|e| match e.into_io_error() {
+            Some(err) => Error::from(err),
+            None => Error::InvalidTree,
+        })?;
+        let relative = entry.path().strip_prefix(root).unwrap_or(entry.path());
+        let relative = if relative.as_os_str().is_empty() {
+            PathBuf::from(".")
+        } else {
+            relative.to_path_buf()
+        };
+        found.push((relative, entry.into_path()));
+    }
+
+    let files: Vec<&PathBuf> = found
+        .iter()
+        .filter(|(_, p)| p.symlink_metadata().is_ok_and(|m| m.is_file()))
+        .map(|(_, p)| p)
+        .collect();
+    let mut stored = HashMap::new();
+    let mut written = vec![];
+    for (path, result) in pipeline::add_files(blob_store, &files, &AddOptions::default()) {
+        let (blob_ref, is_new) = result?;
+        if is_new {
+            written.push(blob_ref.clone());
+        }
+        stored.insert(path, blob_ref);
+    }
+
+    let mut tree = Tree::default();
+    for (relative, path) in found {
+        let metadata = path.symlink_metadata()?;
+        let file_type = metadata.file_type();
+        let (kind, size, blob_ref) = if file_type.is_dir() {
+            (EntryKind::Directory, 0, None)
+        } else if file_type.is_symlink() {
+            let target = path_to_bytes(&fs::read_link(&path)?);
+            let name = path.file_name().map(|f| f.to_string_lossy().into_owned());
+            let (blob_ref, is_new) = blob_store.store_bytes(&target, &name.unwrap_or_default())?;
+            if is_new {
+                written.push(blob_ref.clone());
+            }
+            (EntryKind::Symlink, target.len() as u64, Some(blob_ref))
+        } else if let Some(blob_ref) = stored.remove(&path) {
+            (EntryKind::File, metadata.len(), Some(blob_ref))
+        } else {
+            // Sockets, fifos and the like cannot be stored.
+            continue;
+        };
+        tree.entries.push(TreeEntry {
+            path: relative,
+            kind,
+            mode: mode_of(&metadata),
+            mtime: metadata.modified()?.into(),
+            size,
+            blob_ref,
+        });
+    }
+    tree.entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok((tree, written))
+}
+
+/// Recreates the directory described by `tree` in `dest`.
+///
+/// Manifests are ordinary blobs, so they are not trusted to stay within `dest`: entries
+/// below a symlink of the tree are rejected, nothing is written below a symlink already
+/// in `dest`, and the symlinks are only created once all the files are written.
+pub(crate) fn restore_tree(blob_store: &BlobStore, tree: &Tree, dest: &Path) -> Result<()> {
+    let symlinks: HashSet<PathBuf> = tree
+        .entries
+        .iter()
+        .filter(|e| e.kind == EntryKind::Symlink)
+        .map(|e| normalize(&e.path))
+        .collect();
+    let is_below_symlink = |path: &Path| {
+        normalize(path)
+            .ancestors()
+            .skip(1)
+            .any(|ancestor| symlinks.contains(ancestor))
+    };
+    if tree
+        .entries
+        .iter()
+        .any(|e| !is_safe_relative_path(&e.path) || is_below_symlink(&e.path))
+    {
+        return Err(Error::InvalidTree);
+    }
+    fs::create_dir_all(dest)?;
+
+    for entry in &tree.entries {
+        check_parents(dest, &entry.path)?;
+        let path = dest.join(&entry.path);
+        match (entry.kind, &entry.blob_ref) {
+            (EntryKind::Directory, _) => {
+                // Its permissions are restored, which would follow a symlink
+                if matches!(path.symlink_metadata(), Ok(m) if m.file_type().is_symlink()) {
+                    return Err(Error::InvalidTree);
+                }
+                fs::create_dir_all(&path)?;
+            }
+            (EntryKind::File, Some(blob_ref)) => {
+                if path.symlink_metadata().is_ok() {
+                    fs::remove_file(&path)?;
+                }
+                blob_store.copy_to(blob_ref, &path)?;
+                set_mode(&path, entry.mode)?;
+                filetime::set_file_mtime(&path, file_time(&entry.mtime))?;
+            }
+            (EntryKind::Symlink, Some(_)) => (),
+            _ => return Err(Error::InvalidTree),
+        }
+    }
+
+    for entry in tree.entries.iter().filter(|e| e.kind == EntryKind::Symlink) {
+        let path = dest.join(&entry.path);
+        let blob_ref = entry.blob_ref.as_ref().ok_or(Error::InvalidTree)?;
+        let target = path_from_bytes(blob_store.get(blob_ref)?).ok_or(Error::InvalidTree)?;
+        check_parents(dest, &entry.path)?;
+        if path.symlink_metadata().is_ok() {
+            fs::remove_file(&path)?;
+        }
+        create_symlink(&target, &path)?;
+        let mtime = file_time(&entry.mtime);
+        filetime::set_symlink_file_times(&path, mtime, mtime)?;
+    }
+
+    // Directories are restored last (deepest first) as writing their children changes
+    // their modification time and they may not be writable.
+    for entry in tree.entries.iter().rev() {
+        if entry.kind == EntryKind::Directory {
+            let path = dest.join(&entry.path);
+            set_mode(&path, entry.mode)?;
+            filetime::set_file_mtime(&path, file_time(&entry.mtime))?;
+        }
+    }
+    Ok(())
+}
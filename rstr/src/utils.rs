@@ -1,39 +1,36 @@
+use crossbeam_channel::Sender;
 use ignore::{WalkBuilder, WalkState};
 #[cfg(feature = "progress_bar")]
 use indicatif::{ProgressBar, ProgressStyle};
 use std::path::Path;
 use std::path::PathBuf;
-use std::sync::mpsc;
 
 /// Given a path to a directory it recursively walks all its children in parallel
-/// and returns a list of paths to files.
-pub fn collect_file_paths(path: &Path) -> Vec<PathBuf> {
-    if path.is_file() {
-        return vec![path.to_path_buf()];
+/// and sends the paths to files down the channel as soon as they are found.
+///
+/// If the channel is bounded, the walker threads block until there is room in it.
+pub fn send_file_paths(path: &Path, tx: &Sender<PathBuf>) {
+    // A missing path is sent as is, so that it is reported as such
+    if path.is_file() || !path.exists() {
+        // The receiving end hanging up just means nobody is interested anymore.
+        let _ = tx.send(path.to_path_buf());
+        return;
     }
 
     let walker = WalkBuilder::new(path);
-    let (tx, rx) = mpsc::channel();
     walker.build_parallel().run(|| {
         let tx = tx.clone();
         Box::new(move |entry| match entry {
             Ok(entry) => {
                 let path = entry.path();
-                if path.is_file() {
-                    tx.send(path.into()).expect("Err")
+                if path.is_file() && tx.send(path.into()).is_err() {
+                    return WalkState::Quit;
                 }
                 WalkState::Continue
             }
             Err(_) => WalkState::Continue,
         })
     });
-
-    drop(tx);
-    let mut paths = vec![];
-    for path in rx.iter() {
-        paths.push(path)
-    }
-    paths
 }
 
 #[cfg(feature = "progress_bar")]
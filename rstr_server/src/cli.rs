@@ -1,4 +1,4 @@
-use clap::{crate_authors, crate_version, App, AppSettings, Arg, SubCommand};
+use clap::{crate_authors, crate_version, App, AppSettings, Arg, ArgGroup, SubCommand};
 
 fn server_commands() -> App<'static, 'static> {
     SubCommand::with_name("server")
@@ -37,6 +37,342 @@ fn server_commands() -> App<'static, 'static> {
         )
         .subcommand(SubCommand::with_name("generate-token").about("Generate an API Token."))
 }
+fn tier_commands() -> App<'static, 'static> {
+    SubCommand::with_name("tier")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .about("Commands for moving blobs between the hot and the cold data stores.")
+        .subcommand(
+            SubCommand::with_name("run")
+                .about("Moves the blobs not read for a while to the cold data store.")
+                .arg(
+                    Arg::with_name("demote_after")
+                        .long("demote-after")
+                        .value_name("DAYS")
+                        .help("Move the blobs not read for this many days"),
+                ),
+        )
+}
+fn tree_commands() -> App<'static, 'static> {
+    SubCommand::with_name("tree")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .about("Commands for storing whole directories as trees.")
+        .subcommand(
+            SubCommand::with_name("create")
+                .setting(AppSettings::ArgRequiredElseHelp)
+                .about(
+                    "Stores a directory, including its structure, and prints the tree reference.",
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .required(true)
+                        .index(1)
+                        .value_name("PATH")
+                        .help("Path to the directory to store"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("ls")
+                .setting(AppSettings::ArgRequiredElseHelp)
+                .about("Lists the entries of a tree.")
+                .arg(
+                    Arg::with_name("ref")
+                        .required(true)
+                        .index(1)
+                        .value_name("TREE_REF")
+                        .help("The reference of the tree manifest (or a unique prefix of at least 6 characters)"),
+                ),
+        )
+}
+
+fn keep_arg(name: &'static str, period: &'static str) -> Arg<'static, 'static> {
+    Arg::with_name(name)
+        .long(name)
+        .takes_value(true)
+        .value_name("N")
+        .help(period)
+}
+
+fn snapshot_commands() -> App<'static, 'static> {
+    let id_arg = |name, index| {
+        Arg::with_name(name)
+            .required(true)
+            .index(index)
+            .value_name("SNAPSHOT_ID")
+            .help("The id of the snapshot (or an unambiguous prefix of it)")
+    };
+
+    SubCommand::with_name("snapshot")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .about("Commands for backing up directories as timestamped snapshots.")
+        .subcommand(
+            SubCommand::with_name("create")
+                .setting(AppSettings::ArgRequiredElseHelp)
+                .about("Takes a new snapshot of a directory.")
+                .arg(
+                    Arg::with_name("path")
+                        .required(true)
+                        .index(1)
+                        .value_name("PATH")
+                        .help("Path to the directory to back up"),
+                )
+                .arg(
+                    Arg::with_name("name")
+                        .long("name")
+                        .required(true)
+                        .takes_value(true)
+                        .value_name("LABEL")
+                        .help("Label of the snapshot, retention policies apply per label"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("Lists the snapshots, from oldest to newest.")
+                .arg(
+                    Arg::with_name("name")
+                        .long("name")
+                        .takes_value(true)
+                        .value_name("LABEL")
+                        .help("Only list the snapshots with this label"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .setting(AppSettings::ArgRequiredElseHelp)
+                .about("Lists the files added (+), removed (-) or modified (M) between two snapshots.")
+                .arg(id_arg("old", 1))
+                .arg(id_arg("new", 2)),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .setting(AppSettings::ArgRequiredElseHelp)
+                .about("Recreates the directory stored in a snapshot.")
+                .arg(id_arg("id", 1))
+                .arg(
+                    Arg::with_name("dest")
+                        .required(true)
+                        .index(2)
+                        .value_name("PATH")
+                        .help("Where to recreate the directory"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("forget")
+                .about("Removes the snapshots not kept by the retention policy.")
+                .group(
+                    ArgGroup::with_name("policy")
+                        .args(&[
+                            "keep-last",
+                            "keep-hourly",
+                            "keep-daily",
+                            "keep-weekly",
+                            "keep-monthly",
+                            "keep-yearly",
+                        ])
+                        .multiple(true)
+                        .required(true),
+                )
+                .arg(keep_arg("keep-last", "Keep the N most recent snapshots"))
+                .arg(keep_arg("keep-hourly", "Keep the last snapshot of each of the last N hours"))
+                .arg(keep_arg("keep-daily", "Keep the last snapshot of each of the last N days"))
+                .arg(keep_arg("keep-weekly", "Keep the last snapshot of each of the last N weeks"))
+                .arg(keep_arg("keep-monthly", "Keep the last snapshot of each of the last N months"))
+                .arg(keep_arg("keep-yearly", "Keep the last snapshot of each of the last N years"))
+                .arg(
+                    Arg::with_name("name")
+                        .long("name")
+                        .takes_value(true)
+                        .value_name("LABEL")
+                        .help("Only apply the policy to the snapshots with this label"),
+                )
+                .arg(
+                    Arg::with_name("gc")
+                        .long("gc")
+                        .help("Also delete the blobs written by the removed snapshots which are no longer used"),
+                ),
+        )
+}
+
+fn alias_commands() -> App<'static, 'static> {
+    let name_arg = || {
+        Arg::with_name("name")
+            .required(true)
+            .index(1)
+            .value_name("NAME")
+            .help("The name of the alias, e.g. `releases/latest`")
+    };
+
+    SubCommand::with_name("alias")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .about("Commands for giving human readable names to blobs.")
+        .subcommand(
+            SubCommand::with_name("set")
+                .setting(AppSettings::ArgRequiredElseHelp)
+                .about("Points an alias to a blob, creating the alias if needed.")
+                .arg(name_arg())
+                .arg(
+                    Arg::with_name("ref")
+                        .required(true)
+                        .index(2)
+                        .value_name("REF")
+                        .help("The reference of the blob (or a unique prefix of at least 6 characters)"),
+                )
+                .arg(
+                    Arg::with_name("expect")
+                        .long("expect")
+                        .takes_value(true)
+                        .value_name("REF")
+                        .conflicts_with("create")
+                        .help("Only update the alias if it currently points to this blob"),
+                )
+                .arg(
+                    Arg::with_name("create")
+                        .long("create")
+                        .help("Only create the alias, failing if it already exists"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("get")
+                .setting(AppSettings::ArgRequiredElseHelp)
+                .about("Prints the reference of the blob an alias points to.")
+                .arg(name_arg()),
+        )
+        .subcommand(SubCommand::with_name("list").about("Lists all aliases and their targets."))
+        .subcommand(
+            SubCommand::with_name("history")
+                .setting(AppSettings::ArgRequiredElseHelp)
+                .about("Lists all the blobs an alias pointed to, from oldest to newest.")
+                .arg(name_arg()),
+        )
+}
+
+fn bucket_arg() -> Arg<'static, 'static> {
+    Arg::with_name("bucket")
+        .long("bucket")
+        .short("b")
+        .takes_value(true)
+        .value_name("BUCKET")
+        .env("RSTR_BUCKET")
+        .help("Operate on the blobs of this bucket only")
+}
+
+fn bucket_commands() -> App<'static, 'static> {
+    let name_arg = || {
+        Arg::with_name("name")
+            .required(true)
+            .index(1)
+            .value_name("BUCKET")
+            .help("The name of the bucket")
+    };
+    let quota_arg = || {
+        Arg::with_name("quota")
+            .long("quota")
+            .takes_value(true)
+            .value_name("BYTES")
+            .help("Maximum number of bytes the blobs of the bucket can take")
+    };
+    let meta_arg = || {
+        Arg::with_name("meta")
+            .long("meta")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("KEY=VALUE")
+            .help("Metadata to attach to the bucket, can be repeated")
+    };
+
+    SubCommand::with_name("bucket")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .about("Commands for managing buckets, i.e. named collections of blobs.")
+        .subcommand(
+            SubCommand::with_name("create")
+                .setting(AppSettings::ArgRequiredElseHelp)
+                .about("Creates a new bucket.")
+                .arg(name_arg())
+                .arg(quota_arg())
+                .arg(meta_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("update")
+                .setting(AppSettings::ArgRequiredElseHelp)
+                .about("Changes the quota or the metadata of a bucket.")
+                .arg(name_arg())
+                .arg(quota_arg().conflicts_with("no_quota"))
+                .arg(
+                    Arg::with_name("no_quota")
+                        .long("no-quota")
+                        .help("Remove the quota of the bucket"),
+                )
+                .arg(meta_arg())
+                .arg(
+                    Arg::with_name("unset_meta")
+                        .long("unset-meta")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("KEY")
+                        .help("Metadata to remove from the bucket, can be repeated"),
+                ),
+        )
+        .subcommand(SubCommand::with_name("list").about("Lists the buckets with their usage and quota."))
+        .subcommand(
+            SubCommand::with_name("ls")
+                .setting(AppSettings::ArgRequiredElseHelp)
+                .about("Lists the blobs in a bucket.")
+                .arg(name_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("delete")
+                .setting(AppSettings::ArgRequiredElseHelp)
+                .about("Deletes a bucket. The content of its blobs is deleted unless other buckets use it.")
+                .arg(name_arg()),
+        )
+}
+
+fn car_commands() -> App<'static, 'static> {
+    SubCommand::with_name("car")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .about("Commands for exchanging blobs with IPFS tools as CAR (content-addressed archive) files.")
+        .subcommand(
+            SubCommand::with_name("export")
+                .setting(AppSettings::ArgRequiredElseHelp)
+                .about("Writes blobs to a CAR file, as raw blocks identified by their CID.")
+                .arg(
+                    Arg::with_name("refs")
+                        .index(1)
+                        .value_name("REF")
+                        .multiple(true)
+                        .required_unless("all")
+                        .help("The reference of the blobs to export (or a unique prefix of at least 6 characters, or a CID)"),
+                )
+                .arg(
+                    Arg::with_name("all")
+                        .long("all")
+                        .conflicts_with("refs")
+                        .help("Export all the blobs"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .required(true)
+                        .value_name("PATH")
+                        .help("The CAR file to write"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .setting(AppSettings::ArgRequiredElseHelp)
+                .about("Adds the raw blocks of a CAR file to the blob store, verifying them against their CID.")
+                .arg(
+                    Arg::with_name("path")
+                        .required(true)
+                        .index(1)
+                        .value_name("PATH")
+                        .help("The CAR file to read"),
+                ),
+        )
+}
+
 pub fn app() -> App<'static, 'static> {
     App::new("rstr")
         .version(crate_version!())
@@ -65,7 +401,114 @@ option in the CLI or using environment variables will override what is specified
                 .long("data-store")
                 .short("d")
                 .value_name("PATH")
-                .help("Where rstr saves the blobs."),
+                .multiple(true)
+                .require_delimiter(true)
+                .help("Where rstr saves the blobs. Pass several comma separated paths to spread the blobs over several disks."),
+        )
+        .arg(
+            Arg::with_name("placement")
+                .env("RSTR_PLACEMENT")
+                .long("placement")
+                .value_name("STRATEGY")
+                .possible_values(&["hash-range", "free-space"])
+                .help("How new blobs are spread over several data stores."),
+        )
+        .arg(
+            Arg::with_name("replicas")
+                .env("RSTR_REPLICAS")
+                .long("replicas")
+                .value_name("N")
+                .help("How many data stores each new blob is written to."),
+        )
+        .arg(
+            Arg::with_name("erasure_coding")
+                .env("RSTR_ERASURE_CODING")
+                .long("erasure-coding")
+                .value_name("K+M")
+                .help("Split large blobs into K data shards and M parity shards across the data stores."),
+        )
+        .arg(
+            Arg::with_name("pack_threshold")
+                .env("RSTR_PACK_THRESHOLD")
+                .long("pack-threshold")
+                .value_name("BYTES")
+                .help("Append the blobs smaller than this size to pack files."),
+        )
+        .arg(
+            Arg::with_name("index")
+                .long("index")
+                .help("Keep an index of the blobs in memory, so that checking whether a blob exists does not touch the disk."),
+        )
+        .arg(
+            Arg::with_name("verify_on_read")
+                .long("verify-on-read")
+                .help("Check that the content of the blobs matches their reference whenever they are read."),
+        )
+        .arg(
+            Arg::with_name("cold_store_path")
+                .env("RSTR_COLD_STORE")
+                .long("cold-store")
+                .value_name("PATH")
+                .multiple(true)
+                .require_delimiter(true)
+                .help("Where rstr moves the blobs not read for a while, e.g. on a slower disk. Pass several comma separated paths to spread them over several disks."),
+        )
+        .arg(
+            Arg::with_name("upstream")
+                .env("RSTR_UPSTREAM")
+                .long("upstream")
+                .value_name("URL")
+                .help("Use the data store as a cache of another store: the URL of a remote rstr server, an S3 bucket as s3://BUCKET/PREFIX/, or a path."),
+        )
+        .arg(
+            Arg::with_name("upstream_token")
+                .env("RSTR_UPSTREAM_TOKEN")
+                .long("upstream-token")
+                .value_name("TOKEN")
+                .hide_env_values(true)
+                .help("API token of the remote rstr server used as upstream."),
+        )
+        .arg(
+            Arg::with_name("cache_size")
+                .env("RSTR_CACHE_SIZE")
+                .long("cache-size")
+                .value_name("BYTES")
+                .help("Evict the least recently read blobs fetched from upstream over this size."),
+        )
+        .arg(
+            Arg::with_name("s3_endpoint")
+                .env("RSTR_S3_ENDPOINT")
+                .long("s3-endpoint")
+                .value_name("URL")
+                .help("Endpoint of the S3-compatible object storage, by default AWS S3."),
+        )
+        .arg(
+            Arg::with_name("s3_region")
+                .env("AWS_REGION")
+                .long("s3-region")
+                .value_name("REGION")
+                .help("Region of the S3-compatible object storage."),
+        )
+        .arg(
+            Arg::with_name("s3_access_key")
+                .env("AWS_ACCESS_KEY_ID")
+                .long("s3-access-key")
+                .value_name("KEY")
+                .hide_env_values(true)
+                .help("Access key of the S3-compatible object storage."),
+        )
+        .arg(
+            Arg::with_name("s3_secret_key")
+                .env("AWS_SECRET_ACCESS_KEY")
+                .long("s3-secret-key")
+                .value_name("KEY")
+                .hide_env_values(true)
+                .help("Secret key of the S3-compatible object storage."),
+        )
+        .arg(
+            Arg::with_name("write_through")
+                .long("write-through")
+                .help("Also upload the new blobs to the upstream store, and delete them from it."),
         )
         .subcommand(
             SubCommand::with_name("create-config")
@@ -95,7 +538,15 @@ option in the CLI or using environment variables will override what is specified
                         .required(false)
                         .value_name("NUM_THREADS")
                         .default_value("16")
-                        .help("Number of threads to use when importing files."),
+                        .help("Number of threads used to hash the files being imported."),
+                )
+                .arg(
+                    Arg::with_name("write_threads")
+                        .long("write-threads")
+                        .required(false)
+                        .value_name("NUM_THREADS")
+                        .default_value("4")
+                        .help("Number of threads used to copy new files into the blob store."),
                 )
                 .arg(
                     Arg::with_name("verbose")
@@ -103,7 +554,21 @@ option in the CLI or using environment variables will override what is specified
                         .long("verbose")
                         .required(false)
                         .help("Print details about the process."),
-                ),
+                )
+                .arg(
+                    Arg::with_name("dry_run")
+                        .long("dry-run")
+                        .required(false)
+                        .help("Only report which files would be added, without adding them."),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .required(false)
+                        .requires("dry_run")
+                        .help("Print the dry-run report as JSON."),
+                )
+                .arg(bucket_arg().conflicts_with("dry_run")),
         )
         .subcommand(
             SubCommand::with_name("delete")
@@ -115,7 +580,7 @@ option in the CLI or using environment variables will override what is specified
                         .index(1)
                         .value_name("REF")
                         .multiple(true)
-                        .help("The reference of the blobs to delete"),
+                        .help("The reference of the blobs to delete (or a unique prefix of at least 6 characters)"),
                 )
                 .arg(
                     Arg::with_name("interactive")
@@ -123,7 +588,8 @@ option in the CLI or using environment variables will override what is specified
                         .takes_value(false)
                         .short("I")
                         .help("Ask for confirmation before deleting each blob."),
-                ),
+                )
+                .arg(bucket_arg()),
         )
         .subcommand(
             SubCommand::with_name("check")
@@ -135,14 +601,232 @@ option in the CLI or using environment variables will override what is specified
                         .index(1)
                         .value_name("REF")
                         .multiple(true)
-                        .help("The reference of the blobs to check"),
+                        .help("The reference of the blobs to check (or a unique prefix of at least 6 characters)"),
                 )
                 .arg(
                     Arg::with_name("metadata")
                         .long("metadata")
                         .required(false)
                         .help("Prints the blob's metadata"),
+                )
+                .arg(bucket_arg()),
+        )
+        .subcommand(tree_commands())
+        .subcommand(snapshot_commands())
+        .subcommand(alias_commands())
+        .subcommand(bucket_commands())
+        .subcommand(car_commands())
+        .subcommand(
+            SubCommand::with_name("export")
+                .setting(AppSettings::ArgRequiredElseHelp)
+                .about("Writes blobs with their metadata to a tar archive, e.g. to copy them to another machine.")
+                .arg(
+                    Arg::with_name("refs")
+                        .long("refs")
+                        .takes_value(true)
+                        .multiple(true)
+                        .value_name("REF")
+                        .help("The reference of the blobs to export (or a unique prefix of at least 6 characters)"),
+                )
+                .arg(
+                    Arg::with_name("all")
+                        .long("all")
+                        .help("Export all the blobs"),
+                )
+                .arg(
+                    Arg::with_name("query")
+                        .long("query")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("FILTER")
+                        .help("Export the blobs matching the filter, e.g. filename=*.txt, mime=image/*, size>1000000 or created>=2021-06-01, can be repeated"),
+                )
+                .group(
+                    ArgGroup::with_name("selection")
+                        .args(&["refs", "all", "query"])
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .required(true)
+                        .value_name("PATH")
+                        .help("The archive to write"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .setting(AppSettings::ArgRequiredElseHelp)
+                .about("Adds the blobs of an archive written by `rstr export`, verifying their content. Blobs already present are skipped.")
+                .arg(
+                    Arg::with_name("path")
+                        .required(true)
+                        .index(1)
+                        .value_name("PATH")
+                        .help("The archive to read"),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .setting(AppSettings::ArgRequiredElseHelp)
+                .about("Lists the blobs only in one of two stores or directories, e.g. the files of a directory which are not in the blob store. Stores are local blob stores, URLs of rstr servers or s3://bucket/prefix/ locations.")
+                .arg(
+                    Arg::with_name("left")
+                        .required(true)
+                        .index(1)
+                        .value_name("LEFT")
+                        .help("The store or directory on the left"),
+                )
+                .arg(
+                    Arg::with_name("right")
+                        .index(2)
+                        .value_name("RIGHT")
+                        .help("The store or directory on the right, by default the blob store"),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Print the differences as JSON"),
+                )
+                .arg(
+                    Arg::with_name("left_token")
+                        .long("left-token")
+                        .value_name("TOKEN")
+                        .help("The API token of the left store, if it is an rstr server"),
+                )
+                .arg(
+                    Arg::with_name("right_token")
+                        .long("right-token")
+                        .value_name("TOKEN")
+                        .help("The API token of the right store, if it is an rstr server"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("sync")
+                .setting(AppSettings::ArgRequiredElseHelp)
+                .about("Copies the blobs missing from a store from another one, verifying their content. Stores are local paths, URLs of rstr servers or s3://bucket/prefix/ locations.")
+                .arg(
+                    Arg::with_name("source")
+                        .required(true)
+                        .index(1)
+                        .value_name("SOURCE")
+                        .help("The store to copy the blobs from"),
+                )
+                .arg(
+                    Arg::with_name("destination")
+                        .required(true)
+                        .index(2)
+                        .value_name("DESTINATION")
+                        .help("The store to copy the blobs to"),
+                )
+                .arg(
+                    Arg::with_name("bidirectional")
+                        .long("bidirectional")
+                        .short("b")
+                        .help("Also copy the blobs missing from the source from the destination"),
+                )
+                .arg(
+                    Arg::with_name("delete")
+                        .long("delete")
+                        .help("Delete the blobs of the destination which are not in the source or, with --bidirectional, the blobs deleted from either store since the last sync"),
+                )
+                .arg(
+                    Arg::with_name("dry_run")
+                        .long("dry-run")
+                        .short("n")
+                        .help("Only show what would be copied and deleted"),
+                )
+                .arg(
+                    Arg::with_name("state")
+                        .long("state")
+                        .value_name("PATH")
+                        .help("The file recording the blobs synced by the last sync with --bidirectional, by default in the configuration directory"),
+                )
+                .arg(
+                    Arg::with_name("source_token")
+                        .long("source-token")
+                        .value_name("TOKEN")
+                        .help("The API token of the source, if it is an rstr server"),
+                )
+                .arg(
+                    Arg::with_name("destination_token")
+                        .long("destination-token")
+                        .value_name("TOKEN")
+                        .help("The API token of the destination, if it is an rstr server"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .setting(AppSettings::ArgRequiredElseHelp)
+                .about("Recreates a directory stored as a tree.")
+                .arg(
+                    Arg::with_name("ref")
+                        .required(true)
+                        .index(1)
+                        .value_name("TREE_REF")
+                        .help("The reference of the tree manifest (or a unique prefix of at least 6 characters)"),
+                )
+                .arg(
+                    Arg::with_name("dest")
+                        .required(true)
+                        .index(2)
+                        .value_name("PATH")
+                        .help("Where to recreate the directory"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rebalance")
+                .about("Moves the blobs to the data store chosen by the placement, e.g. after adding a disk.")
+                .arg(
+                    Arg::with_name("drain")
+                        .long("drain")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("PATH")
+                        .help("Move all the blobs out of this data store, e.g. before removing a disk"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("fsck")
+                .about("Verifies the content of every copy of every blob and lists the corrupted, under-replicated or degraded ones."),
+        )
+        .subcommand(
+            SubCommand::with_name("quarantine")
+                .about("Lists the blobs found corrupted when they were read, with when it happened."),
+        )
+        .subcommand(
+            SubCommand::with_name("repair")
+                .about("Removes the corrupted copies of the blobs and restores the missing ones from a healthy copy."),
+        )
+        .subcommand(
+            SubCommand::with_name("repack")
+                .about("Moves the small blobs to pack files and reclaims the space of the deleted packed blobs."),
+        )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("Upgrades the on-disk layout of the data stores to the one of this version of rstr. An interrupted migration is resumed.")
+                .arg(
+                    Arg::with_name("shard_width")
+                        .long("shard-width")
+                        .value_name("N")
+                        .requires("shard_depth")
+                        .help("Also move the blobs to shard directories named after N characters of their reference"),
+                )
+                .arg(
+                    Arg::with_name("shard_depth")
+                        .long("shard-depth")
+                        .value_name("N")
+                        .requires("shard_width")
+                        .help("Also move the blobs to N levels of shard directories"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("reindex")
+                .about("Rebuilds the index of the blobs from the blobs on disk."),
+        )
+        .subcommand(tier_commands())
         .subcommand(server_commands())
 }
@@ -21,14 +21,50 @@ impl ErrorResponse {
             status_code: code,
         }
     }
+
+    /// Logs an error holding paths of the server, which are not sent to the client.
+    fn internal(error: &str, err: &Error) -> Self {
+        log::error!("{}", err);
+        ErrorResponse::new(error, "Error: The blob store cannot be accessed.", 500)
+    }
 }
 
 impl From<Error> for ErrorResponse {
     fn from(err: Error) -> ErrorResponse {
         match err {
-            Error::BlobNotFound => ErrorResponse::new("BlobNotFound", &err.to_string(), 404),
+            Error::BlobNotFound(_) | Error::PrefixNotFound(_) => {
+                ErrorResponse::new("BlobNotFound", &err.to_string(), 404)
+            }
             Error::InvalidRef => ErrorResponse::new("InvalidReference", &err.to_string(), 400),
+            Error::InvalidCid(_) => ErrorResponse::new("InvalidCid", &err.to_string(), 400),
+            Error::InvalidCar(_) => ErrorResponse::new("InvalidCar", &err.to_string(), 400),
+            Error::InvalidBundle(_) => ErrorResponse::new("InvalidBundle", &err.to_string(), 400),
+            Error::AmbiguousRef(_) => {
+                ErrorResponse::new("AmbiguousReference", &err.to_string(), 400)
+            }
+            Error::InvalidTree => ErrorResponse::new("InvalidTree", &err.to_string(), 400),
+            Error::SnapshotNotFound(_) => {
+                ErrorResponse::new("SnapshotNotFound", &err.to_string(), 404)
+            }
+            Error::InvalidSnapshot => ErrorResponse::new("InvalidSnapshot", &err.to_string(), 500),
+            Error::InvalidAlias => ErrorResponse::new("InvalidAlias", &err.to_string(), 400),
+            Error::AliasNotFound(_) => ErrorResponse::new("AliasNotFound", &err.to_string(), 404),
+            Error::AliasConflict => ErrorResponse::new("AliasConflict", &err.to_string(), 412),
+            Error::InvalidBucket => ErrorResponse::new("InvalidBucket", &err.to_string(), 400),
+            Error::BucketNotFound(_) => ErrorResponse::new("BucketNotFound", &err.to_string(), 404),
+            Error::BucketExists => ErrorResponse::new("BucketExists", &err.to_string(), 409),
+            Error::QuotaExceeded { .. } => {
+                ErrorResponse::new("QuotaExceeded", &err.to_string(), 507)
+            }
+            Error::Corrupted(_) => ErrorResponse::new("BlobCorrupted", &err.to_string(), 500),
+            Error::IncompatibleLayout(_) => ErrorResponse::internal("IncompatibleLayout", &err),
+            Error::SourceNotFound(_) => ErrorResponse::internal("SourceNotFound", &err),
+            Error::NotADirectory(_) => ErrorResponse::internal("NotADirectory", &err),
+            Error::PermissionDenied(..) => ErrorResponse::internal("PermissionDenied", &err),
+            // The content came from the upstream store
+            Error::HashMismatch { .. } => ErrorResponse::new("HashMismatch", &err.to_string(), 502),
             Error::Io(_) => ErrorResponse::new("IO", &err.to_string(), 500),
+            _ => ErrorResponse::internal("Unknown", &err),
         }
     }
 }
@@ -39,6 +75,10 @@ impl From<ErrorResponse> for HttpResponse {
             404 => HttpResponse::NotFound().json(err),
             400 => HttpResponse::BadRequest().json(err),
             401 => HttpResponse::Unauthorized().json(err),
+            409 => HttpResponse::Conflict().json(err),
+            412 => HttpResponse::PreconditionFailed().json(err),
+            502 => HttpResponse::BadGateway().json(err),
+            507 => HttpResponse::InsufficientStorage().json(err),
             _ => HttpResponse::InternalServerError().json(err),
         }
     }
@@ -6,13 +6,29 @@ mod settings;
 mod utils;
 use clap::value_t_or_exit;
 use cli::app;
-use rstr::{self, BlobStore, Result};
+use rstr::{
+    self, AddOptions, Backend, BlobStore, DiffSide, Filter, Result, RetentionPolicy, ShardLayout,
+    SyncOptions,
+};
 use security::generate_token;
 use settings::Settings;
-use std::path::PathBuf;
-use utils::{check_blobs, delete_blobs};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use utils::{
+    check_blobs, delete_blobs, parse_metadata, print_add_plan, print_alias_history,
+    print_blob_checks, print_bucket_entries, print_buckets, print_import_report, print_snapshots,
+    print_store_diff, print_sync_report, print_tree, print_tree_diff, select_blobs,
+};
 
-fn main() -> Result<()> {
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<()> {
     let clap_matches = app().get_matches();
 
     if let Some(clap_matches) = clap_matches.subcommand_matches("create-config") {
@@ -30,14 +46,137 @@ fn main() -> Result<()> {
     let mut settings =
         Settings::from_file(clap_matches.value_of("config").map(PathBuf::from)).unwrap_or_default();
 
-    if let Some(data_store_path) = clap_matches.value_of("data_store_path") {
-        settings.data_store_dir = data_store_path.into();
+    if let Some(data_store_paths) = clap_matches.values_of("data_store_path") {
+        settings.data_store_dir = data_store_paths.map(PathBuf::from).collect();
+    }
+    if let Some(placement) = clap_matches.value_of("placement") {
+        settings.data_store_placement = placement.into();
+    }
+    if clap_matches.is_present("replicas") {
+        settings.data_store_replicas = value_t_or_exit!(clap_matches.value_of("replicas"), usize);
+    }
+    if let Some(erasure_coding) = clap_matches.value_of("erasure_coding") {
+        settings.data_store_erasure_coding = Some(erasure_coding.into());
+    }
+    if clap_matches.is_present("index") {
+        settings.data_store_index = true;
+    }
+    if clap_matches.is_present("verify_on_read") {
+        settings.data_store_verify_on_read = true;
+    }
+    if let Some(cold_store_paths) = clap_matches.values_of("cold_store_path") {
+        settings.data_store_cold_dir = cold_store_paths.map(PathBuf::from).collect();
+    }
+    if let Some(upstream) = clap_matches.value_of("upstream") {
+        settings.data_store_upstream = Some(upstream.into());
+    }
+    if let Some(token) = clap_matches.value_of("upstream_token") {
+        settings.data_store_upstream_token = Some(token.into());
+    }
+    if let Some(endpoint) = clap_matches.value_of("s3_endpoint") {
+        settings.s3_endpoint = Some(endpoint.into());
+    }
+    if let Some(region) = clap_matches.value_of("s3_region") {
+        settings.s3_region = region.into();
+    }
+    if let Some(access_key) = clap_matches.value_of("s3_access_key") {
+        settings.s3_access_key = Some(access_key.into());
+    }
+    if let Some(secret_key) = clap_matches.value_of("s3_secret_key") {
+        settings.s3_secret_key = Some(secret_key.into());
+    }
+    if clap_matches.is_present("cache_size") {
+        settings.cache_max_size = Some(value_t_or_exit!(clap_matches.value_of("cache_size"), u64));
+    }
+    if clap_matches.is_present("write_through") {
+        settings.cache_write_through = true;
+    }
+    if clap_matches.is_present("pack_threshold") {
+        settings.data_store_pack_threshold = Some(value_t_or_exit!(
+            clap_matches.value_of("pack_threshold"),
+            u64
+        ));
+    }
+
+    // Syncing copies between the given stores, not the configured one
+    if let Some(clap_matches) = clap_matches.subcommand_matches("sync") {
+        let source = clap_matches.value_of("source").unwrap();
+        let destination = clap_matches.value_of("destination").unwrap();
+        let options = SyncOptions {
+            bidirectional: clap_matches.is_present("bidirectional"),
+            delete: clap_matches.is_present("delete"),
+            state: Some(clap_matches.value_of("state").map_or_else(
+                || Settings::sync_state_path(source, destination),
+                PathBuf::from,
+            )),
+            dry_run: clap_matches.is_present("dry_run"),
+        };
+        let report = rstr::sync(
+            &settings.backend(source, clap_matches.value_of("source_token"))?,
+            &settings.backend(destination, clap_matches.value_of("destination_token"))?,
+            &options,
+        )?;
+        print_sync_report(&report, source, destination);
+        return Ok(());
     }
 
-    let blob_store = match BlobStore::new(&settings.data_store_dir) {
-        Ok(blob_store) => blob_store,
-        Err(e) => return Err(e),
-    };
+    if let Some(clap_matches) = clap_matches.subcommand_matches("diff") {
+        // A local path is diffed as a directory, unless it is a blob store
+        let open = |location: &str, token| -> Result<Option<Box<dyn Backend>>> {
+            if location.contains("://") || BlobStore::is_blob_store(location) {
+                settings.backend(location, token).map(Some)
+            } else {
+                Ok(None)
+            }
+        };
+        let left = clap_matches.value_of("left").unwrap();
+        let left_store = open(left, clap_matches.value_of("left_token"))?;
+        let (right, right_store) = match clap_matches.value_of("right") {
+            Some(right) => (right, open(right, clap_matches.value_of("right_token"))?),
+            None => (
+                "",
+                Some(Box::new(settings.blob_store()?) as Box<dyn Backend>),
+            ),
+        };
+        let diff = rstr::diff(
+            left_store
+                .as_deref()
+                .map_or(DiffSide::Directory(Path::new(left)), DiffSide::Store),
+            right_store
+                .as_deref()
+                .map_or(DiffSide::Directory(Path::new(right)), DiffSide::Store),
+        )?;
+        print_store_diff(&diff, clap_matches.is_present("json"));
+        return Ok(());
+    }
+
+    // Migrating must not open the blob store, which fails until it is migrated
+    if let Some(clap_matches) = clap_matches.subcommand_matches("migrate") {
+        let migrations = if clap_matches.is_present("shard_width") {
+            let layout = ShardLayout::new(
+                value_t_or_exit!(clap_matches.value_of("shard_width"), usize),
+                value_t_or_exit!(clap_matches.value_of("shard_depth"), usize),
+            )?;
+            BlobStore::reshard(&settings.data_store_dir, layout)?
+        } else {
+            BlobStore::migrate(&settings.data_store_dir)?
+        };
+        for migration in &migrations {
+            println!(
+                "{}\t\tMIGRATED\t\t{} -> {}\t\t{} blobs moved",
+                migration.root.to_string_lossy(),
+                migration.from,
+                migration.to,
+                migration.moved
+            );
+        }
+        if migrations.is_empty() {
+            println!("Already at layout version {}", rstr::LAYOUT_VERSION);
+        }
+        return Ok(());
+    }
+
+    let blob_store = settings.blob_store()?;
 
     if let Some(clap_matches) = clap_matches.subcommand_matches("add") {
         let input_paths: Vec<PathBuf> = clap_matches
@@ -45,9 +184,22 @@ fn main() -> Result<()> {
             .unwrap()
             .map(PathBuf::from)
             .collect();
-        let threads = value_t_or_exit!(clap_matches.value_of("threads"), u8);
+        let options = AddOptions {
+            hash_threads: value_t_or_exit!(clap_matches.value_of("threads"), usize),
+            write_threads: value_t_or_exit!(clap_matches.value_of("write_threads"), usize),
+            ..AddOptions::default()
+        };
 
-        let (blob_refs_with_paths, errors) = blob_store.add_files(&input_paths[..], threads);
+        if clap_matches.is_present("dry_run") {
+            let plan = blob_store.plan_add_with_options(&input_paths[..], &options);
+            print_add_plan(&plan, clap_matches.is_present("json"));
+            return Ok(());
+        }
+
+        let (blob_refs_with_paths, errors) = match clap_matches.value_of("bucket") {
+            Some(bucket) => blob_store.add_files_to_bucket(bucket, &input_paths[..], &options)?,
+            None => blob_store.add_files_with_options(&input_paths[..], &options),
+        };
 
         if clap_matches.is_present("verbose") {
             for (path, blob_ref) in blob_refs_with_paths {
@@ -63,14 +215,256 @@ fn main() -> Result<()> {
         let show_metadata = clap_matches.is_present("metadata");
         let hashes = clap_matches.values_of("refs").unwrap();
 
-        check_blobs(&blob_store, hashes, show_metadata);
+        check_blobs(
+            &blob_store,
+            hashes,
+            show_metadata,
+            clap_matches.value_of("bucket"),
+        );
     }
 
     if let Some(clap_matches) = clap_matches.subcommand_matches("delete") {
         let hashes = clap_matches.values_of("refs").unwrap();
         let interactive = clap_matches.is_present("interactive");
 
-        delete_blobs(&blob_store, hashes, interactive);
+        delete_blobs(
+            &blob_store,
+            hashes,
+            interactive,
+            clap_matches.value_of("bucket"),
+        );
+    }
+
+    if let Some(clap_matches) = clap_matches.subcommand_matches("tree") {
+        if let Some(clap_matches) = clap_matches.subcommand_matches("create") {
+            let tree_ref = blob_store.add_tree(clap_matches.value_of("path").unwrap())?;
+            println!("{}", tree_ref.reference());
+        } else if let Some(clap_matches) = clap_matches.subcommand_matches("ls") {
+            let tree_ref = blob_store.resolve_prefix(clap_matches.value_of("ref").unwrap())?;
+            print_tree(&blob_store.tree(&tree_ref)?);
+        }
+    }
+
+    if let Some(clap_matches) = clap_matches.subcommand_matches("restore") {
+        let tree_ref = blob_store.resolve_prefix(clap_matches.value_of("ref").unwrap())?;
+        blob_store.restore_tree(&tree_ref, clap_matches.value_of("dest").unwrap())?;
+    }
+
+    if let Some(clap_matches) = clap_matches.subcommand_matches("snapshot") {
+        if let Some(clap_matches) = clap_matches.subcommand_matches("create") {
+            let snapshot = blob_store.create_snapshot(
+                clap_matches.value_of("path").unwrap(),
+                clap_matches.value_of("name").unwrap(),
+            )?;
+            print_snapshots(&[snapshot]);
+        } else if let Some(clap_matches) = clap_matches.subcommand_matches("list") {
+            let mut snapshots = blob_store.snapshots()?;
+            if let Some(name) = clap_matches.value_of("name") {
+                snapshots.retain(|s| s.name == name);
+            }
+            print_snapshots(&snapshots);
+        } else if let Some(clap_matches) = clap_matches.subcommand_matches("diff") {
+            let diff = blob_store.diff_snapshots(
+                clap_matches.value_of("old").unwrap(),
+                clap_matches.value_of("new").unwrap(),
+            )?;
+            print_tree_diff(&diff);
+        } else if let Some(clap_matches) = clap_matches.subcommand_matches("restore") {
+            blob_store.restore_snapshot(
+                clap_matches.value_of("id").unwrap(),
+                clap_matches.value_of("dest").unwrap(),
+            )?;
+        } else if let Some(clap_matches) = clap_matches.subcommand_matches("forget") {
+            let keep = |name| {
+                if clap_matches.is_present(name) {
+                    value_t_or_exit!(clap_matches.value_of(name), usize)
+                } else {
+                    0
+                }
+            };
+            let policy = RetentionPolicy {
+                keep_last: keep("keep-last"),
+                keep_hourly: keep("keep-hourly"),
+                keep_daily: keep("keep-daily"),
+                keep_weekly: keep("keep-weekly"),
+                keep_monthly: keep("keep-monthly"),
+                keep_yearly: keep("keep-yearly"),
+            };
+            let forgotten = blob_store.forget_snapshots(&policy, clap_matches.value_of("name"))?;
+            for snapshot in &forgotten {
+                println!("{}\t\tFORGOTTEN", snapshot.short_id());
+            }
+            if clap_matches.is_present("gc") {
+                for blob_ref in blob_store.gc_snapshots(&forgotten)? {
+                    println!("{}\t\tDELETED", blob_ref);
+                }
+            }
+        }
+    }
+
+    if let Some(clap_matches) = clap_matches.subcommand_matches("alias") {
+        if let Some(clap_matches) = clap_matches.subcommand_matches("set") {
+            let name = clap_matches.value_of("name").unwrap();
+            let target = blob_store.resolve_prefix(clap_matches.value_of("ref").unwrap())?;
+            if clap_matches.is_present("create") {
+                blob_store.compare_and_swap_alias(name, None, &target)?;
+            } else if let Some(expected) = clap_matches.value_of("expect") {
+                let expected = blob_store.resolve_prefix(expected)?;
+                blob_store.compare_and_swap_alias(name, Some(&expected), &target)?;
+            } else {
+                blob_store.set_alias(name, &target)?;
+            }
+        } else if let Some(clap_matches) = clap_matches.subcommand_matches("get") {
+            let target = blob_store.resolve(clap_matches.value_of("name").unwrap())?;
+            println!("{}", target.reference());
+        } else if clap_matches.subcommand_matches("list").is_some() {
+            for (name, target) in blob_store.aliases()? {
+                println!("{}\t{}", target.reference(), name);
+            }
+        } else if let Some(clap_matches) = clap_matches.subcommand_matches("history") {
+            print_alias_history(&blob_store.alias_history(clap_matches.value_of("name").unwrap())?);
+        }
+    }
+
+    if let Some(clap_matches) = clap_matches.subcommand_matches("bucket") {
+        if let Some(clap_matches) = clap_matches.subcommand_matches("create") {
+            let quota = clap_matches
+                .is_present("quota")
+                .then(|| value_t_or_exit!(clap_matches.value_of("quota"), u64));
+            let metadata = parse_metadata(clap_matches.values_of("meta").into_iter().flatten());
+            let bucket = blob_store.create_bucket(
+                clap_matches.value_of("name").unwrap(),
+                quota,
+                metadata,
+            )?;
+            print_buckets(&[bucket]);
+        } else if let Some(clap_matches) = clap_matches.subcommand_matches("update") {
+            let name = clap_matches.value_of("name").unwrap();
+            let mut bucket = blob_store.bucket(name)?;
+            if clap_matches.is_present("quota") {
+                bucket.quota = Some(value_t_or_exit!(clap_matches.value_of("quota"), u64));
+            } else if clap_matches.is_present("no_quota") {
+                bucket.quota = None;
+            }
+            for key in clap_matches.values_of("unset_meta").into_iter().flatten() {
+                bucket.metadata.remove(key);
+            }
+            bucket.metadata.extend(parse_metadata(
+                clap_matches.values_of("meta").into_iter().flatten(),
+            ));
+            blob_store.update_bucket(name, bucket.quota, bucket.metadata)?;
+        } else if clap_matches.subcommand_matches("list").is_some() {
+            print_buckets(&blob_store.buckets()?);
+        } else if let Some(clap_matches) = clap_matches.subcommand_matches("ls") {
+            print_bucket_entries(
+                &blob_store.bucket_entries(clap_matches.value_of("name").unwrap())?,
+            );
+        } else if let Some(clap_matches) = clap_matches.subcommand_matches("delete") {
+            for blob_ref in blob_store.delete_bucket(clap_matches.value_of("name").unwrap())? {
+                println!("{}\t\tDELETED", blob_ref);
+            }
+        }
+    }
+
+    if let Some(clap_matches) = clap_matches.subcommand_matches("export") {
+        let filters = clap_matches
+            .values_of("query")
+            .into_iter()
+            .flatten()
+            .map(str::parse)
+            .collect::<Result<Vec<Filter>>>()?;
+        let blob_refs = select_blobs(&blob_store, clap_matches.values_of("refs"), &filters)?;
+        let file = File::create(clap_matches.value_of("output").unwrap())?;
+        blob_store.export_bundle(&blob_refs, BufWriter::new(file))?;
+        for blob_ref in &blob_refs {
+            println!("{}\t\tEXPORTED", blob_ref);
+        }
+    }
+
+    if let Some(clap_matches) = clap_matches.subcommand_matches("import") {
+        let file = File::open(clap_matches.value_of("path").unwrap())?;
+        print_import_report(&blob_store.import_bundle(BufReader::new(file))?);
+    }
+
+    if let Some(clap_matches) = clap_matches.subcommand_matches("car") {
+        if let Some(clap_matches) = clap_matches.subcommand_matches("export") {
+            let blob_refs = select_blobs(&blob_store, clap_matches.values_of("refs"), &[])?;
+            let file = File::create(clap_matches.value_of("output").unwrap())?;
+            blob_store.export_car(&blob_refs, BufWriter::new(file))?;
+            for blob_ref in &blob_refs {
+                println!("{}\t\tEXPORTED\t\t{}", blob_ref, blob_ref.to_cid());
+            }
+        } else if let Some(clap_matches) = clap_matches.subcommand_matches("import") {
+            let file = File::open(clap_matches.value_of("path").unwrap())?;
+            print_import_report(&blob_store.import_car(BufReader::new(file))?);
+        }
+    }
+
+    if let Some(clap_matches) = clap_matches.subcommand_matches("rebalance") {
+        let drain: Vec<PathBuf> = clap_matches
+            .values_of("drain")
+            .into_iter()
+            .flatten()
+            .map(PathBuf::from)
+            .collect();
+        for (blob_ref, root) in blob_store.rebalance(&drain)? {
+            println!("{}\t\tMOVED\t\t{}", blob_ref, root.to_string_lossy());
+        }
+    }
+
+    if clap_matches.subcommand_matches("fsck").is_some() {
+        print_blob_checks(&blob_store.fsck()?);
+    }
+
+    if clap_matches.subcommand_matches("quarantine").is_some() {
+        for entry in &blob_store.quarantine()? {
+            println!(
+                "{}\t\tCORRUPTED\t\t{}",
+                entry.blob_ref,
+                entry
+                    .detected
+                    .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+            );
+        }
+    }
+
+    if clap_matches.subcommand_matches("repair").is_some() {
+        let report = blob_store.repair()?;
+        for (blob_ref, root) in &report.removed {
+            println!("{}\t\tREMOVED\t\t{}", blob_ref, root.to_string_lossy());
+        }
+        for (blob_ref, root) in &report.copied {
+            println!("{}\t\tCOPIED\t\t{}", blob_ref, root.to_string_lossy());
+        }
+        for blob_ref in &report.lost {
+            eprintln!("{}\t\tLOST", blob_ref);
+        }
+    }
+
+    if clap_matches.subcommand_matches("repack").is_some() {
+        let report = blob_store.repack()?;
+        for blob_ref in &report.packed {
+            println!("{}\t\tPACKED", blob_ref);
+        }
+        println!("Reclaimed {} bytes", report.reclaimed);
+    }
+
+    if clap_matches.subcommand_matches("reindex").is_some() {
+        println!("Indexed {} blobs", blob_store.rebuild_index()?);
+    }
+
+    if let Some(clap_matches) = clap_matches.subcommand_matches("tier") {
+        if let Some(clap_matches) = clap_matches.subcommand_matches("run") {
+            if clap_matches.is_present("demote_after") {
+                settings.tier_demote_after_days =
+                    value_t_or_exit!(clap_matches.value_of("demote_after"), u64);
+            }
+            let unused_for = chrono::Duration::days(settings.tier_demote_after_days as i64);
+            let report = blob_store.run_tiering(unused_for)?;
+            for blob_ref in &report.demoted {
+                println!("{}\t\tDEMOTED", blob_ref);
+            }
+        }
     }
 
     if let Some(clap_matches) = clap_matches.subcommand_matches("server") {
@@ -1,4 +1,4 @@
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io::{prelude::*, BufReader};
 use std::path::Path;
 use uuid::Uuid;
@@ -15,6 +15,9 @@ pub fn generate_token(token_store_path: &Path) -> std::io::Result<String> {
 
 /// Appends a new token to the file containing the tokens.
 fn save_token(token: &str, token_store_path: &Path) -> std::io::Result<()> {
+    if let Some(dir) = token_store_path.parent() {
+        fs::create_dir_all(dir)?;
+    }
     let mut file = OpenOptions::new()
         .append(true)
         .create(true)
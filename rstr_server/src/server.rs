@@ -2,102 +2,554 @@ use crate::error::ErrorResponse;
 use crate::security::validate_token;
 use crate::settings::Settings;
 use actix_multipart::Multipart;
+use actix_web::body::SizedStream;
 use actix_web::dev::Service;
+use actix_web::http::Method;
 use actix_web::middleware::Logger;
-use actix_web::{delete, get, post, route, web, App, HttpResponse, HttpServer, Responder};
+use actix_web::{
+    delete, get, post, put, route, web, App, HttpRequest, HttpResponse, HttpServer, Responder,
+};
 use env_logger::Env;
 use futures::future::{ok, Either};
-use futures::{StreamExt, TryStreamExt};
-use rstr::{BlobRef, BlobStore, Sha2Digest};
-use std::io::Write;
-use tempfile::NamedTempFile;
+use futures::io::{AsyncRead, AsyncReadExt};
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use rstr::{AsyncBlobStore, BlobRef, BlobStore, Bucket, BucketEntry, EntryKind, TreeEntry};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::pin::Pin;
+
+/// Size of the chunks the content of the blobs is streamed in
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Query parameters of the routes taking a blob reference
+#[derive(Deserialize)]
+struct RefQuery {
+    /// Whether the reference can be an unambiguous prefix of the full reference
+    #[serde(default)]
+    prefix: bool,
+}
+
+/// Parses the reference of a blob, expanding it if it is a prefix and the request opted
+/// in with `?prefix=true`. The reference can also be the CID of the blob.
+fn parse_ref(blob_store: &BlobStore, hash: &str, query: &RefQuery) -> rstr::Result<BlobRef> {
+    if query.prefix && hash.len() < 64 && !is_cid(hash) {
+        blob_store.resolve_prefix(hash)
+    } else {
+        parse_full_ref(hash)
+    }
+}
+
+/// Parses the full reference of a blob, either in hex or as its CID.
+fn parse_full_ref(hash: &str) -> rstr::Result<BlobRef> {
+    if is_cid(hash) {
+        BlobRef::from_cid(hash)
+    } else {
+        BlobRef::new(hash)
+    }
+}
+
+/// Whether `hash` is meant as a CID rather than as a hex reference or prefix. Base32 CIDs
+/// start with `b` and, unlike hex references, contain letters past `f`.
+fn is_cid(hash: &str) -> bool {
+    hash.starts_with(['b', 'B']) && !hash.bytes().all(|c| c.is_ascii_hexdigit())
+}
 
 #[get("/status")]
 async fn app_status() -> impl Responder {
     HttpResponse::Ok()
 }
 
+#[get("/blobs")]
+async fn list_blobs(blob_store: web::Data<AsyncBlobStore>) -> impl Responder {
+    match blob_store.run(BlobStore::blob_refs).await {
+        Ok(blob_refs) => {
+            let hashes: Vec<&str> = blob_refs.iter().map(BlobRef::reference).collect();
+            HttpResponse::Ok().json(hashes)
+        }
+        Err(e) => HttpResponse::from(ErrorResponse::from(e)),
+    }
+}
+
 #[route("/blobs/{hash}", method = "GET", method = "HEAD")]
 async fn get_blob(
+    req: HttpRequest,
     web::Path((hash,)): web::Path<(String,)>,
-    data: web::Data<Settings>,
+    query: web::Query<RefQuery>,
+    blob_store: web::Data<AsyncBlobStore>,
 ) -> impl Responder {
-    let blob_ref = match BlobRef::new(&hash) {
+    let query = query.into_inner();
+    let blob_ref = match blob_store
+        .run(move |blob_store| parse_ref(blob_store, &hash, &query))
+        .await
+    {
         Ok(blob_ref) => blob_ref,
         Err(e) => return HttpResponse::from(ErrorResponse::from(e)),
     };
 
-    let blob_store = BlobStore::new(&data.data_store_dir).unwrap();
+    // The content of the blob is only read for GET requests. It is streamed, so a
+    // corrupted blob must be caught before responding. Opening the blob first brings it
+    // to the local store, e.g. from the cold tier.
+    let body = if req.method() == Method::HEAD {
+        None
+    } else if blob_store.blob_store().verifies_on_read() {
+        match blob_store.verify_and_open(&blob_ref).await {
+            Ok(reader) => Some(reader),
+            Err(e) => {
+                if let rstr::Error::Corrupted(_) = e {
+                    log::error!("{} is corrupted and has been quarantined", blob_ref);
+                }
+                return HttpResponse::from(ErrorResponse::from(e));
+            }
+        }
+    } else {
+        match blob_store.open(&blob_ref).await {
+            Ok(reader) => Some(reader),
+            Err(e) => return HttpResponse::from(ErrorResponse::from(e)),
+        }
+    };
+    match blob_store.metadata(&blob_ref).await {
+        Ok(metadata) => HttpResponse::Ok()
+            .header("created", &metadata.created_str()[..])
+            .header("filename", &metadata.filename[..])
+            .header("content-disposition", "attachment")
+            .content_type(&metadata.mime_type[..])
+            .body(SizedStream::new(
+                metadata.size,
+                match body {
+                    Some(reader) => read_stream(reader),
+                    None => read_stream(futures::io::empty()),
+                },
+            )),
+        Err(e) => HttpResponse::from(ErrorResponse::from(e)),
+    }
+}
+
+/// Streams the content of `reader` in chunks, e.g. as the body of a response.
+fn read_stream<R>(reader: R) -> Pin<Box<dyn Stream<Item = Result<web::Bytes, actix_web::Error>>>>
+where
+    R: AsyncRead + Unpin + 'static,
+{
+    Box::pin(stream::try_unfold(reader, |mut reader| async move {
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let read = reader.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        chunk.truncate(read);
+        Ok(Some((web::Bytes::from(chunk), reader)))
+    }))
+}
+
+/// JSON representation of a [`TreeEntry`]
+#[derive(Serialize)]
+struct TreeEntryResponse<'a> {
+    path: String,
+    kind: &'static str,
+    mode: String,
+    mtime: String,
+    size: u64,
+    #[serde(rename = "ref")]
+    blob_ref: Option<&'a str>,
+}
+
+impl<'a> From<&'a TreeEntry> for TreeEntryResponse<'a> {
+    fn from(entry: &'a TreeEntry) -> Self {
+        let kind = match entry.kind {
+            EntryKind::File => "file",
+            EntryKind::Directory => "dir",
+            EntryKind::Symlink => "link",
+        };
+        TreeEntryResponse {
+            path: entry.path.to_string_lossy().into_owned(),
+            kind,
+            mode: format!("{:o}", entry.mode),
+            mtime: entry
+                .mtime
+                .to_rfc3339_opts(chrono::SecondsFormat::Nanos, true),
+            size: entry.size,
+            blob_ref: entry.blob_ref.as_ref().map(BlobRef::reference),
+        }
+    }
+}
+
+/// Streams the entries of a tree manifest as newline delimited JSON.
+#[get("/trees/{hash}")]
+async fn get_tree(
+    web::Path((hash,)): web::Path<(String,)>,
+    query: web::Query<RefQuery>,
+    blob_store: web::Data<AsyncBlobStore>,
+) -> impl Responder {
+    let query = query.into_inner();
+    let tree = blob_store
+        .run(move |blob_store| {
+            let blob_ref = parse_ref(blob_store, &hash, &query)?;
+            blob_store.tree(&blob_ref)
+        })
+        .await;
 
-    // TODO: change to stream?
-    match blob_store.get(&blob_ref) {
-        Ok(content) => {
-            let metadata = blob_store.metadata(&blob_ref).unwrap();
+    match tree {
+        Ok(tree) => {
+            let lines = tree.entries.into_iter().map(|entry| {
+                let mut line = serde_json::to_vec(&TreeEntryResponse::from(&entry)).unwrap();
+                line.push(b'\n');
+                Ok::<_, actix_web::Error>(web::Bytes::from(line))
+            });
             HttpResponse::Ok()
-                .header("created", &metadata.created_str()[..])
-                .header("filename", &metadata.filename[..])
-                .header("content-disposition", "attachment")
-                .content_type(&metadata.mime_type[..])
-                .body(content)
+                .content_type("application/x-ndjson")
+                .streaming(stream::iter(lines))
+        }
+        Err(e) => HttpResponse::from(ErrorResponse::from(e)),
+    }
+}
+
+/// JSON representation of an alias and its history
+#[derive(Serialize)]
+struct AliasResponse {
+    name: String,
+    #[serde(rename = "ref")]
+    blob_ref: String,
+    history: Vec<AliasEntryResponse>,
+}
+
+/// JSON representation of an [`rstr::AliasEntry`]
+#[derive(Serialize)]
+struct AliasEntryResponse {
+    #[serde(rename = "ref")]
+    blob_ref: String,
+    updated: String,
+}
+
+/// Body of a request updating an alias
+#[derive(Deserialize)]
+struct SetAliasRequest {
+    #[serde(rename = "ref")]
+    blob_ref: String,
+}
+
+#[get("/refs/{name:.+}")]
+async fn get_alias(
+    web::Path((name,)): web::Path<(String,)>,
+    blob_store: web::Data<AsyncBlobStore>,
+) -> impl Responder {
+    let history = {
+        let name = name.clone();
+        blob_store
+            .run(move |blob_store| blob_store.alias_history(&name))
+            .await
+    };
+
+    match history {
+        Ok(history) if !history.is_empty() => {
+            let blob_ref = history[history.len() - 1].target.reference().to_string();
+            let history: Vec<AliasEntryResponse> = history
+                .into_iter()
+                .map(|entry| AliasEntryResponse {
+                    blob_ref: entry.target.reference().to_string(),
+                    updated: entry
+                        .updated
+                        .to_rfc3339_opts(chrono::SecondsFormat::Nanos, true),
+                })
+                .collect();
+            HttpResponse::Ok().json(AliasResponse {
+                name,
+                blob_ref,
+                history,
+            })
+        }
+        // An alias file left empty, e.g. by an interrupted write, has no current target
+        Ok(_) => HttpResponse::from(ErrorResponse::from(rstr::Error::AliasNotFound(name))),
+        Err(e) => HttpResponse::from(ErrorResponse::from(e)),
+    }
+}
+
+/// Points an alias to a blob. The update is conditional if the request has either an
+/// `If-Match: <ref>` header (the alias must currently point to `<ref>`) or an
+/// `If-None-Match: *` header (the alias must not exist).
+#[put("/refs/{name:.+}")]
+async fn put_alias(
+    web::Path((name,)): web::Path<(String,)>,
+    body: web::Json<SetAliasRequest>,
+    req: HttpRequest,
+    blob_store: web::Data<AsyncBlobStore>,
+) -> impl Responder {
+    let header = |name| {
+        req.headers()
+            .get(name)
+            .map(|value| value.to_str().unwrap_or_default().trim_matches('"'))
+    };
+    let expected = match header("If-Match").map(BlobRef::new).transpose() {
+        Ok(expected) => expected,
+        Err(e) => return HttpResponse::from(ErrorResponse::from(e)),
+    };
+    let create_only = header("If-None-Match") == Some("*");
+
+    let target = match BlobRef::new(&body.blob_ref) {
+        Ok(blob_ref) => blob_ref,
+        Err(e) => return HttpResponse::from(ErrorResponse::from(e)),
+    };
+
+    let result = blob_store
+        .run(move |blob_store| {
+            if create_only {
+                blob_store.compare_and_swap_alias(&name, None, &target)
+            } else if let Some(expected) = &expected {
+                blob_store.compare_and_swap_alias(&name, Some(expected), &target)
+            } else {
+                blob_store.set_alias(&name, &target)
+            }
+        })
+        .await;
+    match result {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => HttpResponse::from(ErrorResponse::from(e)),
+    }
+}
+
+/// JSON representation of a [`Bucket`]
+#[derive(Serialize)]
+struct BucketResponse {
+    name: String,
+    created: String,
+    quota: Option<u64>,
+    metadata: BTreeMap<String, String>,
+    blob_count: usize,
+    usage: u64,
+}
+
+impl From<Bucket> for BucketResponse {
+    fn from(bucket: Bucket) -> Self {
+        BucketResponse {
+            name: bucket.name,
+            created: bucket
+                .created
+                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            quota: bucket.quota,
+            metadata: bucket.metadata,
+            blob_count: bucket.blob_count,
+            usage: bucket.usage,
+        }
+    }
+}
+
+/// JSON representation of a [`BucketEntry`]
+#[derive(Serialize)]
+struct BucketEntryResponse {
+    #[serde(rename = "ref")]
+    blob_ref: String,
+    filename: String,
+    size: u64,
+    added: String,
+}
+
+impl From<BucketEntry> for BucketEntryResponse {
+    fn from(entry: BucketEntry) -> Self {
+        BucketEntryResponse {
+            blob_ref: entry.blob_ref.reference().to_string(),
+            filename: entry.filename,
+            size: entry.size,
+            added: entry
+                .added
+                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        }
+    }
+}
+
+#[get("/buckets")]
+async fn list_buckets(blob_store: web::Data<AsyncBlobStore>) -> impl Responder {
+    match blob_store.run(BlobStore::buckets).await {
+        Ok(buckets) => {
+            let buckets: Vec<BucketResponse> =
+                buckets.into_iter().map(BucketResponse::from).collect();
+            HttpResponse::Ok().json(buckets)
         }
         Err(e) => HttpResponse::from(ErrorResponse::from(e)),
     }
 }
 
+#[get("/buckets/{bucket}")]
+async fn get_bucket(
+    web::Path((bucket,)): web::Path<(String,)>,
+    blob_store: web::Data<AsyncBlobStore>,
+) -> impl Responder {
+    match blob_store
+        .run(move |blob_store| blob_store.bucket(&bucket))
+        .await
+    {
+        Ok(bucket) => HttpResponse::Ok().json(BucketResponse::from(bucket)),
+        Err(e) => HttpResponse::from(ErrorResponse::from(e)),
+    }
+}
+
+#[get("/buckets/{bucket}/blobs")]
+async fn list_bucket_blobs(
+    web::Path((bucket,)): web::Path<(String,)>,
+    blob_store: web::Data<AsyncBlobStore>,
+) -> impl Responder {
+    match blob_store
+        .run(move |blob_store| blob_store.bucket_entries(&bucket))
+        .await
+    {
+        Ok(entries) => {
+            let entries: Vec<BucketEntryResponse> =
+                entries.into_iter().map(BucketEntryResponse::from).collect();
+            HttpResponse::Ok().json(entries)
+        }
+        Err(e) => HttpResponse::from(ErrorResponse::from(e)),
+    }
+}
+
+#[route("/buckets/{bucket}/blobs/{hash}", method = "GET", method = "HEAD")]
+async fn get_bucket_blob(
+    web::Path((bucket, hash)): web::Path<(String, String)>,
+    query: web::Query<RefQuery>,
+    blob_store: web::Data<AsyncBlobStore>,
+) -> impl Responder {
+    let query = query.into_inner();
+    let blob = blob_store
+        .run(move |blob_store| {
+            let blob_ref = parse_ref(blob_store, &hash, &query)?;
+            let entry = blob_store.bucket_entry(&bucket, &blob_ref)?;
+            let content = blob_store.get(&blob_ref)?;
+            let metadata = blob_store.metadata(&blob_ref)?;
+            Ok::<_, rstr::Error>((entry, content, metadata))
+        })
+        .await;
+    match blob {
+        Ok((entry, content, metadata)) => HttpResponse::Ok()
+            .header(
+                "created",
+                &entry
+                    .added
+                    .to_rfc3339_opts(chrono::SecondsFormat::Secs, false)[..],
+            )
+            .header("filename", &entry.filename[..])
+            .header("content-disposition", "attachment")
+            .content_type(&metadata.mime_type[..])
+            .body(content),
+        Err(e) => HttpResponse::from(ErrorResponse::from(e)),
+    }
+}
+
+/// Uploads files to a bucket. Files that would take the bucket over its quota are
+/// rejected with `507 Insufficient Storage`; the files before them are kept.
+#[post("/buckets/{bucket}/blobs")]
+async fn upload_bucket_blobs(
+    web::Path((bucket,)): web::Path<(String,)>,
+    mut payload: Multipart,
+    blob_store: web::Data<AsyncBlobStore>,
+) -> impl Responder {
+    let bucket_name = bucket.clone();
+    if let Err(e) = blob_store
+        .run(move |blob_store| blob_store.bucket(&bucket_name))
+        .await
+    {
+        return HttpResponse::from(ErrorResponse::from(e));
+    }
+
+    let mut blobs: Vec<BlobRef> = Vec::new();
+    loop {
+        let mut field = match payload.try_next().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return invalid_payload(&e),
+        };
+        let content_type = field.content_disposition().unwrap();
+        if content_type.get_name() != Some("file") {
+            while field.next().await.is_some() {}
+            continue;
+        }
+        let filename = sanitize_filename::sanitize(content_type.get_filename().unwrap_or("file"));
+
+        let reader = field
+            .map_err(|e| io::Error::other(e.to_string()))
+            .into_async_read();
+        match blob_store
+            .add_reader_to_bucket(&bucket, reader, &filename)
+            .await
+        {
+            Ok(blob_ref) => {
+                log::info!("{} has been added to {}", blob_ref, bucket);
+                blobs.push(blob_ref)
+            }
+            Err(e) => return HttpResponse::from(ErrorResponse::from(e)),
+        }
+    }
+    let hashes: Vec<&str> = blobs.iter().map(BlobRef::reference).collect();
+    HttpResponse::Ok().json(hashes)
+}
+
+#[delete("/buckets/{bucket}/blobs/{hash}")]
+async fn delete_bucket_blob(
+    web::Path((bucket, hash)): web::Path<(String, String)>,
+    blob_store: web::Data<AsyncBlobStore>,
+) -> impl Responder {
+    let blob_ref = match parse_full_ref(&hash) {
+        Ok(blob_ref) => blob_ref,
+        Err(e) => return HttpResponse::from(ErrorResponse::from(e)),
+    };
+
+    match blob_store
+        .run(move |blob_store| blob_store.remove_from_bucket(&bucket, &blob_ref))
+        .await
+    {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => HttpResponse::from(ErrorResponse::from(e)),
+    }
+}
+
 #[delete("/blobs/{hash}")]
 async fn delete_blob(
     web::Path((hash,)): web::Path<(String,)>,
-    data: web::Data<Settings>,
+    blob_store: web::Data<AsyncBlobStore>,
 ) -> impl Responder {
-    let blob_ref = match BlobRef::new(&hash) {
+    let blob_ref = match parse_full_ref(&hash) {
         Ok(blob_ref) => blob_ref,
         Err(e) => {
             return HttpResponse::from(ErrorResponse::from(e));
         }
     };
 
-    let blob_store = BlobStore::new(&data.data_store_dir).unwrap();
-
-    match blob_store.delete(&blob_ref) {
+    match blob_store.delete(&blob_ref).await {
         Ok(_) => HttpResponse::NoContent().finish(),
         Err(e) => HttpResponse::from(ErrorResponse::from(e)),
     }
 }
 
+/// Responds to a multipart payload which cannot be read, e.g. malformed or cut short.
+fn invalid_payload(e: &actix_multipart::MultipartError) -> HttpResponse {
+    HttpResponse::from(ErrorResponse::new("InvalidPayload", &e.to_string(), 400))
+}
+
 #[post("/blobs")]
-async fn upload_blobs(mut payload: Multipart, data: web::Data<Settings>) -> impl Responder {
-    // TODO: handle errors
+async fn upload_blobs(
+    mut payload: Multipart,
+    blob_store: web::Data<AsyncBlobStore>,
+) -> impl Responder {
     let mut blobs: Vec<BlobRef> = Vec::new();
-    while let Ok(Some(mut field)) = payload.try_next().await {
+    loop {
+        let mut field = match payload.try_next().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return invalid_payload(&e),
+        };
         let content_type = field.content_disposition().unwrap();
+        if content_type.get_name() != Some("file") {
+            while field.next().await.is_some() {}
+            continue;
+        }
+        let filename = sanitize_filename::sanitize(content_type.get_filename().unwrap_or("file"));
 
-        let filename = content_type.get_filename().unwrap_or("file");
-        let filename = sanitize_filename::sanitize(filename);
-
-        let tmp_dir = data.server.tmp_directory.clone();
-        let mut tmp_file = web::block(|| NamedTempFile::new_in(tmp_dir)).await.unwrap();
-
-        let mut hasher = BlobStore::hasher();
-        while let Some(Ok(chunk)) = field.next().await {
-            if content_type.get_name().unwrap() == "file" {
-                hasher.update(&chunk);
-                tmp_file = web::block(move || tmp_file.write_all(&chunk).map(|_| tmp_file))
-                    .await
-                    .unwrap();
+        let reader = field
+            .map_err(|e| io::Error::other(e.to_string()))
+            .into_async_read();
+        match blob_store.add_reader(reader, &filename).await {
+            Ok(blob_ref) => {
+                log::info!("{} has been created", blob_ref);
+                blobs.push(blob_ref)
             }
+            Err(e) => return HttpResponse::from(ErrorResponse::from(e)),
         }
-        let blob_ref = BlobRef::from(hasher);
-
-        let save_path = std::path::PathBuf::from(&data.data_store_dir).join(blob_ref.to_path());
-        web::block(move || {
-            std::fs::create_dir_all(&save_path).unwrap();
-            tmp_file.persist(&save_path.join(&filename))
-        })
-        .await
-        .unwrap();
-
-        log::info!("{} has been created", blob_ref);
-        blobs.push(blob_ref)
     }
     let hashes: Vec<&str> = blobs.iter().map(BlobRef::reference).collect();
     HttpResponse::Ok().json(hashes)
@@ -105,9 +557,19 @@ async fn upload_blobs(mut payload: Multipart, data: web::Data<Settings>) -> impl
 
 fn init_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(app_status);
+    cfg.service(list_blobs);
     cfg.service(get_blob);
     cfg.service(upload_blobs);
     cfg.service(delete_blob);
+    cfg.service(get_tree);
+    cfg.service(get_alias);
+    cfg.service(put_alias);
+    cfg.service(list_buckets);
+    cfg.service(get_bucket);
+    cfg.service(list_bucket_blobs);
+    cfg.service(get_bucket_blob);
+    cfg.service(upload_bucket_blobs);
+    cfg.service(delete_bucket_blob);
 }
 
 #[actix_web::main]
@@ -123,14 +585,22 @@ pub async fn start_server(settings: Settings) -> std::io::Result<()> {
 
     let addr = format!("127.0.0.1:{}", &settings.server.port.to_string());
 
-    if let Err(e) = settings.server.create_dirs() {
-        return Err(e);
-    }
+    settings.server.create_dirs()?;
+
+    // Opening the blob store can be costly, e.g. loading its index, so it is only opened
+    // once and shared by all the requests.
+    let blob_store = AsyncBlobStore::build({
+        let settings = settings.clone();
+        move || settings.blob_store()
+    })
+    .await
+    .map_err(|e| io::Error::other(e.to_string()))?;
 
     HttpServer::new(move || {
         let settings = settings.clone();
         App::new()
             .data(settings.clone())
+            .data(blob_store.clone())
             .wrap_fn( move |req, srv| {
                 let auth_token = req.headers().get("X-Auth-Token");
                 match auth_token {
@@ -155,6 +625,13 @@ pub async fn start_server(settings: Settings) -> std::io::Result<()> {
 mod tests {
     use super::*;
     use actix_web::{http, test, web, App};
+    use rstr::{Backend, CacheOptions, HttpBackend, S3Backend};
+    use std::sync::Mutex;
+
+    /// Opens the blob store described by `settings`, as `start_server` does.
+    fn store(settings: &Settings) -> AsyncBlobStore {
+        AsyncBlobStore::from(settings.blob_store().unwrap())
+    }
 
     #[actix_rt::test]
     async fn test_app_status() {
@@ -167,10 +644,16 @@ mod tests {
     #[actix_rt::test]
     async fn test_get_blob() {
         let settings = Settings {
-            data_store_dir: "../tests/test_data_store".into(),
+            data_store_dir: vec!["../tests/test_data_store".into()],
             ..Settings::default()
         };
-        let mut app = test::init_service(App::new().data(settings).configure(init_routes)).await;
+        let mut app = test::init_service(
+            App::new()
+                .data(store(&settings))
+                .data(settings.clone())
+                .configure(init_routes),
+        )
+        .await;
 
         // Test getting the blob and its metadata
         let url = "/blobs/f29bc64a9d3732b4b9035125fdb3285f5b6455778edca72414671e0ca3b2e0de";
@@ -198,19 +681,38 @@ mod tests {
         assert_eq!(
             result.unwrap().unwrap(),
             web::Bytes::from_static(b"This is a test file.")
-        )
+        );
+
+        // The content is not read for HEAD requests
+        let req = test::TestRequest::default()
+            .method(http::Method::HEAD)
+            .uri(url)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("filename").unwrap(),
+            http::HeaderValue::from_static("test_file.txt")
+        );
+        assert_eq!(test::read_body(resp).await, web::Bytes::new());
     }
 
     #[actix_rt::test]
     async fn test_get_errors() {
         let settings = Settings {
-            data_store_dir: "../tests/test_data_store".into(),
+            data_store_dir: vec!["../tests/test_data_store".into()],
             ..Settings::default()
         };
-        let mut app = test::init_service(App::new().data(settings).configure(init_routes)).await;
+        let mut app = test::init_service(
+            App::new()
+                .data(store(&settings))
+                .data(settings.clone())
+                .configure(init_routes),
+        )
+        .await;
 
         let missing_ref_url =
-            "/blobs/f29bc64a9d3732b4b9035125fdb3285f5b6455778edca72414671e0ca3b2e0dx";
+            "/blobs/f29bc64a9d3732b4b9035125fdb3285f5b6455778edca72414671e0ca3b2e0df";
         let req = test::TestRequest::get().uri(missing_ref_url).to_request();
         let resp = test::call_service(&mut app, req).await;
         assert_eq!(resp.status(), http::StatusCode::NOT_FOUND);
@@ -221,6 +723,674 @@ mod tests {
         assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
     }
 
-    // TODO: how can we test multipart blob upload?
+    #[actix_rt::test]
+    async fn test_get_blob_by_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = Settings {
+            data_store_dir: vec![dir.path().into()],
+            ..Settings::default()
+        };
+        let blob_store = BlobStore::new(dir.path()).unwrap();
+        blob_store.add("../tests/test_file.txt").unwrap();
+        // Fake a second blob sharing the first 10 characters of the reference
+        let other = dir
+            .path()
+            .join("f2/9b/c6")
+            .join(format!("4a9d{}", "0".repeat(54)));
+        std::fs::create_dir_all(&other).unwrap();
+        std::fs::write(other.join("other.txt"), b"other").unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(store(&settings))
+                .data(settings.clone())
+                .configure(init_routes),
+        )
+        .await;
+
+        // Prefixes are only accepted when opting in
+        let req = test::TestRequest::get()
+            .uri("/blobs/f29bc64a9d37")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::get()
+            .uri("/blobs/f29bc64a9d37?prefix=true")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("filename").unwrap(),
+            http::HeaderValue::from_static("test_file.txt")
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/blobs/f29bc64a9d?prefix=true")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"], "AmbiguousReference");
+    }
+
+    #[actix_rt::test]
+    async fn test_blob_by_cid() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = Settings {
+            data_store_dir: vec![dir.path().into()],
+            ..Settings::default()
+        };
+        let blob_store = BlobStore::new(dir.path()).unwrap();
+        let blob_ref = blob_store.add_bytes(b"hello world", "hello.txt").unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(store(&settings))
+                .data(settings.clone())
+                .configure(init_routes),
+        )
+        .await;
+
+        for query in &["", "?prefix=true"] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/blobs/{}{}", blob_ref.to_cid(), query))
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), http::StatusCode::OK);
+            assert_eq!(
+                test::read_body(resp).await,
+                web::Bytes::from_static(b"hello world")
+            );
+        }
+
+        // The CID of a dag-pb node is not the CID of a blob
+        let req = test::TestRequest::get()
+            .uri("/blobs/bafybeiczsscdsbs7ffqz55asqdf3smv6klcw3gofszvwlyarci47bgf354")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"], "InvalidCid");
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/blobs/{}", blob_ref.to_cid()))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::NO_CONTENT);
+        assert!(!blob_store.exists(&blob_ref));
+    }
+
+    #[actix_rt::test]
+    async fn test_get_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        std::fs::create_dir_all(source.join("sub")).unwrap();
+        std::fs::write(source.join("sub/test_file.txt"), b"This is a test file.").unwrap();
+
+        let settings = Settings {
+            data_store_dir: vec![dir.path().join("store")],
+            ..Settings::default()
+        };
+        let tree_ref = settings.blob_store().unwrap().add_tree(&source).unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(store(&settings))
+                .data(settings.clone())
+                .configure(init_routes),
+        )
+        .await;
+
+        let url = format!("/trees/{}", tree_ref.reference());
+        let req = test::TestRequest::get().uri(&url).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+
+        let body = test::read_body(resp).await;
+        let entries: Vec<serde_json::Value> = body
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[2]["path"], "sub/test_file.txt");
+        assert_eq!(entries[2]["kind"], "file");
+        assert_eq!(
+            entries[2]["ref"],
+            "f29bc64a9d3732b4b9035125fdb3285f5b6455778edca72414671e0ca3b2e0de"
+        );
+
+        // The file in the tree is a blob, but not a tree manifest
+        let url = "/trees/f29bc64a9d3732b4b9035125fdb3285f5b6455778edca72414671e0ca3b2e0de";
+        let req = test::TestRequest::get().uri(url).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_aliases() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = Settings {
+            data_store_dir: vec![dir.path().into()],
+            ..Settings::default()
+        };
+        let blob_store = BlobStore::new(dir.path()).unwrap();
+        let v1 = blob_store.add_bytes(b"version 1", "release.txt").unwrap();
+        let v2 = blob_store.add_bytes(b"version 2", "release.txt").unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(store(&settings))
+                .data(settings.clone())
+                .configure(init_routes),
+        )
+        .await;
+
+        let put = |expected: Option<(&str, &str)>, target: &BlobRef| {
+            let mut req = test::TestRequest::put()
+                .uri("/refs/releases/latest")
+                .set_json(&serde_json::json!({ "ref": target.reference() }));
+            if let Some((header, value)) = expected {
+                req = req.header(header, value);
+            }
+            req.to_request()
+        };
+
+        let resp = test::call_service(&mut app, put(Some(("If-None-Match", "*")), &v1)).await;
+        assert_eq!(resp.status(), http::StatusCode::NO_CONTENT);
+        let resp = test::call_service(&mut app, put(Some(("If-None-Match", "*")), &v2)).await;
+        assert_eq!(resp.status(), http::StatusCode::PRECONDITION_FAILED);
+        let resp = test::call_service(&mut app, put(Some(("If-Match", v2.reference())), &v2)).await;
+        assert_eq!(resp.status(), http::StatusCode::PRECONDITION_FAILED);
+        let resp = test::call_service(&mut app, put(Some(("If-Match", v1.reference())), &v2)).await;
+        assert_eq!(resp.status(), http::StatusCode::NO_CONTENT);
+
+        let req = test::TestRequest::get()
+            .uri("/refs/releases/latest")
+            .to_request();
+        let alias: serde_json::Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(alias["name"], "releases/latest");
+        assert_eq!(alias["ref"], v2.reference());
+        assert_eq!(alias["history"].as_array().unwrap().len(), 2);
+        assert_eq!(alias["history"][0]["ref"], v1.reference());
+
+        let req = test::TestRequest::get().uri("/refs/missing").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::NOT_FOUND);
+
+        std::fs::write(dir.path().join("refs/empty"), "").unwrap();
+        let req = test::TestRequest::get().uri("/refs/empty").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_buckets() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = Settings {
+            data_store_dir: vec![dir.path().join("store")],
+            ..Settings::default()
+        };
+        settings.server.tmp_directory = dir.path().join("tmp");
+        std::fs::create_dir_all(&settings.server.tmp_directory).unwrap();
+        let blob_store = settings.blob_store().unwrap();
+        blob_store
+            .create_bucket("team-a", Some(100), BTreeMap::new())
+            .unwrap();
+        blob_store
+            .create_bucket("team-b", None, BTreeMap::new())
+            .unwrap();
+        let blob_ref = blob_store
+            .add_to_bucket("team-a", "../tests/test_file.txt")
+            .unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(store(&settings))
+                .data(settings.clone())
+                .configure(init_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/buckets/team-a").to_request();
+        let bucket: serde_json::Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(bucket["usage"], 20);
+        assert_eq!(bucket["quota"], 100);
+
+        let req = test::TestRequest::get()
+            .uri("/buckets/team-a/blobs")
+            .to_request();
+        let entries: serde_json::Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(entries[0]["ref"], blob_ref.reference());
+        assert_eq!(entries[0]["filename"], "test_file.txt");
+
+        let url = format!("/buckets/team-a/blobs/{}", blob_ref.reference());
+        let req = test::TestRequest::get().uri(&url).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let body = test::read_body(resp).await;
+        assert_eq!(body, web::Bytes::from_static(b"This is a test file."));
+
+        // The blob is in the store, but not in `team-b`
+        let url = format!("/buckets/team-b/blobs/{}", blob_ref.reference());
+        let req = test::TestRequest::get().uri(&url).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::NOT_FOUND);
+
+        let req = test::TestRequest::get()
+            .uri("/buckets/missing/blobs")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::NOT_FOUND);
+
+        let upload = |bucket: &str| {
+            let body = "--BOUNDARY\r\n\
+                Content-Disposition: form-data; name=\"file\"; filename=\"test_file.txt\"\r\n\
+                Content-Type: text/plain\r\n\r\n\
+                This is a test file.\r\n\
+                --BOUNDARY--\r\n";
+            test::TestRequest::post()
+                .uri(&format!("/buckets/{}/blobs", bucket))
+                .header("content-type", "multipart/form-data; boundary=BOUNDARY")
+                .set_payload(body)
+                .to_request()
+        };
+        let refs: Vec<String> = test::read_response_json(&mut app, upload("team-b")).await;
+        assert_eq!(refs, vec![blob_ref.reference().to_string()]);
+
+        // A payload cut short is not stored
+        let body = "--BOUNDARY\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"cut.txt\"\r\n\
+            Content-Type: text/plain\r\n\r\n\
+            This is a";
+        let req = test::TestRequest::post()
+            .uri("/buckets/team-b/blobs")
+            .header("content-type", "multipart/form-data; boundary=BOUNDARY")
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert!(!resp.status().is_success());
+        assert_eq!(blob_store.bucket_entries("team-b").unwrap().len(), 1);
+
+        blob_store
+            .update_bucket("team-b", Some(4), BTreeMap::new())
+            .unwrap();
+        blob_store
+            .create_bucket("team-c", Some(4), BTreeMap::new())
+            .unwrap();
+        let resp = test::call_service(&mut app, upload("team-c")).await;
+        assert_eq!(resp.status(), http::StatusCode::INSUFFICIENT_STORAGE);
+
+        // The content is still in `team-b`
+        let url = format!("/buckets/team-a/blobs/{}", blob_ref.reference());
+        let req = test::TestRequest::delete().uri(&url).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::NO_CONTENT);
+        assert!(blob_store.exists(&blob_ref));
+
+        let url = format!("/buckets/team-b/blobs/{}", blob_ref.reference());
+        let req = test::TestRequest::delete().uri(&url).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::NO_CONTENT);
+        assert!(!blob_store.exists(&blob_ref));
+    }
+
+    #[actix_rt::test]
+    async fn test_upload_replicated() {
+        let dir = tempfile::tempdir().unwrap();
+        let roots = vec![dir.path().join("disk1"), dir.path().join("disk2")];
+        let mut settings = Settings {
+            data_store_dir: roots.clone(),
+            data_store_replicas: 2,
+            data_store_verify_on_read: true,
+            ..Settings::default()
+        };
+        settings.server.tmp_directory = dir.path().join("tmp");
+        std::fs::create_dir_all(&settings.server.tmp_directory).unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(store(&settings))
+                .data(settings.clone())
+                .configure(init_routes),
+        )
+        .await;
+
+        let body = "--BOUNDARY\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"test_file.txt\"\r\n\
+            Content-Type: text/plain\r\n\r\n\
+            This is a test file.\r\n\
+            --BOUNDARY--\r\n";
+        let req = test::TestRequest::post()
+            .uri("/blobs")
+            .header("content-type", "multipart/form-data; boundary=BOUNDARY")
+            .set_payload(body)
+            .to_request();
+        let refs: Vec<String> = test::read_response_json(&mut app, req).await;
+        let blob_ref = BlobRef::new(&refs[0]).unwrap();
+        for root in &roots {
+            assert!(root.join(blob_ref.to_path()).join("test_file.txt").exists());
+        }
+
+        // A corrupted copy is skipped when blobs are verified on read
+        let file = roots[0].join(blob_ref.to_path()).join("test_file.txt");
+        std::fs::write(file, b"This is a test fi1e.").unwrap();
+        let req = test::TestRequest::get()
+            .uri(&format!("/blobs/{}", blob_ref.reference()))
+            .to_request();
+        let body = test::read_response(&mut app, req).await;
+        assert_eq!(body, web::Bytes::from_static(b"This is a test file."));
+    }
+
+    #[actix_rt::test]
+    async fn test_verify_on_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = Settings {
+            data_store_dir: vec![dir.path().join("store")],
+            data_store_verify_on_read: true,
+            ..Settings::default()
+        };
+        let blob_store = settings.blob_store().unwrap();
+        let blob_ref = blob_store.add_bytes(b"hello world", "hello.txt").unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(store(&settings))
+                .data(settings.clone())
+                .configure(init_routes),
+        )
+        .await;
+
+        let url = format!("/blobs/{}", blob_ref.reference());
+        let req = test::TestRequest::get().uri(&url).to_request();
+        let body = test::read_response(&mut app, req).await;
+        assert_eq!(body, web::Bytes::from_static(b"hello world"));
+
+        // A corrupted blob is not served, and is quarantined
+        let file = blob_store.blob_dir(&blob_ref).join("hello.txt");
+        std::fs::write(file, b"hello w0rld").unwrap();
+        let req = test::TestRequest::get().uri(&url).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::INTERNAL_SERVER_ERROR);
+        let error: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(error["error"], "BlobCorrupted");
+
+        let quarantine = blob_store.quarantine().unwrap();
+        assert_eq!(quarantine.len(), 1);
+        assert_eq!(quarantine[0].blob_ref.reference(), blob_ref.reference());
+    }
+
+    #[actix_rt::test]
+    async fn test_upload_indexed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = Settings {
+            data_store_dir: vec![dir.path().join("store")],
+            data_store_index: true,
+            ..Settings::default()
+        };
+        settings.server.tmp_directory = dir.path().join("tmp");
+        std::fs::create_dir_all(&settings.server.tmp_directory).unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .data(store(&settings))
+                .data(settings.clone())
+                .configure(init_routes),
+        )
+        .await;
+
+        let body = "--BOUNDARY\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"test_file.txt\"\r\n\
+            Content-Type: text/plain\r\n\r\n\
+            This is a test file.\r\n\
+            --BOUNDARY--\r\n";
+        let req = test::TestRequest::post()
+            .uri("/blobs")
+            .header("content-type", "multipart/form-data; boundary=BOUNDARY")
+            .set_payload(body)
+            .to_request();
+        let refs: Vec<String> = test::read_response_json(&mut app, req).await;
+        let blob_ref = BlobRef::new(&refs[0]).unwrap();
+        assert!(settings.blob_store().unwrap().exists(&blob_ref));
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/blobs/{}", blob_ref.reference()))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::NO_CONTENT);
+        assert!(!settings.blob_store().unwrap().exists(&blob_ref));
+    }
+
+    #[test]
+    fn test_read_through_cache() {
+        // The test server needs the runtime of actix-web, which predates `actix_rt`
+        let _system = actix_web::rt::System::new("test");
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = Settings {
+            data_store_dir: vec![dir.path().join("central")],
+            ..Settings::default()
+        };
+        settings.server.tmp_directory = dir.path().join("tmp");
+        std::fs::create_dir_all(&settings.server.tmp_directory).unwrap();
+        let central = settings.blob_store().unwrap();
+        let blob_ref = central
+            .add_bytes(b"This is a test file.", "test_file.txt")
+            .unwrap();
+
+        let srv = test::start(move || {
+            App::new()
+                .data(store(&settings))
+                .data(settings.clone())
+                .configure(init_routes)
+        });
+        let options = CacheOptions {
+            max_size: None,
+            write_through: true,
+        };
+        let cache = BlobStore::new(dir.path().join("cache"))
+            .unwrap()
+            .with_upstream(HttpBackend::new(&srv.url("")), options);
+
+        // Blobs are fetched on miss
+        assert!(cache.exists(&blob_ref));
+        assert_eq!(cache.metadata(&blob_ref).unwrap().filename, "test_file.txt");
+        assert_eq!(cache.get(&blob_ref).unwrap(), b"This is a test file.");
+        assert!(dir.path().join("cache").join(blob_ref.to_path()).exists());
+
+        // New blobs are written through and listed on both sides
+        let new_ref = cache
+            .add_bytes(b"This is a new file.", "new_file.txt")
+            .unwrap();
+        assert!(central.exists(&new_ref));
+        assert_eq!(cache.blob_refs().unwrap().len(), 2);
+
+        cache.delete(&new_ref).unwrap();
+        assert!(!central.exists(&new_ref));
+    }
+
+    /// Headers of an object stored by [`MockS3`], i.e. its content type and metadata
+    type Headers = Vec<(String, String)>;
+
+    /// In-memory stand-in for an S3-compatible object storage with a single bucket.
+    #[derive(Default)]
+    struct MockS3 {
+        /// Content and headers of the objects, by key
+        objects: BTreeMap<String, (Vec<u8>, Headers)>,
+        /// Headers and parts of the multipart uploads in progress, by upload id
+        uploads: BTreeMap<String, (Headers, BTreeMap<u32, Vec<u8>>)>,
+    }
+
+    /// Headers of the requests stored with the objects.
+    fn object_headers(req: &HttpRequest) -> Headers {
+        req.headers()
+            .iter()
+            .filter(|(name, _)| {
+                name.as_str() == "content-type" || name.as_str().starts_with("x-amz-meta-")
+            })
+            .map(|(name, value)| (name.to_string(), value.to_str().unwrap().to_string()))
+            .collect()
+    }
+
+    async fn mock_s3(
+        req: HttpRequest,
+        body: web::Bytes,
+        data: web::Data<Mutex<MockS3>>,
+    ) -> HttpResponse {
+        let auth = req
+            .headers()
+            .get("authorization")
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(auth.starts_with("AWS4-HMAC-SHA256 Credential=test-key/"));
+
+        let mut s3 = data.lock().unwrap();
+        let key = req.path().strip_prefix("/bucket/").unwrap().to_string();
+        let query: BTreeMap<&str, &str> = req
+            .query_string()
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .collect();
+        let upload_id = query.get("uploadId").map(|id| id.to_string());
+        match (req.method().as_str(), upload_id) {
+            // Listing, one object per page
+            ("GET", _) if key.is_empty() => {
+                let prefix = query["prefix"].replace("%2F", "/");
+                let start: usize = query
+                    .get("continuation-token")
+                    .map_or(0, |token| token.parse().unwrap());
+                let keys: Vec<&String> = s3
+                    .objects
+                    .keys()
+                    .filter(|k| k.starts_with(&prefix))
+                    .collect();
+                let mut xml = String::from("<ListBucketResult>");
+                if let Some(key) = keys.get(start) {
+                    xml.push_str(&format!("<Contents><Key>{}</Key></Contents>", key));
+                }
+                if start + 1 < keys.len() {
+                    xml.push_str(&format!(
+                        "<IsTruncated>true</IsTruncated><NextContinuationToken>{}</NextContinuationToken>",
+                        start + 1
+                    ));
+                } else {
+                    xml.push_str("<IsTruncated>false</IsTruncated>");
+                }
+                xml.push_str("</ListBucketResult>");
+                HttpResponse::Ok().body(xml)
+            }
+            ("POST", None) => {
+                let upload_id = (s3.uploads.len() + 1).to_string();
+                s3.uploads
+                    .insert(upload_id.clone(), (object_headers(&req), BTreeMap::new()));
+                HttpResponse::Ok().body(format!(
+                    "<InitiateMultipartUploadResult><UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+                    upload_id
+                ))
+            }
+            ("PUT", Some(upload_id)) => {
+                let number: u32 = query["partNumber"].parse().unwrap();
+                let upload = s3.uploads.get_mut(&upload_id).unwrap();
+                upload.1.insert(number, body.to_vec());
+                HttpResponse::Ok()
+                    .header("etag", format!("\"{}\"", number))
+                    .finish()
+            }
+            ("POST", Some(upload_id)) => {
+                let (headers, parts) = s3.uploads.remove(&upload_id).unwrap();
+                assert_eq!(
+                    String::from_utf8(body.to_vec())
+                        .unwrap()
+                        .matches("<Part>")
+                        .count(),
+                    parts.len()
+                );
+                s3.objects
+                    .insert(key, (parts.into_values().flatten().collect(), headers));
+                HttpResponse::Ok().body("<CompleteMultipartUploadResult/>")
+            }
+            ("DELETE", Some(upload_id)) => {
+                s3.uploads.remove(&upload_id);
+                HttpResponse::NoContent().finish()
+            }
+            ("PUT", None) => {
+                let headers = object_headers(&req);
+                s3.objects.insert(key, (body.to_vec(), headers));
+                HttpResponse::Ok().finish()
+            }
+            ("GET", None) | ("HEAD", None) => match s3.objects.get(&key) {
+                Some((content, headers)) => {
+                    let mut response = HttpResponse::Ok();
+                    for (name, value) in headers {
+                        response.header(name.as_str(), value.as_str());
+                    }
+                    response.body(content.clone())
+                }
+                None => HttpResponse::NotFound().finish(),
+            },
+            ("DELETE", None) => {
+                s3.objects.remove(&key);
+                HttpResponse::NoContent().finish()
+            }
+            _ => HttpResponse::MethodNotAllowed().finish(),
+        }
+    }
+
+    #[test]
+    fn test_s3_upstream() {
+        // The test server needs the runtime of actix-web, which predates `actix_rt`
+        let _system = actix_web::rt::System::new("test");
+        let data = web::Data::new(Mutex::new(MockS3::default()));
+        let srv = test::start(move || {
+            App::new()
+                .app_data(data.clone())
+                .app_data(web::PayloadConfig::new(1 << 20))
+                .default_service(web::route().to(mock_s3))
+        });
+        let s3 = S3Backend::new(&srv.url(""), "us-east-1", "bucket")
+            .with_credentials("test-key", "test-secret")
+            .with_prefix("rstr/")
+            .with_part_size(8);
+
+        let dir = tempfile::tempdir().unwrap();
+        let central = BlobStore::new(dir.path().join("central")).unwrap();
+        let small_ref = central.add_bytes(b"small", "small.txt").unwrap();
+        let large_ref = central
+            .add_bytes(b"This is a test file.", "test_file.txt")
+            .unwrap();
+
+        // Blobs larger than a part are uploaded in parts
+        for blob_ref in [&small_ref, &large_ref] {
+            let blob_dir = central.blob_dir(blob_ref);
+            let path = std::fs::read_dir(blob_dir)
+                .unwrap()
+                .next()
+                .unwrap()
+                .unwrap()
+                .path();
+            s3.upload(&path, blob_ref).unwrap();
+        }
+        assert!(s3.exists(&large_ref).unwrap());
+        let metadata = s3.metadata(&large_ref).unwrap();
+        assert_eq!(metadata.filename, "test_file.txt");
+        assert_eq!(metadata.mime_type, "text/plain");
+        assert_eq!(metadata.size, 20);
+        let refs: Vec<String> = s3
+            .blob_refs()
+            .unwrap()
+            .iter()
+            .map(|blob_ref| blob_ref.reference().to_string())
+            .collect();
+        let mut expected = vec![small_ref.reference(), large_ref.reference()];
+        expected.sort();
+        assert_eq!(refs, expected);
+
+        // Blobs are read through from the bucket
+        let cache = BlobStore::new(dir.path().join("cache"))
+            .unwrap()
+            .with_upstream(s3.clone(), CacheOptions::default());
+        assert_eq!(cache.get(&large_ref).unwrap(), b"This is a test file.");
+
+        s3.delete(&large_ref).unwrap();
+        assert!(!s3.exists(&large_ref).unwrap());
+        assert!(matches!(
+            s3.delete(&large_ref),
+            Err(rstr::Error::BlobNotFound(_))
+        ));
+    }
+
     // TODO: test authentication
 }
@@ -1,5 +1,9 @@
 use directories::ProjectDirs;
-use serde::{Deserialize, Serialize};
+use rstr::{
+    Backend, BlobRef, BlobStore, CacheOptions, ErasureCoding, HttpBackend, Placement, S3Backend,
+    Sha2Digest,
+};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::default::Default;
 use std::fs::File;
 use std::io::Write;
@@ -22,11 +26,71 @@ pub struct ServerSettings {
     pub token_store_path: PathBuf,
 }
 
+/// Deserializes either a single path or a list of paths.
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<PathBuf>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(PathBuf),
+        Many(Vec<PathBuf>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(path) => vec![path],
+        OneOrMany::Many(paths) => paths,
+    })
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(default)]
 pub struct Settings {
-    /// Path to the directory where all the blobs will be stored
-    pub data_store_dir: PathBuf,
+    /// Paths to the directories where the blobs will be stored, e.g. one per disk. The
+    /// first one also holds snapshots, aliases and buckets.
+    #[serde(deserialize_with = "one_or_many")]
+    pub data_store_dir: Vec<PathBuf>,
+    /// How new blobs are spread over the directories: `hash-range` or `free-space`
+    pub data_store_placement: String,
+    /// How many directories each new blob is written to
+    pub data_store_replicas: usize,
+    /// Erasure code large blobs across the directories as `K+M`, i.e. `K` data shards
+    /// and `M` parity shards
+    pub data_store_erasure_coding: Option<String>,
+    /// Size in bytes from which blobs are erasure coded
+    pub data_store_erasure_min_size: Option<u64>,
+    /// Size in bytes under which blobs are appended to pack files
+    pub data_store_pack_threshold: Option<u64>,
+    /// Keep an index of the blobs, so that existence checks do not touch the disk
+    pub data_store_index: bool,
+    /// Check that the content of the blobs matches their reference whenever they are read
+    pub data_store_verify_on_read: bool,
+    /// Paths to the directories of the cold tier, where the blobs not read for a while
+    /// are moved to, e.g. on HDD. There is no cold tier if empty.
+    #[serde(deserialize_with = "one_or_many")]
+    pub data_store_cold_dir: Vec<PathBuf>,
+    /// How many days without reads before `rstr tier run` moves a blob to the cold tier
+    pub tier_demote_after_days: u64,
+    /// Store the data store caches, either the URL of a remote rstr server, a bucket of
+    /// an S3-compatible object storage as `s3://bucket/prefix/` or the path to a
+    /// directory. There is none if not set.
+    pub data_store_upstream: Option<String>,
+    /// API token of the remote rstr server used as upstream
+    pub data_store_upstream_token: Option<String>,
+    /// Size in bytes over which the least recently read blobs fetched from the upstream
+    /// store are evicted
+    pub cache_max_size: Option<u64>,
+    /// Also upload the new blobs to the upstream store, and delete them from it
+    pub cache_write_through: bool,
+    /// Endpoint of the S3-compatible object storage, by default the one of AWS S3 in
+    /// `s3_region`
+    pub s3_endpoint: Option<String>,
+    /// Region of the S3-compatible object storage
+    pub s3_region: String,
+    /// Access key of the S3-compatible object storage
+    pub s3_access_key: Option<String>,
+    /// Secret key of the S3-compatible object storage
+    pub s3_secret_key: Option<String>,
     /// Server settings, see [`ServerSettings`]
     pub server: ServerSettings,
 }
@@ -45,15 +109,112 @@ impl Default for ServerSettings {
 
 impl Default for Settings {
     fn default() -> Self {
-        let data_store_dir = project_dirs().data_dir().into();
+        let data_store_dir = vec![project_dirs().data_dir().into()];
         Settings {
             data_store_dir,
+            data_store_placement: "hash-range".into(),
+            data_store_replicas: 1,
+            data_store_erasure_coding: None,
+            data_store_erasure_min_size: None,
+            data_store_pack_threshold: None,
+            data_store_index: false,
+            data_store_verify_on_read: false,
+            data_store_cold_dir: vec![],
+            tier_demote_after_days: 60,
+            data_store_upstream: None,
+            data_store_upstream_token: None,
+            cache_max_size: None,
+            cache_write_through: false,
+            s3_endpoint: None,
+            s3_region: "us-east-1".into(),
+            s3_access_key: None,
+            s3_secret_key: None,
             server: ServerSettings::default(),
         }
     }
 }
 
 impl Settings {
+    /// Opens the blob store described by the configuration.
+    pub fn blob_store(&self) -> rstr::Result<BlobStore> {
+        let placement: Placement = self.data_store_placement.parse()?;
+        let mut blob_store = BlobStore::with_roots(&self.data_store_dir, placement)?
+            .with_replicas(self.data_store_replicas)?;
+        if let Some(threshold) = self.data_store_pack_threshold {
+            blob_store = blob_store.with_packing(threshold)?;
+        }
+        if self.data_store_index {
+            blob_store = blob_store.with_index()?;
+        }
+        if self.data_store_verify_on_read {
+            blob_store = blob_store.with_verify_on_read();
+        }
+        if !self.data_store_cold_dir.is_empty() {
+            let cold = BlobStore::with_roots(&self.data_store_cold_dir, placement)?;
+            blob_store = blob_store.with_cold_tier(cold);
+        }
+        if let Some(upstream) = &self.data_store_upstream {
+            let options = CacheOptions {
+                max_size: self.cache_max_size,
+                write_through: self.cache_write_through,
+            };
+            let token = self.data_store_upstream_token.as_deref();
+            blob_store = blob_store.with_upstream(self.backend(upstream, token)?, options);
+        }
+        match &self.data_store_erasure_coding {
+            Some(coding) => {
+                let mut coding: ErasureCoding = coding.parse()?;
+                if let Some(min_size) = self.data_store_erasure_min_size {
+                    coding.min_size = min_size;
+                }
+                blob_store.with_erasure_coding(coding)
+            }
+            None => Ok(blob_store),
+        }
+    }
+
+    /// Opens the store at `location`: the URL of an rstr server, authenticated with
+    /// `token`, an `s3://bucket/prefix/` location or the path of a local blob store.
+    pub fn backend(&self, location: &str, token: Option<&str>) -> rstr::Result<Box<dyn Backend>> {
+        if location.starts_with("http://") || location.starts_with("https://") {
+            let mut backend = HttpBackend::new(location);
+            if let Some(token) = token {
+                backend = backend.with_token(token);
+            }
+            Ok(Box::new(backend))
+        } else if let Some(location) = location.strip_prefix("s3://") {
+            Ok(Box::new(self.s3_backend(location)))
+        } else {
+            Ok(Box::new(BlobStore::new(location)?))
+        }
+    }
+
+    /// Default path of the file recording the blobs synced between two stores, see
+    /// [`rstr::SyncOptions::state`].
+    pub fn sync_state_path(source: &str, destination: &str) -> PathBuf {
+        let mut hasher = BlobStore::hasher();
+        hasher.update(format!("{}\t{}", source, destination));
+        let key = BlobRef::from(hasher);
+        project_dirs()
+            .config_dir()
+            .join("sync")
+            .join(key.reference())
+    }
+
+    /// Creates the backend of the S3 bucket at `location`, i.e. `bucket/prefix/`.
+    fn s3_backend(&self, location: &str) -> S3Backend {
+        let (bucket, prefix) = location.split_once('/').unwrap_or((location, ""));
+        let endpoint = self
+            .s3_endpoint
+            .clone()
+            .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", self.s3_region));
+        let mut backend = S3Backend::new(&endpoint, &self.s3_region, bucket).with_prefix(prefix);
+        if let (Some(access_key), Some(secret_key)) = (&self.s3_access_key, &self.s3_secret_key) {
+            backend = backend.with_credentials(access_key, secret_key);
+        }
+        backend
+    }
+
     /// Default path for the configuration file.
     fn default_config_path() -> PathBuf {
         project_dirs().config_dir().join("rstr.toml")
@@ -93,7 +254,7 @@ impl Settings {
 
         let toml_str = toml::to_string(&self).unwrap();
         let mut file = File::create(&path)?;
-        file.write_all(&toml_str.as_bytes())?;
+        file.write_all(toml_str.as_bytes())?;
         println!("Created config in {:?}", &path);
 
         Ok(())
@@ -104,7 +265,7 @@ impl ServerSettings {
     /// Create all directories definied in the current configuration.
     pub fn create_dirs(&self) -> std::io::Result<()> {
         std::fs::create_dir_all(&self.tmp_directory)?;
-        std::fs::create_dir_all(&self.token_store_path.parent().unwrap())?;
+        std::fs::create_dir_all(self.token_store_path.parent().unwrap())?;
         Ok(())
     }
 }
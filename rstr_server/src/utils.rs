@@ -1,20 +1,206 @@
-use rstr::{BlobRef, BlobStore};
+use rstr::{
+    AddPlan, AliasEntry, BlobCheck, BlobRef, BlobStore, Bucket, BucketEntry, EntryKind, Error,
+    Filter, ImportReport, PlannedFile, Result, Snapshot, StoreDiff, SyncReport, Tree, TreeDiff,
+};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
 use std::{io, io::Write};
-pub fn delete_blobs<'a, I>(blob_store: &BlobStore, hashes: I, interactive: bool)
-where
+
+/// JSON representation of a [`PlannedFile`]
+#[derive(Serialize)]
+struct PlannedFileReport<'a> {
+    path: String,
+    #[serde(rename = "ref")]
+    blob_ref: &'a str,
+    size: u64,
+}
+
+/// JSON representation of a file that could not be added
+#[derive(Serialize)]
+struct FileErrorReport {
+    path: String,
+    error: String,
+}
+
+/// JSON representation of an [`AddPlan`]
+#[derive(Serialize)]
+struct AddPlanReport<'a> {
+    new: Vec<PlannedFileReport<'a>>,
+    existing: Vec<PlannedFileReport<'a>>,
+    duplicates: Vec<PlannedFileReport<'a>>,
+    errors: Vec<FileErrorReport>,
+    new_bytes: u64,
+    deduplicated_bytes: u64,
+}
+
+impl<'a> From<&'a PlannedFile> for PlannedFileReport<'a> {
+    fn from(file: &'a PlannedFile) -> Self {
+        PlannedFileReport {
+            path: file.path.to_string_lossy().into_owned(),
+            blob_ref: file.blob_ref.reference(),
+            size: file.size,
+        }
+    }
+}
+
+impl<'a> From<&'a AddPlan> for AddPlanReport<'a> {
+    fn from(plan: &'a AddPlan) -> Self {
+        AddPlanReport {
+            new: plan.new.iter().map(PlannedFileReport::from).collect(),
+            existing: plan.existing.iter().map(PlannedFileReport::from).collect(),
+            duplicates: plan
+                .duplicates
+                .iter()
+                .map(PlannedFileReport::from)
+                .collect(),
+            errors: plan
+                .errors
+                .iter()
+                .map(|(path, error)| FileErrorReport {
+                    path: path.to_string_lossy().into_owned(),
+                    error: error.to_string(),
+                })
+                .collect(),
+            new_bytes: plan.new_bytes(),
+            deduplicated_bytes: plan.deduplicated_bytes(),
+        }
+    }
+}
+
+/// JSON representation of a file of a [`StoreDiff`]
+#[derive(Serialize)]
+struct DiffFileReport<'a> {
+    path: String,
+    #[serde(rename = "ref")]
+    blob_ref: &'a str,
+}
+
+/// JSON representation of a [`StoreDiff`]
+#[derive(Serialize)]
+struct StoreDiffReport<'a> {
+    only_left: Vec<&'a str>,
+    only_right: Vec<&'a str>,
+    only_left_files: Vec<DiffFileReport<'a>>,
+    only_right_files: Vec<DiffFileReport<'a>>,
+    errors: Vec<FileErrorReport>,
+}
+
+impl<'a> From<&'a StoreDiff> for StoreDiffReport<'a> {
+    fn from(diff: &'a StoreDiff) -> Self {
+        let files = |files: &'a [(PathBuf, BlobRef)]| {
+            files
+                .iter()
+                .map(|(path, blob_ref)| DiffFileReport {
+                    path: path.to_string_lossy().into_owned(),
+                    blob_ref: blob_ref.reference(),
+                })
+                .collect()
+        };
+        StoreDiffReport {
+            only_left: diff.only_left.iter().map(BlobRef::reference).collect(),
+            only_right: diff.only_right.iter().map(BlobRef::reference).collect(),
+            only_left_files: files(&diff.only_left_files),
+            only_right_files: files(&diff.only_right_files),
+            errors: diff
+                .errors
+                .iter()
+                .map(|(path, error)| FileErrorReport {
+                    path: path.to_string_lossy().into_owned(),
+                    error: error.to_string(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Prints the result of a dry-run of `rstr add`, either as text or as JSON.
+pub fn print_add_plan(plan: &AddPlan, json: bool) {
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&AddPlanReport::from(plan)).unwrap()
+        );
+        return;
+    }
+
+    let sections = [
+        ("NEW", &plan.new),
+        ("EXISTING", &plan.existing),
+        ("DUPLICATE", &plan.duplicates),
+    ];
+    for (status, files) in sections {
+        for file in files {
+            println!(
+                "{}\t{}\t{}",
+                file.blob_ref.reference(),
+                status,
+                file.path.to_string_lossy()
+            );
+        }
+    }
+    for (path, error) in &plan.errors {
+        eprintln!("{}\t{}", error, path.to_string_lossy());
+    }
+    println!(
+        "{} new blobs ({} bytes), {} already present, {} duplicates ({} bytes deduplicated)",
+        plan.new.len(),
+        plan.new_bytes(),
+        plan.existing.len(),
+        plan.duplicates.len(),
+        plan.deduplicated_bytes()
+    );
+}
+/// Parses a full reference or a CID, or expands a prefix of a reference. If it fails, it prints the hash
+/// followed by `INVALID`, `MISSING` or `AMBIGUOUS` and the matching references.
+fn parse_ref(blob_store: &BlobStore, hash: &str) -> Option<BlobRef> {
+    let result = if hash.len() == 64 {
+        BlobRef::new(hash)
+    } else if hash.starts_with(['b', 'B']) && !hash.bytes().all(|c| c.is_ascii_hexdigit()) {
+        BlobRef::from_cid(hash)
+    } else {
+        blob_store.resolve_prefix(hash)
+    };
+    match result {
+        Ok(blob_ref) => Some(blob_ref),
+        Err(Error::PrefixNotFound(_)) => {
+            println!("{}\t\tMISSING", hash);
+            None
+        }
+        Err(Error::AmbiguousRef(candidates)) => {
+            let candidates: Vec<&str> = candidates.iter().map(BlobRef::reference).collect();
+            eprintln!("{}\t\tAMBIGUOUS\t\t{}", hash, candidates.join(" "));
+            None
+        }
+        Err(_) => {
+            eprintln!("{}\t\tINVALID", hash);
+            None
+        }
+    }
+}
+
+/// Deletes the given blobs. If `bucket` is given, the blobs are only removed from it and
+/// their content is deleted only if no other bucket uses it.
+pub fn delete_blobs<'a, I>(
+    blob_store: &BlobStore,
+    hashes: I,
+    interactive: bool,
+    bucket: Option<&str>,
+) where
     I: Iterator<Item = &'a str>,
 {
+    let is_present = |blob_ref: &BlobRef| match bucket {
+        Some(bucket) => blob_store.bucket_entry(bucket, blob_ref).is_ok(),
+        None => blob_store.exists(blob_ref),
+    };
     for hash in hashes {
-        let blob_ref = match BlobRef::new(&hash) {
-            Ok(blob_ref) if !blob_store.exists(&blob_ref) => {
+        let blob_ref = match parse_ref(blob_store, hash) {
+            Some(blob_ref) if !is_present(&blob_ref) => {
                 println!("{}\t\tMISSING", blob_ref);
                 continue;
             }
-            Ok(blob_ref) => blob_ref,
-            Err(_) => {
-                eprintln!("{}\t\tINVALID", &hash);
-                continue;
-            }
+            Some(blob_ref) => blob_ref,
+            None => continue,
         };
 
         if interactive {
@@ -23,40 +209,300 @@ where
             io::stdout().flush().unwrap();
             io::stdin().read_line(&mut confirm).unwrap();
 
-            if confirm.trim().to_ascii_lowercase() != "y" {
+            if !confirm.trim().eq_ignore_ascii_case("y") {
                 continue;
             }
         };
 
-        match blob_store.delete(&blob_ref) {
-            Ok(_) => println!("{}\t\tDELETED", blob_ref),
+        let result = match bucket {
+            Some(bucket) => blob_store.remove_from_bucket(bucket, &blob_ref),
+            None => blob_store.delete(&blob_ref).map(|()| true),
+        };
+        match result {
+            Ok(true) => println!("{}\t\tDELETED", blob_ref),
+            Ok(false) => println!("{}\t\tREMOVED", blob_ref),
             Err(_) => eprintln!("{}\t\tERROR", blob_ref),
         }
     }
 }
 
-pub fn check_blobs<'a, I>(blob_store: &BlobStore, hashes: I, show_metadata: bool)
-where
+/// Checks whether the given blobs are in the blob store, or in `bucket` if given.
+pub fn check_blobs<'a, I>(
+    blob_store: &BlobStore,
+    hashes: I,
+    show_metadata: bool,
+    bucket: Option<&str>,
+) where
     I: Iterator<Item = &'a str>,
 {
     for hash in hashes {
-        let blob_ref = if let Ok(blob_ref) = BlobRef::new(&hash) {
+        let blob_ref = if let Some(blob_ref) = parse_ref(blob_store, hash) {
             blob_ref
         } else {
-            eprintln!("{}\t\tINVALID", &hash);
             continue;
         };
 
-        if !blob_store.exists(&blob_ref) {
+        if let Some(bucket) = bucket {
+            match blob_store.bucket_entry(bucket, &blob_ref) {
+                Ok(entry) if show_metadata => println!(
+                    "{}\t\tPRESENT\t\t{}",
+                    blob_ref,
+                    serde_json::to_string(&entry).unwrap()
+                ),
+                Ok(_) => println!("{}\t\tPRESENT", blob_ref),
+                Err(_) => println!("{}\t\tMISSING", blob_ref),
+            }
+        } else if !blob_store.exists(&blob_ref) {
             println!("{}\t\tMISSING", blob_ref)
         } else if show_metadata {
             println!(
-                "{}\t\tPRESENT\t\t{:?}",
+                "{}\t\tPRESENT\t\t{}",
                 blob_ref,
-                blob_store.metadata(&blob_ref).unwrap()
+                serde_json::to_string(&blob_store.metadata(&blob_ref).unwrap()).unwrap()
             )
         } else {
             println!("{}\t\tPRESENT", blob_ref)
         }
     }
 }
+
+/// Returns the given blobs, or the blobs matching all the filters, or all the blobs if
+/// neither is given. The blobs which cannot be found are reported and left out.
+pub fn select_blobs<'a, I>(
+    blob_store: &BlobStore,
+    hashes: Option<I>,
+    filters: &[Filter],
+) -> Result<Vec<BlobRef>>
+where
+    I: Iterator<Item = &'a str>,
+{
+    match hashes {
+        Some(hashes) => Ok(hashes
+            .filter_map(|hash| match parse_ref(blob_store, hash) {
+                Some(blob_ref) if !blob_store.exists(&blob_ref) => {
+                    println!("{}\t\tMISSING", blob_ref);
+                    None
+                }
+                blob_ref => blob_ref,
+            })
+            .collect()),
+        None if !filters.is_empty() => blob_store.query(filters),
+        None => blob_store.blob_refs(),
+    }
+}
+
+/// Prints what importing a CAR file or a bundle did, one line per blob or block.
+pub fn print_import_report(report: &ImportReport) {
+    for blob_ref in &report.imported {
+        println!("{}\t\tIMPORTED", blob_ref);
+    }
+    for blob_ref in &report.present {
+        println!("{}\t\tPRESENT", blob_ref);
+    }
+    for cid in &report.skipped {
+        println!("{}\t\tSKIPPED", cid);
+    }
+}
+
+/// Prints what a sync copied to and deleted from each store.
+pub fn print_sync_report(report: &SyncReport, source: &str, destination: &str) {
+    for blob_ref in &report.to_destination {
+        println!("{}\t\tCOPIED\t\t{}", blob_ref, destination);
+    }
+    for blob_ref in &report.to_source {
+        println!("{}\t\tCOPIED\t\t{}", blob_ref, source);
+    }
+    for blob_ref in &report.deleted_from_destination {
+        println!("{}\t\tDELETED\t\t{}", blob_ref, destination);
+    }
+    for blob_ref in &report.deleted_from_source {
+        println!("{}\t\tDELETED\t\t{}", blob_ref, source);
+    }
+    for (blob_ref, error) in &report.failed {
+        eprintln!("{}\t\tERROR\t\t{}", blob_ref, error);
+    }
+}
+
+/// Prints the differences between two stores or directories, either as text or as JSON.
+/// The blobs of a directory are printed as the files they come from.
+pub fn print_store_diff(diff: &StoreDiff, json: bool) {
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&StoreDiffReport::from(diff)).unwrap()
+        );
+        return;
+    }
+
+    let sections = [
+        ("LEFT", &diff.only_left, &diff.only_left_files),
+        ("RIGHT", &diff.only_right, &diff.only_right_files),
+    ];
+    for (side, blob_refs, files) in sections {
+        for (path, blob_ref) in files {
+            println!(
+                "{}\t{}\t{}",
+                blob_ref.reference(),
+                side,
+                path.to_string_lossy()
+            );
+        }
+        // The blobs of a directory are all in its files
+        if files.is_empty() {
+            for blob_ref in blob_refs {
+                println!("{}\t{}", blob_ref.reference(), side);
+            }
+        }
+    }
+    for (path, error) in &diff.errors {
+        eprintln!("{}\t{}", error, path.to_string_lossy());
+    }
+}
+
+/// Prints the entries of a tree, one per line, similarly to `ls -l`.
+pub fn print_tree(tree: &Tree) {
+    for entry in &tree.entries {
+        let kind = match entry.kind {
+            EntryKind::File => '-',
+            EntryKind::Directory => 'd',
+            EntryKind::Symlink => 'l',
+        };
+        let blob_ref = entry.blob_ref.as_ref().map_or("-", BlobRef::reference);
+        println!(
+            "{}{:04o}\t{}\t{}\t{}\t{}",
+            kind,
+            entry.mode,
+            entry.size,
+            entry
+                .mtime
+                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            blob_ref,
+            entry.path.to_string_lossy()
+        );
+    }
+}
+
+/// Prints a list of snapshots, one per line.
+pub fn print_snapshots(snapshots: &[Snapshot]) {
+    for snapshot in snapshots {
+        println!(
+            "{}\t{}\t{}\t{}",
+            snapshot.short_id(),
+            snapshot
+                .created
+                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            snapshot.name,
+            snapshot.source.to_string_lossy()
+        );
+    }
+}
+
+/// Prints the paths added (`+`), removed (`-`) and modified (`M`) between two trees.
+pub fn print_tree_diff(diff: &TreeDiff) {
+    for entry in &diff.added {
+        println!("+\t{}", entry.path.to_string_lossy());
+    }
+    for entry in &diff.removed {
+        println!("-\t{}", entry.path.to_string_lossy());
+    }
+    for (_, entry) in &diff.modified {
+        println!("M\t{}", entry.path.to_string_lossy());
+    }
+}
+
+/// Prints the history of an alias, one target per line from oldest to newest.
+pub fn print_alias_history(history: &[AliasEntry]) {
+    for entry in history {
+        println!(
+            "{}\t{}",
+            entry
+                .updated
+                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            entry.target.reference()
+        );
+    }
+}
+
+/// Prints a list of buckets, one per line.
+pub fn print_buckets(buckets: &[Bucket]) {
+    for bucket in buckets {
+        let quota = bucket
+            .quota
+            .map_or_else(|| "-".to_string(), |q| q.to_string());
+        println!(
+            "{}\t{}\t{}/{}\t{} blobs",
+            bucket.name,
+            bucket
+                .created
+                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            bucket.usage,
+            quota,
+            bucket.blob_count
+        );
+        for (key, value) in &bucket.metadata {
+            println!("\t{}={}", key, value);
+        }
+    }
+}
+
+/// Prints the blobs in a bucket, one per line.
+pub fn print_bucket_entries(entries: &[BucketEntry]) {
+    for entry in entries {
+        println!(
+            "{}\t{}\t{}\t{}",
+            entry.blob_ref.reference(),
+            entry.size,
+            entry
+                .added
+                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            entry.filename
+        );
+    }
+}
+
+/// Prints the blobs which failed a check, one line per corrupted copy (or shard) and one
+/// line per under-replicated or degraded blob.
+pub fn print_blob_checks(checks: &[BlobCheck]) {
+    for check in checks {
+        for root in &check.corrupted {
+            println!(
+                "{}\t\tCORRUPTED\t\t{}",
+                check.blob_ref,
+                root.to_string_lossy()
+            );
+        }
+        if check.is_lost() {
+            println!("{}\t\tLOST", check.blob_ref);
+        } else if check.data_shards.is_some() && check.healthy.len() < check.replicas {
+            println!(
+                "{}\t\tDEGRADED\t\t{}/{} shards",
+                check.blob_ref,
+                check.healthy.len(),
+                check.replicas
+            );
+        } else if check.healthy.len() < check.replicas {
+            println!(
+                "{}\t\tUNDER-REPLICATED\t\t{}/{}",
+                check.blob_ref,
+                check.healthy.len(),
+                check.replicas
+            );
+        }
+    }
+}
+
+/// Parses `KEY=VALUE` pairs, exiting with an error message if one is malformed.
+pub fn parse_metadata<'a, I>(pairs: I) -> BTreeMap<String, String>
+where
+    I: Iterator<Item = &'a str>,
+{
+    pairs
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) => (key.to_string(), value.to_string()),
+            None => {
+                eprintln!("Error: invalid metadata `{}`, expected KEY=VALUE", pair);
+                std::process::exit(1)
+            }
+        })
+        .collect()
+}